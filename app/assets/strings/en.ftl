@@ -0,0 +1,6 @@
+# English, the fallback for every other locale
+
+-game-name = Project Pixel
+
+window-title = { -game-name }
+discord-state = Growing Worlds...
@@ -1,4 +1,4 @@
-use assets_pipeline::AssetPipeline;
+use assets_pipeline::{AssetPipeline, CompileOptions};
 use std::io::Write;
 use std::path::Path;
 use std::env;
@@ -11,13 +11,46 @@ pub fn main() {
     }
 
     println!("cargo:rerun-if-changed={}", asset_dir.display());
+    println!("cargo:rerun-if-env-changed=PXL_SIGNING_KEY");
+
+    // Official builds are signed, PXL_SIGNING_KEY points to a file containing the raw 32 byte ed25519 secret key
+    let signing_key = env::var("PXL_SIGNING_KEY").ok()
+        .map(|path| std::fs::read(path).expect("Failed to read PXL_SIGNING_KEY"));
+
+    // Unchanged assets are reused from the last build instead of being compressed again.
+    // Patches for this build name the crate version as the version they apply to.
+    // WAV and FLAC sounds get a gain that brings them to the same loudness.
+    let options = CompileOptions {
+        cache_dir: Some(Path::new(&env::var("OUT_DIR").unwrap()).join("asset-cache")),
+        content_version: env::var("CARGO_PKG_VERSION").unwrap(),
+        audio_loudness: Some(-16.0),
+        ..CompileOptions::default()
+    };
 
     // do NOT use ./assets otherwise it would simply break
-    let databases = AssetPipeline::compile_folder(asset_dir);
+    let databases = AssetPipeline::compile_folder_with(&asset_dir, &options)
+        .unwrap_or_else(|err| panic!("Failed to compile assets: {}", err));
+
+    // Strings one locale has and another doesn't, they fall back to another locale in game
+    let missing_strings = AssetPipeline::missing_strings(&databases)
+        .unwrap_or_else(|err| panic!("Failed to read string tables: {}", err));
+    for (key, missing) in missing_strings {
+        println!("cargo:warning={} is missing {}", key, missing.join(", "));
+    }
+
+    // Files nothing is compiled from, references that don't resolve fail the build already
+    let report = AssetPipeline::build_report(&asset_dir, &databases)
+        .unwrap_or_else(|err| panic!("Failed to check asset dependencies: {}", err));
+    for orphan in report.orphans {
+        println!("cargo:warning={} isn't used by any asset", orphan);
+    }
 
     let mut i = 0;
     for mut database in databases {
-        let bytes = database.to_bytes().unwrap();
+        let bytes = match &signing_key {
+            Some(signing_key) => database.to_signed_bytes(signing_key),
+            None => database.to_bytes()
+        }.unwrap_or_else(|err| panic!("Failed to write asset database: {}", err));
 
         let mut file = std::fs::File::create(format!("./assets-{:04}.pxl", i)).unwrap();
         file.write_all(bytes.as_slice()).unwrap();
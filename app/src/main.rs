@@ -1,15 +1,17 @@
 use graphics_engine::RenderPipeline;
-use audio_engine::AudioSystem;
+use graphics_engine::gl_wrap::Texture2D;
+use audio_engine::{Audio, AudioSystem};
 use event_pipeline::{EventPipeline, Event};
 
 use discord_rpc_client::Client;
 use lazy_static::lazy_static;
 use parking_lot::Mutex;
 
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::sync::Arc;
 use std::time::Duration;
-use assets_pipeline::AssetPipeline;
+use assets_pipeline::{AssetCache, AssetPipeline, DatabasePriority, Localization, MountSource};
 use imgui_debug_utils::{ImGuiConsole, AssetBrowser};
 
 lazy_static! {
@@ -26,6 +28,8 @@ pub struct PxlGame {
     audio_system: AudioSystem,
     event_pipeline: EventPipeline,
     asset_pipeline: AssetPipeline,
+    asset_cache: AssetCache,
+    strings: Localization,
 
     asset_browser: AssetBrowser
 }
@@ -36,14 +40,40 @@ unsafe impl Sync for PxlGame {}
 
 impl PxlGame {
     pub fn new() -> PxlGame {
-        let asset_pipeline = AssetPipeline::new("assets-*.pxl");
+        let mut asset_pipeline = AssetPipeline::new("assets-*.pxl")
+            .expect("Invalid asset database pattern");
+
+        // Loose files in mods/ override the archives
+        if Path::new("mods").is_dir() {
+            if let Err(err) = asset_pipeline.mount("", MountSource::Directory(PathBuf::from("mods")), DatabasePriority::Mod) {
+                log::error!("Failed to mount mods: {}", err);
+            }
+        }
+
+        // Picks up art and shader changes without rebuilding the archives
+        #[cfg(build = "debug")]
+        {
+            if let Err(err) = asset_pipeline.mount_overlay(concat!(env!("CARGO_MANIFEST_DIR"), "/assets")) {
+                log::error!("Failed to mount the asset overlay: {}", err);
+            }
+        }
+
+        // Anything the language of the system doesn't have is shown in English
+        let strings = Localization::load(&asset_pipeline, "strings", &system_locale(), "en")
+            .unwrap_or_else(|err| {
+                log::error!("Failed to load strings: {}", err);
+                Localization::new(Vec::new())
+            });
+
         let asset_browser = AssetBrowser::new(&asset_pipeline);
 
         PxlGame {
-            render_pipeline: RenderPipeline::new("Project Pixel", 800, 600),
+            render_pipeline: RenderPipeline::new(&strings.get("window-title"), 800, 600),
             audio_system: AudioSystem::new(),
             event_pipeline: EventPipeline::new(),
             asset_pipeline,
+            asset_cache: AssetCache::new(),
+            strings,
             asset_browser
         }
     }
@@ -76,15 +106,27 @@ impl PxlGame {
         &mut self.asset_pipeline
     }
 
+    pub fn asset_cache(&self) -> &AssetCache {
+        &self.asset_cache
+    }
+    pub fn asset_cache_mut(&mut self) -> &mut AssetCache {
+        &mut self.asset_cache
+    }
+
+    pub fn strings(&self) -> &Localization {
+        &self.strings
+    }
+
 
     pub fn init(&mut self) {
+        let discord_state = self.strings.get("discord-state");
         std::thread::spawn(move || { // This would freeze if called too many times!
             // Fancy discord RPC
             let mut drpc = Client::new(724417347938549840);
             drpc.start();
 
             drpc.set_activity(|act| {
-                act.state("Growing Worlds...")
+                act.state(discord_state.clone())
                     .assets(|assets|
                         assets
                             .large_image("world")
@@ -99,7 +141,7 @@ impl PxlGame {
         self.render_pipeline.register_renderer(
             |delta| GAME.lock().render(delta));
 
-        self.asset_browser.init(&self.audio_system);
+        self.asset_browser.init();
     }
 
     pub fn run(&mut self) -> ! {
@@ -133,12 +175,21 @@ impl PxlGame {
     }
 
     fn update(&mut self, _delta: &Duration) {
+        // Uploads whatever finished decoding in the background since the last frame
+        self.asset_cache.process::<Texture2D>(&());
+        self.asset_cache.process::<Audio>(&self.audio_system);
+
         #[cfg(build = "debug")]
         {
+            for key in self.asset_pipeline.poll_changes() {
+                self.asset_cache.invalidate(key);
+            }
+            self.asset_browser.reload(&self.asset_pipeline);
+
             let ui = self.render_pipeline.get_imgui_ui().unwrap();
 
             ImGuiConsole::update(ui);
-            self.asset_browser.update(ui);
+            self.asset_browser.update(ui, &self.asset_pipeline, &mut self.asset_cache, &self.audio_system);
 
             ui.show_demo_window(&mut true);
         }
@@ -150,6 +201,20 @@ impl PxlGame {
     }
 }
 
+// E.G de-DE for LANG=de_DE.UTF-8, en if the system doesn't say
+fn system_locale() -> String {
+    let locale = ["LC_ALL", "LC_MESSAGES", "LANG"].iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|locale| !locale.is_empty())
+        .unwrap_or_default();
+
+    let locale = locale.split(&['.', '@'][..]).next().unwrap_or("").replace('_', "-");
+    match locale.as_str() {
+        "" | "C" | "POSIX" => "en".to_string(),
+        _ => locale
+    }
+}
+
 fn main() {
     GAME.lock().init();
     GAME.lock().run();
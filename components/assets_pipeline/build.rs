@@ -0,0 +1,7 @@
+use std::env;
+
+pub fn main() {
+    if let Ok(profile) = env::var("PROFILE") {
+        println!(r"cargo:rustc-cfg=build={:?}", profile);
+    }
+}
@@ -0,0 +1,280 @@
+// Animated textures, imported from animated GIF/APNG files or from spritesheets
+// that have a .anim file next to them describing how the sheet is split up.
+//
+// Example: characters/walk.png + characters/walk.anim
+//   frame_width = 32
+//   frame_height = 32
+//   frames = 6              # Optional, defaults to every cell of the sheet
+//   duration_ms = 100       # Optional, used for every frame
+//   durations = [ 200, 100 ] # Optional, per frame, falls back to duration_ms
+//   loop_mode = "ping_pong" # once, loop or ping_pong. Defaults to loop
+//
+// GIF and APNG files can have a .anim file as well, only loop_mode is used there.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::time::Duration;
+
+use image::{AnimationDecoder, GenericImageView, RgbaImage};
+use serde::Deserialize;
+
+use crate::AssetDatabaseError;
+
+pub const SIDECAR_EXTENSION: &str = "anim";
+
+// Used for frames that don't specify a duration, what browsers do for GIFs as well
+const DEFAULT_FRAME_DURATION_MS: u32 = 100;
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoopMode {
+    Once = 0,     // Stops at the last frame
+    Loop = 1,
+    PingPong = 2  // Plays forwards then backwards
+}
+
+impl From<u8> for LoopMode {
+    fn from(v: u8) -> Self {
+        match v {
+            0 => LoopMode::Once,
+            2 => LoopMode::PingPong,
+
+            _ => LoopMode::Loop
+        }
+    }
+}
+
+impl Default for LoopMode {
+    fn default() -> Self {
+        LoopMode::Loop
+    }
+}
+
+pub struct AnimationFrame {
+    pub image: RgbaImage,
+    pub duration_ms: u32
+}
+
+pub struct AnimatedTexture {
+    pub width: u32,
+    pub height: u32,
+    pub loop_mode: LoopMode,
+    pub frames: Vec<AnimationFrame>
+}
+
+impl AnimatedTexture {
+    pub fn new(width: u32, height: u32, loop_mode: LoopMode) -> AnimatedTexture {
+        AnimatedTexture {
+            width,
+            height,
+            loop_mode,
+            frames: Vec::new()
+        }
+    }
+
+    pub fn push_frame(&mut self, image: RgbaImage, duration_ms: u32) {
+        assert_eq!(image.dimensions(), (self.width, self.height));
+
+        self.frames.push(AnimationFrame {
+            image,
+            duration_ms
+        });
+    }
+
+    pub fn duration_ms(&self) -> u64 {
+        self.frames.iter().map(|frame| frame.duration_ms as u64).sum()
+    }
+
+    // Index of the frame that is visible after the animation played for elapsed
+    pub fn frame_index_at(&self, elapsed: Duration) -> usize {
+        let total = self.duration_ms();
+        if self.frames.len() < 2 || total == 0 {
+            return 0;
+        }
+
+        let elapsed = elapsed.as_millis() as u64;
+        let (time, reversed) = match self.loop_mode {
+            LoopMode::Once => (std::cmp::min(elapsed, total - 1), false),
+            LoopMode::Loop => (elapsed % total, false),
+
+            LoopMode::PingPong => {
+                let time = elapsed % (total * 2);
+
+                if time < total {
+                    (time, false)
+                } else {
+                    (time - total, true)
+                }
+            }
+        };
+
+        let mut passed = 0;
+        for i in 0..self.frames.len() {
+            let frame = if reversed { self.frames.len() - 1 - i } else { i };
+
+            passed += self.frames[frame].duration_ms as u64;
+            if time < passed {
+                return frame;
+            }
+        }
+
+        self.frames.len() - 1
+    }
+
+    pub fn frame_at(&self, elapsed: Duration) -> &AnimationFrame {
+        &self.frames[self.frame_index_at(elapsed)]
+    }
+
+    // Imports an animation from path, None if it's a plain still image
+    pub fn import<P: AsRef<Path>>(key: &str, path: P) -> Result<Option<AnimatedTexture>, AssetDatabaseError> {
+        let path = path.as_ref();
+        let decode_failed = |_| AssetDatabaseError::DecodeFailed { key: key.to_string() };
+
+        let sidecar = AnimationDescription::load(path)?;
+        let extension = path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase());
+
+        let frames = match extension.as_deref() {
+            Some("gif") => {
+                let decoder = image::gif::GifDecoder::new(BufReader::new(File::open(path)?))
+                    .map_err(decode_failed)?;
+
+                decoder.into_frames().collect_frames().map_err(decode_failed)?
+            }
+
+            Some("png") => {
+                let decoder = image::png::PngDecoder::new(BufReader::new(File::open(path)?))
+                    .map_err(decode_failed)?;
+
+                if decoder.is_apng() {
+                    decoder.apng().into_frames().collect_frames().map_err(decode_failed)?
+                } else {
+                    match sidecar {
+                        Some(sidecar) => return sidecar.split_sheet(key, path).map(Some),
+                        None => return Ok(None)
+                    }
+                }
+            }
+
+            _ => return Ok(None)
+        };
+
+        // A single frame GIF is just a texture
+        if frames.len() < 2 {
+            return Ok(None);
+        }
+
+        let (width, height) = frames[0].buffer().dimensions();
+        let loop_mode = sidecar.map(|sidecar| sidecar.loop_mode).unwrap_or_default();
+
+        let mut animation = AnimatedTexture::new(width, height, loop_mode);
+        for frame in frames {
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            let duration_ms = match numer / std::cmp::max(denom, 1) {
+                0 => DEFAULT_FRAME_DURATION_MS,
+                duration_ms => duration_ms
+            };
+
+            // Decoders composite frames onto the full canvas already
+            animation.push_frame(frame.into_buffer(), duration_ms);
+        }
+
+        Ok(Some(animation))
+    }
+}
+
+// Contents of a .anim file
+#[derive(Deserialize)]
+struct AnimationDescription {
+    #[serde(default)]
+    frame_width: u32,
+    #[serde(default)]
+    frame_height: u32,
+    frames: Option<u32>,
+    duration_ms: Option<u32>,
+    #[serde(default)]
+    durations: Vec<u32>,
+    #[serde(default)]
+    loop_mode: LoopMode
+}
+
+impl AnimationDescription {
+    fn load(path: &Path) -> Result<Option<AnimationDescription>, AssetDatabaseError> {
+        let sidecar_path = path.with_extension(SIDECAR_EXTENSION);
+        if !sidecar_path.is_file() {
+            return Ok(None);
+        }
+
+        let content = std::fs::read_to_string(&sidecar_path)?;
+
+        toml::from_str(&content)
+            .map(Some)
+            .map_err(|err| AssetDatabaseError::InvalidSidecar { path: sidecar_path, message: err.to_string() })
+    }
+
+    // Cuts a spritesheet into frames, left to right then top to bottom
+    fn split_sheet(&self, key: &str, path: &Path) -> Result<AnimatedTexture, AssetDatabaseError> {
+        let invalid = |message: &str| AssetDatabaseError::InvalidSidecar {
+            path: path.with_extension(SIDECAR_EXTENSION),
+            message: message.to_string()
+        };
+
+        let sheet = image::open(path)
+            .map_err(|_| AssetDatabaseError::DecodeFailed { key: key.to_string() })?
+            .into_rgba();
+
+        if self.frame_width == 0 || self.frame_height == 0 {
+            return Err(invalid("frame_width and frame_height are required for spritesheets"));
+        }
+
+        let columns = sheet.width() / self.frame_width;
+        let cells = columns * (sheet.height() / self.frame_height);
+        let frame_count = self.frames.unwrap_or(cells);
+
+        if frame_count == 0 || frame_count > cells {
+            return Err(invalid("spritesheet doesn't contain that many frames"));
+        }
+
+        let mut animation = AnimatedTexture::new(self.frame_width, self.frame_height, self.loop_mode);
+        for i in 0..frame_count {
+            let x = (i % columns) * self.frame_width;
+            let y = (i / columns) * self.frame_height;
+
+            let frame = sheet.view(x, y, self.frame_width, self.frame_height).to_image();
+            let duration_ms = self.durations.get(i as usize)
+                .cloned()
+                .or(self.duration_ms)
+                .unwrap_or(DEFAULT_FRAME_DURATION_MS);
+
+            animation.push_frame(frame, duration_ms);
+        }
+
+        Ok(animation)
+    }
+}
+
+#[test]
+fn frame_lookup_test() {
+    let mut animation = AnimatedTexture::new(1, 1, LoopMode::Loop);
+    animation.push_frame(RgbaImage::new(1, 1), 100);
+    animation.push_frame(RgbaImage::new(1, 1), 50);
+    animation.push_frame(RgbaImage::new(1, 1), 100);
+
+    let at = |animation: &AnimatedTexture, ms| animation.frame_index_at(Duration::from_millis(ms));
+
+    assert_eq!(at(&animation, 0), 0);
+    assert_eq!(at(&animation, 120), 1);
+    assert_eq!(at(&animation, 150), 2);
+    assert_eq!(at(&animation, 260), 0);
+
+    animation.loop_mode = LoopMode::Once;
+    assert_eq!(at(&animation, 1000), 2);
+
+    animation.loop_mode = LoopMode::PingPong;
+    assert_eq!(at(&animation, 260), 2);
+    assert_eq!(at(&animation, 360), 1);
+    assert_eq!(at(&animation, 400), 0);
+    assert_eq!(at(&animation, 520), 0);
+}
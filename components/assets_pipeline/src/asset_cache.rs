@@ -0,0 +1,659 @@
+// Decoded assets shared by everyone who needs them. Assets are decoded the first time
+// they're requested and handed out as Handle<T>, every handle to the same key and type
+// points at the same asset.
+//
+// Assets nobody holds a handle to anymore stay cached until the byte budget runs out,
+// then the least recently used ones are dropped first.
+//
+//   let texture: Handle<Texture2D> = cache.load(&pipeline, "textures/world.png", &())?;
+//   let music: Handle<Audio> = cache.load(&pipeline, "audio/theme.ogg", &audio_system)?;
+//
+// load_async() reads and decodes on worker threads instead and returns a pending handle,
+// the thread owning the context (E.G the GL context for textures) finishes them in process():
+//
+//   let world: Handle<Texture2D> = cache.load_async(&pipeline, "textures/world.png")?;
+//   ...
+//   cache.process::<Texture2D>(&()); // Once per frame on the render thread
+//   if let Some(texture) = world.get() { ... }
+
+use std::any::{Any, TypeId};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use audio_engine::{Audio, AudioSystem};
+
+use crate::{AssetDatabaseError, AssetEntry, AssetPipeline, EntryLocation};
+
+pub const DEFAULT_CACHE_BUDGET: usize = 0x10000000; // 256 MB
+
+// Progress of entries that don't come from a database
+pub const OVERLAY_PROGRESS: &str = "@overlay";
+
+// Anything that can be decoded from an entry. Loading is split in two so the heavy lifting
+// can happen on a worker thread, Context is whatever the second half needs (E.G the AudioSystem for sounds)
+pub trait Asset: Sized + 'static {
+    type Context: ?Sized;
+    type Decoded: Send + 'static;
+
+    // Called on any thread
+    fn decode(entry: AssetEntry) -> Result<Self::Decoded, AssetDatabaseError>;
+
+    // Called on the thread that owns the context
+    fn load(decoded: Self::Decoded, context: &Self::Context) -> Result<Self, AssetDatabaseError>;
+
+    // Roughly how much memory the loaded asset takes up, counted against the budget
+    fn memory_size(&self) -> usize;
+}
+
+// FMOD decodes it itself
+impl Asset for Audio {
+    type Context = AudioSystem;
+    type Decoded = AssetEntry;
+
+    fn decode(entry: AssetEntry) -> Result<AssetEntry, AssetDatabaseError> {
+        Ok(entry)
+    }
+
+    fn load(entry: AssetEntry, audio_system: &AudioSystem) -> Result<Audio, AssetDatabaseError> {
+        Ok(entry.into_audio(audio_system))
+    }
+
+    fn memory_size(&self) -> usize {
+        Audio::memory_size(self)
+    }
+}
+
+enum LoadState<T> {
+    Pending,
+    Ready(T),
+    Failed(String)
+}
+
+pub struct Handle<T> {
+    key: String,
+    state: Arc<Mutex<LoadState<T>>>
+}
+
+impl<T> Handle<T> {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    // Handles from load() are always ready, reloads keep the old version until the new one is
+    pub fn is_ready(&self) -> bool {
+        matches!(*self.state.lock().unwrap(), LoadState::Ready(_))
+    }
+
+    pub fn is_pending(&self) -> bool {
+        matches!(*self.state.lock().unwrap(), LoadState::Pending)
+    }
+
+    pub fn error(&self) -> Option<String> {
+        match &*self.state.lock().unwrap() {
+            LoadState::Failed(err) => Some(err.clone()),
+            _ => None
+        }
+    }
+
+    // None while it's pending or if it failed to load
+    pub fn get(&self) -> Option<AssetGuard<'_, T>> {
+        let guard = self.state.lock().unwrap();
+
+        match *guard {
+            LoadState::Ready(_) => Some(AssetGuard { guard }),
+            _ => None
+        }
+    }
+
+    // Reloading an asset replaces it in place, so don't hold on to the guard for longer than needed.
+    // Panics if the asset isn't ready, see get() for handles from load_async()
+    pub fn lock(&self) -> AssetGuard<'_, T> {
+        match self.get() {
+            Some(guard) => guard,
+            None => panic!("{} isn't loaded", self.key)
+        }
+    }
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        Handle {
+            key: self.key.clone(),
+            state: self.state.clone()
+        }
+    }
+}
+
+pub struct AssetGuard<'a, T> {
+    guard: MutexGuard<'a, LoadState<T>> // Always LoadState::Ready
+}
+
+impl<'a, T> Deref for AssetGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match &*self.guard {
+            LoadState::Ready(asset) => asset,
+            _ => unreachable!()
+        }
+    }
+}
+
+impl<'a, T> DerefMut for AssetGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        match &mut *self.guard {
+            LoadState::Ready(asset) => asset,
+            _ => unreachable!()
+        }
+    }
+}
+
+// Entries and bytes of a database that were requested through load_async() and how many of them are done
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct LoadProgress {
+    pub entries: usize,
+    pub entries_done: usize, // Loaded or failed, only counted by process()
+    pub bytes: u64,          // As they're stored in the archive
+    pub bytes_done: u64
+}
+
+impl LoadProgress {
+    pub fn is_done(&self) -> bool {
+        self.entries_done == self.entries
+    }
+
+    // 0.0 - 1.0, by bytes as that's what takes the time
+    pub fn fraction(&self) -> f32 {
+        if self.bytes == 0 {
+            return if self.is_done() { 1.0 } else { 0.0 };
+        }
+
+        self.bytes_done as f32 / self.bytes as f32
+    }
+}
+
+// Arc<Mutex<LoadState<T>>> without the T, so assets of every type fit into one map
+trait CachedAsset {
+    fn handles(&self) -> usize;
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl<T: 'static> CachedAsset for Arc<Mutex<LoadState<T>>> {
+    fn handles(&self) -> usize {
+        Arc::strong_count(self) - 1 // The cache holds one itself
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+struct CacheSlot {
+    asset: Box<dyn CachedAsset>,
+    size: usize,
+
+    database: Option<String>, // None for overlay entries
+    last_used: u64,
+    stale: bool,  // The entry changed, decoded again on the next load()
+    loading: bool // A worker is decoding it
+}
+
+// A worker is done with an entry, process() picks it up
+struct FinishedJob {
+    id: (TypeId, String),
+    progress: String,
+    decoded: Result<Box<dyn Any + Send>, AssetDatabaseError>
+}
+
+pub struct AssetCache {
+    slots: HashMap<(TypeId, String), CacheSlot>,
+
+    finished: Arc<Mutex<Vec<FinishedJob>>>,
+    progress: Arc<Mutex<BTreeMap<String, LoadProgress>>>, // Database name -> progress
+
+    budget: usize,
+    used: usize,
+    clock: u64
+}
+
+impl AssetCache {
+    pub fn new() -> AssetCache {
+        AssetCache::with_budget(DEFAULT_CACHE_BUDGET)
+    }
+
+    pub fn with_budget(budget: usize) -> AssetCache {
+        AssetCache {
+            slots: HashMap::new(),
+
+            finished: Arc::new(Mutex::new(Vec::new())),
+            progress: Arc::new(Mutex::new(BTreeMap::new())),
+
+            budget,
+            used: 0,
+            clock: 0
+        }
+    }
+
+    // Loads it right away if it isn't cached, assets that are still pending are loaded right away as well
+    pub fn load<T: Asset, S: AsRef<str>>(&mut self, pipeline: &AssetPipeline, key: S, context: &T::Context) -> Result<Handle<T>, AssetDatabaseError> {
+        let key = key.as_ref();
+        let id = (TypeId::of::<T>(), key.to_string());
+
+        self.clock += 1;
+
+        if let Some(slot) = self.slots.get_mut(&id) {
+            slot.last_used = self.clock;
+
+            let state = AssetCache::state_of::<T>(slot);
+
+            // Everyone holding a handle sees the new version
+            let is_ready = matches!(*state.lock().unwrap(), LoadState::Ready(_));
+            if slot.stale || !is_ready {
+                let location = pipeline.locate(key)
+                    .ok_or_else(|| AssetDatabaseError::NotFound { key: key.to_string() })?;
+
+                let asset = AssetCache::load_now::<T>(&location, context)?;
+                let size = asset.memory_size();
+
+                *state.lock().unwrap() = LoadState::Ready(asset);
+
+                self.used = self.used - slot.size + size;
+                slot.size = size;
+                slot.database = location.database().map(|name| name.to_string());
+                slot.stale = false;
+                slot.loading = false; // Whatever the worker comes up with is outdated now
+
+                self.evict();
+            }
+
+            return Ok(Handle { key: key.to_string(), state });
+        }
+
+        let location = pipeline.locate(key)
+            .ok_or_else(|| AssetDatabaseError::NotFound { key: key.to_string() })?;
+
+        log::debug!("Loading {}", key);
+
+        let asset = AssetCache::load_now::<T>(&location, context)?;
+        let size = asset.memory_size();
+        let state = Arc::new(Mutex::new(LoadState::Ready(asset)));
+
+        self.slots.insert(id, CacheSlot {
+            asset: Box::new(state.clone()),
+            size,
+
+            database: location.database().map(|name| name.to_string()),
+            last_used: self.clock,
+            stale: false,
+            loading: false
+        });
+        self.used += size;
+
+        self.evict();
+
+        Ok(Handle { key: key.to_string(), state })
+    }
+
+    // The entry is read and decoded on a worker thread, the handle is pending until process::<T>() finished it.
+    // Stale assets keep their old version until then.
+    pub fn load_async<T: Asset, S: AsRef<str>>(&mut self, pipeline: &AssetPipeline, key: S) -> Result<Handle<T>, AssetDatabaseError> {
+        let key = key.as_ref();
+        let id = (TypeId::of::<T>(), key.to_string());
+
+        self.clock += 1;
+
+        if let Some(slot) = self.slots.get_mut(&id) {
+            slot.last_used = self.clock;
+
+            let state = AssetCache::state_of::<T>(slot);
+
+            if slot.stale && !slot.loading {
+                let location = pipeline.locate(key)
+                    .ok_or_else(|| AssetDatabaseError::NotFound { key: key.to_string() })?;
+
+                slot.loading = true;
+                self.spawn::<T>(id, location);
+            }
+
+            return Ok(Handle { key: key.to_string(), state });
+        }
+
+        let location = pipeline.locate(key)
+            .ok_or_else(|| AssetDatabaseError::NotFound { key: key.to_string() })?;
+
+        let state = Arc::new(Mutex::new(LoadState::Pending));
+
+        self.slots.insert(id.clone(), CacheSlot {
+            asset: Box::new(state.clone()),
+            size: 0,
+
+            database: location.database().map(|name| name.to_string()),
+            last_used: self.clock,
+            stale: false,
+            loading: true
+        });
+
+        self.spawn::<T>(id, location);
+
+        Ok(Handle { key: key.to_string(), state })
+    }
+
+    fn spawn<T: Asset>(&self, id: (TypeId, String), location: EntryLocation) {
+        let progress_key = location.database().unwrap_or(OVERLAY_PROGRESS).to_string();
+        let bytes = location.entry().size() as u64;
+
+        {
+            let mut progress = self.progress.lock().unwrap();
+            let progress = progress.entry(progress_key.clone()).or_default();
+
+            progress.entries += 1;
+            progress.bytes += bytes;
+        }
+
+        let finished = self.finished.clone();
+        let progress = self.progress.clone();
+
+        rayon::spawn(move || {
+            log::debug!("Loading {} in the background", id.1);
+
+            let decoded = location.load()
+                .and_then(|entry| T::decode(AssetEntry::clone(&entry)))
+                .map(|decoded| Box::new(decoded) as Box<dyn Any + Send>);
+
+            if let Some(progress) = progress.lock().unwrap().get_mut(&progress_key) {
+                progress.bytes_done += bytes;
+            }
+
+            finished.lock().unwrap().push(FinishedJob {
+                id,
+                progress: progress_key,
+                decoded
+            });
+        });
+    }
+
+    // Finishes every asset of type T the workers are done with, call it once per frame
+    // on the thread that owns the context. Returns how many were finished.
+    pub fn process<T: Asset>(&mut self, context: &T::Context) -> usize {
+        let jobs: Vec<FinishedJob> = {
+            let mut finished = self.finished.lock().unwrap();
+            let (jobs, others) = finished.drain(..)
+                .partition(|job| job.id.0 == TypeId::of::<T>());
+
+            *finished = others;
+
+            jobs
+        };
+
+        let count = jobs.len();
+        for job in jobs {
+            if let Some(progress) = self.progress.lock().unwrap().get_mut(&job.progress) {
+                progress.entries_done += 1;
+            }
+
+            // Evicted or loaded by load() in the meantime
+            let slot = match self.slots.get_mut(&job.id) {
+                Some(slot) if slot.loading => slot,
+                _ => continue
+            };
+
+            slot.loading = false;
+            slot.stale = false;
+
+            let state = AssetCache::state_of::<T>(slot);
+            let loaded = job.decoded.and_then(|decoded| {
+                let decoded = decoded.downcast::<T::Decoded>()
+                    .unwrap(); // Jobs are keyed by type
+
+                T::load(*decoded, context)
+            });
+
+            let mut state = state.lock().unwrap();
+            match loaded {
+                Ok(asset) => {
+                    let size = asset.memory_size();
+                    *state = LoadState::Ready(asset);
+
+                    self.used = self.used - slot.size + size;
+                    slot.size = size;
+                }
+
+                Err(err) => {
+                    log::error!("Failed to load {}: {}", job.id.1, err);
+
+                    // A reload that failed keeps the old version
+                    if !matches!(*state, LoadState::Ready(_)) {
+                        *state = LoadState::Failed(err.to_string());
+                    }
+                }
+            }
+        }
+
+        self.evict();
+
+        count
+    }
+
+    fn load_now<T: Asset>(location: &EntryLocation, context: &T::Context) -> Result<T, AssetDatabaseError> {
+        let entry = location.load()?;
+
+        T::load(T::decode(AssetEntry::clone(&entry))?, context)
+    }
+
+    fn state_of<T: Asset>(slot: &CacheSlot) -> Arc<Mutex<LoadState<T>>> {
+        slot.asset.as_any()
+            .downcast_ref::<Arc<Mutex<LoadState<T>>>>()
+            .unwrap() // Slots are keyed by type
+            .clone()
+    }
+
+    // Progress of everything requested through load_async() per database, until clear_progress() is called
+    pub fn progress(&self) -> BTreeMap<String, LoadProgress> {
+        self.progress.lock().unwrap().clone()
+    }
+
+    // Progress of all databases combined
+    pub fn total_progress(&self) -> LoadProgress {
+        self.progress.lock().unwrap().values()
+            .fold(LoadProgress::default(), |total, progress| LoadProgress {
+                entries: total.entries + progress.entries,
+                entries_done: total.entries_done + progress.entries_done,
+                bytes: total.bytes + progress.bytes,
+                bytes_done: total.bytes_done + progress.bytes_done
+            })
+    }
+
+    // Forgets the progress of databases that are done, E.G once the loading screen is gone
+    pub fn clear_progress(&mut self) {
+        let mut progress = self.progress.lock().unwrap();
+        let done: Vec<String> = progress.iter()
+            .filter(|(_, progress)| progress.is_done())
+            .map(|(database, _)| database.clone())
+            .collect();
+
+        for database in done {
+            progress.remove(&database);
+        }
+    }
+
+    // Call it with the keys from AssetPipeline::subscribe(), cached assets of that key are
+    // decoded again the next time they're loaded.
+    pub fn invalidate<S: AsRef<str>>(&mut self, key: S) {
+        for (slot_key, slot) in self.slots.iter_mut() {
+            if slot_key.1 == key.as_ref() {
+                slot.stale = true;
+            }
+        }
+    }
+
+    // Drops the least recently used assets until we're within budget again,
+    // assets somebody still holds a handle to wouldn't free anything so they're kept.
+    fn evict(&mut self) {
+        while self.used > self.budget {
+            let oldest = self.slots.iter()
+                .filter(|(_, slot)| slot.asset.handles() == 0)
+                .min_by_key(|(_, slot)| slot.last_used)
+                .map(|(id, _)| id.clone());
+
+            let id = match oldest {
+                Some(id) => id,
+                None => break
+            };
+
+            let slot = self.slots.remove(&id).unwrap();
+            self.used -= slot.size;
+
+            log::debug!("Evicted {} ({})", id.1, bytesize::to_string(slot.size as u64, false));
+        }
+    }
+
+    // Unloads every database none of the handed out handles came from, returns their names.
+    // They're loaded again once something is read from them.
+    pub fn unload_unused(&self, pipeline: &mut AssetPipeline) -> Vec<String> {
+        let referenced: HashSet<&str> = self.slots.values()
+            .filter(|slot| slot.asset.handles() > 0)
+            .filter_map(|slot| slot.database.as_deref())
+            .collect();
+
+        let unused: Vec<String> = pipeline.database_names().into_iter()
+            .filter(|name| !referenced.contains(name))
+            .map(|name| name.to_string())
+            .collect();
+
+        unused.into_iter()
+            .filter(|name| pipeline.unload_database(name))
+            .collect()
+    }
+
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+
+        self.evict();
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    // Bytes taken up by everything that's cached right now
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+}
+
+impl Default for AssetCache {
+    fn default() -> Self {
+        AssetCache::new()
+    }
+}
+
+#[cfg(test)]
+struct TestAsset(Vec<u8>);
+
+#[cfg(test)]
+impl Asset for TestAsset {
+    type Context = ();
+    type Decoded = Vec<u8>;
+
+    fn decode(entry: AssetEntry) -> Result<Vec<u8>, AssetDatabaseError> {
+        Ok(entry.raw_data().clone())
+    }
+
+    fn load(data: Vec<u8>, _: &()) -> Result<TestAsset, AssetDatabaseError> {
+        Ok(TestAsset(data))
+    }
+
+    fn memory_size(&self) -> usize {
+        self.0.len()
+    }
+}
+
+#[test]
+fn asset_cache_test() {
+    use crate::{AssetDatabase, DatabasePriority};
+
+    let path = std::env::temp_dir().join(format!("pxl_asset_cache_test_{}.pxl", std::process::id()));
+
+    let mut db = AssetDatabase::new();
+    for key in &["a", "b", "c"] {
+        db.push_entry(AssetEntry::from_audio(key, vec![0; 4])).unwrap();
+    }
+    std::fs::write(&path, db.to_bytes().unwrap()).unwrap();
+
+    let mut pipeline = AssetPipeline::with_public_key("does-not-exist-*.pxl", None).unwrap();
+    pipeline.load_database(&path, DatabasePriority::Base).unwrap();
+
+    let mut cache = AssetCache::with_budget(8);
+    let a = cache.load::<TestAsset, _>(&pipeline, "a", &()).unwrap();
+    cache.load::<TestAsset, _>(&pipeline, "b", &()).unwrap();
+
+    // b is the only one nobody holds on to
+    cache.load::<TestAsset, _>(&pipeline, "c", &()).unwrap();
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.used(), 8);
+
+    let again = cache.load::<TestAsset, _>(&pipeline, "a", &()).unwrap();
+    assert!(Arc::ptr_eq(&a.state, &again.state));
+
+    assert!(matches!(cache.load::<TestAsset, _>(&pipeline, "missing", &()), Err(AssetDatabaseError::NotFound { .. })));
+
+    // a is still referenced
+    let name = path.file_name().unwrap().to_str().unwrap().to_string();
+    assert!(cache.unload_unused(&mut pipeline).is_empty());
+
+    drop(a);
+    drop(again);
+    assert_eq!(cache.unload_unused(&mut pipeline), vec![name]);
+
+    // Mapped again on demand
+    cache.invalidate("a");
+    assert_eq!(cache.load::<TestAsset, _>(&pipeline, "a", &()).unwrap().lock().0, vec![0; 4]);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn async_asset_cache_test() {
+    use crate::{AssetDatabase, DatabasePriority};
+
+    let mut db = AssetDatabase::new();
+    db.push_entry(AssetEntry::from_audio("a", vec![1; 16])).unwrap();
+    db.push_entry(AssetEntry::from_audio("b", vec![2; 16])).unwrap();
+
+    let mut pipeline = AssetPipeline::with_public_key("does-not-exist-*.pxl", None).unwrap();
+    pipeline.insert_database("assets-0000.pxl", DatabasePriority::Base, AssetDatabase::from_bytes(db.to_bytes().unwrap()).unwrap());
+
+    let mut cache = AssetCache::new();
+    let a = cache.load_async::<TestAsset, _>(&pipeline, "a").unwrap();
+    let b = cache.load_async::<TestAsset, _>(&pipeline, "b").unwrap();
+    assert!(a.get().is_none());
+
+    for _ in 0..100 {
+        cache.process::<TestAsset>(&());
+        if cache.total_progress().is_done() {
+            break;
+        }
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    let progress = cache.progress()["assets-0000.pxl"];
+    assert_eq!(progress.entries_done, 2);
+    assert_eq!(progress.fraction(), 1.0);
+
+    assert_eq!(a.lock().0, vec![1; 16]);
+    assert_eq!(b.lock().0, vec![2; 16]);
+    assert_eq!(cache.used(), 32);
+
+    cache.clear_progress();
+    assert!(cache.progress().is_empty());
+}
@@ -1,37 +1,72 @@
 /* Assets File (binary)
-Version Id (i32)
+Version Id (u8)
+Zstd Dictionary: [u8]  -- (>= 1.4) Shared by every zstd entry, empty if there is none
+Content Version: String -- (>= 1.6) E.G 1.2.0, empty if it's unknown
+Is Patch: u8           -- (>= 1.6)
+
+Patch (>= 1.6, only if Is Patch) {
+    Base Versions: [String] -- Content versions the patch applies on top of, empty for any
+    Tombstones: [String]    -- Keys the patch removes from the archives below it
+}
 
 Database[] {
-    Entry Key: String -- E.G textures/world.png
-    Entry Type: u8    -- Texture / Audio / Video / Particle
+    Entry Key: String  -- E.G textures/world.png
+    Entry Type: u8     -- Texture / Audio / Video / Particle / Shader / Sprite / Font / StringTable
+    Codec: u8          -- None / Gzip / Zstd / Lz4, before 1.4 just a compressed flag (Gzip or raw)
+    Offset: u64        -- (>= 1.2) Absolute offset of the data row
+    Length: u32        -- Length of the data row
+    Checksum: u32      -- (>= 1.3) CRC32 of the data row as it's stored
+    Metadata: [u8]     -- (>= 1.5) Import settings from the .meta file, empty if there was none
+    References: [String] -- (>= 1.7) Keys of the entries it needs (sprite -> atlas page, particle -> texture)
+    Includes: [String]   -- (>= 1.7) Keys of the source files compiled into it (shader includes, video frames)
 }
 
 Data Rows {
     data...
 }
 
+Trailer (>= 1.3) {
+    Archive Hash: [u8; 32] -- SHA-256 of everything before the trailer
+    Signature: [u8; 64]    -- ed25519 signature of the archive hash
+    Is Signed: u8
+}
+
 [] = Array  {
     size: u32
     data...
 }
+
+Versions before 1.2 don't have an offset, their data rows simply follow
+the database in the same order. Since 1.2 we can open an archive, read
+the table and only decode a data row once it's actually requested.
+
+Identical data rows are only stored once, every entry that shares one simply
+points at the same offset. Readers don't have to know about it.
+
+A patch is layered over the archives of its content version by AssetPipeline,
+its entries replace theirs and its tombstones hide theirs.
 */
 
-use std::io::{Write, Read, Cursor};
+use std::io::{Write, Read, Cursor, Seek, SeekFrom};
 use byteorder::{WriteBytesExt, LittleEndian, ReadBytesExt};
 use image::{RgbaImage, ImageBuffer};
 use std::slice::Iter;
-use audio_engine::{Audio, AudioSystem};
-use flate2::read::GzDecoder;
-use flate2::write::GzEncoder;
-use flate2::Compression;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::collections::{BTreeSet, HashMap};
+use audio_engine::{Audio, AudioInfo, AudioSystem, AUDIO_HEADER_SIZE};
+use memmap::Mmap;
+use sha2::{Sha256, Digest};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use crate::{AnimatedTexture, AssetDatabaseError, AssetMetadata, AtlasRect, Codec, FontData, LoopMode, ParticleEffect, ShaderSource, Sprite, StringTable, TextureData, VideoData};
 
 pub const MAX_SIZE: usize = 0x8000000; // 128 MB
-pub const DATABASE_VERSION: u8 = 0x11; // 1.1
+pub const DATABASE_VERSION: u8 = 0x17; // 1.7
 
-#[derive(Copy, Clone, Debug, PartialOrd, PartialEq)]
-pub enum AssetDatabaseError {
-    DatabaseFull
-}
+pub(crate) const MAX_KEY_LEN: u32 = 0x1000;
+const MAX_METADATA_LEN: u32 = 0x20000; // 256 tags of 256 bytes and change
+const TRAILER_LEN: u64 = 32 + 64 + 1;
 
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Ord, Eq, Hash)]
@@ -44,7 +79,10 @@ pub enum AssetEntryType {
     Video = 4,
     Particle = 5,
 
-    Shader = 6
+    Shader = 6,
+    Sprite = 7, // A rectangle on an atlas page
+    Font = 8,   // Glyph atlas with metrics and kerning
+    StringTable = 9 // Localized strings of one locale
 }
 
 impl From<u8> for AssetEntryType {
@@ -56,6 +94,11 @@ impl From<u8> for AssetEntryType {
             4 => AssetEntryType::Video,
             5 => AssetEntryType::Particle,
 
+            6 => AssetEntryType::Shader,
+            7 => AssetEntryType::Sprite,
+            8 => AssetEntryType::Font,
+            9 => AssetEntryType::StringTable,
+
             _ => AssetEntryType::Unknown
         }
     }
@@ -65,31 +108,39 @@ impl From<u8> for AssetEntryType {
 pub struct AssetEntry {
     pub (crate) entry_type: AssetEntryType,
     pub (crate) entry_key: String,
-    pub (crate) is_compressed: bool,
+    pub (crate) codec: Codec,
+    pub (crate) metadata: AssetMetadata,
+    pub (crate) references: Vec<String>, // Keys of entries it needs, E.G the atlas page of a sprite
+    pub (crate) includes: Vec<String>,   // Keys of source files compiled into it, E.G shader includes
     pub (crate) data: Vec<u8>,
-    pub (crate) compressed_data: Vec<u8> // just for the builder
+    pub (crate) compressed_data: Vec<u8>, // just for the builder
+
+    // Where the (possibly compressed) data row lives inside of the database source,
+    // None if the data is already in memory.
+    pub (crate) location: Option<(u64, u32)>,
+    pub (crate) checksum: Option<u32> // None for archives older than 1.3
 }
 
 impl AssetEntry {
-    // TODO: implement
-    pub fn into_texture(self) -> RgbaImage {
-        assert_eq!(self.entry_type, AssetEntryType::Texture);
-
-        let mut cursor = Cursor::new(self.data);
-
-        // Decompress data
-        //let decoder = GzDecoder::new(raw_cursor);
-        //let mut cursor = decoder.into_inner();
+    // The largest mip level as RGBA8, whatever format it's stored in
+    pub fn into_texture(self) -> Result<RgbaImage, AssetDatabaseError> {
+        Ok(self.into_texture_data()?.to_image())
+    }
 
-        let width = cursor.read_u32::<LittleEndian>().unwrap();
-        let height = cursor.read_u32::<LittleEndian>().unwrap();
+    // Everything the GPU needs to upload the texture as it's stored
+    pub fn into_texture_data(self) -> Result<TextureData, AssetDatabaseError> {
+        assert_eq!(self.entry_type, AssetEntryType::Texture);
 
-        let mut pixel_data = Vec::new();
-        cursor.read_to_end(&mut pixel_data).unwrap();
+        TextureData::read_from(&self.data)
+            .map_err(|_| AssetDatabaseError::DecodeFailed { key: self.entry_key.clone() })
+    }
 
-        let img = ImageBuffer::from_raw(width, height, pixel_data).unwrap();
+    // Width and height of a texture without decoding it
+    pub fn texture_size(&self) -> Result<(u32, u32), AssetDatabaseError> {
+        assert_eq!(self.entry_type, AssetEntryType::Texture);
 
-        img
+        TextureData::read_size(&self.data)
+            .map_err(|_| AssetDatabaseError::DecodeFailed { key: self.entry_key.clone() })
     }
 
     pub fn r#type(&self) -> AssetEntryType {
@@ -101,206 +152,1557 @@ impl AssetEntry {
     pub fn raw_data(&self) -> &Vec<u8> {
         &self.data
     }
+    pub fn is_loaded(&self) -> bool {
+        self.location.is_none()
+    }
+
+    // Size of the entry inside of the database, the compressed size if it's not loaded yet.
+    pub fn size(&self) -> usize {
+        match self.location {
+            Some((_, len)) => len as usize,
+            None => self.data.len()
+        }
+    }
+
+    // Length of the data row it's written as, the data itself until compress() ran
+    pub(crate) fn stored_size(&self) -> usize {
+        match self.location {
+            Some((_, len)) => len as usize,
+            None if self.codec != Codec::None && !self.compressed_data.is_empty() => self.compressed_data.len(),
+            None => self.data.len()
+        }
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn metadata(&self) -> &AssetMetadata {
+        &self.metadata
+    }
+
+    pub fn with_metadata(mut self, metadata: AssetMetadata) -> AssetEntry {
+        self.metadata = metadata;
+
+        self
+    }
+
+    // Keys of the entries this one needs, relative to the mount it's in. See DependencyGraph
+    pub fn references(&self) -> &[String] {
+        &self.references
+    }
+
+    // Keys of the source files that were compiled into this entry, they aren't entries themselves
+    pub fn includes(&self) -> &[String] {
+        &self.includes
+    }
+
+    // Throws away the compressed row if the codec changed, it's redone on the next compress()
+    pub fn with_codec(mut self, codec: Codec) -> AssetEntry {
+        assert!(self.is_loaded(), "the codec of an entry can only be changed once it's loaded");
+
+        if codec != self.codec {
+            self.codec = codec;
+            self.compressed_data.clear();
+        }
+
+        self
+    }
+
+    // Fills compressed_data ahead of time, so it can happen in parallel.
+    // dictionary has to be the one of the database the entry ends up in.
+    pub (crate) fn compress(&mut self, dictionary: Option<&[u8]>) -> Result<(), AssetDatabaseError> {
+        if self.codec != Codec::None && self.compressed_data.is_empty() && self.location.is_none() {
+            self.compressed_data = self.codec.compress(&self.data, dictionary)?;
+        }
+
+        Ok(())
+    }
+
+    // The data row as it ends up in an archive, compress() has to be called first
+    pub (crate) fn stored_data(&self) -> &[u8] {
+        if self.codec == Codec::None {
+            &self.data
+        } else {
+            &self.compressed_data
+        }
+    }
 
-    // TODO: implement
-    pub fn into_animated_texture(self) {
+    pub fn into_animated_texture(self) -> Result<AnimatedTexture, AssetDatabaseError> {
         assert_eq!(self.entry_type, AssetEntryType::AnimatedTexture);
 
-        unimplemented!();
+        let decode_failed = || AssetDatabaseError::DecodeFailed { key: self.entry_key.clone() };
+
+        let mut cursor = Cursor::new(&self.data);
+
+        let width = cursor.read_u32::<LittleEndian>().map_err(|_| decode_failed())?;
+        let height = cursor.read_u32::<LittleEndian>().map_err(|_| decode_failed())?;
+        let loop_mode = LoopMode::from(cursor.read_u8().map_err(|_| decode_failed())?);
+        let frame_count = cursor.read_u32::<LittleEndian>().map_err(|_| decode_failed())?;
+
+        // Everything here comes from the archive, the frames have to be in the data that's left
+        let frame_size = (width as usize).checked_mul(height as usize)
+            .and_then(|pixels| pixels.checked_mul(4))
+            .filter(|frame_size| *frame_size > 0)
+            .ok_or_else(decode_failed)?;
+
+        let frames_len = (frame_count as usize).checked_mul(4 + frame_size).ok_or_else(decode_failed)?;
+        if frames_len > self.data.len() - cursor.position() as usize {
+            return Err(decode_failed());
+        }
+
+        let mut animation = AnimatedTexture::new(width, height, loop_mode);
+        for _ in 0..frame_count {
+            let duration_ms = cursor.read_u32::<LittleEndian>().map_err(|_| decode_failed())?;
+
+            let start = cursor.position() as usize;
+            let pixel_data = self.data.get(start..start + frame_size).ok_or_else(decode_failed)?;
+            cursor.set_position((start + frame_size) as u64);
+
+            let image = ImageBuffer::from_raw(width, height, pixel_data.to_vec()).ok_or_else(decode_failed)?;
+            animation.push_frame(image, duration_ms);
+        }
+
+        Ok(animation)
     }
 
-    // TODO: implement
-    pub fn into_video(self) {
+    pub fn into_video(self) -> Result<VideoData, AssetDatabaseError> {
         assert_eq!(self.entry_type, AssetEntryType::Video);
 
-        unimplemented!();
+        VideoData::read_from(&self.data)
+            .map_err(|_| AssetDatabaseError::DecodeFailed { key: self.entry_key.clone() })
     }
 
     pub fn into_audio(self, audio_system: &AudioSystem) -> Audio {
         assert_eq!(self.entry_type, AssetEntryType::Audio);
 
-        audio_system.from_memory(self.data)
+        audio_system.from_memory_with(self.data, &self.metadata.audio)
+    }
+
+    // What the pipeline measured while importing the sound, None for sounds stored without it
+    pub fn audio_info(&self) -> Option<AudioInfo> {
+        assert_eq!(self.entry_type, AssetEntryType::Audio);
+
+        AudioInfo::read_from(&self.data)
+    }
+
+    // The sound file as it was imported (or transcoded), without the audio header
+    pub fn audio_file(&self) -> &[u8] {
+        assert_eq!(self.entry_type, AssetEntryType::Audio);
+
+        match AudioInfo::read_from(&self.data) {
+            Some(_) => &self.data[AUDIO_HEADER_SIZE..],
+            None => &self.data
+        }
+    }
+
+    pub fn into_font(self) -> Result<FontData, AssetDatabaseError> {
+        assert_eq!(self.entry_type, AssetEntryType::Font);
+
+        FontData::read_from(&self.data)
+            .map_err(|_| AssetDatabaseError::DecodeFailed { key: self.entry_key.clone() })
+    }
+
+    pub fn into_string_table(self) -> Result<StringTable, AssetDatabaseError> {
+        assert_eq!(self.entry_type, AssetEntryType::StringTable);
+
+        StringTable::read_from(&self.data)
+            .map_err(|_| AssetDatabaseError::DecodeFailed { key: self.entry_key.clone() })
+    }
+
+    pub fn into_sprite(self) -> Result<Sprite, AssetDatabaseError> {
+        assert_eq!(self.entry_type, AssetEntryType::Sprite);
+
+        let decode_failed = |_| AssetDatabaseError::DecodeFailed { key: self.entry_key.clone() };
+
+        let mut cursor = Cursor::new(&self.data);
+
+        let page_len = cursor.read_u32::<LittleEndian>().map_err(decode_failed)?;
+        if page_len > MAX_KEY_LEN {
+            return Err(AssetDatabaseError::DecodeFailed { key: self.entry_key.clone() });
+        }
+
+        let mut page = vec![0x00; page_len as usize];
+        cursor.read_exact(&mut page).map_err(decode_failed)?;
+        let page = String::from_utf8(page).map_err(|_| AssetDatabaseError::InvalidKey)?;
+
+        let rect = AtlasRect {
+            x: cursor.read_u32::<LittleEndian>().map_err(decode_failed)?,
+            y: cursor.read_u32::<LittleEndian>().map_err(decode_failed)?,
+            width: cursor.read_u32::<LittleEndian>().map_err(decode_failed)?,
+            height: cursor.read_u32::<LittleEndian>().map_err(decode_failed)?
+        };
+
+        let mut uv = [0.0; 4];
+        cursor.read_f32_into::<LittleEndian>(&mut uv).map_err(decode_failed)?;
+
+        Ok(Sprite {
+            page,
+            rect,
+            uv
+        })
     }
 
-    // TODO: implement
-    pub fn into_particles(self) {
+    pub fn into_shader(self) -> Result<ShaderSource, AssetDatabaseError> {
+        assert_eq!(self.entry_type, AssetEntryType::Shader);
+
+        ShaderSource::read_from(&mut Cursor::new(&self.data))
+            .map_err(|_| AssetDatabaseError::DecodeFailed { key: self.entry_key.clone() })
+    }
+
+    pub fn into_particles(self) -> Result<ParticleEffect, AssetDatabaseError> {
         assert_eq!(self.entry_type, AssetEntryType::Particle);
 
-        unimplemented!();
+        ParticleEffect::read_from(&mut Cursor::new(&self.data))
+            .map_err(|_| AssetDatabaseError::DecodeFailed { key: self.entry_key.clone() })
     }
 
     pub fn from_image<S: AsRef<str>>(key: S, img: RgbaImage) -> AssetEntry {
+        AssetEntry::from_texture_data(key, &TextureData::from_image(img))
+    }
+
+    pub fn from_texture_data<S: AsRef<str>>(key: S, texture: &TextureData) -> AssetEntry {
         let mut pixel_data = Vec::<u8>::new();
+        texture.write_to(&mut pixel_data).unwrap();
+
+        AssetEntry {
+            entry_key: key.as_ref().to_string(),
+            entry_type: AssetEntryType::Texture,
+            codec: Codec::default_for(AssetEntryType::Texture),
+            metadata: AssetMetadata::default(),
+            references: Vec::new(),
+            includes: Vec::new(),
+            data: pixel_data,
+            compressed_data: Vec::new(),
+            location: None,
+            checksum: None
+        }
+    }
 
-        pixel_data.write_u32::<LittleEndian>(img.width()).unwrap();
-        pixel_data.write_u32::<LittleEndian>(img.height()).unwrap();
+    pub fn from_animated_texture<S: AsRef<str>>(key: S, animation: &AnimatedTexture) -> AssetEntry {
+        let mut data = Vec::<u8>::new();
+
+        data.write_u32::<LittleEndian>(animation.width).unwrap();
+        data.write_u32::<LittleEndian>(animation.height).unwrap();
+        data.write_u8(animation.loop_mode as u8).unwrap();
+        data.write_u32::<LittleEndian>(animation.frames.len() as u32).unwrap();
 
-        // Get all the uncompressed pixel data and push it into an u8 vector
-        for pxl in img.pixels() {
-            pixel_data.push(pxl[0]); // R
-            pixel_data.push(pxl[1]); // G
-            pixel_data.push(pxl[2]); // B
-            pixel_data.push(pxl[3]); // A
+        for frame in &animation.frames {
+            data.write_u32::<LittleEndian>(frame.duration_ms).unwrap();
+            data.extend_from_slice(&frame.image); // RGBA
         }
 
         AssetEntry {
             entry_key: key.as_ref().to_string(),
-            entry_type: AssetEntryType::Texture,
-            is_compressed: true,
-            data: pixel_data,
-            compressed_data: Vec::new()
+            entry_type: AssetEntryType::AnimatedTexture,
+            codec: Codec::default_for(AssetEntryType::AnimatedTexture),
+            metadata: AssetMetadata::default(),
+            references: Vec::new(),
+            includes: Vec::new(),
+            data,
+            compressed_data: Vec::new(),
+            location: None,
+            checksum: None
+        }
+    }
+
+    pub fn from_shader<S: AsRef<str>>(key: S, shader: &ShaderSource) -> AssetEntry {
+        let mut data = Vec::<u8>::new();
+        shader.write_to(&mut data).unwrap();
+
+        AssetEntry {
+            entry_key: key.as_ref().to_string(),
+            entry_type: AssetEntryType::Shader,
+            codec: Codec::default_for(AssetEntryType::Shader),
+            metadata: AssetMetadata::default(),
+            references: Vec::new(),
+            includes: shader.files.iter().skip(1).cloned().collect(), // The shader itself comes first
+            data,
+            compressed_data: Vec::new(),
+            location: None,
+            checksum: None
+        }
+    }
+
+    pub fn from_particles<S: AsRef<str>>(key: S, effect: &ParticleEffect) -> AssetEntry {
+        let mut data = Vec::<u8>::new();
+        effect.write_to(&mut data).unwrap();
+
+        AssetEntry {
+            entry_key: key.as_ref().to_string(),
+            entry_type: AssetEntryType::Particle,
+            codec: Codec::default_for(AssetEntryType::Particle),
+            metadata: AssetMetadata::default(),
+            references: vec![effect.texture.clone()],
+            includes: Vec::new(),
+            data,
+            compressed_data: Vec::new(),
+            location: None,
+            checksum: None
+        }
+    }
+
+    pub fn from_font<S: AsRef<str>>(key: S, font: &FontData) -> AssetEntry {
+        let mut data = Vec::<u8>::new();
+        font.write_to(&mut data).unwrap();
+
+        AssetEntry {
+            entry_key: key.as_ref().to_string(),
+            entry_type: AssetEntryType::Font,
+            codec: Codec::default_for(AssetEntryType::Font),
+            metadata: AssetMetadata::default(),
+            references: Vec::new(),
+            includes: Vec::new(),
+            data,
+            compressed_data: Vec::new(),
+            location: None,
+            checksum: None
+        }
+    }
+
+    pub fn from_string_table<S: AsRef<str>>(key: S, table: &StringTable) -> AssetEntry {
+        let mut data = Vec::<u8>::new();
+        table.write_to(&mut data).unwrap();
+
+        AssetEntry {
+            entry_key: key.as_ref().to_string(),
+            entry_type: AssetEntryType::StringTable,
+            codec: Codec::default_for(AssetEntryType::StringTable),
+            metadata: AssetMetadata::default(),
+            references: Vec::new(),
+            includes: Vec::new(),
+            data,
+            compressed_data: Vec::new(),
+            location: None,
+            checksum: None
         }
     }
 
+    pub fn from_video<S: AsRef<str>>(key: S, video: &VideoData) -> AssetEntry {
+        let mut data = Vec::<u8>::new();
+        video.write_to(&mut data).unwrap();
+
+        AssetEntry {
+            entry_key: key.as_ref().to_string(),
+            entry_type: AssetEntryType::Video,
+            codec: Codec::default_for(AssetEntryType::Video),
+            metadata: AssetMetadata::default(),
+            references: Vec::new(),
+            includes: Vec::new(),
+            data,
+            compressed_data: Vec::new(),
+            location: None,
+            checksum: None
+        }
+    }
+
+    pub fn from_sprite<S: AsRef<str>>(key: S, sprite: &Sprite) -> AssetEntry {
+        let mut data = Vec::<u8>::new();
+
+        data.write_u32::<LittleEndian>(sprite.page.len() as u32).unwrap();
+        data.write_all(sprite.page.as_bytes()).unwrap();
+
+        data.write_u32::<LittleEndian>(sprite.rect.x).unwrap();
+        data.write_u32::<LittleEndian>(sprite.rect.y).unwrap();
+        data.write_u32::<LittleEndian>(sprite.rect.width).unwrap();
+        data.write_u32::<LittleEndian>(sprite.rect.height).unwrap();
+
+        for uv in &sprite.uv {
+            data.write_f32::<LittleEndian>(*uv).unwrap();
+        }
+
+        AssetEntry {
+            entry_key: key.as_ref().to_string(),
+            entry_type: AssetEntryType::Sprite,
+            codec: Codec::default_for(AssetEntryType::Sprite),
+            metadata: AssetMetadata::default(),
+            references: vec![sprite.page.clone()],
+            includes: Vec::new(),
+            data,
+            compressed_data: Vec::new(),
+            location: None,
+            checksum: None
+        }
+    }
+
+    // The audio header followed by the sound file, see AudioInfo
+    pub fn from_audio_file<S: AsRef<str>>(key: S, info: &AudioInfo, file: &[u8]) -> AssetEntry {
+        let mut data = Vec::with_capacity(AUDIO_HEADER_SIZE + file.len());
+        info.write_to(&mut data);
+        data.extend_from_slice(file);
+
+        AssetEntry::from_audio(key, data)
+    }
+
     pub fn from_audio<S: AsRef<str>>(key: S, audio: Vec<u8>) -> AssetEntry {
         AssetEntry {
             entry_key: key.as_ref().to_string(),
             entry_type: AssetEntryType::Audio,
-            is_compressed: false, // Dont compress audio, not worth it :c
+            codec: Codec::default_for(AssetEntryType::Audio),
+            metadata: AssetMetadata::default(),
+            references: Vec::new(),
+            includes: Vec::new(),
             data: audio,
-            compressed_data: Vec::new()
+            compressed_data: Vec::new(),
+            location: None,
+            checksum: None
+        }
+    }
+}
+
+// Where the data rows of a database are read from
+pub(crate) enum DatabaseSource {
+    Memory(Vec<u8>),
+    File(Mutex<File>),
+    Mapped(Mmap)
+}
+
+impl DatabaseSource {
+    fn read_at(&self, offset: u64, len: u32) -> Result<Vec<u8>, AssetDatabaseError> {
+        match self {
+            DatabaseSource::Memory(buff) => DatabaseSource::slice_at(buff, offset, len),
+            DatabaseSource::Mapped(map) => DatabaseSource::slice_at(&map[..], offset, len),
+
+            DatabaseSource::File(file) => {
+                let mut file = file.lock().unwrap();
+
+                // len comes from the entry table, it's checked before we allocate it
+                match offset.checked_add(len as u64) {
+                    Some(end) if end <= file.metadata()?.len() => {},
+                    _ => return Err(AssetDatabaseError::Corrupt { offset })
+                }
+
+                let mut data = vec![0x00; len as usize];
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(&mut data).map_err(|err| match err.kind() {
+                    std::io::ErrorKind::UnexpectedEof => AssetDatabaseError::Corrupt { offset },
+                    _ => AssetDatabaseError::Io(err)
+                })?;
+
+                Ok(data)
+            }
+        }
+    }
+
+    fn len(&self) -> Result<u64, AssetDatabaseError> {
+        match self {
+            DatabaseSource::Memory(buff) => Ok(buff.len() as u64),
+            DatabaseSource::Mapped(map) => Ok(map.len() as u64),
+            DatabaseSource::File(file) => Ok(file.lock().unwrap().metadata()?.len())
+        }
+    }
+
+    fn slice_at(buff: &[u8], offset: u64, len: u32) -> Result<Vec<u8>, AssetDatabaseError> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= buff.len() as u64 => Ok(buff[offset as usize..end as usize].to_vec()),
+            _ => Err(AssetDatabaseError::Corrupt { offset })
+        }
+    }
+}
+
+// What an entry stores, entries with the same payload share a single data row
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Payload {
+    Stored(u64, u32),     // Offset and length of a row that's already in the archive
+    Data(Codec, [u8; 32]) // SHA-256 of the data, the same data with another codec is another row
+}
+
+impl Payload {
+    pub(crate) fn of(entry: &AssetEntry) -> Payload {
+        match entry.location {
+            Some((offset, len)) => Payload::Stored(offset, len),
+            None => {
+                let mut hash = [0u8; 32];
+                hash.copy_from_slice(&Sha256::digest(&entry.data));
+
+                Payload::Data(entry.codec, hash)
+            }
         }
     }
 }
 
 pub struct AssetDatabase {
     total_size: usize,
-    entries: Vec<AssetEntry>
+    entries: Vec<Arc<AssetEntry>>,
+    index: HashMap<String, usize>, // key -> entries
+    entry_payloads: Vec<Payload>, // Same order as entries
+    payloads: HashMap<Payload, (usize, usize)>, // payload -> (entries using it, size)
+    deduplicated_size: usize,
+    source: RwLock<Option<DatabaseSource>>, // None once unloaded, see unload()
+    path: Option<PathBuf>, // Archives opened from a file are mapped again when they're needed
+    dictionary: Option<Arc<Vec<u8>>>, // zstd dictionary, see Codec
+
+    content_version: String,
+    base_versions: Option<Vec<String>>, // Some if this is a patch
+    tombstones: BTreeSet<String>,
+
+    archive_hash: Option<[u8; 32]>,
+    signature: Option<[u8; 64]>
 }
 
 impl AssetDatabase {
     pub fn new() -> AssetDatabase {
         AssetDatabase {
             total_size: 0,
-            entries: Vec::new()
+            entries: Vec::new(),
+            index: HashMap::new(),
+            entry_payloads: Vec::new(),
+            payloads: HashMap::new(),
+            deduplicated_size: 0,
+            source: RwLock::new(None),
+            path: None,
+            dictionary: None,
+
+            content_version: String::new(),
+            base_versions: None,
+            tombstones: BTreeSet::new(),
+
+            archive_hash: None,
+            signature: None
         }
     }
 
+    pub fn dictionary(&self) -> Option<&[u8]> {
+        self.dictionary.as_ref().map(|dict| dict.as_slice())
+    }
+
+    // Zstd rows compressed with the old dictionary are loaded, they're compressed again when written
+    pub fn set_dictionary(&mut self, dictionary: Option<Arc<Vec<u8>>>) -> Result<(), AssetDatabaseError> {
+        if dictionary == self.dictionary {
+            return Ok(());
+        }
+
+        let mut loaded = Vec::new();
+        for entry in &self.entries {
+            if entry.codec == Codec::Zstd {
+                let mut entry = AssetEntry::clone(&*self.load_entry(entry)?);
+                entry.compressed_data.clear();
+
+                loaded.push(entry);
+            }
+        }
+
+        // Pushed again so they're counted by their data instead of the stored row. Decoded they can
+        // be too big, the database is left the way it was then.
+        let saved = (self.total_size, self.entries.clone(), self.entry_payloads.clone(), self.payloads.clone(), self.deduplicated_size);
+        for entry in loaded {
+            if let Err(err) = self.push_entry(entry) {
+                let (total_size, entries, entry_payloads, payloads, deduplicated_size) = saved;
+                self.total_size = total_size;
+                self.entries = entries;
+                self.entry_payloads = entry_payloads;
+                self.payloads = payloads;
+                self.deduplicated_size = deduplicated_size;
+
+                return Err(err);
+            }
+        }
+
+        self.dictionary = dictionary;
+
+        Ok(())
+    }
+
+    pub fn content_version(&self) -> &str {
+        &self.content_version
+    }
+
+    pub fn set_content_version<S: AsRef<str>>(&mut self, version: S) {
+        self.content_version = version.as_ref().to_string();
+    }
+
+    pub fn is_patch(&self) -> bool {
+        self.base_versions.is_some()
+    }
+
+    // Empty unless this is a patch
+    pub fn base_versions(&self) -> &[String] {
+        self.base_versions.as_deref().unwrap_or(&[])
+    }
+
+    // Some turns the database into a patch for those content versions (or any if it's empty),
+    // None back into a regular one which drops the tombstones.
+    pub fn set_base_versions(&mut self, base_versions: Option<Vec<String>>) {
+        if base_versions.is_none() {
+            self.tombstones.clear();
+        }
+
+        self.base_versions = base_versions;
+    }
+
+    // True if the patch goes on top of archives of that content version. Parts of the
+    // same patch share its version, so they apply on top of each other too.
+    pub fn applies_to<S: AsRef<str>>(&self, version: S) -> bool {
+        let version = version.as_ref();
+
+        match &self.base_versions {
+            Some(base_versions) => base_versions.is_empty()
+                || base_versions.iter().any(|base| base == version)
+                || self.content_version == version,
+
+            None => false
+        }
+    }
+
+    pub fn tombstones(&self) -> &BTreeSet<String> {
+        &self.tombstones
+    }
+
+    // Removes key from the archives the patch is applied to, and from the patch itself
+    pub fn push_tombstone<S: AsRef<str>>(&mut self, key: S) {
+        assert!(self.is_patch(), "only patches can remove entries of other archives");
+
+        self.remove_entry(key.as_ref());
+        self.tombstones.insert(key.as_ref().to_string());
+    }
+
     pub fn does_fit(&self, entry: &AssetEntry) -> bool {
-        let size = self.total_size + entry.data.len() + 1;
+        self.fits(&Payload::of(entry), entry.size())
+    }
+
+    // Data that's stored already costs nothing but the entry itself
+    pub(crate) fn fits(&self, payload: &Payload, size: usize) -> bool {
+        let size = if self.payloads.contains_key(payload) { 0 } else { size };
+
+        self.total_size + size + 1 < MAX_SIZE
+    }
+
+    pub(crate) fn stores(&self, payload: &Payload) -> bool {
+        self.payloads.contains_key(payload)
+    }
 
-        size < MAX_SIZE
+    // How much smaller the archive is because entries share their data row. Exact once
+    // every entry is compressed, which writing the archive takes care of.
+    pub fn deduplicated_size(&self) -> usize {
+        self.deduplicated_size
     }
 
+    // Pushing an entry with a key that already exists replaces the old entry
     pub fn push_entry(&mut self, entry: AssetEntry) -> Result<(), AssetDatabaseError> {
-        if !self.does_fit(&entry) {
+        let payload = Payload::of(&entry);
+        let replaced = self.index.get(&entry.entry_key).copied();
+
+        let replaced_size = match replaced {
+            Some(i) if self.entry_payloads[i] != payload && self.payloads[&self.entry_payloads[i]].0 == 1 => self.entries[i].size() + 1,
+            Some(_) => 1,
+            None => 0
+        };
+
+        let added_size = if self.payloads.contains_key(&payload) { 1 } else { entry.size() + 1 };
+
+        if self.total_size - replaced_size + added_size >= MAX_SIZE {
             return Err(AssetDatabaseError::DatabaseFull)
         }
 
-        self.total_size += entry.data.len() + 1;
+        if let Some(i) = replaced {
+            self.release(i);
+        }
+
+        let (uses, size) = self.payloads.entry(payload).or_insert((0, entry.stored_size()));
+        *uses += 1;
+        if *uses > 1 {
+            self.deduplicated_size += *size;
+        }
+
+        self.total_size -= replaced_size;
+        self.total_size += added_size;
 
-        self.entries.push(entry);
+        self.tombstones.remove(&entry.entry_key);
+
+        match replaced {
+            Some(i) => {
+                self.entries[i] = Arc::new(entry);
+                self.entry_payloads[i] = payload;
+            },
+            None => {
+                self.index.insert(entry.key(), self.entries.len());
+                self.entries.push(Arc::new(entry));
+                self.entry_payloads.push(payload);
+            }
+        }
 
         Ok(())
     }
 
-    pub fn get_entry(&self, key: String) -> Option<AssetEntry> {
+    // Drops the entry at i from the payload it uses, total_size is up to the caller
+    fn release(&mut self, i: usize) {
+        let payload = self.entry_payloads[i];
+        let (uses, size) = self.payloads.get_mut(&payload).unwrap();
+
+        *uses -= 1;
+        if *uses == 0 {
+            self.payloads.remove(&payload);
+        } else {
+            self.deduplicated_size -= *size;
+        }
+    }
+
+    pub fn remove_entry<S: AsRef<str>>(&mut self, key: S) -> Option<Arc<AssetEntry>> {
+        let i = self.index.remove(key.as_ref())?;
+
+        self.total_size -= match self.payloads[&self.entry_payloads[i]].0 {
+            1 => self.entries[i].size() + 1,
+            _ => 1
+        };
+        self.release(i);
+
+        let entry = self.entries.remove(i);
+        self.entry_payloads.remove(i);
+
+        // Everything after it moved down by one
+        for index in self.index.values_mut() {
+            if *index > i {
+                *index -= 1;
+            }
+        }
+
+        Some(entry)
+    }
+
+    // The entry without it's data, nothing is read from the archive
+    pub fn entry<S: AsRef<str>>(&self, key: S) -> Option<&Arc<AssetEntry>> {
+        self.index.get(key.as_ref()).map(|i| &self.entries[*i])
+    }
+
+    pub fn get_entry<S: AsRef<str>>(&self, key: S) -> Result<Option<Arc<AssetEntry>>, AssetDatabaseError> {
+        match self.entry(key) {
+            Some(entry) => Ok(Some(self.load_entry(entry)?)),
+            None => Ok(None)
+        }
+    }
+
+    // Reads and decompresses the data row of an entry if it's not in memory yet
+    pub fn load_entry(&self, entry: &Arc<AssetEntry>) -> Result<Arc<AssetEntry>, AssetDatabaseError> {
+        let raw_data = match self.read_raw(entry)? {
+            Some(raw_data) => raw_data,
+            None => return Ok(entry.clone())
+        };
+
+        let mut loaded = AssetEntry::clone(entry);
+        loaded.location = None;
+
+        if entry.codec == Codec::None {
+            loaded.data = raw_data;
+        } else {
+            loaded.data = entry.codec.decompress(&raw_data, self.dictionary.as_ref().map(|dict| dict.as_slice()))
+                .map_err(|_| AssetDatabaseError::DecodeFailed { key: entry.key() })?;
+        }
+
+        let comp;
+        if entry.codec != Codec::None {
+            comp = format!("({}) ", entry.codec.name());
+        } else {
+            comp = String::new();
+        }
+
+        log::debug!("Loaded {}{}<{:#?}> {:>5}", comp, loaded.entry_key, loaded.entry_type,
+            bytesize::to_string((loaded.entry_key.len() + 1 + loaded.data.len()) as u64, false));
+
+        Ok(Arc::new(loaded))
+    }
+
+    // The data row exactly as it's stored, None if the entry isn't backed by a source
+    fn read_raw(&self, entry: &AssetEntry) -> Result<Option<Vec<u8>>, AssetDatabaseError> {
+        let (offset, len) = match entry.location {
+            Some(location) => location,
+            None => return Ok(None)
+        };
+
+        let raw_data = self.with_source(|source| source.read_at(offset, len))?;
+
+        if let Some(checksum) = entry.checksum {
+            if crc32(&raw_data) != checksum {
+                return Err(AssetDatabaseError::ChecksumMismatch { key: entry.key() });
+            }
+        }
+
+        Ok(Some(raw_data))
+    }
+
+    // Archives that were unloaded are mapped again first
+    fn with_source<R, F>(&self, f: F) -> Result<R, AssetDatabaseError>
+        where F: FnOnce(&DatabaseSource) -> Result<R, AssetDatabaseError>
+    {
+        if let Some(source) = self.source.read().unwrap().as_ref() {
+            return f(source);
+        }
+
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Err(AssetDatabaseError::Io(std::io::Error::new(std::io::ErrorKind::NotFound,
+                                                                          "database has no source to read from")))
+        };
+
+        let mut source = self.source.write().unwrap();
+        if source.is_none() {
+            log::info!("Mapping {} again", path.display());
+
+            *source = Some(AssetDatabase::map_file(path, self.archive_hash)?);
+        }
+
+        f(source.as_ref().unwrap())
+    }
+
+    // The entry table is kept, offsets into a different file would read garbage
+    fn map_file(path: &Path, archive_hash: Option<[u8; 32]>) -> Result<DatabaseSource, AssetDatabaseError> {
+        let file = File::open(path)?;
+        let map = unsafe { Mmap::map(&file)? };
+
+        if let Some(archive_hash) = archive_hash {
+            let start = (map.len() as u64).saturating_sub(TRAILER_LEN) as usize;
+            if map.len() < TRAILER_LEN as usize || map[start..start + 32] != archive_hash {
+                return Err(AssetDatabaseError::ArchiveHashMismatch);
+            }
+        }
+
+        Ok(DatabaseSource::Mapped(map))
+    }
+
+    // Drops the memory map of an archive opened with open(), the entry table stays around.
+    // Entries are read just fine afterwards, the archive is simply mapped again.
+    // Returns false if the database can't be mapped again (e.g it lives in memory).
+    pub fn unload(&self) -> bool {
+        if self.path.is_none() {
+            return false;
+        }
+
+        self.source.write().unwrap().take().is_some()
+    }
+
+    pub fn is_resident(&self) -> bool {
+        self.source.read().unwrap().is_some()
+    }
+
+    // Checks the archive hash and the checksum of every entry, this reads the whole archive!
+    pub fn verify(&self) -> Result<(), AssetDatabaseError> {
+        if let Some(archive_hash) = self.archive_hash {
+            if self.compute_archive_hash()? != archive_hash {
+                return Err(AssetDatabaseError::ArchiveHashMismatch);
+            }
+        }
+
         for entry in &self.entries {
-            if key == entry.entry_key {
-                return Some(entry.clone());
+            self.read_raw(entry)?;
+        }
+
+        Ok(())
+    }
+
+    // true if the archive was signed by the owner of public_key, false if it's unsigned
+    // or signed by someone else (e.g a mod).
+    pub fn verify_signature(&self, public_key: &[u8]) -> Result<bool, AssetDatabaseError> {
+        let signature = match self.signature {
+            Some(signature) => Signature::from(signature),
+            None => return Ok(false)
+        };
+
+        let public_key = PublicKey::from_bytes(public_key)
+            .map_err(|_| AssetDatabaseError::InvalidKeyPair)?;
+
+        // Never trust the stored hash, otherwise the data could be swapped out under the signature
+        let archive_hash = self.compute_archive_hash()?;
+        if Some(archive_hash) != self.archive_hash {
+            return Err(AssetDatabaseError::ArchiveHashMismatch);
+        }
+
+        Ok(public_key.verify(&archive_hash, &signature).is_ok())
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.signature.is_some()
+    }
+
+    fn compute_archive_hash(&self) -> Result<[u8; 32], AssetDatabaseError> {
+        self.with_source(|source| {
+            let len = source.len()?;
+            if len < TRAILER_LEN {
+                return Err(AssetDatabaseError::Corrupt { offset: len });
+            }
+
+            let mut hasher = Sha256::new();
+
+            // Read it in chunks so we don't have to keep a whole file handle backed archive in memory
+            let mut offset = 0;
+            while offset < len - TRAILER_LEN {
+                let chunk_len = std::cmp::min(0x100000, len - TRAILER_LEN - offset);
+                hasher.update(&source.read_at(offset, chunk_len as u32)?);
+
+                offset += chunk_len;
+            }
+
+            let mut archive_hash = [0u8; 32];
+            archive_hash.copy_from_slice(&hasher.finalize());
+
+            Ok(archive_hash)
+        })
+    }
+
+    // Opens a database by memory mapping it, only the entry table is read upfront.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<AssetDatabase, AssetDatabaseError> {
+        let file = File::open(path.as_ref())?;
+        let map = unsafe { Mmap::map(&file)? };
+
+        let mut cursor = Cursor::new(&map[..]);
+        let index = AssetDatabase::read_index(&mut cursor)?;
+
+        let mut db = AssetDatabase::from_index(index, DatabaseSource::Mapped(map))?;
+        db.path = Some(path.as_ref().to_path_buf());
+
+        Ok(db)
+    }
+
+    // Same as open() but reads data rows through the file handle instead of a memory map
+    pub fn from_file(file: File) -> Result<AssetDatabase, AssetDatabaseError> {
+        let mut reader = std::io::BufReader::new(&file);
+        let index = AssetDatabase::read_index(&mut reader)?;
+
+        AssetDatabase::from_index(index, DatabaseSource::File(Mutex::new(file)))
+    }
+
+    // The whole archive is in memory anyway, so the archive hash is checked right away.
+    pub fn from_bytes(buff: Vec<u8>) -> Result<AssetDatabase, AssetDatabaseError> {
+        let mut cursor = Cursor::new(&buff[..]);
+        let index = AssetDatabase::read_index(&mut cursor)?;
+
+        let db = AssetDatabase::from_index(index, DatabaseSource::Memory(buff))?;
+
+        if let Some(archive_hash) = db.archive_hash {
+            if db.compute_archive_hash()? != archive_hash {
+                return Err(AssetDatabaseError::ArchiveHashMismatch);
             }
         }
 
-        None
+        Ok(db)
     }
 
-    // TODO: implement
-    #[allow(unused_variables)]
-    pub fn from_bytes(buff: Vec<u8>) -> std::io::Result<AssetDatabase> {
+    fn from_index(index: DatabaseIndex, source: DatabaseSource) -> Result<AssetDatabase, AssetDatabaseError> {
+        let DatabaseIndex { version, dictionary, content_version, base_versions, tombstones, entries } = index;
+
         let mut db = AssetDatabase::new();
+        db.dictionary = dictionary.map(Arc::new);
+        db.content_version = content_version;
+        db.base_versions = base_versions;
 
-        let mut cursor = Cursor::new(buff);
+        if version >= 0x13 /* 1.3 */ {
+            let len = source.len()?;
+            if len < TRAILER_LEN {
+                return Err(AssetDatabaseError::Corrupt { offset: len });
+            }
 
-        let version = cursor.read_u8()?;
-        let entry_len = cursor.read_u32::<LittleEndian>()?;
-        for _ in 0..entry_len {
-            if version >= 0x10 /* 1.0 */ {
-                let key_len = cursor.read_u32::<LittleEndian>()?;
-                let mut key_bytes = Vec::new();
-                key_bytes.resize(key_len as usize, 0);
-                cursor.read_exact(&mut key_bytes)?;
-
-                let key = std::str::from_utf8(&key_bytes).unwrap();
-                let entry_type = cursor.read_u8()?.into();
-                let is_compressed = cursor.read_u8()? != 0;
-                let data_len = cursor.read_u32::<LittleEndian>()?;
-
-                log::info!("Found asset {}<{:#?}>", key, entry_type);
-
-                db.push_entry(AssetEntry {
-                    entry_key: key.to_string(),
-                    entry_type,
-                    is_compressed,
-                    data: Vec::<u8>::with_capacity(data_len as usize),
-                    compressed_data: Vec::new()
-                }).unwrap();
+            let trailer = source.read_at(len - TRAILER_LEN, TRAILER_LEN as u32)?;
+
+            let mut archive_hash = [0u8; 32];
+            archive_hash.copy_from_slice(&trailer[0..32]);
+            db.archive_hash = Some(archive_hash);
+
+            if trailer[96] != 0 {
+                let mut signature = [0u8; 64];
+                signature.copy_from_slice(&trailer[32..96]);
+                db.signature = Some(signature);
             }
         }
 
-        for entry in &mut db.entries {
-            let mut raw_data = vec![];
-            raw_data.resize(entry.data.capacity(), 0x00); // read into raw_data
-            cursor.read_exact(&mut raw_data)?;
+        *db.source.get_mut().unwrap() = Some(source);
 
-            if entry.is_compressed {
-                entry.data = vec![]; // Reset capacity
+        for entry in entries {
+            db.push_entry(entry)?;
+        }
+
+        db.tombstones = tombstones.into_iter().collect();
+
+        Ok(db)
+    }
+
+    // Reads the entry table, none of the data rows are touched.
+    fn read_index<R: Read>(reader: &mut R) -> Result<DatabaseIndex, AssetDatabaseError> {
+        let mut reader = IndexReader { reader, offset: 0 };
+        let mut entries = Vec::new();
+
+        let version = reader.read_u8().map_err(|err| reader.error(err))?;
+        if version < 0x10 || version > DATABASE_VERSION {
+            return Err(AssetDatabaseError::UnsupportedVersion(version));
+        }
 
-                let mut decoder = GzDecoder::new(Cursor::new(raw_data));
-                decoder.read_to_end(&mut entry.data)?;
-            } else {
-                entry.data = raw_data;
+        let mut dictionary = None;
+        if version >= 0x14 /* 1.4 */ {
+            let dict_len = reader.read_u32::<LittleEndian>().map_err(|err| reader.error(err))?;
+            if dict_len as usize > MAX_SIZE {
+                return Err(AssetDatabaseError::Corrupt { offset: reader.offset - 4 });
             }
 
-            let comp;
-            if entry.is_compressed {
-                comp = "(Compressed) ";
-            } else {
-                comp = "";
+            if dict_len > 0 {
+                let mut dict = vec![0x00; dict_len as usize];
+                reader.read_exact(&mut dict).map_err(|err| reader.error(err))?;
+
+                dictionary = Some(dict);
             }
+        }
 
-            log::info!("Loaded {}{}<{:#?}> {:>5}", comp, entry.entry_key, entry.entry_type,
-                bytesize::to_string((entry.entry_key.len() + 1 + entry.data.len()) as u64, false));
+        let mut content_version = String::new();
+        let mut base_versions = None;
+        let mut tombstones = Vec::new();
+        if version >= 0x16 /* 1.6 */ {
+            content_version = AssetDatabase::read_key(&mut reader)?;
+
+            if reader.read_u8().map_err(|err| reader.error(err))? != 0 {
+                base_versions = Some(AssetDatabase::read_keys(&mut reader)?);
+                tombstones = AssetDatabase::read_keys(&mut reader)?;
+            }
         }
 
-        Ok(db)
+        let entry_len = reader.read_u32::<LittleEndian>().map_err(|err| reader.error(err))?;
+
+        for _ in 0..entry_len {
+            let key = AssetDatabase::read_key(&mut reader)?;
+            let entry_type = reader.read_u8().map_err(|err| reader.error(err))?.into();
+            let codec_id = reader.read_u8().map_err(|err| reader.error(err))?;
+            let codec = Codec::from_id(codec_id)
+                .ok_or_else(|| AssetDatabaseError::UnknownCodec { key: key.to_string(), id: codec_id })?;
+
+            let mut offset = 0;
+            if version >= 0x12 /* 1.2 */ {
+                offset = reader.read_u64::<LittleEndian>().map_err(|err| reader.error(err))?;
+            }
+
+            let data_len = reader.read_u32::<LittleEndian>().map_err(|err| reader.error(err))?;
+
+            let mut checksum = None;
+            if version >= 0x13 /* 1.3 */ {
+                checksum = Some(reader.read_u32::<LittleEndian>().map_err(|err| reader.error(err))?);
+            }
+
+            let mut metadata = AssetMetadata::default();
+            if version >= 0x15 /* 1.5 */ {
+                let metadata_offset = reader.offset;
+
+                let metadata_len = reader.read_u32::<LittleEndian>().map_err(|err| reader.error(err))?;
+                if metadata_len > MAX_METADATA_LEN {
+                    return Err(AssetDatabaseError::Corrupt { offset: metadata_offset });
+                }
+
+                let mut metadata_bytes = vec![0x00; metadata_len as usize];
+                reader.read_exact(&mut metadata_bytes).map_err(|err| reader.error(err))?;
+
+                metadata = AssetMetadata::read_from(&metadata_bytes)
+                    .map_err(|_| AssetDatabaseError::Corrupt { offset: metadata_offset })?;
+            }
+
+            let mut references = Vec::new();
+            let mut includes = Vec::new();
+            if version >= 0x17 /* 1.7 */ {
+                references = AssetDatabase::read_keys(&mut reader)?;
+                includes = AssetDatabase::read_keys(&mut reader)?;
+            }
+
+            log::info!("Found asset {}<{:#?}>", key, entry_type);
+
+            entries.push(AssetEntry {
+                entry_key: key.to_string(),
+                entry_type,
+                codec,
+                metadata,
+                references,
+                includes,
+                data: Vec::new(),
+                compressed_data: Vec::new(),
+                location: Some((offset, data_len)),
+                checksum
+            });
+        }
+
+        // Before 1.2 the data rows directly follow the table
+        if version < 0x12 {
+            let mut offset = reader.offset;
+            for entry in &mut entries {
+                let (_, len) = entry.location.unwrap();
+                entry.location = Some((offset, len));
+
+                offset += len as u64;
+            }
+        }
+
+        Ok(DatabaseIndex { version, dictionary, content_version, base_versions, tombstones, entries })
+    }
+
+    fn read_keys<R: Read>(reader: &mut IndexReader<'_, R>) -> Result<Vec<String>, AssetDatabaseError> {
+        let len = reader.read_u32::<LittleEndian>().map_err(|err| reader.error(err))?;
+
+        let mut keys = Vec::new();
+        for _ in 0..len {
+            keys.push(AssetDatabase::read_key(reader)?);
+        }
+
+        Ok(keys)
     }
 
-    pub fn to_bytes(&mut self) -> std::io::Result<Vec<u8>> {
+    fn read_key<R: Read>(reader: &mut IndexReader<'_, R>) -> Result<String, AssetDatabaseError> {
+        let key_len = reader.read_u32::<LittleEndian>().map_err(|err| reader.error(err))?;
+        if key_len > MAX_KEY_LEN {
+            return Err(AssetDatabaseError::Corrupt { offset: reader.offset - 4 });
+        }
+
+        let mut key_bytes = vec![0x00; key_len as usize];
+        reader.read_exact(&mut key_bytes).map_err(|err| reader.error(err))?;
+
+        String::from_utf8(key_bytes).map_err(|_| AssetDatabaseError::InvalidKey)
+    }
+
+    pub fn to_bytes(&mut self) -> Result<Vec<u8>, AssetDatabaseError> {
+        self.write_bytes(None)
+    }
+
+    // secret_key is the 32 byte ed25519 secret key of whoever ships the archive
+    pub fn to_signed_bytes(&mut self, secret_key: &[u8]) -> Result<Vec<u8>, AssetDatabaseError> {
+        let secret = SecretKey::from_bytes(secret_key)
+            .map_err(|_| AssetDatabaseError::InvalidKeyPair)?;
+        let public = PublicKey::from(&secret);
+
+        self.write_bytes(Some(Keypair { secret, public }))
+    }
+
+    fn write_bytes(&mut self, keypair: Option<Keypair>) -> Result<Vec<u8>, AssetDatabaseError> {
         let mut data = Vec::<u8>::new();
 
+        // Pre compress, we need to know the length of every data row before writing the table.
+        // Identical rows are only written once, rows[row_of[i]] is the row of entry i.
+        let mut rows = Vec::with_capacity(self.entries.len());
+        let mut row_of = Vec::with_capacity(self.entries.len());
+        let mut row_hashes = HashMap::new();
+        for i in 0..self.entries.len() {
+            let row = match self.read_raw(&self.entries[i])? {
+                Some(raw_data) => raw_data, // Already stored the way we want it
+                None => {
+                    let dictionary = self.dictionary.clone();
+
+                    let entry = Arc::make_mut(&mut self.entries[i]);
+                    entry.compress(dictionary.as_ref().map(|dict| dict.as_slice()))?;
+
+                    entry.stored_data().to_vec()
+                }
+            };
+
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(&Sha256::digest(&row));
+
+            let index = *row_hashes.entry(hash).or_insert(rows.len());
+            if index == rows.len() {
+                rows.push(row);
+            }
+
+            row_of.push(index);
+        }
+
+        // The rows are compressed now, so we know what sharing them actually saved
+        for (i, payload) in self.entry_payloads.iter().enumerate() {
+            if let Some((_, size)) = self.payloads.get_mut(payload) {
+                *size = rows[row_of[i]].len();
+            }
+        }
+        self.deduplicated_size = self.payloads.values().map(|(uses, size)| (uses - 1) * size).sum();
+
+        let dictionary = self.dictionary().unwrap_or(&[]);
+
+        // Everything of an entry that follows its checksum
+        let mut metadata = Vec::with_capacity(self.entries.len());
+        for entry in &self.entries {
+            let mut bytes = Vec::new();
+            entry.metadata.write_to(&mut bytes)?;
+
+            let mut tail = Vec::new();
+            tail.write_u32::<LittleEndian>(bytes.len() as u32)?;
+            tail.write_all(&bytes)?;
+
+            for keys in &[&entry.references, &entry.includes] {
+                tail.write_u32::<LittleEndian>(keys.len() as u32)?;
+                for key in keys.iter() {
+                    write_key(&mut tail, key)?;
+                }
+            }
+
+            metadata.push(tail);
+        }
+
         data.write_u8(DATABASE_VERSION)?;
+        data.write_u32::<LittleEndian>(dictionary.len() as u32)?;
+        data.write_all(dictionary)?;
+
+        write_key(&mut data, &self.content_version)?;
+        data.write_u8(self.is_patch() as u8)?;
+        if let Some(base_versions) = &self.base_versions {
+            data.write_u32::<LittleEndian>(base_versions.len() as u32)?;
+            for version in base_versions {
+                write_key(&mut data, version)?;
+            }
+
+            data.write_u32::<LittleEndian>(self.tombstones.len() as u32)?;
+            for key in &self.tombstones {
+                write_key(&mut data, key)?;
+            }
+        }
+
+        let mut offset = data.len() as u64 + 4;
+        for (entry, metadata) in self.entries.iter().zip(&metadata) {
+            offset += 4 + entry.entry_key.len() as u64 + 1 + 1 + 8 + 4 + 4 + metadata.len() as u64;
+        }
+
+        let mut row_offsets = Vec::with_capacity(rows.len());
+        for row in &rows {
+            row_offsets.push(offset);
+            offset += row.len() as u64;
+        }
+
         data.write_u32::<LittleEndian>(self.entries.len() as u32)?;
-        for entry in &mut self.entries {
-            data.write_u32::<LittleEndian>(entry.entry_key.len() as u32)?;
-            data.write_all(entry.entry_key.as_bytes())?;
+        for ((entry, &row), metadata) in self.entries.iter().zip(&row_of).zip(&metadata) {
+            let (offset, row) = (row_offsets[row], &rows[row]);
+
+            write_key(&mut data, &entry.entry_key)?;
 
             data.write_u8(entry.entry_type.clone() as u8)?;
-            data.write_u8(entry.is_compressed as u8)?;
+            data.write_u8(entry.codec as u8)?;
 
-            // Pre compress
-            if entry.is_compressed {
-                let mut encoder = GzEncoder::new(vec![], Compression::best());
-                encoder.write_all(&entry.data)?;
-                entry.compressed_data = encoder.finish()?;
+            data.write_u64::<LittleEndian>(offset)?;
+            data.write_u32::<LittleEndian>(row.len() as u32)?;
+            data.write_u32::<LittleEndian>(crc32(row))?;
 
-                data.write_u32::<LittleEndian>(entry.compressed_data.len() as u32)?;
-            } else {
-                data.write_u32::<LittleEndian>(entry.data.len() as u32)?;
-            }
+            data.write_all(metadata)?;
         };
 
-        for entry in &self.entries {
-            if entry.is_compressed {
-                data.write_all(&entry.compressed_data)?;
-            } else {
-                data.write_all(&entry.data)?;
+        for row in &rows {
+            data.write_all(row)?;
+        }
+
+        let archive_hash = Sha256::digest(&data);
+        data.write_all(&archive_hash)?;
+
+        match keypair {
+            Some(keypair) => {
+                data.write_all(&keypair.sign(&archive_hash).to_bytes())?;
+                data.write_u8(1)?;
+            },
+            None => {
+                data.write_all(&[0x00; 64])?;
+                data.write_u8(0)?;
             }
         }
 
         Ok(data)
     }
 
-    pub fn iter(&self) -> Iter<'_, AssetEntry> {
+    pub fn iter(&self) -> Iter<'_, Arc<AssetEntry>> {
         self.entries.iter()
     }
 }
+
+fn write_key(w: &mut Vec<u8>, key: &str) -> std::io::Result<()> {
+    w.write_u32::<LittleEndian>(key.len() as u32)?;
+    w.write_all(key.as_bytes())
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+// Everything in front of the data rows
+struct DatabaseIndex {
+    version: u8,
+    dictionary: Option<Vec<u8>>,
+    content_version: String,
+    base_versions: Option<Vec<String>>,
+    tombstones: Vec<String>,
+    entries: Vec<AssetEntry>
+}
+
+// Keeps track of how far we've read into the entry table, so we can tell where an archive is broken
+struct IndexReader<'a, R: Read> {
+    reader: &'a mut R,
+    offset: u64
+}
+
+impl<'a, R: Read> IndexReader<'a, R> {
+    fn error(&self, err: std::io::Error) -> AssetDatabaseError {
+        match err.kind() {
+            std::io::ErrorKind::UnexpectedEof => AssetDatabaseError::Corrupt { offset: self.offset },
+            _ => AssetDatabaseError::Io(err)
+        }
+    }
+}
+
+impl<'a, R: Read> Read for IndexReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.offset += len as u64;
+
+        Ok(len)
+    }
+}
+
+#[test]
+fn lazy_database_roundtrip_test() {
+    let img = RgbaImage::from_raw(2, 1, vec![255, 0, 0, 255, 0, 255, 0, 255]).unwrap();
+
+    let mut db = AssetDatabase::new();
+    db.push_entry(AssetEntry::from_image("textures/test.png", img.clone())).unwrap();
+    db.push_entry(AssetEntry::from_audio("audio/test.ogg", vec![1, 2, 3, 4])).unwrap();
+
+    let db = AssetDatabase::from_bytes(db.to_bytes().unwrap()).unwrap();
+    assert!(db.iter().all(|entry| !entry.is_loaded()));
+
+    let texture = db.get_entry("textures/test.png").unwrap().unwrap();
+    assert_eq!(AssetEntry::clone(&texture).into_texture().unwrap(), img);
+
+    let audio = db.get_entry("audio/test.ogg").unwrap().unwrap();
+    assert_eq!(audio.raw_data(), &vec![1, 2, 3, 4]);
+}
+
+#[test]
+fn legacy_database_test() {
+    // 1.1 archive with a single uncompressed entry
+    let mut data = vec![0x11];
+    data.write_u32::<LittleEndian>(1).unwrap();
+    data.write_u32::<LittleEndian>(4).unwrap();
+    data.write_all(b"test").unwrap();
+    data.write_u8(AssetEntryType::Audio as u8).unwrap();
+    data.write_u8(0).unwrap();
+    data.write_u32::<LittleEndian>(3).unwrap();
+    data.write_all(&[7, 8, 9]).unwrap();
+
+    let db = AssetDatabase::from_bytes(data).unwrap();
+    let entry = db.get_entry("test").unwrap().unwrap();
+
+    assert_eq!(entry.r#type(), AssetEntryType::Audio);
+    assert_eq!(entry.raw_data(), &vec![7, 8, 9]);
+}
+
+#[test]
+fn corrupt_database_test() {
+    let mut db = AssetDatabase::new();
+    db.push_entry(AssetEntry::from_audio("audio/test.ogg", vec![1, 2, 3, 4])).unwrap();
+
+    let mut data = db.to_bytes().unwrap();
+    data.truncate(10);
+
+    match AssetDatabase::from_bytes(data) {
+        Err(AssetDatabaseError::Corrupt { offset }) => assert_eq!(offset, 10),
+        _ => panic!("expected a corrupt archive")
+    }
+
+    match AssetDatabase::from_bytes(vec![0x01, 0x00, 0x00, 0x00, 0x00]) {
+        Err(AssetDatabaseError::UnsupportedVersion(0x01)) => {},
+        _ => panic!("expected an unsupported version")
+    }
+
+    // So are sprites pointing at a page key longer than a key can be
+    let sprite = Sprite::new("@atlas/0000", AtlasRect { x: 0, y: 0, width: 4, height: 4 }, 16, 16);
+    let mut entry = AssetEntry::from_sprite("tiles/grass.png", &sprite);
+    assert_eq!(AssetEntry::clone(&entry).into_sprite().unwrap().page, "@atlas/0000");
+
+    entry.data[..4].copy_from_slice(&(MAX_KEY_LEN + 1).to_le_bytes());
+    assert!(matches!(entry.into_sprite(), Err(AssetDatabaseError::DecodeFailed { .. })));
+
+    // Rows past the end of a file aren't allocated just because the table says so
+    let path = std::env::temp_dir().join(format!("pxl_corrupt_database_test_{}.pxl", std::process::id()));
+    std::fs::write(&path, &[0u8; 16]).unwrap();
+
+    let source = DatabaseSource::File(Mutex::new(File::open(&path).unwrap()));
+    assert_eq!(source.read_at(8, 8).unwrap(), vec![0u8; 8]);
+    assert!(matches!(source.read_at(8, u32::MAX), Err(AssetDatabaseError::Corrupt { offset: 8 })));
+    assert!(matches!(source.read_at(u64::MAX, 1), Err(AssetDatabaseError::Corrupt { .. })));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn signed_database_test() {
+    let secret_key = [7u8; 32];
+    let public_key = PublicKey::from(&SecretKey::from_bytes(&secret_key).unwrap());
+
+    let mut db = AssetDatabase::new();
+    db.push_entry(AssetEntry::from_audio("audio/test.ogg", vec![1, 2, 3, 4])).unwrap();
+
+    let signed = AssetDatabase::from_bytes(db.to_signed_bytes(&secret_key).unwrap()).unwrap();
+    assert!(signed.verify_signature(public_key.as_bytes()).unwrap());
+    signed.verify().unwrap();
+
+    let unsigned = AssetDatabase::from_bytes(db.to_bytes().unwrap()).unwrap();
+    assert!(!unsigned.verify_signature(public_key.as_bytes()).unwrap());
+
+    // Flip a bit inside of the data row
+    let mut tampered = db.to_bytes().unwrap();
+    let len = tampered.len();
+    tampered[len - TRAILER_LEN as usize - 1] ^= 0x01;
+
+    match AssetDatabase::from_bytes(tampered) {
+        Err(AssetDatabaseError::ArchiveHashMismatch) => {},
+        _ => panic!("expected a hash mismatch")
+    }
+}
+
+#[test]
+fn animated_texture_roundtrip_test() {
+    let mut animation = AnimatedTexture::new(2, 2, LoopMode::PingPong);
+    animation.push_frame(RgbaImage::from_pixel(2, 2, image::Rgba([255, 0, 0, 255])), 80);
+    animation.push_frame(RgbaImage::from_pixel(2, 2, image::Rgba([0, 0, 255, 255])), 120);
+
+    let mut db = AssetDatabase::new();
+    db.push_entry(AssetEntry::from_animated_texture("characters/walk.png", &animation)).unwrap();
+
+    let db = AssetDatabase::from_bytes(db.to_bytes().unwrap()).unwrap();
+    let entry = db.get_entry("characters/walk.png").unwrap().unwrap();
+
+    let decoded = AssetEntry::clone(&entry).into_animated_texture().unwrap();
+    assert_eq!(decoded.loop_mode, LoopMode::PingPong);
+    assert_eq!(decoded.frames.len(), 2);
+    assert_eq!(decoded.frames[1].duration_ms, 120);
+    assert_eq!(decoded.frames[1].image, animation.frames[1].image);
+
+    // Sizes and frame counts the data can't hold are rejected before anything is allocated
+    let entry = AssetEntry::from_animated_texture("characters/walk.png", &animation);
+    let broken = |offset: usize, value: u32| {
+        let mut broken = entry.clone();
+        broken.data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+
+        broken.into_animated_texture().is_err()
+    };
+
+    assert!(broken(0, 0));              // width
+    assert!(broken(4, u32::MAX));       // height
+    assert!(broken(9, u32::MAX));       // frame count
+    assert!(broken(9, 3));
+}
+
+#[test]
+fn codec_database_test() {
+    let shader = b"#version 330 core\nuniform mat4 iMVP;\nvoid main() {}\n".to_vec();
+    let img = RgbaImage::from_pixel(16, 16, image::Rgba([0, 128, 255, 255]));
+
+    let mut db = AssetDatabase::new();
+    db.set_dictionary(Some(Arc::new(b"#version 330 core\nuniform mat4 iMVP;\n".to_vec()))).unwrap();
+    db.push_entry(AssetEntry::from_image("textures/test.png", img.clone())).unwrap();
+    db.push_entry(AssetEntry::from_audio("shaders/test.frag", shader.clone()).with_codec(Codec::Zstd)).unwrap();
+    db.push_entry(AssetEntry::from_audio("audio/test.ogg", vec![1, 2, 3, 4]).with_codec(Codec::Gzip)).unwrap();
+
+    let mut db = AssetDatabase::from_bytes(db.to_bytes().unwrap()).unwrap();
+    assert!(db.dictionary().is_some());
+
+    let texture = db.get_entry("textures/test.png").unwrap().unwrap();
+    assert_eq!(texture.codec(), Codec::Lz4);
+    assert_eq!(AssetEntry::clone(&texture).into_texture().unwrap(), img);
+
+    assert_eq!(db.get_entry("shaders/test.frag").unwrap().unwrap().raw_data(), &shader);
+    assert_eq!(db.get_entry("audio/test.ogg").unwrap().unwrap().raw_data(), &vec![1, 2, 3, 4]);
+
+    // Dropping the dictionary recompresses the zstd rows without it
+    db.set_dictionary(None).unwrap();
+
+    let db = AssetDatabase::from_bytes(db.to_bytes().unwrap()).unwrap();
+    assert!(db.dictionary().is_none());
+    assert_eq!(db.get_entry("shaders/test.frag").unwrap().unwrap().raw_data(), &shader);
+}
+
+#[test]
+fn dedup_database_test() {
+    let img = RgbaImage::from_pixel(16, 16, image::Rgba([0, 128, 255, 255]));
+
+    let mut db = AssetDatabase::new();
+    db.push_entry(AssetEntry::from_image("textures/a.png", img.clone())).unwrap();
+    db.push_entry(AssetEntry::from_image("textures/b.png", img.clone())).unwrap();
+    db.push_entry(AssetEntry::from_audio("audio/test.ogg", vec![1, 2, 3, 4])).unwrap();
+
+    let image_size = db.entry("textures/a.png").unwrap().size();
+    assert_eq!(db.deduplicated_size(), image_size);
+
+    // Writing compresses the rows, what's saved is the row that isn't written
+    let mut db = db;
+    let bytes = db.to_bytes().unwrap();
+    let written = db.deduplicated_size();
+
+    let db = AssetDatabase::from_bytes(bytes).unwrap();
+    let (a, b) = (db.entry("textures/a.png").unwrap(), db.entry("textures/b.png").unwrap());
+    assert_eq!(a.location, b.location);
+
+    assert!(a.size() < image_size);
+    assert_eq!(db.deduplicated_size(), a.size());
+    assert_eq!(written, a.size());
+
+    for key in &["textures/a.png", "textures/b.png"] {
+        let texture = db.get_entry(key).unwrap().unwrap();
+        assert_eq!(AssetEntry::clone(&texture).into_texture().unwrap(), img);
+    }
+
+    let mut db = db;
+    db.remove_entry("textures/a.png").unwrap();
+    assert_eq!(db.deduplicated_size(), 0);
+
+    db.remove_entry("textures/b.png").unwrap();
+    db.remove_entry("audio/test.ogg").unwrap();
+    assert_eq!(db.total_size, 0);
+}
+
+#[test]
+fn dedup_dictionary_test() {
+    let data = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+    let mut db = AssetDatabase::new();
+    db.push_entry(AssetEntry::from_audio("audio/a.ogg", data.clone()).with_codec(Codec::Zstd)).unwrap();
+    db.push_entry(AssetEntry::from_audio("audio/b.ogg", data.clone()).with_codec(Codec::Zstd)).unwrap();
+
+    // The loaded entries are counted by their decoded size, not by the row they came from
+    let mut db = AssetDatabase::from_bytes(db.to_bytes().unwrap()).unwrap();
+    db.set_dictionary(Some(Arc::new(data[..1024].to_vec()))).unwrap();
+    assert_eq!(db.deduplicated_size(), data.len());
+
+    db.remove_entry("audio/a.ogg").unwrap();
+    assert_eq!(db.deduplicated_size(), 0);
+
+    db.push_entry(AssetEntry::from_audio("audio/b.ogg", vec![1, 2, 3, 4]).with_codec(Codec::Zstd)).unwrap();
+    assert_eq!(db.total_size, 4 + 1);
+
+    // A row that's too big once it's decoded leaves the database the way it was
+    let mut full = AssetDatabase::new();
+    full.push_entry(AssetEntry::from_audio("audio/c.ogg", vec![0; MAX_SIZE / 2 + 1]).with_codec(Codec::Zstd)).unwrap();
+    let mut full = AssetDatabase::from_bytes(full.to_bytes().unwrap()).unwrap();
+    full.push_entry(AssetEntry::from_audio("audio/d.ogg", vec![1; MAX_SIZE / 2]).with_codec(Codec::None)).unwrap();
+
+    let total_size = full.total_size;
+    assert!(matches!(full.set_dictionary(Some(Arc::new(vec![0; 64]))), Err(AssetDatabaseError::DatabaseFull)));
+    assert!(full.dictionary().is_none());
+    assert_eq!(full.total_size, total_size);
+    assert!(!full.entry("audio/c.ogg").unwrap().is_loaded());
+
+    let db = AssetDatabase::from_bytes(db.to_bytes().unwrap()).unwrap();
+    assert!(db.entry("audio/a.ogg").is_none());
+    assert_eq!(db.get_entry("audio/b.ogg").unwrap().unwrap().raw_data(), &vec![1, 2, 3, 4]);
+}
+
+#[test]
+fn patch_database_test() {
+    let mut patch = AssetDatabase::new();
+    patch.set_content_version("1.1.0");
+    patch.set_base_versions(Some(vec!["1.0.0".to_string()]));
+    patch.push_entry(AssetEntry::from_audio("audio/new.ogg", vec![1, 2, 3, 4])).unwrap();
+    patch.push_entry(AssetEntry::from_audio("audio/old.ogg", vec![5])).unwrap();
+    patch.push_tombstone("audio/old.ogg");
+
+    assert!(patch.entry("audio/old.ogg").is_none());
+
+    let patch = AssetDatabase::from_bytes(patch.to_bytes().unwrap()).unwrap();
+    assert!(patch.is_patch());
+    assert_eq!(patch.content_version(), "1.1.0");
+    assert_eq!(patch.base_versions(), &["1.0.0".to_string()]);
+    assert!(patch.tombstones().contains("audio/old.ogg"));
+    assert_eq!(patch.get_entry("audio/new.ogg").unwrap().unwrap().raw_data(), &vec![1, 2, 3, 4]);
+
+    assert!(patch.applies_to("1.0.0"));
+    assert!(patch.applies_to("1.1.0")); // Another part of the same patch
+    assert!(!patch.applies_to("0.9.0"));
+
+    let mut db = AssetDatabase::new();
+    db.set_content_version("1.0.0");
+
+    let db = AssetDatabase::from_bytes(db.to_bytes().unwrap()).unwrap();
+    assert!(!db.is_patch());
+    assert_eq!(db.content_version(), "1.0.0");
+    assert!(!db.applies_to("1.0.0"));
+}
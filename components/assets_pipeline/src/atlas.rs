@@ -0,0 +1,309 @@
+// Packs small sprites into one or more atlas pages, so the renderer can batch
+// everything that lives on the same page into a single draw call.
+
+use image::RgbaImage;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AtlasRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32
+}
+
+// Skyline bottom-left bin packing.
+// The skyline is the silhouette of everything packed so far, new rectangles
+// are placed on top of it wherever they end up the lowest.
+pub(crate) struct SkylinePacker {
+    width: u32,
+    height: u32,
+    skyline: Vec<(u32, u32, u32)> // x, y, width
+}
+
+impl SkylinePacker {
+    pub(crate) fn new(width: u32, height: u32) -> SkylinePacker {
+        SkylinePacker {
+            width,
+            height,
+            skyline: vec![(0, 0, width)]
+        }
+    }
+
+    pub(crate) fn insert(&mut self, width: u32, height: u32) -> Option<(u32, u32)> {
+        let mut best: Option<(usize, u32, u32)> = None; // node, x, y
+
+        for i in 0..self.skyline.len() {
+            if let Some(y) = self.fits(i, width, height) {
+                // Lowest first, on a tie the narrowest node wastes the least space
+                let better = match best {
+                    Some((best_i, _, best_y)) => y < best_y
+                        || (y == best_y && self.skyline[i].2 < self.skyline[best_i].2),
+                    None => true
+                };
+
+                if better {
+                    best = Some((i, self.skyline[i].0, y));
+                }
+            }
+        }
+
+        let (i, x, y) = best?;
+        self.add_level(i, x, y + height, width);
+
+        Some((x, y))
+    }
+
+    // Lowest y a rectangle can be placed at when starting at skyline node i
+    fn fits(&self, i: usize, width: u32, height: u32) -> Option<u32> {
+        let x = self.skyline[i].0;
+        if x + width > self.width {
+            return None;
+        }
+
+        let mut y = 0;
+        let mut width_left = width as i64;
+        let mut i = i;
+
+        while width_left > 0 {
+            let node = self.skyline.get(i)?;
+
+            y = std::cmp::max(y, node.1);
+            if y + height > self.height {
+                return None;
+            }
+
+            width_left -= node.2 as i64;
+            i += 1;
+        }
+
+        Some(y)
+    }
+
+    fn add_level(&mut self, i: usize, x: u32, y: u32, width: u32) {
+        self.skyline.insert(i, (x, y, width));
+
+        // Shrink or remove every node the new one covers
+        let j = i + 1;
+        while j < self.skyline.len() {
+            let (prev_x, _, prev_width) = self.skyline[j - 1];
+            let node = &mut self.skyline[j];
+
+            if node.0 >= prev_x + prev_width {
+                break;
+            }
+
+            let shrink = prev_x + prev_width - node.0;
+            if node.2 <= shrink {
+                self.skyline.remove(j);
+            } else {
+                node.0 += shrink;
+                node.2 -= shrink;
+                break;
+            }
+        }
+
+        // Merge neighbours on the same level
+        let mut j = 0;
+        while j + 1 < self.skyline.len() {
+            if self.skyline[j].1 == self.skyline[j + 1].1 {
+                self.skyline[j].2 += self.skyline[j + 1].2;
+                self.skyline.remove(j + 1);
+            } else {
+                j += 1;
+            }
+        }
+    }
+}
+
+// A sprite entry, the part of an atlas page a sprite was packed into
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sprite {
+    pub page: String, // Key of the atlas page texture
+    pub rect: AtlasRect,
+    pub uv: [f32; 4]  // u0, v0, u1, v1
+}
+
+impl Sprite {
+    pub fn new<S: AsRef<str>>(page: S, rect: AtlasRect, page_width: u32, page_height: u32) -> Sprite {
+        Sprite {
+            page: page.as_ref().to_string(),
+            rect,
+            uv: [
+                rect.x as f32 / page_width as f32,
+                rect.y as f32 / page_height as f32,
+                (rect.x + rect.width) as f32 / page_width as f32,
+                (rect.y + rect.height) as f32 / page_height as f32
+            ]
+        }
+    }
+}
+
+pub struct AtlasPage {
+    pub image: RgbaImage,
+    pub sprites: Vec<(String, AtlasRect)>
+}
+
+pub struct AtlasBuilder {
+    page_size: u32,
+    padding: u32,
+    extrude: u32,
+
+    sprites: Vec<(String, RgbaImage)>
+}
+
+impl AtlasBuilder {
+    pub fn new(page_size: u32, padding: u32, extrude: u32) -> AtlasBuilder {
+        AtlasBuilder {
+            page_size,
+            padding,
+            extrude,
+
+            sprites: Vec::new()
+        }
+    }
+
+    // Space a sprite takes up on a page, including the extruded border and padding
+    fn padded_size(&self, img: &RgbaImage) -> (u32, u32) {
+        (img.width() + self.extrude * 2 + self.padding,
+         img.height() + self.extrude * 2 + self.padding)
+    }
+
+    pub fn does_fit(&self, img: &RgbaImage) -> bool {
+        self.does_fit_size(img.width(), img.height())
+    }
+
+    pub fn does_fit_size(&self, width: u32, height: u32) -> bool {
+        let border = self.extrude * 2 + self.padding;
+
+        width > 0 && height > 0
+            && width.saturating_add(border) <= self.page_size && height.saturating_add(border) <= self.page_size
+    }
+
+    // False if the sprite doesn't fit on a page, it's not packed then
+    pub fn push<S: AsRef<str>>(&mut self, key: S, img: RgbaImage) -> bool {
+        if !self.does_fit(&img) {
+            return false;
+        }
+
+        self.sprites.push((key.as_ref().to_string(), img));
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sprites.is_empty()
+    }
+
+    pub fn build(mut self) -> Vec<AtlasPage> {
+        // Tallest first packs the tightest, the key keeps the order deterministic
+        self.sprites.sort_by(|a, b| b.1.height().cmp(&a.1.height())
+            .then(b.1.width().cmp(&a.1.width()))
+            .then(a.0.cmp(&b.0)));
+
+        let mut pages = Vec::new();
+        let mut packer = SkylinePacker::new(self.page_size, self.page_size);
+        let mut placed = Vec::new();
+
+        let sprites = std::mem::take(&mut self.sprites);
+        for (key, img) in sprites {
+            let (width, height) = self.padded_size(&img);
+
+            let pos = match packer.insert(width, height) {
+                Some(pos) => pos,
+                None => { // Page is full, start a new one
+                    pages.push(self.render_page(std::mem::take(&mut placed)));
+                    packer = SkylinePacker::new(self.page_size, self.page_size);
+
+                    packer.insert(width, height).unwrap() // push() made sure it fits an empty page
+                }
+            };
+
+            placed.push((key, img, pos));
+        }
+
+        if !placed.is_empty() {
+            pages.push(self.render_page(placed));
+        }
+
+        pages
+    }
+
+    fn render_page(&self, placed: Vec<(String, RgbaImage, (u32, u32))>) -> AtlasPage {
+        // Shrink the page down to the smallest power of two that still fits everything
+        let mut used_width = 1;
+        let mut used_height = 1;
+        for (_, img, (x, y)) in &placed {
+            let (width, height) = self.padded_size(img);
+
+            used_width = std::cmp::max(used_width, x + width);
+            used_height = std::cmp::max(used_height, y + height);
+        }
+
+        let mut page = RgbaImage::new(used_width.next_power_of_two(), used_height.next_power_of_two());
+        let mut sprites = Vec::new();
+
+        for (key, img, (x, y)) in placed {
+            let rect = AtlasRect {
+                x: x + self.extrude,
+                y: y + self.extrude,
+                width: img.width(),
+                height: img.height()
+            };
+
+            // Repeat the edge pixels around the sprite so filtering doesn't bleed in its neighbours
+            let extrude = self.extrude as i64;
+            for py in -extrude..img.height() as i64 + extrude {
+                for px in -extrude..img.width() as i64 + extrude {
+                    let src_x = std::cmp::min(std::cmp::max(px, 0), img.width() as i64 - 1);
+                    let src_y = std::cmp::min(std::cmp::max(py, 0), img.height() as i64 - 1);
+
+                    page.put_pixel((rect.x as i64 + px) as u32, (rect.y as i64 + py) as u32,
+                                   *img.get_pixel(src_x as u32, src_y as u32));
+                }
+            }
+
+            sprites.push((key, rect));
+        }
+
+        AtlasPage {
+            image: page,
+            sprites
+        }
+    }
+}
+
+#[test]
+fn atlas_packing_test() {
+    let mut builder = AtlasBuilder::new(64, 2, 1);
+    for i in 0..20 {
+        assert!(builder.push(format!("sprite_{}", i), RgbaImage::new(12, 12 + i % 3)));
+    }
+
+    // Sprites bigger than a page are turned away instead of failing the build
+    assert!(!builder.push("huge", RgbaImage::new(64, 8)));
+    assert!(!builder.push("empty", RgbaImage::new(0, 0)));
+
+    let pages = builder.build();
+
+    let mut rects = Vec::new();
+    for (page_i, page) in pages.iter().enumerate() {
+        for (_, rect) in &page.sprites {
+            assert!(rect.x + rect.width <= page.image.width());
+            assert!(rect.y + rect.height <= page.image.height());
+
+            rects.push((page_i, *rect));
+        }
+    }
+
+    assert_eq!(rects.len(), 20);
+
+    // Nothing on the same page may overlap, including the extruded border
+    for (i, (page_a, a)) in rects.iter().enumerate() {
+        for (page_b, b) in &rects[i + 1..] {
+            let overlaps = page_a == page_b
+                && a.x < b.x + b.width + 2 && b.x < a.x + a.width + 2
+                && a.y < b.y + b.height + 2 && b.y < a.y + a.height + 2;
+
+            assert!(!overlaps, "{:?} overlaps {:?}", a, b);
+        }
+    }
+}
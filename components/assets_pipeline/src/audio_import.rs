@@ -0,0 +1,383 @@
+// Imports sounds and measures them, see audio_engine::AudioInfo for what's stored in front of the file.
+//
+// WAV and FLAC files are decoded, so their loudness is measured (CompileOptions::audio_loudness)
+// and they can be transcoded (CompileOptions::audio_format). OGG and MP3 files are stored as they are,
+// only their headers are read.
+//
+// Loop points come from the file itself: the smpl chunk of WAV files and the LOOPSTART, LOOPEND
+// or LOOPLENGTH comments (in samples) of FLAC and Ogg Vorbis files. loop_start/loop_end
+// in the .meta file still win at runtime.
+
+use std::convert::TryInto;
+use std::path::Path;
+
+use audio_engine::{AudioFormat, AudioInfo};
+
+use crate::{flac, wav, AssetDatabaseError, AssetEntry, CompileOptions};
+
+// Decoded samples, interleaved
+pub(crate) struct Pcm {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub bits: u8,         // 16 or 24 for WAV files, whatever the stream has for FLAC
+    pub samples: Vec<i32>
+}
+
+impl Pcm {
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels as usize
+    }
+}
+
+pub(crate) fn import(key: &str, path: &Path, options: &CompileOptions) -> Result<AssetEntry, AssetDatabaseError> {
+    let data = std::fs::read(path)?;
+    let format = AudioFormat::from_extension(path.extension().and_then(|ext| ext.to_str()).unwrap_or(""));
+
+    let decode_failed = || AssetDatabaseError::DecodeFailed { key: key.to_string() };
+
+    let (pcm, loop_points) = match format {
+        AudioFormat::Wav => wav::decode(&data).map(|(pcm, loop_points)| (Some(pcm), loop_points)).ok_or_else(decode_failed)?,
+        AudioFormat::Flac => flac::decode(&data).map(|(pcm, loop_points)| (Some(pcm), loop_points)).ok_or_else(decode_failed)?,
+        _ => (None, None)
+    };
+
+    let mut info = match &pcm {
+        Some(pcm) => AudioInfo {
+            format,
+            channels: pcm.channels as u8,
+            sample_rate: pcm.sample_rate,
+            length: pcm.frames() as u32,
+            gain: 0.0,
+            loop_points
+        },
+
+        None => {
+            let probed = match format {
+                AudioFormat::Ogg => probe_ogg(&data),
+                AudioFormat::Mp3 => probe_mp3(&data),
+                _ => None
+            };
+
+            // FMOD might still be able to play it
+            probed.unwrap_or_else(|| {
+                log::warn!("Couldn't read the header of {}, storing it without any info", key);
+
+                AudioInfo { format, channels: 0, sample_rate: 0, length: 0, gain: 0.0, loop_points: None }
+            })
+        }
+    };
+
+    // OGG and MP3 aren't decoded, mixed with normalized sounds they stand out
+    if options.audio_loudness.is_some() && pcm.is_none() {
+        log::warn!("Can't measure the loudness of {}, only WAV and FLAC sources are normalized", key);
+    }
+
+    if let (Some(target), Some(pcm)) = (options.audio_loudness, &pcm) {
+        match loudness(pcm) {
+            // Never louder than the loudest sample allows
+            Some((loudness, peak)) => {
+                info.gain = (target - loudness).min(-peak);
+
+                log::debug!("{} is {:.1} LUFS, peaks at {:.1} dBFS, gain {:+.1} dB", key, loudness, peak, info.gain);
+            }
+
+            None => log::debug!("{} is silent, not normalizing it", key)
+        }
+    }
+
+    let data = match (options.audio_format, &pcm) {
+        (Some(target), _) if target == format => data,
+
+        (Some(AudioFormat::Wav), Some(pcm)) => {
+            info.format = AudioFormat::Wav;
+            wav::encode(pcm)
+        }
+
+        (Some(AudioFormat::Flac), Some(pcm)) => {
+            info.format = AudioFormat::Flac;
+            flac::encode(pcm)
+        }
+
+        (Some(target), _) => {
+            log::warn!("Can't transcode {} to {:?}, WAV and FLAC sources can only be transcoded to WAV or FLAC", key, target);
+            data
+        }
+
+        (None, _) => data
+    };
+
+    Ok(AssetEntry::from_audio_file(key, &info, &data))
+}
+
+// Vorbis comments (used by FLAC and Ogg Vorbis), without the vendor string. Lengths are little endian
+pub(crate) fn read_vorbis_comments(data: &[u8]) -> Option<Vec<String>> {
+    let u32_at = |offset: usize| data.get(offset..offset + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()) as usize);
+
+    let mut offset = 4 + u32_at(0)?;
+    let count = u32_at(offset)?;
+    offset += 4;
+
+    let mut comments = Vec::new();
+    for _ in 0..count {
+        let len = u32_at(offset)?;
+        comments.push(String::from_utf8_lossy(data.get(offset + 4..offset + 4 + len)?).to_string());
+
+        offset += 4 + len;
+    }
+
+    Some(comments)
+}
+
+// LOOPSTART with either LOOPEND (exclusive) or LOOPLENGTH, the loop ends with the sound if neither is there
+pub(crate) fn loop_points_of(comments: &[String], length: u32) -> Option<(u32, u32)> {
+    let tag = |name: &str| comments.iter()
+        .filter_map(|comment| {
+            let at = comment.find('=')?;
+            match comment[..at].eq_ignore_ascii_case(name) {
+                true => comment[at + 1..].trim().parse::<u32>().ok(),
+                false => None
+            }
+        })
+        .next();
+
+    let start = tag("LOOPSTART")?;
+    let end = match (tag("LOOPEND"), tag("LOOPLENGTH")) {
+        (Some(end), _) => end,
+        (None, Some(len)) => start.saturating_add(len),
+        (None, None) => length
+    };
+
+    if end > start { Some((start, end)) } else { None }
+}
+
+// Ogg Vorbis. The first two packets are the identification and the comment header,
+// the granule position of the last page is the length.
+fn probe_ogg(data: &[u8]) -> Option<AudioInfo> {
+    let mut packets = Vec::new();
+    let mut packet = Vec::new();
+    let mut offset = 0;
+    while packets.len() < 2 {
+        let page = data.get(offset..)?;
+        if !page.starts_with(b"OggS") {
+            return None;
+        }
+
+        let segments = *page.get(26)? as usize;
+        let mut body = 27 + segments;
+        for len in page.get(27..27 + segments)?.iter().map(|len| *len as usize) {
+            packet.extend_from_slice(page.get(body..body + len)?);
+            body += len;
+
+            // Packets continue in the next segment if this one is full
+            if len < 255 {
+                packets.push(std::mem::take(&mut packet));
+            }
+        }
+
+        offset += body;
+    }
+
+    let identification = &packets[0];
+    if !identification.starts_with(b"\x01vorbis") || identification.len() < 16 {
+        return None;
+    }
+
+    let last_page = data.windows(4).rposition(|window| window == b"OggS")?;
+    let granule = i64::from_le_bytes(data.get(last_page + 6..last_page + 14)?.try_into().unwrap());
+    let length = std::cmp::max(granule, 0) as u32;
+
+    let comments = match packets[1].starts_with(b"\x03vorbis") {
+        true => read_vorbis_comments(&packets[1][7..]).unwrap_or_default(),
+        false => Vec::new()
+    };
+
+    Some(AudioInfo {
+        format: AudioFormat::Ogg,
+        channels: identification[11],
+        sample_rate: u32::from_le_bytes(identification[12..16].try_into().unwrap()),
+        length,
+        gain: 0.0,
+        loop_points: loop_points_of(&comments, length)
+    })
+}
+
+// MPEG layer III, counts the frames for the length
+fn probe_mp3(data: &[u8]) -> Option<AudioInfo> {
+    const BITRATES_V1: [u32; 15] = [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320];
+    const BITRATES_V2: [u32; 15] = [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160];
+    const SAMPLE_RATES: [[u32; 3]; 4] = [[11025, 12000, 8000], [0, 0, 0], [22050, 24000, 16000], [44100, 48000, 32000]];
+
+    // The size of an ID3v2 tag is stored in 7 bits per byte
+    let mut offset = 0;
+    if data.starts_with(b"ID3") && data.len() >= 10 {
+        offset = 10 + data[6..10].iter().fold(0, |size, b| (size << 7) | (*b & 0x7f) as usize);
+    }
+
+    let (mut frames, mut channels, mut sample_rate, mut samples_per_frame) = (0, 0, 0, 0);
+    while let Some(header) = data.get(offset..offset + 4) {
+        let header = u32::from_be_bytes(header.try_into().unwrap());
+
+        let version = (header >> 19 & 3) as usize; // 3 = MPEG 1, 2 = MPEG 2, 0 = MPEG 2.5
+        let layer = header >> 17 & 3;              // 1 = Layer III
+        let bitrate = (header >> 12 & 0xf) as usize;
+        let rate = (header >> 10 & 3) as usize;
+
+        // Anything else is the end of the stream, E.G an ID3v1 tag
+        if header >> 21 != 0x7ff || version == 1 || layer != 1 || bitrate == 0 || bitrate == 15 || rate == 3 {
+            break;
+        }
+
+        let mpeg1 = version == 3;
+        let bitrate = if mpeg1 { BITRATES_V1[bitrate] } else { BITRATES_V2[bitrate] } * 1000;
+
+        sample_rate = SAMPLE_RATES[version][rate];
+        samples_per_frame = if mpeg1 { 1152 } else { 576 };
+        channels = if header >> 6 & 3 == 3 { 1 } else { 2 };
+
+        offset += (samples_per_frame / 8 * bitrate / sample_rate + (header >> 9 & 1)) as usize;
+        frames += 1;
+    }
+
+    if frames == 0 {
+        return None;
+    }
+
+    Some(AudioInfo {
+        format: AudioFormat::Mp3,
+        channels,
+        sample_rate,
+        length: frames * samples_per_frame,
+        gain: 0.0,
+        loop_points: None
+    })
+}
+
+// Transposed direct form II
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2]
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+
+        y
+    }
+}
+
+// The K-weighting filter of BS.1770 is a high shelf followed by a high pass,
+// both of them recalculated for the sample rate like libebur128 does
+fn k_weighting(sample_rate: f64) -> (Biquad, Biquad) {
+    let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (std::f64::consts::PI * f0 / sample_rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+
+    let shelf = Biquad {
+        b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2]
+    };
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (std::f64::consts::PI * f0 / sample_rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2]
+    };
+
+    (shelf, high_pass)
+}
+
+// Integrated loudness in LUFS (ITU-R BS.1770-4) and the sample peak in dBFS, None for silence.
+// Every channel is weighted the same, sounds shorter than a block are measured as one.
+fn loudness(pcm: &Pcm) -> Option<(f32, f32)> {
+    let channels = pcm.channels as usize;
+    let scale = 1.0 / (1i64 << (pcm.bits - 1)) as f64;
+
+    let mut filters = (0..channels)
+        .map(|_| k_weighting(pcm.sample_rate as f64))
+        .collect::<Vec<_>>();
+
+    // Blocks are 400 ms long and overlap by 75%, so they're summed up from 100 ms steps
+    let step = std::cmp::max(pcm.sample_rate as usize / 10, 1);
+
+    let mut steps = Vec::new();
+    let mut peak = 0f64;
+    for (i, frame) in pcm.samples.chunks_exact(channels).enumerate() {
+        if i % step == 0 {
+            steps.push(0.0);
+        }
+
+        let power = steps.last_mut().unwrap();
+        for (sample, (shelf, high_pass)) in frame.iter().zip(&mut filters) {
+            let x = *sample as f64 * scale;
+            let y = high_pass.process(shelf.process(x));
+
+            peak = peak.max(x.abs());
+            *power += y * y;
+        }
+    }
+
+    if peak == 0.0 {
+        return None;
+    }
+
+    let blocks = match steps.len() {
+        0..=3 => vec![steps.iter().sum::<f64>() / pcm.frames() as f64],
+        _ => steps.windows(4).map(|block| block.iter().sum::<f64>() / (step * 4) as f64).collect()
+    };
+
+    let lufs = |power: f64| -0.691 + 10.0 * power.log10();
+    let gated = |threshold: f64| {
+        let (sum, count) = blocks.iter()
+            .filter(|power| lufs(**power) > threshold)
+            .fold((0.0, 0), |(sum, count), power| (sum + power, count + 1));
+
+        if count > 0 { Some(sum / count as f64) } else { None }
+    };
+
+    // An absolute gate at -70 LUFS, then a relative one 10 LU below what's left
+    let absolute = gated(-70.0)?;
+    let relative = gated(lufs(absolute) - 10.0)?;
+
+    Some((lufs(relative) as f32, (20.0 * peak.log10()) as f32))
+}
+
+#[test]
+fn loudness_test() {
+    // A full scale 997 Hz sine is -3.01 LUFS
+    let sine = |amplitude: f64, seconds: usize| Pcm {
+        channels: 1,
+        sample_rate: 48000,
+        bits: 16,
+        samples: (0..48000 * seconds)
+            .map(|i| ((i as f64 * 997.0 * 2.0 * std::f64::consts::PI / 48000.0).sin() * amplitude * 32767.0) as i32)
+            .collect()
+    };
+
+    let (lufs, peak) = loudness(&sine(1.0, 3)).unwrap();
+    assert!((lufs + 3.01).abs() < 0.05, "{}", lufs);
+    assert!(peak.abs() < 0.01);
+
+    let (lufs, _) = loudness(&sine(0.5, 3)).unwrap();
+    assert!((lufs + 9.03).abs() < 0.05, "{}", lufs);
+
+    // Shorter than a block
+    assert!(loudness(&sine(1.0, 0)).is_none());
+    let short = Pcm { samples: sine(1.0, 1).samples[..4800].to_vec(), ..sine(1.0, 0) };
+    assert!((loudness(&short).unwrap().0 + 3.01).abs() < 0.5);
+
+    let comments = vec!["TITLE=Theme".to_string(), "loopstart=100".to_string(), "LOOPLENGTH=50".to_string()];
+    assert_eq!(loop_points_of(&comments, 1000), Some((100, 150)));
+    assert_eq!(loop_points_of(&comments[..2], 1000), Some((100, 1000)));
+    assert_eq!(loop_points_of(&comments[..1], 1000), None);
+}
@@ -0,0 +1,245 @@
+// Compiled entries of earlier builds, keyed by a hash of everything that went into them
+// (source files, import settings, format versions). Reused entries keep their stored data row,
+// so they end up in the new archive byte-for-byte without being imported or compressed again.
+//
+// Every hash is one file in the cache folder:
+//   Entries[] {
+//       Entry Key: String
+//       Entry Type: u8
+//       Codec: u8
+//       Metadata: [u8]
+//       References: [String]
+//       Includes: [String]
+//       Data Row: [u8]
+//   }
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use sha2::{Digest, Sha256};
+
+use crate::{AssetDatabaseError, AssetEntry, AssetEntryType, AssetMetadata, Codec, DATABASE_VERSION};
+
+// Bump whenever an importer produces different output for the same input
+const CACHE_VERSION: u8 = 6;
+const CACHE_EXTENSION: &str = "bin";
+
+pub struct BuildCache {
+    dir: PathBuf,
+    dictionary: Option<Arc<Vec<u8>>>, // Cached zstd rows were compressed with it
+
+    used: Mutex<HashSet<String>>, // Hashes needed by this build, everything else gets pruned
+    hits: AtomicUsize,
+    misses: AtomicUsize
+}
+
+impl BuildCache {
+    pub fn open<P: AsRef<Path>>(dir: P, dictionary: Option<Arc<Vec<u8>>>) -> Result<BuildCache, AssetDatabaseError> {
+        std::fs::create_dir_all(dir.as_ref())?;
+
+        Ok(BuildCache {
+            dir: dir.as_ref().to_path_buf(),
+            dictionary,
+
+            used: Mutex::new(HashSet::new()),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0)
+        })
+    }
+
+    // Hex encoded SHA-256 of all inputs, each of them is length prefixed so ["ab", "c"] != ["a", "bc"]
+    pub fn hash(inputs: &[&[u8]]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(&[CACHE_VERSION, DATABASE_VERSION]);
+
+        for input in inputs {
+            hasher.update(&(input.len() as u64).to_le_bytes());
+            hasher.update(input);
+        }
+
+        hex::encode(hasher.finalize())
+    }
+
+    // A damaged cache file is treated like a miss
+    pub fn get(&self, hash: &str) -> Option<Vec<AssetEntry>> {
+        self.used.lock().unwrap().insert(hash.to_string());
+
+        let path = self.path_of(hash);
+        if !path.is_file() {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        match self.read_entries(&path) {
+            Ok(entries) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+
+                Some(entries)
+            }
+
+            Err(err) => {
+                log::warn!("Ignoring broken build cache file {}: {}", path.display(), err);
+                self.misses.fetch_add(1, Ordering::Relaxed);
+
+                None
+            }
+        }
+    }
+
+    // The entries have to be compressed already
+    pub fn insert(&self, hash: &str, entries: &[AssetEntry]) -> Result<(), AssetDatabaseError> {
+        self.used.lock().unwrap().insert(hash.to_string());
+
+        let mut data = Vec::new();
+        data.write_u32::<LittleEndian>(entries.len() as u32)?;
+
+        for entry in entries {
+            data.write_u32::<LittleEndian>(entry.entry_key.len() as u32)?;
+            data.write_all(entry.entry_key.as_bytes())?;
+
+            data.write_u8(entry.entry_type as u8)?;
+            data.write_u8(entry.codec as u8)?;
+
+            let mut metadata = Vec::new();
+            entry.metadata.write_to(&mut metadata)?;
+            data.write_u32::<LittleEndian>(metadata.len() as u32)?;
+            data.write_all(&metadata)?;
+
+            for keys in &[&entry.references, &entry.includes] {
+                data.write_u32::<LittleEndian>(keys.len() as u32)?;
+                for key in keys.iter() {
+                    data.write_u32::<LittleEndian>(key.len() as u32)?;
+                    data.write_all(key.as_bytes())?;
+                }
+            }
+
+            let row = entry.stored_data();
+            data.write_u32::<LittleEndian>(row.len() as u32)?;
+            data.write_all(row)?;
+        }
+
+        // Write and rename, a build that gets killed halfway doesn't leave a broken file behind
+        let path = self.path_of(hash);
+        let tmp_path = path.with_extension("tmp");
+
+        File::create(&tmp_path)?.write_all(&data)?;
+        std::fs::rename(&tmp_path, &path)?;
+
+        Ok(())
+    }
+
+    // Deletes every file this build didn't ask for, returns how many
+    pub fn prune(&self) -> Result<usize, AssetDatabaseError> {
+        let used = self.used.lock().unwrap();
+        let mut pruned = 0;
+
+        for file in std::fs::read_dir(&self.dir)? {
+            let path = file?.path();
+
+            let is_used = path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .map(|stem| used.contains(stem))
+                .unwrap_or(false);
+
+            if !is_used && path.is_file() {
+                std::fs::remove_file(&path)?;
+                pruned += 1;
+            }
+        }
+
+        Ok(pruned)
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> usize {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    fn path_of(&self, hash: &str) -> PathBuf {
+        self.dir.join(hash).with_extension(CACHE_EXTENSION)
+    }
+
+    fn read_entries(&self, path: &Path) -> Result<Vec<AssetEntry>, AssetDatabaseError> {
+        let data = std::fs::read(path)?;
+        let mut cursor = Cursor::new(&data);
+
+        let corrupt = |cursor: &Cursor<&Vec<u8>>| AssetDatabaseError::Corrupt { offset: cursor.position() };
+
+        let read_key = |cursor: &mut Cursor<&Vec<u8>>| -> Result<String, AssetDatabaseError> {
+            let key_len = cursor.read_u32::<LittleEndian>().map_err(|_| corrupt(cursor))?;
+            if key_len > crate::asset_database::MAX_KEY_LEN {
+                return Err(AssetDatabaseError::Corrupt { offset: cursor.position() - 4 });
+            }
+
+            let mut key = vec![0x00; key_len as usize];
+            cursor.read_exact(&mut key).map_err(|_| corrupt(cursor))?;
+
+            String::from_utf8(key).map_err(|_| AssetDatabaseError::InvalidKey)
+        };
+
+        let read_keys = |cursor: &mut Cursor<&Vec<u8>>| -> Result<Vec<String>, AssetDatabaseError> {
+            let count = cursor.read_u32::<LittleEndian>().map_err(|_| corrupt(cursor))?;
+
+            (0..count).map(|_| read_key(cursor)).collect()
+        };
+
+        let count = cursor.read_u32::<LittleEndian>().map_err(|_| corrupt(&cursor))?;
+
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let key = read_key(&mut cursor)?;
+
+            let entry_type = AssetEntryType::from(cursor.read_u8().map_err(|_| corrupt(&cursor))?);
+            let codec = Codec::from_id(cursor.read_u8().map_err(|_| corrupt(&cursor))?)
+                .ok_or_else(|| corrupt(&cursor))?;
+
+            let metadata_len = cursor.read_u32::<LittleEndian>().map_err(|_| corrupt(&cursor))? as usize;
+            let start = cursor.position() as usize;
+            let metadata = data.get(start..start + metadata_len)
+                .and_then(|metadata| AssetMetadata::read_from(metadata).ok())
+                .ok_or_else(|| corrupt(&cursor))?;
+            cursor.set_position((start + metadata_len) as u64);
+
+            let references = read_keys(&mut cursor)?;
+            let includes = read_keys(&mut cursor)?;
+
+            let row_len = cursor.read_u32::<LittleEndian>().map_err(|_| corrupt(&cursor))? as usize;
+            let start = cursor.position() as usize;
+            let row = data.get(start..start + row_len).ok_or_else(|| corrupt(&cursor))?.to_vec();
+            cursor.set_position((start + row_len) as u64);
+
+            // Databases count the uncompressed size, so we still need the data
+            let (data, compressed_data) = if codec == Codec::None {
+                (row, Vec::new())
+            } else {
+                let data = codec.decompress(&row, self.dictionary.as_ref().map(|dict| dict.as_slice()))
+                    .map_err(|_| AssetDatabaseError::DecodeFailed { key: key.clone() })?;
+
+                (data, row)
+            };
+
+            entries.push(AssetEntry {
+                entry_type,
+                entry_key: key,
+                codec,
+                metadata,
+                references,
+                includes,
+                data,
+                compressed_data,
+                location: None,
+                checksum: None
+            });
+        }
+
+        Ok(entries)
+    }
+}
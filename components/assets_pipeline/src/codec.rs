@@ -0,0 +1,174 @@
+// How the data row of an entry is compressed. The id is stored in the entry table,
+// 0 and 1 are the old "Compressed" flag so archives before 1.4 read just fine.
+//
+// Zstd entries share the dictionary stored in the archive header (if there is one),
+// lz4 is meant for big rows that are needed at startup, it decodes a lot faster than the others.
+
+use std::io::{self, Cursor, Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::Deserialize;
+
+use crate::{AssetEntryType, MAX_SIZE};
+
+const ZSTD_LEVEL: i32 = 19;
+const LZ4_LEVEL: i32 = 9;
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    None = 0,
+    Gzip = 1,
+    Zstd = 2,
+    Lz4 = 3
+}
+
+impl Codec {
+    // None for ids we don't know, reading such an archive would only produce garbage
+    pub fn from_id(id: u8) -> Option<Codec> {
+        match id {
+            0 => Some(Codec::None),
+            1 => Some(Codec::Gzip),
+            2 => Some(Codec::Zstd),
+            3 => Some(Codec::Lz4),
+
+            _ => None
+        }
+    }
+
+    // Used by compile_folder unless CompileOptions::codecs says otherwise
+    pub fn default_for(entry_type: AssetEntryType) -> Codec {
+        match entry_type {
+            AssetEntryType::Texture
+            | AssetEntryType::AnimatedTexture
+            | AssetEntryType::Font => Codec::Lz4,
+
+            // Audio is ogg/mp3/flac already (WAV is better off transcoded to FLAC), the rest is tiny.
+            // Video frames are compressed one by one.
+            AssetEntryType::Audio
+            | AssetEntryType::Video
+            | AssetEntryType::Particle
+            | AssetEntryType::Sprite => Codec::None,
+
+            AssetEntryType::Shader
+            | AssetEntryType::StringTable => Codec::Zstd,
+
+            AssetEntryType::Unknown => Codec::Gzip
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+            Codec::Lz4 => "lz4"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Codec> {
+        match name {
+            "none" => Some(Codec::None),
+            "gzip" => Some(Codec::Gzip),
+            "zstd" => Some(Codec::Zstd),
+            "lz4" => Some(Codec::Lz4),
+
+            _ => None
+        }
+    }
+
+    // dictionary is only used by zstd
+    pub fn compress(self, data: &[u8], dictionary: Option<&[u8]>) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+
+            Codec::Gzip => {
+                let mut encoder = GzEncoder::new(vec![], Compression::best());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+
+            Codec::Zstd => {
+                let mut encoder = zstd::stream::Encoder::with_dictionary(vec![], ZSTD_LEVEL, dictionary.unwrap_or(&[]))?;
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+
+            // The uncompressed size is prepended, decompress() needs it
+            Codec::Lz4 => lz4::block::compress(data, Some(lz4::block::CompressionMode::HIGHCOMPRESSION(LZ4_LEVEL)), true)
+        }
+    }
+
+    pub fn decompress(self, row: &[u8], dictionary: Option<&[u8]>) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(row.to_vec()),
+
+            Codec::Gzip => read_limited(GzDecoder::new(Cursor::new(row)), MAX_SIZE),
+            Codec::Zstd => read_limited(zstd::stream::Decoder::with_dictionary(Cursor::new(row), dictionary.unwrap_or(&[]))?, MAX_SIZE),
+
+            // The size prefix is whatever the archive says, lz4 would allocate it without asking
+            Codec::Lz4 => {
+                let len = Cursor::new(row).read_u32::<LittleEndian>()? as usize;
+                if len > MAX_SIZE {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "lz4 row is bigger than an entry can be"));
+                }
+
+                if len == 0 {
+                    return Ok(Vec::new());
+                }
+
+                lz4::block::decompress(&row[4..], Some(len as i32))
+            }
+        }
+    }
+
+    // Trains a zstd dictionary, samples should be a bunch of small entries that look alike (e.g shaders)
+    pub fn train_dictionary<S: AsRef<[u8]>>(samples: &[S], max_size: usize) -> io::Result<Vec<u8>> {
+        zstd::dict::from_samples(samples, max_size)
+    }
+}
+
+// A tiny row can expand to gigabytes, we stop reading once it's bigger than an entry can be
+fn read_limited<R: Read>(reader: R, limit: usize) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader.take(limit as u64 + 1).read_to_end(&mut data)?;
+
+    if data.len() > limit {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "row decompresses to more than an entry can be"));
+    }
+
+    Ok(data)
+}
+
+#[test]
+fn codec_roundtrip_test() {
+    let data = b"#version 330 core\nvoid main() { gl_Position = vec4(0.0); }\n".repeat(16);
+
+    for codec in &[Codec::None, Codec::Gzip, Codec::Zstd, Codec::Lz4] {
+        let row = codec.compress(&data, None).unwrap();
+        assert_eq!(codec.decompress(&row, None).unwrap(), data);
+
+        assert_eq!(Codec::from_id(*codec as u8), Some(*codec));
+        assert_eq!(Codec::from_name(codec.name()), Some(*codec));
+    }
+
+    assert_eq!(Codec::from_id(0xFF), None);
+
+    // A size prefix past MAX_SIZE is rejected before anything is allocated
+    let mut row = Codec::Lz4.compress(&data, None).unwrap();
+    row[..4].copy_from_slice(&(MAX_SIZE as u32 + 1).to_le_bytes());
+    assert_eq!(Codec::Lz4.decompress(&row, None).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    // Gzip and zstd don't say how big they are, they're cut off instead
+    let row = Codec::Gzip.compress(&data, None).unwrap();
+    assert_eq!(read_limited(GzDecoder::new(Cursor::new(&row)), data.len()).unwrap(), data);
+    assert_eq!(read_limited(GzDecoder::new(Cursor::new(&row)), data.len() - 1).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    let row = Codec::Zstd.compress(&data, None).unwrap();
+    let decoder = zstd::stream::Decoder::new(Cursor::new(&row)).unwrap();
+    assert_eq!(read_limited(decoder, 64).unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
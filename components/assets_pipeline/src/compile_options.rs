@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use audio_engine::AudioFormat;
+use sha2::{Digest, Sha256};
+
+use crate::{AssetEntryType, Codec};
+
+// Settings for AssetPipeline::compile_folder_with()
+#[derive(Clone, Debug)]
+pub struct CompileOptions {
+    pub atlas_page_size: u32,
+    pub atlas_padding: u32,         // Empty pixels between two sprites
+    pub atlas_extrude: u32,         // How often the edge pixels of a sprite are repeated
+    pub atlas_max_sprite_size: u32, // Larger textures are stored on their own, 0 disables the atlas
+
+    pub codecs: HashMap<AssetEntryType, Codec>, // Overrides Codec::default_for()
+    pub zstd_dictionary: Option<Arc<Vec<u8>>>,  // Stored in every archive, see Codec::train_dictionary()
+
+    pub audio_format: Option<AudioFormat>, // WAV and FLAC sources are transcoded to this, None keeps them as they are
+    // Target loudness in LUFS (E.G -16.0), None doesn't normalize. Only WAV and FLAC sources can be
+    // measured, OGG and MP3 keep their loudness (with a warning), so keep SFX you normalize in WAV or FLAC.
+    pub audio_loudness: Option<f32>,
+
+    pub cache_dir: Option<PathBuf>, // Unchanged entries are reused from here, see BuildCache
+
+    pub content_version: String // Stored in every archive, patches name the versions they apply to
+}
+
+impl CompileOptions {
+    pub fn codec_for(&self, entry_type: AssetEntryType) -> Codec {
+        match self.codecs.get(&entry_type) {
+            Some(codec) => *codec,
+            None => Codec::default_for(entry_type)
+        }
+    }
+
+    // Everything that changes how entries are imported or compressed, part of every build cache hash
+    pub(crate) fn build_inputs(&self) -> Vec<u8> {
+        let mut codecs = self.codecs.iter()
+            .map(|(entry_type, codec)| [*entry_type as u8, *codec as u8])
+            .collect::<Vec<_>>();
+        codecs.sort();
+
+        let mut inputs = codecs.concat();
+        if let Some(dictionary) = &self.zstd_dictionary {
+            inputs.extend_from_slice(&Sha256::digest(dictionary));
+        }
+
+        inputs.push(self.audio_format.map_or(0xff, |format| format as u8));
+        inputs.extend_from_slice(&self.audio_loudness.unwrap_or(f32::NAN).to_le_bytes());
+
+        inputs
+    }
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        CompileOptions {
+            atlas_page_size: 2048,
+            atlas_padding: 2,
+            atlas_extrude: 1,
+            atlas_max_sprite_size: 256,
+
+            codecs: HashMap::new(),
+            zstd_dictionary: None,
+
+            audio_format: None,
+            audio_loudness: None,
+
+            cache_dir: None,
+
+            content_version: String::new()
+        }
+    }
+}
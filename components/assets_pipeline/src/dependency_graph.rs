@@ -0,0 +1,204 @@
+// Which entries need which. Every entry records the entries it references at runtime (a sprite
+// its atlas page, a particle effect its texture) and the source files that were compiled into it
+// (shader includes, the frames of a video). Neither needs a data row, the entry table has them.
+//
+// References to keys that aren't an entry are dangling, compile_folder fails on them.
+// Includes never are, they're part of the entry that includes them.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use crate::{AssetDatabase, AssetEntry};
+
+// See AssetPipeline::build_report()
+#[derive(Debug, Default)]
+pub struct BuildReport {
+    pub orphans: Vec<String>,           // Files below the root nothing was compiled from, relative to it
+    pub dangling: Vec<(String, String)> // (entry, reference) for every reference that isn't an entry
+}
+
+impl BuildReport {
+    pub fn is_clean(&self) -> bool {
+        self.orphans.is_empty() && self.dangling.is_empty()
+    }
+}
+
+#[derive(Default)]
+struct Node {
+    references: Vec<String>,
+    includes: Vec<String>
+}
+
+#[derive(Default)]
+pub struct DependencyGraph {
+    nodes: BTreeMap<String, Node>,                 // Every entry by key
+    dependents: BTreeMap<String, BTreeSet<String>> // Key -> entries that reference or include it
+}
+
+impl DependencyGraph {
+    pub fn new() -> DependencyGraph {
+        DependencyGraph::default()
+    }
+
+    // Later databases replace the entries of earlier ones, patches remove their tombstones
+    pub fn from_databases(databases: &[AssetDatabase]) -> DependencyGraph {
+        let mut graph = DependencyGraph::new();
+
+        for db in databases {
+            for key in db.tombstones() {
+                graph.remove(key);
+            }
+
+            for entry in db.iter() {
+                graph.insert(entry);
+            }
+        }
+
+        graph
+    }
+
+    pub fn insert(&mut self, entry: &AssetEntry) {
+        self.insert_key(&entry.entry_key, entry.references.clone(), entry.includes.clone());
+    }
+
+    // Replaces what the graph knew about key
+    pub(crate) fn insert_key(&mut self, key: &str, references: Vec<String>, includes: Vec<String>) {
+        self.remove(key);
+
+        for dependency in references.iter().chain(includes.iter()) {
+            self.dependents.entry(dependency.clone())
+                .or_default()
+                .insert(key.to_string());
+        }
+
+        self.nodes.insert(key.to_string(), Node { references, includes });
+    }
+
+    pub fn remove(&mut self, key: &str) -> bool {
+        let node = match self.nodes.remove(key) {
+            Some(node) => node,
+            None => return false
+        };
+
+        for dependency in node.references.iter().chain(node.includes.iter()) {
+            if let Some(dependents) = self.dependents.get_mut(dependency) {
+                dependents.remove(key);
+
+                if dependents.is_empty() {
+                    self.dependents.remove(dependency);
+                }
+            }
+        }
+
+        true
+    }
+
+    // True if key is an entry, files that are only included aren't
+    pub fn contains(&self, key: &str) -> bool {
+        self.nodes.contains_key(key)
+    }
+
+    // True if key is an entry or something includes it
+    pub fn is_used(&self, key: &str) -> bool {
+        self.contains(key) || self.dependents.contains_key(key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.nodes.keys().map(|key| key.as_str())
+    }
+
+    // What key references and includes directly, empty if it's not an entry
+    pub fn dependencies(&self, key: &str) -> Vec<&str> {
+        match self.nodes.get(key) {
+            Some(node) => node.references.iter().chain(node.includes.iter()).map(|key| key.as_str()).collect(),
+            None => Vec::new()
+        }
+    }
+
+    // Entries that reference or include key directly, sorted
+    pub fn dependents(&self, key: &str) -> Vec<&str> {
+        match self.dependents.get(key) {
+            Some(dependents) => dependents.iter().map(|key| key.as_str()).collect(),
+            None => Vec::new()
+        }
+    }
+
+    // Everything key needs, E.G the atlas page behind the sprite a particle effect uses
+    pub fn all_dependencies(&self, key: &str) -> Vec<&str> {
+        self.walk(key, |graph, key| graph.dependencies(key))
+    }
+
+    // Everything that has to be reloaded when key changes
+    pub fn all_dependents(&self, key: &str) -> Vec<&str> {
+        self.walk(key, |graph, key| graph.dependents(key))
+    }
+
+    // (entry, reference) for every reference that isn't an entry, sorted by entry
+    pub fn dangling(&self) -> Vec<(&str, &str)> {
+        let mut dangling = Vec::new();
+
+        for (key, node) in &self.nodes {
+            for reference in &node.references {
+                if !self.contains(reference) {
+                    dangling.push((key.as_str(), reference.as_str()));
+                }
+            }
+        }
+
+        dangling
+    }
+
+    // Breadth first, without key itself and every key only once (references can form cycles)
+    fn walk<'a, F>(&'a self, key: &str, next: F) -> Vec<&'a str>
+        where F: Fn(&'a DependencyGraph, &str) -> Vec<&'a str>
+    {
+        let mut seen = BTreeSet::new();
+        let mut found = Vec::new();
+        let mut queue = VecDeque::new();
+        queue.extend(next(self, key));
+
+        while let Some(current) = queue.pop_front() {
+            if current == key || !seen.insert(current) {
+                continue;
+            }
+
+            found.push(current);
+            queue.extend(next(self, current));
+        }
+
+        found
+    }
+}
+
+#[test]
+fn dependency_graph_test() {
+    use crate::{AtlasRect, ShaderSource, ShaderStage, Sprite};
+
+    let sprite = Sprite::new("atlas/0", AtlasRect { x: 0, y: 0, width: 8, height: 8 }, 64, 64);
+    let shader = ShaderSource {
+        stage: ShaderStage::Fragment,
+        source: String::new(),
+        files: vec!["shaders/glow.frag".to_string(), "shaders/common.glsl".to_string()]
+    };
+
+    let mut db = AssetDatabase::new();
+    db.push_entry(AssetEntry::from_image("atlas/0", image::RgbaImage::new(64, 64))).unwrap();
+    db.push_entry(AssetEntry::from_sprite("textures/spark.png", &sprite)).unwrap();
+    db.push_entry(AssetEntry::from_shader("shaders/glow.frag", &shader)).unwrap();
+
+    let mut graph = DependencyGraph::from_databases(&[db]);
+    graph.insert_key("effects/sparks.particle", vec!["textures/spark.png".to_string()], Vec::new());
+    graph.insert_key("effects/smoke.particle", vec!["textures/smoke.png".to_string()], Vec::new());
+
+    assert_eq!(graph.dependencies("textures/spark.png"), vec!["atlas/0"]);
+    assert_eq!(graph.dependents("shaders/common.glsl"), vec!["shaders/glow.frag"]);
+    assert_eq!(graph.all_dependencies("effects/sparks.particle"), vec!["textures/spark.png", "atlas/0"]);
+    assert_eq!(graph.all_dependents("atlas/0"), vec!["textures/spark.png", "effects/sparks.particle"]);
+
+    // Includes aren't entries, but they aren't dangling either
+    assert!(!graph.contains("shaders/common.glsl") && graph.is_used("shaders/common.glsl"));
+    assert_eq!(graph.dangling(), vec![("effects/smoke.particle", "textures/smoke.png")]);
+
+    assert!(graph.remove("effects/smoke.particle"));
+    assert!(graph.dangling().is_empty());
+    assert!(graph.dependents("textures/smoke.png").is_empty());
+}
@@ -0,0 +1,68 @@
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub enum AssetDatabaseError {
+    DatabaseFull,
+
+    Io(std::io::Error),
+    Corrupt { offset: u64 },   // The archive ends early or contains garbage at offset
+    UnsupportedVersion(u8),
+    InvalidKey,                // Key is not valid UTF-8
+    DecodeFailed { key: String },
+    NotFound { key: String },
+    UnknownCodec { key: String, id: u8 }, // Written by a newer version of the pipeline
+    TooLarge { key: String, size: usize },
+    DuplicateKey { key: String, first: PathBuf, second: PathBuf },
+    InvalidSidecar { path: PathBuf, message: String },
+    ParseFailed { path: PathBuf, message: String },
+    MissingReference { key: String, reference: String }, // key references an entry that doesn't exist
+
+    ChecksumMismatch { key: String },
+    ArchiveHashMismatch,
+    InvalidKeyPair
+}
+
+impl fmt::Display for AssetDatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssetDatabaseError::DatabaseFull => write!(f, "database is full"),
+
+            AssetDatabaseError::Io(err) => write!(f, "io error: {}", err),
+            AssetDatabaseError::Corrupt { offset } => write!(f, "archive is corrupt at offset {:#x}", offset),
+            AssetDatabaseError::UnsupportedVersion(version) => write!(f, "unsupported database version {:#x}", version),
+            AssetDatabaseError::InvalidKey => write!(f, "entry key is not valid UTF-8"),
+            AssetDatabaseError::DecodeFailed { key } => write!(f, "failed to decode {}", key),
+            AssetDatabaseError::NotFound { key } => write!(f, "{} doesn't exist", key),
+            AssetDatabaseError::UnknownCodec { key, id } => write!(f, "{} uses an unknown codec {:#x}", key, id),
+            AssetDatabaseError::TooLarge { key, size } => write!(f, "{} is too large! {} > {}",
+                key, bytesize::to_string(*size as u64, false), bytesize::to_string(crate::MAX_SIZE as u64, false)),
+
+            AssetDatabaseError::DuplicateKey { key, first, second } => write!(f, "{} is used by both {} and {}",
+                key, first.display(), second.display()),
+            AssetDatabaseError::InvalidSidecar { path, message } => write!(f, "{} is invalid: {}", path.display(), message),
+            AssetDatabaseError::ParseFailed { path, message } => write!(f, "failed to parse {}: {}", path.display(), message),
+            AssetDatabaseError::MissingReference { key, reference } => write!(f, "{} references {}, which doesn't exist", key, reference),
+
+            AssetDatabaseError::ChecksumMismatch { key } => write!(f, "checksum of {} doesn't match", key),
+            AssetDatabaseError::ArchiveHashMismatch => write!(f, "archive hash doesn't match, the archive is damaged or was tampered with"),
+            AssetDatabaseError::InvalidKeyPair => write!(f, "invalid ed25519 key")
+        }
+    }
+}
+
+impl std::error::Error for AssetDatabaseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AssetDatabaseError::Io(err) => Some(err),
+
+            _ => None
+        }
+    }
+}
+
+impl From<std::io::Error> for AssetDatabaseError {
+    fn from(err: std::io::Error) -> Self {
+        AssetDatabaseError::Io(err)
+    }
+}
@@ -0,0 +1,581 @@
+// FLAC files for the audio importer. The decoder handles everything up to 24 bit, the encoder only
+// uses fixed predictors with a single rice partition per subframe. That's a bit larger than what
+// the reference encoder makes, but FMOD doesn't care and it's fast.
+
+use crate::audio_import::{self, Pcm};
+
+const BLOCK_SIZE: usize = 4096;
+
+// Coefficients of the fixed predictors, by order
+const FIXED_PREDICTORS: [&[i64]; 5] = [&[], &[1], &[2, -1], &[3, -3, 1], &[4, -6, 4, -1]];
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize // In bits
+}
+
+impl<'a> BitReader<'a> {
+    // Up to 32 bits
+    fn read(&mut self, bits: u32) -> Option<u32> {
+        let mut value = 0u64;
+        let mut left = bits;
+        while left > 0 {
+            let byte = *self.data.get(self.pos / 8)? as u32;
+            let offset = (self.pos % 8) as u32;
+            let take = std::cmp::min(8 - offset, left);
+
+            value = (value << take) | ((byte >> (8 - offset - take)) & ((1 << take) - 1)) as u64;
+            left -= take;
+            self.pos += take as usize;
+        }
+
+        Some(value as u32)
+    }
+
+    fn read_signed(&mut self, bits: u32) -> Option<i32> {
+        match bits {
+            0 => Some(0),
+            bits => Some(((self.read(bits)? << (32 - bits)) as i32) >> (32 - bits))
+        }
+    }
+
+    // Zeros until the next one
+    fn read_unary(&mut self) -> Option<u32> {
+        let mut count = 0;
+        loop {
+            let offset = self.pos % 8;
+            let rest = *self.data.get(self.pos / 8)? << offset;
+
+            if rest == 0 {
+                count += 8 - offset as u32;
+                self.pos += 8 - offset;
+            } else {
+                count += rest.leading_zeros();
+                self.pos += rest.leading_zeros() as usize + 1;
+
+                return Some(count);
+            }
+        }
+    }
+
+    fn align(&mut self) {
+        self.pos = (self.pos + 7) & !7;
+    }
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u64,
+    bits: u32 // In acc, always less than 8 between writes
+}
+
+impl BitWriter {
+    // Up to 32 bits
+    fn write(&mut self, bits: u32, value: u64) {
+        self.acc = (self.acc << bits) | (value & ((1 << bits) - 1));
+        self.bits += bits;
+
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.acc >> self.bits) as u8);
+        }
+    }
+
+    fn write_signed(&mut self, bits: u32, value: i32) {
+        self.write(bits, value as u32 as u64);
+    }
+
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(8 - self.bits, 0);
+        }
+    }
+}
+
+// The samples and the loop points of the LOOPSTART/LOOPEND comments
+pub(crate) fn decode(data: &[u8]) -> Option<(Pcm, Option<(u32, u32)>)> {
+    if !data.starts_with(b"fLaC") {
+        return None;
+    }
+
+    let mut stream_info = None;
+    let mut comments = Vec::new();
+
+    let mut offset = 4;
+    loop {
+        let header = data.get(offset..offset + 4)?;
+        let size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let block = data.get(offset + 4..offset + 4 + size)?;
+
+        match header[0] & 0x7f {
+            0 => {
+                let mut r = BitReader { data: block, pos: 0 };
+                r.read(16)?; // Min and max block size
+                r.read(16)?;
+                r.read(24)?; // Min and max frame size
+                r.read(24)?;
+
+                let sample_rate = r.read(20)?;
+                let channels = r.read(3)? + 1;
+                let bits = r.read(5)? + 1;
+                let length = ((r.read(4)? as u64) << 32) | r.read(32)? as u64;
+
+                stream_info = Some((sample_rate, channels as usize, bits, length));
+            }
+
+            4 => comments = audio_import::read_vorbis_comments(block)?,
+
+            _ => {}
+        }
+
+        offset += 4 + size;
+
+        // The last metadata block is flagged
+        if header[0] & 0x80 != 0 {
+            break;
+        }
+    }
+
+    let (sample_rate, channels, bits, length) = stream_info?;
+    if bits > 24 || sample_rate == 0 {
+        return None;
+    }
+
+    let mut decoded = vec![Vec::new(); channels];
+
+    let mut r = BitReader { data, pos: offset * 8 };
+    while r.pos / 8 + 2 < data.len() {
+        // Anything after the last frame (E.G an ID3v1 tag) is ignored
+        if r.read(14)? != 0x3ffe {
+            break;
+        }
+
+        for (channel, samples) in decoded.iter_mut().zip(decode_frame(&mut r, bits, channels)?) {
+            channel.extend(samples);
+        }
+    }
+
+    // The length is optional
+    let mut frames = decoded.iter().map(|channel| channel.len()).min().unwrap_or(0);
+    if length > 0 {
+        frames = std::cmp::min(frames, length as usize);
+    }
+
+    let mut samples = Vec::with_capacity(frames * channels);
+    for i in 0..frames {
+        samples.extend(decoded.iter().map(|channel| channel[i]));
+    }
+
+    let loop_points = audio_import::loop_points_of(&comments, frames as u32);
+
+    Some((Pcm {
+        channels: channels as u16,
+        sample_rate,
+        bits: bits as u8,
+        samples
+    }, loop_points))
+}
+
+// Everything after the sync code
+fn decode_frame(r: &mut BitReader, stream_bits: u32, channels: usize) -> Option<Vec<Vec<i32>>> {
+    r.read(2)?; // Reserved and the blocking strategy
+
+    let block_size_code = r.read(4)?;
+    let sample_rate_code = r.read(4)?;
+    let assignment = r.read(4)?;
+    let bits_code = r.read(3)?;
+    r.read(1)?;
+
+    // The frame or sample number, coded like UTF-8
+    let first = r.read(8)? as u8;
+    for _ in 1..(!first).leading_zeros() {
+        r.read(8)?;
+    }
+
+    let block_size = match block_size_code {
+        1 => 192,
+        2..=5 => 576 << (block_size_code - 2),
+        6 => r.read(8)? + 1,
+        7 => r.read(16)? + 1,
+        8..=15 => 256 << (block_size_code - 8),
+
+        _ => return None
+    } as usize;
+
+    // The sample rate of the stream info is used either way
+    match sample_rate_code {
+        12 => { r.read(8)?; }
+        13 | 14 => { r.read(16)?; }
+        15 => return None,
+
+        _ => {}
+    }
+
+    let bits = match bits_code {
+        0 => stream_bits,
+        1 => 8,
+        2 => 12,
+        4 => 16,
+        5 => 20,
+        6 => 24,
+
+        _ => return None
+    };
+
+    r.read(8)?; // CRC-8
+
+    let assigned_channels = match assignment {
+        0..=7 => assignment as usize + 1,
+        8..=10 => 2,
+
+        _ => return None
+    };
+
+    if assigned_channels != channels {
+        return None;
+    }
+
+    let mut decoded = Vec::with_capacity(channels);
+    for channel in 0..channels {
+        // Side channels have one bit more
+        let side = match assignment {
+            8 | 10 => channel == 1,
+            9 => channel == 0,
+
+            _ => false
+        };
+
+        decoded.push(decode_subframe(r, bits + side as u32, block_size)?);
+    }
+
+    if assignment >= 8 {
+        let (left, right) = decoded.split_at_mut(1);
+
+        for (a, b) in left[0].iter_mut().zip(right[0].iter_mut()) {
+            let (x, y) = (*a, *b);
+
+            match assignment {
+                8 => *b = x - y, // Left and side
+                9 => *a = x + y, // Side and right
+
+                _ => { // Mid and side
+                    let mid = (x << 1) | (y & 1);
+                    *a = (mid + y) >> 1;
+                    *b = (mid - y) >> 1;
+                }
+            }
+        }
+    }
+
+    r.align();
+    r.read(16)?; // CRC-16
+
+    Some(decoded)
+}
+
+fn decode_subframe(r: &mut BitReader, bits: u32, block_size: usize) -> Option<Vec<i32>> {
+    if r.read(1)? != 0 {
+        return None;
+    }
+
+    let kind = r.read(6)?;
+    let wasted = match r.read(1)? {
+        1 => r.read_unary()? + 1,
+        _ => 0
+    };
+    let bits = bits.checked_sub(wasted)?;
+
+    let mut samples = match kind {
+        0 => vec![r.read_signed(bits)?; block_size],
+        1 => (0..block_size).map(|_| r.read_signed(bits)).collect::<Option<Vec<_>>>()?,
+
+        8..=12 | 32..=63 => {
+            let order = if kind < 32 { kind - 8 } else { kind - 31 } as usize;
+            if order > block_size {
+                return None;
+            }
+
+            let mut samples = Vec::with_capacity(block_size);
+            for _ in 0..order {
+                samples.push(r.read_signed(bits)?);
+            }
+
+            let (coefficients, shift) = match kind {
+                8..=12 => (FIXED_PREDICTORS[order].to_vec(), 0),
+
+                _ => {
+                    let precision = r.read(4)? + 1;
+                    let shift = r.read_signed(5)?;
+                    if precision > 15 || shift < 0 {
+                        return None;
+                    }
+
+                    let coefficients = (0..order)
+                        .map(|_| r.read_signed(precision).map(|c| c as i64))
+                        .collect::<Option<Vec<_>>>()?;
+
+                    (coefficients, shift as u32)
+                }
+            };
+
+            for residual in decode_residual(r, block_size, order)? {
+                let history = &samples[samples.len() - order..];
+                let prediction = coefficients.iter()
+                    .zip(history.iter().rev())
+                    .map(|(c, s)| c * *s as i64)
+                    .sum::<i64>() >> shift;
+
+                samples.push((prediction + residual as i64) as i32);
+            }
+
+            samples
+        }
+
+        _ => return None
+    };
+
+    if wasted > 0 {
+        for sample in &mut samples {
+            *sample <<= wasted;
+        }
+    }
+
+    Some(samples)
+}
+
+fn decode_residual(r: &mut BitReader, block_size: usize, order: usize) -> Option<Vec<i32>> {
+    let parameter_bits = match r.read(2)? {
+        0 => 4,
+        1 => 5,
+
+        _ => return None
+    };
+
+    let partitions = 1 << r.read(4)?;
+    if block_size % partitions != 0 || block_size / partitions < order {
+        return None;
+    }
+
+    let mut residual = Vec::with_capacity(block_size - order);
+    for partition in 0..partitions {
+        // The warm up samples are part of the first partition
+        let count = block_size / partitions - if partition == 0 { order } else { 0 };
+
+        let parameter = r.read(parameter_bits)?;
+        if parameter == (1 << parameter_bits) - 1 {
+            // Escaped, the samples are stored as they are
+            let bits = r.read(5)?;
+            for _ in 0..count {
+                residual.push(r.read_signed(bits)?);
+            }
+        } else {
+            for _ in 0..count {
+                let value = ((r.read_unary()? as u64) << parameter) | r.read(parameter)? as u64;
+
+                residual.push(((value >> 1) as i64 ^ -((value & 1) as i64)) as i32);
+            }
+        }
+    }
+
+    Some(residual)
+}
+
+pub(crate) fn encode(pcm: &Pcm) -> Vec<u8> {
+    let channels = pcm.channels as usize;
+    let bits = pcm.bits as u32;
+    let frames = pcm.frames();
+
+    let mut w = BitWriter { bytes: Vec::new(), acc: 0, bits: 0 };
+    w.bytes.extend_from_slice(b"fLaC");
+
+    // The stream info is the only metadata block, so it's the last one as well
+    w.write(1, 1);
+    w.write(7, 0);
+    w.write(24, 34);
+    w.write(16, BLOCK_SIZE as u64);
+    w.write(16, BLOCK_SIZE as u64);
+    w.write(24, 0); // Min and max frame size are unknown
+    w.write(24, 0);
+    w.write(20, pcm.sample_rate as u64);
+    w.write(3, channels as u64 - 1);
+    w.write(5, bits as u64 - 1);
+    w.write(4, frames as u64 >> 32);
+    w.write(32, frames as u64);
+    for _ in 0..16 {
+        w.write(8, 0); // No MD5
+    }
+
+    for (number, start) in (0..frames).step_by(BLOCK_SIZE).enumerate() {
+        let block_size = std::cmp::min(BLOCK_SIZE, frames - start);
+        let frame_start = w.bytes.len();
+
+        w.write(16, 0xfff8); // Sync code, fixed block size
+        w.write(4, 7);       // Block size at the end of the header
+        w.write(4, 0);       // Sample rate and bits of the stream info
+        w.write(4, channels as u64 - 1);
+        w.write(3, 0);
+        w.write(1, 0);
+        write_utf8(&mut w, number as u64);
+        w.write(16, block_size as u64 - 1);
+
+        let crc = crc8(&w.bytes[frame_start..]);
+        w.write(8, crc as u64);
+
+        for channel in 0..channels {
+            let samples = (start..start + block_size)
+                .map(|i| pcm.samples[i * channels + channel])
+                .collect::<Vec<_>>();
+
+            encode_subframe(&mut w, &samples, bits);
+        }
+
+        w.align();
+
+        let crc = crc16(&w.bytes[frame_start..]);
+        w.write(16, crc as u64);
+    }
+
+    w.bytes
+}
+
+// Picks the fixed predictor that needs the least bits, falls back to verbatim samples
+fn encode_subframe(w: &mut BitWriter, samples: &[i32], bits: u32) {
+    if samples.iter().all(|sample| *sample == samples[0]) {
+        w.write(8, 0); // Constant
+        w.write_signed(bits, samples[0]);
+        return;
+    }
+
+    let mut best: Option<(usize, Vec<u64>, u32, u64)> = None;
+    for (order, coefficients) in FIXED_PREDICTORS.iter().enumerate().take(samples.len()) {
+        let residual = (order..samples.len())
+            .map(|i| {
+                let prediction = coefficients.iter()
+                    .zip(samples[..i].iter().rev())
+                    .map(|(c, s)| c * *s as i64)
+                    .sum::<i64>();
+                let value = samples[i] as i64 - prediction;
+
+                ((value << 1) ^ (value >> 63)) as u64
+            })
+            .collect::<Vec<_>>();
+
+        let (parameter, size) = rice_parameter(&residual);
+        let size = size + order as u64 * bits as u64;
+
+        if best.as_ref().map_or(true, |best| size < best.3) {
+            best = Some((order, residual, parameter, size));
+        }
+    }
+
+    let (order, residual, parameter, size) = best.unwrap();
+    if size >= samples.len() as u64 * bits as u64 {
+        w.write(8, 1 << 1); // Verbatim
+        for sample in samples {
+            w.write_signed(bits, *sample);
+        }
+
+        return;
+    }
+
+    w.write(8, (8 + order as u64) << 1); // Fixed
+    for sample in &samples[..order] {
+        w.write_signed(bits, *sample);
+    }
+
+    w.write(2, 0); // Rice coding with 4 bit parameters
+    w.write(4, 0); // A single partition
+    w.write(4, parameter as u64);
+    for value in residual {
+        let mut zeros = value >> parameter;
+        while zeros >= 32 {
+            w.write(32, 0);
+            zeros -= 32;
+        }
+
+        w.write(zeros as u32 + 1, 1);
+        w.write(parameter, value);
+    }
+}
+
+// The rice parameter that takes the least bits for the residual and how many bits that is
+fn rice_parameter(residual: &[u64]) -> (u32, u64) {
+    (0..15)
+        .map(|parameter| {
+            let size = residual.iter()
+                .map(|value| (value >> parameter) + 1 + parameter as u64)
+                .sum::<u64>();
+
+            (parameter, size)
+        })
+        .min_by_key(|(_, size)| *size)
+        .unwrap()
+}
+
+fn write_utf8(w: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        w.write(8, value);
+        return;
+    }
+
+    // n bytes hold 5n + 1 bits
+    let mut bytes = 2;
+    while value >> (5 * bytes + 1) != 0 {
+        bytes += 1;
+    }
+
+    w.write(8, ((0xff00 >> bytes) & 0xff) | (value >> (6 * (bytes - 1))));
+    for i in (0..bytes - 1).rev() {
+        w.write(8, 0x80 | ((value >> (6 * i)) & 0x3f));
+    }
+}
+
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 })
+    })
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u16) << 8), |crc, _| if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 })
+    })
+}
+
+#[test]
+fn flac_test() {
+    // A bit of everything: silence (constant), a ramp (fixed), noise (verbatim) and a short last block
+    let frames = BLOCK_SIZE * 3 + 100;
+    let mut seed = 1u32;
+    let samples = (0..frames)
+        .flat_map(|i| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+
+            let left = match i / BLOCK_SIZE {
+                0 => 0,
+                1 => (i as i32 % 2000) * 16 - 16000,
+                _ => (seed >> 16) as i16 as i32
+            };
+
+            vec![left, ((i as f64 / 20.0).sin() * 30000.0) as i32]
+        })
+        .collect::<Vec<_>>();
+
+    let pcm = Pcm { channels: 2, sample_rate: 44100, bits: 16, samples };
+
+    let encoded = encode(&pcm);
+    assert!(encoded.len() < pcm.samples.len() * 2);
+
+    let (decoded, loop_points) = decode(&encoded).unwrap();
+    assert_eq!((decoded.channels, decoded.sample_rate, decoded.bits), (2, 44100, 16));
+    assert!(decoded.samples == pcm.samples);
+    assert_eq!(loop_points, None);
+
+    let mut w = BitWriter { bytes: Vec::new(), acc: 0, bits: 0 };
+    write_utf8(&mut w, 0x7ff);
+    assert_eq!(w.bytes, vec![0xdf, 0xbf]);
+
+    // The check value of the CRC catalogue
+    assert_eq!(crc8(b"123456789"), 0xf4);
+    assert_eq!(crc16(b"123456789"), 0xfee8);
+}
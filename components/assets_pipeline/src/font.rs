@@ -0,0 +1,559 @@
+// Fonts (.ttf/.otf with TrueType outlines) are rasterized while building, so the game only has
+// to draw quads from a glyph atlas. graphics_engine::TextRenderer lays out and draws them.
+//
+// Pixel fonts are rendered as plain coverage bitmaps, scalable text as signed distance fields:
+// 128 is the outline, every 128 / spread steps are one pixel further in (up) or out (down).
+// The [font] section of the .meta file picks the size, mode and characters (see FontSettings).
+//
+// Font Entry Layout:
+//   Magic          "PXF1"
+//   Mode           u8  (FontMode)
+//   Size           f32 (pixels per em the glyphs were rendered at)
+//   Spread         f32 (pixels, 0 for bitmaps)
+//   Ascent         f32 (pixels above the baseline)
+//   Descent        f32 (pixels below the baseline, negative)
+//   Line Gap       f32
+//   Glyphs         [Glyph] { Character u32, X u16, Y u16, Width u16, Height u16, Offset X f32, Offset Y f32, Advance f32 }
+//   Kerning        [Pair] { Left u32, Right u32, Amount f32 }
+//   Atlas          Texture entry (R8)
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, Cursor, Read, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::Deserialize;
+
+use crate::atlas::SkylinePacker;
+use crate::truetype::{Line, TrueType};
+use crate::{AssetDatabaseError, AssetEntry, AtlasRect, FontSettings, PixelFormat, TextureData};
+
+const FONT_MAGIC: &[u8; 4] = b"PXF1";
+
+const MAX_ATLAS_SIZE: u32 = 4096;
+const MAX_GLYPHS: u32 = 0x10000;
+
+// Vertical samples per pixel while rasterizing, horizontal coverage is exact
+const SUB_ROWS: usize = 8;
+
+// Drawn for characters the font doesn't have, it's the font's own missing glyph box unless the
+// font has a real one
+pub const REPLACEMENT_CHARACTER: char = '\u{fffd}';
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FontMode {
+    Bitmap = 0, // Coverage, sharp at the size it was rendered at
+    Sdf = 1     // Signed distance field, scales up and down
+}
+
+impl FontMode {
+    pub fn from_u8(v: u8) -> Option<FontMode> {
+        match v {
+            0 => Some(FontMode::Bitmap),
+            1 => Some(FontMode::Sdf),
+
+            _ => None
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Glyph {
+    pub rect: AtlasRect,  // Empty for glyphs without an outline, E.G the space
+    pub offset: [f32; 2], // Top left corner of the rect relative to the pen on the baseline, y points down
+    pub advance: f32
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FontData {
+    pub mode: FontMode,
+    pub size: f32,
+    pub spread: f32,
+
+    pub ascent: f32,
+    pub descent: f32,
+    pub line_gap: f32,
+
+    pub glyphs: BTreeMap<char, Glyph>,
+    pub kerning: BTreeMap<(char, char), f32>,
+
+    pub atlas: TextureData
+}
+
+impl FontData {
+    // Falls back to the replacement character
+    pub fn glyph(&self, c: char) -> Option<&Glyph> {
+        self.glyphs.get(&c).or_else(|| self.glyphs.get(&REPLACEMENT_CHARACTER))
+    }
+
+    // Added to the advance of left when right follows it
+    pub fn kern(&self, left: char, right: char) -> f32 {
+        self.kerning.get(&(left, right)).copied().unwrap_or(0.0)
+    }
+
+    // Distance between two baselines
+    pub fn line_height(&self) -> f32 {
+        self.ascent - self.descent + self.line_gap
+    }
+
+    // Printable ASCII, Latin-1 and whatever the settings add, in the size and mode they ask for
+    pub(crate) fn build(ttf: &[u8], settings: &FontSettings) -> Result<FontData, &'static str> {
+        let font = TrueType::parse(ttf)?;
+        let scale = settings.size / font.units_per_em as f32;
+
+        let (padding, spread) = match settings.mode {
+            FontMode::Bitmap => (0, 0.0),
+            FontMode::Sdf => (settings.spread.ceil() as i32, settings.spread)
+        };
+
+        let characters = (0x20..=0x7e).chain(0xa0..=0xff)
+            .filter_map(std::char::from_u32)
+            .chain(settings.characters.chars())
+            .chain(std::iter::once(REPLACEMENT_CHARACTER))
+            .collect::<BTreeSet<_>>();
+
+        let mut glyphs = BTreeMap::new();
+        let mut bitmaps = Vec::new();
+        let mut characters_of = HashMap::new();
+
+        for c in characters {
+            let index = font.glyph_index(c);
+            if index == 0 && c != REPLACEMENT_CHARACTER {
+                continue; // Not in the font
+            }
+
+            let lines = font.outline(index, scale)?;
+            let mut glyph = Glyph {
+                rect: AtlasRect { x: 0, y: 0, width: 0, height: 0 },
+                offset: [0.0, 0.0],
+                advance: font.advance(index) as f32 * scale
+            };
+
+            if !lines.is_empty() {
+                let (bitmap, width, height, offset) = FontData::render(&lines, settings.mode, padding, spread);
+
+                glyph.rect.width = width as u32;
+                glyph.rect.height = height as u32;
+                glyph.offset = offset;
+
+                bitmaps.push((c, bitmap));
+            }
+
+            glyphs.insert(c, glyph);
+            characters_of.entry(index).or_insert_with(Vec::new).push(c);
+        }
+
+        if glyphs.len() > MAX_GLYPHS as usize {
+            return Err("too many characters");
+        }
+
+        let mut kerning = BTreeMap::new();
+        for (left, right, amount) in font.kerning() {
+            if let (Some(lefts), Some(rights), true) = (characters_of.get(&left), characters_of.get(&right), amount != 0) {
+                for l in lefts {
+                    for r in rights {
+                        kerning.insert((*l, *r), amount as f32 * scale);
+                    }
+                }
+            }
+        }
+
+        let atlas = FontData::pack(&mut glyphs, &bitmaps)?;
+
+        Ok(FontData {
+            mode: settings.mode,
+            size: settings.size,
+            spread,
+
+            ascent: font.ascent as f32 * scale,
+            descent: font.descent as f32 * scale,
+            line_gap: font.line_gap as f32 * scale,
+
+            glyphs,
+            kerning,
+
+            atlas
+        })
+    }
+
+    // The bitmap of a glyph, its size and where its top left corner is relative to the pen
+    fn render(lines: &[Line], mode: FontMode, padding: i32, spread: f32) -> (Vec<u8>, usize, usize, [f32; 2]) {
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (f32::MAX, f32::MAX, f32::MIN, f32::MIN);
+        for line in lines {
+            min_x = min_x.min(line[0]).min(line[2]);
+            max_x = max_x.max(line[0]).max(line[2]);
+            min_y = min_y.min(line[1]).min(line[3]);
+            max_y = max_y.max(line[1]).max(line[3]);
+        }
+
+        // Whole pixels, so bitmap fonts stay on the pixel grid. Y flips to point down.
+        let x0 = min_x.floor() as i32 - padding;
+        let y0 = (-max_y).floor() as i32 - padding;
+        let width = (max_x.ceil() as i32 + padding - x0) as usize;
+        let height = ((-min_y).ceil() as i32 + padding - y0) as usize;
+
+        let local = lines.iter()
+            .map(|line| [line[0] - x0 as f32, -line[1] - y0 as f32, line[2] - x0 as f32, -line[3] - y0 as f32])
+            .collect::<Vec<_>>();
+
+        let bitmap = match mode {
+            FontMode::Bitmap => rasterize(&local, width, height).iter()
+                .map(|coverage| (coverage.min(1.0) * 255.0).round() as u8)
+                .collect(),
+            FontMode::Sdf => distance_field(&local, width, height, spread)
+        };
+
+        (bitmap, width, height, [x0 as f32, y0 as f32])
+    }
+
+    // Packs the bitmaps onto the smallest atlas they fit on, one pixel apart
+    fn pack(glyphs: &mut BTreeMap<char, Glyph>, bitmaps: &[(char, Vec<u8>)]) -> Result<TextureData, &'static str> {
+        let mut order = (0..bitmaps.len()).collect::<Vec<_>>();
+        order.sort_by_key(|i| std::cmp::Reverse(glyphs[&bitmaps[*i].0].rect.height));
+
+        let (mut width, mut height) = (64, 64);
+        let positions = loop {
+            let mut packer = SkylinePacker::new(width, height);
+            let positions = order.iter()
+                .map(|i| {
+                    let rect = glyphs[&bitmaps[*i].0].rect;
+                    packer.insert(rect.width + 1, rect.height + 1)
+                })
+                .collect::<Option<Vec<_>>>();
+
+            if let Some(positions) = positions {
+                break positions;
+            }
+
+            if width <= height {
+                width *= 2;
+            } else {
+                height *= 2;
+            }
+
+            if width > MAX_ATLAS_SIZE {
+                return Err("the glyphs don't fit on a 4096x4096 atlas, use a smaller size or less characters");
+            }
+        };
+
+        let mut pixels = vec![0; width as usize * height as usize];
+        for (i, (x, y)) in order.iter().zip(positions) {
+            let (c, bitmap) = &bitmaps[*i];
+            let glyph = glyphs.get_mut(c).unwrap();
+            glyph.rect.x = x;
+            glyph.rect.y = y;
+
+            let glyph_width = glyph.rect.width as usize;
+            for (row, line) in bitmap.chunks(glyph_width).enumerate() {
+                let start = (y as usize + row) * width as usize + x as usize;
+                pixels[start..start + glyph_width].copy_from_slice(line);
+            }
+        }
+
+        Ok(TextureData {
+            width,
+            height,
+            format: PixelFormat::R8,
+            premultiplied: false,
+            levels: vec![pixels]
+        })
+    }
+
+    pub(crate) fn write_to(&self, w: &mut Vec<u8>) -> io::Result<()> {
+        w.write_all(FONT_MAGIC)?;
+        w.write_u8(self.mode as u8)?;
+        for value in &[self.size, self.spread, self.ascent, self.descent, self.line_gap] {
+            w.write_f32::<LittleEndian>(*value)?;
+        }
+
+        w.write_u32::<LittleEndian>(self.glyphs.len() as u32)?;
+        for (c, glyph) in &self.glyphs {
+            w.write_u32::<LittleEndian>(*c as u32)?;
+            for value in &[glyph.rect.x, glyph.rect.y, glyph.rect.width, glyph.rect.height] {
+                w.write_u16::<LittleEndian>(*value as u16)?;
+            }
+            for value in &[glyph.offset[0], glyph.offset[1], glyph.advance] {
+                w.write_f32::<LittleEndian>(*value)?;
+            }
+        }
+
+        w.write_u32::<LittleEndian>(self.kerning.len() as u32)?;
+        for ((left, right), amount) in &self.kerning {
+            w.write_u32::<LittleEndian>(*left as u32)?;
+            w.write_u32::<LittleEndian>(*right as u32)?;
+            w.write_f32::<LittleEndian>(*amount)?;
+        }
+
+        self.atlas.write_to(w)
+    }
+
+    pub(crate) fn read_from(data: &[u8]) -> io::Result<FontData> {
+        let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        if !data.starts_with(FONT_MAGIC) {
+            return Err(invalid("not a font"));
+        }
+
+        let mut r = Cursor::new(data);
+        r.set_position(FONT_MAGIC.len() as u64);
+
+        let mode = FontMode::from_u8(r.read_u8()?)
+            .ok_or_else(|| invalid("unknown font mode"))?;
+
+        let mut metrics = [0.0; 5];
+        r.read_f32_into::<LittleEndian>(&mut metrics)?;
+        let [size, spread, ascent, descent, line_gap] = metrics;
+
+        let read_char = |r: &mut Cursor<&[u8]>| -> io::Result<char> {
+            std::char::from_u32(r.read_u32::<LittleEndian>()?)
+                .ok_or_else(|| invalid("invalid character"))
+        };
+
+        let glyph_count = r.read_u32::<LittleEndian>()?;
+        if glyph_count > MAX_GLYPHS {
+            return Err(invalid("too many glyphs"));
+        }
+
+        let mut glyphs = BTreeMap::new();
+        for _ in 0..glyph_count {
+            let c = read_char(&mut r)?;
+
+            let mut rect = [0; 4];
+            r.read_u16_into::<LittleEndian>(&mut rect)?;
+            let mut values = [0.0; 3];
+            r.read_f32_into::<LittleEndian>(&mut values)?;
+
+            glyphs.insert(c, Glyph {
+                rect: AtlasRect { x: rect[0] as u32, y: rect[1] as u32, width: rect[2] as u32, height: rect[3] as u32 },
+                offset: [values[0], values[1]],
+                advance: values[2]
+            });
+        }
+
+        let pair_count = r.read_u32::<LittleEndian>()?;
+        if pair_count > MAX_GLYPHS * 16 {
+            return Err(invalid("too many kerning pairs"));
+        }
+
+        let mut kerning = BTreeMap::new();
+        for _ in 0..pair_count {
+            let pair = (read_char(&mut r)?, read_char(&mut r)?);
+            kerning.insert(pair, r.read_f32::<LittleEndian>()?);
+        }
+
+        let atlas = TextureData::read_from(&data[r.position() as usize..])?;
+
+        Ok(FontData {
+            mode,
+            size,
+            spread,
+
+            ascent,
+            descent,
+            line_gap,
+
+            glyphs,
+            kerning,
+
+            atlas
+        })
+    }
+}
+
+pub(crate) fn import(key: &str, path: &Path, settings: &FontSettings) -> Result<AssetEntry, AssetDatabaseError> {
+    let mut ttf = Vec::new();
+    std::fs::File::open(path)?.read_to_end(&mut ttf)?;
+
+    let font = FontData::build(&ttf, settings)
+        .map_err(|message| AssetDatabaseError::ParseFailed { path: path.to_path_buf(), message: message.to_string() })?;
+
+    log::debug!("{}: {} glyphs, {} kerning pairs on a {}x{} atlas", key, font.glyphs.len(), font.kerning.len(),
+                font.atlas.width, font.atlas.height);
+
+    Ok(AssetEntry::from_font(key, &font))
+}
+
+// Coverage of every pixel with the non-zero rule, the lines are in pixels with y pointing down
+fn rasterize(lines: &[Line], width: usize, height: usize) -> Vec<f32> {
+    let mut coverage = vec![0.0; width * height];
+    let mut crossings = Vec::new();
+
+    for row in 0..height {
+        for sub_row in 0..SUB_ROWS {
+            let y = row as f32 + (sub_row as f32 + 0.5) / SUB_ROWS as f32;
+
+            crossings.clear();
+            for &[x0, y0, x1, y1] in lines {
+                if (y0 <= y) != (y1 <= y) {
+                    crossings.push((x0 + (y - y0) * (x1 - x0) / (y1 - y0), if y1 > y0 { 1 } else { -1 }));
+                }
+            }
+            crossings.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+            let mut winding = 0;
+            let mut span_start = 0.0;
+            for (x, direction) in &crossings {
+                let inside = winding != 0;
+                winding += direction;
+
+                if !inside && winding != 0 {
+                    span_start = *x;
+                } else if inside && winding == 0 {
+                    add_span(&mut coverage[row * width..(row + 1) * width], span_start, *x, 1.0 / SUB_ROWS as f32);
+                }
+            }
+        }
+    }
+
+    coverage
+}
+
+fn add_span(row: &mut [f32], x0: f32, x1: f32, weight: f32) {
+    let width = row.len() as f32;
+    let (x0, x1) = (x0.max(0.0).min(width), x1.max(0.0).min(width));
+    if x1 <= x0 {
+        return;
+    }
+
+    let (first, last) = (x0 as usize, x1 as usize);
+    if first == last {
+        row[first] += (x1 - x0) * weight;
+        return;
+    }
+
+    row[first] += (first as f32 + 1.0 - x0) * weight;
+    for pixel in &mut row[first + 1..last] {
+        *pixel += weight;
+    }
+    if last < row.len() {
+        row[last] += (x1 - last as f32) * weight;
+    }
+}
+
+// Distance from every pixel center to the outline, 128 on the outline and 255 spread pixels inside
+fn distance_field(lines: &[Line], width: usize, height: usize, spread: f32) -> Vec<u8> {
+    let mut field = Vec::with_capacity(width * height);
+
+    for y in 0..height {
+        for x in 0..width {
+            let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+
+            let mut closest = f32::MAX;
+            let mut winding = 0;
+            for &[x0, y0, x1, y1] in lines {
+                let (dx, dy) = (x1 - x0, y1 - y0);
+                let len = dx * dx + dy * dy;
+                let t = match len > 0.0 {
+                    true => (((px - x0) * dx + (py - y0) * dy) / len).max(0.0).min(1.0),
+                    false => 0.0
+                };
+
+                let (ex, ey) = (x0 + t * dx - px, y0 + t * dy - py);
+                closest = closest.min(ex * ex + ey * ey);
+
+                // A ray to the right, same rule as rasterize()
+                if (y0 <= py) != (y1 <= py) && x0 + (py - y0) * dx / dy > px {
+                    winding += if y1 > y0 { 1 } else { -1 };
+                }
+            }
+
+            let distance = if winding != 0 { closest.sqrt() } else { -closest.sqrt() };
+            field.push(((0.5 + distance / (2.0 * spread)).max(0.0).min(1.0) * 255.0).round() as u8);
+        }
+    }
+
+    field
+}
+
+// A font with a square A, a curved V and an A V kerning pair
+#[cfg(test)]
+fn test_font() -> Vec<u8> {
+    fn be16(values: &[i32]) -> Vec<u8> {
+        values.iter().flat_map(|v| (*v as u16).to_be_bytes().to_vec()).collect()
+    }
+
+    let square = [be16(&[1, 100, 0, 600, 700, 3, 0]), vec![1; 4], be16(&[100, 0, 500, 0, 0, 700, 0, -700])].concat();
+    let hump = [be16(&[1, 100, 0, 600, 350, 2, 0]), vec![1, 0, 1], be16(&[100, 250, 250, 0, 700, -700]), vec![0]].concat(); // Padded to an even size
+
+    let mut head = vec![0; 54];
+    head[18..20].copy_from_slice(&1000u16.to_be_bytes());
+
+    let mut hhea = vec![0; 36];
+    hhea[4..10].copy_from_slice(&be16(&[800, -200, 0]));
+    hhea[34..36].copy_from_slice(&3u16.to_be_bytes());
+
+    let tables: Vec<(&[u8; 4], Vec<u8>)> = vec![
+        (b"cmap", be16(&[0, 1, 3, 1, 0, 12, 4, 40, 0, 6, 0, 0, 0, 65, 86, 0xffff, 0, 65, 86, 0xffff, 1 - 65, 2 - 86, 1, 0, 0, 0])),
+        (b"glyf", [square.clone(), hump.clone()].concat()),
+        (b"head", head),
+        (b"hhea", hhea),
+        (b"hmtx", be16(&[500, 0, 700, 0, 700, 0])),
+        (b"kern", be16(&[0, 1, 0, 20, 1, 1, 0, 0, 0, 1, 2, -50])),
+        (b"loca", be16(&[0, 0, square.len() as i32 / 2, (square.len() + hump.len()) as i32 / 2])),
+        (b"maxp", be16(&[0, 0x5000, 3]))
+    ];
+
+    let mut font = [vec![0, 1, 0, 0], be16(&[tables.len() as i32, 0, 0, 0])].concat();
+    let mut offset = 12 + tables.len() * 16;
+    for (tag, table) in &tables {
+        font.extend_from_slice(*tag);
+        font.extend_from_slice(&[0; 4]);
+        font.extend_from_slice(&(offset as u32).to_be_bytes());
+        font.extend_from_slice(&(table.len() as u32).to_be_bytes());
+        offset += table.len();
+    }
+
+    for (_, table) in tables {
+        font.extend(table);
+    }
+
+    font
+}
+
+#[test]
+fn font_test() {
+    let ttf = test_font();
+
+    let settings = FontSettings { size: 20.0, mode: FontMode::Bitmap, ..FontSettings::default() };
+    let font = FontData::build(&ttf, &settings).unwrap();
+
+    // The font only has A and V, everything else falls back to the missing glyph
+    assert_eq!(font.glyphs.keys().copied().collect::<Vec<_>>(), vec!['A', 'V', REPLACEMENT_CHARACTER]);
+    assert_eq!(font.glyph('x'), font.glyph(REPLACEMENT_CHARACTER));
+    assert_eq!((font.ascent, font.descent, font.line_height()), (16.0, -4.0, 20.0));
+    assert_eq!(font.kern('A', 'V'), -1.0);
+    assert_eq!(font.kern('V', 'A'), 0.0);
+
+    let a = font.glyph('A').unwrap();
+    assert_eq!((a.rect.width, a.rect.height, a.offset, a.advance), (10, 14, [2.0, -14.0], 14.0));
+
+    let pixel = |font: &FontData, glyph: &Glyph, x: u32, y: u32| {
+        font.atlas.levels[0][((glyph.rect.y + y) * font.atlas.width + glyph.rect.x + x) as usize]
+    };
+    assert_eq!(pixel(&font, a, 5, 7), 255);
+
+    // The hump is half covered where the curve passes through a pixel
+    let v = font.glyph('V').unwrap();
+    assert_eq!((v.rect.width, v.rect.height), (10, 7));
+    assert_eq!(pixel(&font, v, 5, 6), 255);
+    assert_eq!(pixel(&font, v, 0, 0), 0);
+
+    let mut data = Vec::new();
+    font.write_to(&mut data).unwrap();
+    assert_eq!(FontData::read_from(&data).unwrap(), font);
+
+    // Distance fields have the spread as padding around the glyph, the outline is at 128
+    let settings = FontSettings { size: 20.0, mode: FontMode::Sdf, spread: 4.0, ..FontSettings::default() };
+    let font = FontData::build(&ttf, &settings).unwrap();
+
+    let a = font.glyph('A').unwrap();
+    assert_eq!((a.rect.width, a.rect.height, a.offset), (18, 22, [-2.0, -18.0]));
+    assert!(pixel(&font, a, 9, 11) == 255);
+    assert!(pixel(&font, a, 0, 0) == 0);
+    assert!((pixel(&font, a, 4, 11) as i32 - 128).abs() <= 16);
+
+    assert!(FontData::build(b"OTTO\0\0\0\0", &settings).is_err());
+    assert!(FontData::read_from(b"PXT1").is_err());
+}
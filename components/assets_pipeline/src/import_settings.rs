@@ -0,0 +1,353 @@
+// Per asset import settings, read from an optional .meta file next to the asset.
+//
+// Example: textures/grass.png.meta
+//   key = "tiles/grass"      # Key alias, the entry is stored under it instead of textures/grass.png
+//   codec = "zstd"           # none, gzip, zstd or lz4. Overrides CompileOptions::codecs
+//   tags = [ "tiles", "nature" ]
+//
+//   [texture]
+//   filter = "linear"        # nearest or linear, defaults to nearest
+//   wrap = "clamp"           # repeat, clamp or mirror, defaults to repeat
+//   mipmaps = true           # Computed while building, not when the texture is uploaded
+//   premultiply_alpha = true
+//   format = "rgba4444"      # rgba8, r8, rgb565, rgba4444, bc1 or bc3, defaults to rgba8
+//   pivot = [ 0.5, 1.0 ]     # 0.0 - 1.0, from the top left corner
+//   atlas_group = "tiles"    # Sprites are only packed with sprites of the same group
+//
+//   [audio]
+//   stream = true            # Decoded while playing instead of upfront
+//   looping = true
+//   loop_start = 1200        # Milliseconds
+//   loop_end = 9600
+//
+//   [font]
+//   size = 48.0              # Pixels per em the glyphs are rendered at
+//   mode = "sdf"             # bitmap (pixel fonts) or sdf, defaults to sdf
+//   spread = 4.0             # Pixels the distance field reaches past the outline
+//   characters = "€ĞğŞş"     # Rendered on top of printable ASCII and Latin-1
+//
+// key, codec and font only matter while importing, everything else ends up in the archive
+// as the AssetMetadata of the entry.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use audio_engine::AudioSettings;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::Deserialize;
+
+use crate::{AssetDatabaseError, AssetPipeline, Codec, FontMode, PixelFormat};
+use crate::shader::read_string;
+
+pub const META_EXTENSION: &str = "meta";
+
+const MAX_TAGS: u32 = 0x100;
+const MAX_TAG_LEN: u32 = 0x100;
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextureFilter {
+    Nearest = 0,
+    Linear = 1
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextureWrap {
+    Repeat = 0,
+    Clamp = 1,
+    Mirror = 2
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TextureSettings {
+    pub filter: TextureFilter,
+    pub wrap: TextureWrap,
+    pub mipmaps: bool,
+    pub pivot: [f32; 2],
+    pub atlas_group: Option<String>,
+
+    pub premultiply_alpha: bool,
+    pub format: PixelFormat
+}
+
+impl Default for TextureSettings {
+    fn default() -> Self {
+        TextureSettings {
+            filter: TextureFilter::Nearest,
+            wrap: TextureWrap::Repeat,
+            mipmaps: false,
+            pivot: [0.5, 0.5],
+            atlas_group: None,
+
+            premultiply_alpha: false,
+            format: PixelFormat::Rgba8
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FontSettings {
+    pub size: f32,
+    pub mode: FontMode,
+    pub spread: f32,       // Only used by distance fields
+    pub characters: String // Extra characters, printable ASCII and Latin-1 are always there
+}
+
+impl Default for FontSettings {
+    fn default() -> Self {
+        FontSettings {
+            size: 32.0,
+            mode: FontMode::Sdf,
+            spread: 4.0,
+            characters: String::new()
+        }
+    }
+}
+
+// What an entry keeps of its import settings
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AssetMetadata {
+    pub tags: Vec<String>,
+    pub texture: TextureSettings,
+    pub audio: AudioSettings
+}
+
+impl AssetMetadata {
+    pub fn has_tag<S: AsRef<str>>(&self, tag: S) -> bool {
+        self.tags.iter().any(|t| t == tag.as_ref())
+    }
+
+    // Entries without a .meta file store nothing at all
+    pub(crate) fn write_to(&self, w: &mut Vec<u8>) -> io::Result<()> {
+        if *self == AssetMetadata::default() {
+            return Ok(());
+        }
+
+        w.write_u32::<LittleEndian>(self.tags.len() as u32)?;
+        for tag in &self.tags {
+            write_string(w, tag)?;
+        }
+
+        let texture = &self.texture;
+        w.write_u8(texture.filter as u8)?;
+        w.write_u8(texture.wrap as u8)?;
+        w.write_u8(texture.mipmaps as u8)?;
+        w.write_f32::<LittleEndian>(texture.pivot[0])?;
+        w.write_f32::<LittleEndian>(texture.pivot[1])?;
+        write_string(w, texture.atlas_group.as_deref().unwrap_or(""))?;
+
+        let audio = &self.audio;
+        w.write_u8(audio.stream as u8)?;
+        w.write_u8(audio.looping as u8)?;
+        w.write_u32::<LittleEndian>(audio.loop_start)?;
+        w.write_u8(audio.loop_end.is_some() as u8)?;
+        w.write_u32::<LittleEndian>(audio.loop_end.unwrap_or(0))?;
+
+        // Added with processed textures, older entries end here
+        w.write_u8(texture.premultiply_alpha as u8)?;
+        w.write_u8(texture.format as u8)?;
+
+        Ok(())
+    }
+
+    pub(crate) fn read_from(data: &[u8]) -> io::Result<AssetMetadata> {
+        if data.is_empty() {
+            return Ok(AssetMetadata::default());
+        }
+
+        let mut r = io::Cursor::new(data);
+        let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let tag_count = r.read_u32::<LittleEndian>()?;
+        if tag_count > MAX_TAGS {
+            return Err(invalid("too many tags"));
+        }
+
+        let mut tags = Vec::new();
+        for _ in 0..tag_count {
+            tags.push(read_string(&mut r, MAX_TAG_LEN)?);
+        }
+
+        let filter = match r.read_u8()? {
+            0 => TextureFilter::Nearest,
+            1 => TextureFilter::Linear,
+
+            _ => return Err(invalid("unknown texture filter"))
+        };
+
+        let wrap = match r.read_u8()? {
+            0 => TextureWrap::Repeat,
+            1 => TextureWrap::Clamp,
+            2 => TextureWrap::Mirror,
+
+            _ => return Err(invalid("unknown texture wrap mode"))
+        };
+
+        let mipmaps = r.read_u8()? != 0;
+        let pivot = [r.read_f32::<LittleEndian>()?, r.read_f32::<LittleEndian>()?];
+        let atlas_group = read_string(&mut r, crate::asset_database::MAX_KEY_LEN)?;
+
+        let stream = r.read_u8()? != 0;
+        let looping = r.read_u8()? != 0;
+        let loop_start = r.read_u32::<LittleEndian>()?;
+        let has_loop_end = r.read_u8()? != 0;
+        let loop_end = r.read_u32::<LittleEndian>()?;
+
+        let (premultiply_alpha, format) = match r.position() as usize == data.len() {
+            true => (false, PixelFormat::Rgba8),
+            false => (r.read_u8()? != 0, PixelFormat::from_u8(r.read_u8()?)
+                .ok_or_else(|| invalid("unknown pixel format"))?)
+        };
+
+        Ok(AssetMetadata {
+            tags,
+            texture: TextureSettings {
+                filter,
+                wrap,
+                mipmaps,
+                pivot,
+                atlas_group: if atlas_group.is_empty() { None } else { Some(atlas_group) },
+
+                premultiply_alpha,
+                format
+            },
+            audio: AudioSettings {
+                stream,
+                looping,
+                loop_start,
+                loop_end: if has_loop_end { Some(loop_end) } else { None }
+            }
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ImportSettings {
+    pub key: Option<String>,
+    pub codec: Option<Codec>,
+    pub tags: Vec<String>,
+
+    pub texture: TextureSettings,
+    pub audio: AudioSettings,
+    pub font: FontSettings
+}
+
+impl ImportSettings {
+    // Path of the .meta file that belongs to an asset, textures/grass.png -> textures/grass.png.meta
+    pub fn path_of<P: AsRef<Path>>(asset: P) -> PathBuf {
+        let mut path = asset.as_ref().as_os_str().to_owned();
+        path.push(".");
+        path.push(META_EXTENSION);
+
+        PathBuf::from(path)
+    }
+
+    // The default settings if the asset doesn't have a .meta file
+    pub fn load<P: AsRef<Path>>(asset: P) -> Result<ImportSettings, AssetDatabaseError> {
+        let path = ImportSettings::path_of(asset);
+        if !path.is_file() {
+            return Ok(ImportSettings::default());
+        }
+
+        let invalid = |message: String| AssetDatabaseError::InvalidSidecar { path: path.clone(), message };
+
+        let content = std::fs::read_to_string(&path)?;
+        let settings: ImportSettings = toml::from_str(&content)
+            .map_err(|err| invalid(err.to_string()))?;
+
+        // Keys and groups end up in keys, so they follow the same rules as paths do
+        let is_valid = |key: &str| !key.is_empty() && AssetPipeline::key_from_path("", key).ok().as_deref() == Some(key);
+
+        if let Some(key) = &settings.key {
+            if !is_valid(key) {
+                return Err(invalid(format!("{} is not a valid key", key)));
+            }
+        }
+
+        if let Some(group) = &settings.texture.atlas_group {
+            if !is_valid(group) {
+                return Err(invalid(format!("{} is not a valid atlas group", group)));
+            }
+        }
+
+        if settings.tags.len() > MAX_TAGS as usize || settings.tags.iter().any(|tag| tag.len() > MAX_TAG_LEN as usize) {
+            return Err(invalid(format!("at most {} tags of up to {} bytes each", MAX_TAGS, MAX_TAG_LEN)));
+        }
+
+        if let Some(loop_end) = settings.audio.loop_end {
+            if loop_end <= settings.audio.loop_start {
+                return Err(invalid("loop_end must come after loop_start".to_string()));
+            }
+        }
+
+        if !(1.0..=512.0).contains(&settings.font.size) {
+            return Err(invalid("font size must be between 1 and 512".to_string()));
+        }
+
+        if settings.font.mode == FontMode::Sdf && !(1.0..=64.0).contains(&settings.font.spread) {
+            return Err(invalid("font spread must be between 1 and 64".to_string()));
+        }
+
+        Ok(settings)
+    }
+
+    pub fn metadata(&self) -> AssetMetadata {
+        AssetMetadata {
+            tags: self.tags.clone(),
+            texture: self.texture.clone(),
+            audio: self.audio.clone()
+        }
+    }
+}
+
+fn write_string(w: &mut Vec<u8>, s: &str) -> io::Result<()> {
+    w.write_u32::<LittleEndian>(s.len() as u32)?;
+    w.extend_from_slice(s.as_bytes());
+
+    Ok(())
+}
+
+#[test]
+fn import_settings_test() {
+    let settings: ImportSettings = toml::from_str(r#"
+        key = "tiles/grass"
+        codec = "zstd"
+        tags = [ "tiles" ]
+
+        [texture]
+        filter = "linear"
+        pivot = [ 0.5, 1.0 ]
+        format = "bc3"
+        atlas_group = "tiles"
+
+        [audio]
+        looping = true
+        loop_end = 9600
+    "#).unwrap();
+
+    assert_eq!(settings.key.as_deref(), Some("tiles/grass"));
+    assert_eq!(settings.codec, Some(Codec::Zstd));
+    assert_eq!(settings.texture.wrap, TextureWrap::Repeat);
+    assert_eq!(settings.texture.format, PixelFormat::Bc3);
+    assert_eq!(settings.audio.loop_end, Some(9600));
+
+    let metadata = settings.metadata();
+    assert!(metadata.has_tag("tiles"));
+
+    let mut data = Vec::new();
+    metadata.write_to(&mut data).unwrap();
+    assert_eq!(AssetMetadata::read_from(&data).unwrap(), metadata);
+
+    // Nothing to store without a .meta file
+    let mut data = Vec::new();
+    AssetMetadata::default().write_to(&mut data).unwrap();
+    assert!(data.is_empty());
+
+    assert!(toml::from_str::<ImportSettings>("filter = \"linear\"").is_err());
+}
@@ -1,135 +1,1455 @@
+mod animation;
+mod asset_cache;
 mod asset_database;
+mod atlas;
+mod audio_import;
+mod build_cache;
+mod codec;
+mod compile_options;
+mod dependency_graph;
+mod error;
+mod flac;
+mod font;
+mod import_settings;
+mod mount;
+#[cfg(build = "debug")]
+mod overlay;
+mod particle;
+mod shader;
+mod string_table;
+mod texture;
+mod truetype;
+mod video;
+mod wav;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use globwalk::glob;
+use rayon::prelude::*;
+pub use animation::{AnimatedTexture, AnimationFrame, LoopMode};
+pub use asset_cache::{Asset, AssetCache, AssetGuard, Handle, LoadProgress, DEFAULT_CACHE_BUDGET, OVERLAY_PROGRESS};
 pub use asset_database::*;
-use std::collections::HashMap;
+pub use atlas::{AtlasBuilder, AtlasPage, AtlasRect, Sprite};
+pub use audio_engine::{AudioFormat, AudioInfo}; // CompileOptions::audio_format, AssetEntry::audio_info()
+pub use build_cache::BuildCache;
+pub use codec::Codec;
+pub use compile_options::CompileOptions;
+pub use dependency_graph::{BuildReport, DependencyGraph};
+pub use error::AssetDatabaseError;
+pub use font::{FontData, FontMode, Glyph, REPLACEMENT_CHARACTER};
+pub use import_settings::{AssetMetadata, FontSettings, ImportSettings, TextureFilter, TextureSettings, TextureWrap, META_EXTENSION};
+pub use mount::MountSource;
+pub use particle::{Curve, CurveKey, EmitterShape, Lerp, ParticleEffect};
+pub use shader::{ShaderSource, ShaderStage};
+pub use string_table::{FormatArg, Localization, StringTable, STRING_TABLE_EXTENSIONS};
+pub use texture::{PixelFormat, TextureData};
+pub use video::{VideoData, VideoDecoder, VideoFrame, VIDEO_EXTENSION};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+#[cfg(build = "debug")]
+use overlay::AssetOverlay;
+use mount::{FolderMount, Mounted};
 
-// TODO: implement a cache system && search for assets through databases
+// Atlas pages are stored as textures with keys like @atlas/0000,
+// pages of an atlas group like @atlas/tiles/0000
+pub const ATLAS_PREFIX: &str = "@atlas";
+
+// Hex encoded ed25519 public key our official archives are signed with
+pub const OFFICIAL_PUBLIC_KEY: Option<&str> = option_env!("PXL_PUBLIC_KEY");
+
+// Mounts with a higher priority override entries of lower ones,
+// mounts with the same priority are ordered by their name.
+#[derive(Copy, Clone, Debug, PartialOrd, PartialEq, Ord, Eq, Hash)]
+pub enum DatabasePriority {
+    Base = 0,
+    Dlc = 1,
+    Mod = 2
+}
+
+// A file compile_folder imports, key is the alias from its .meta file if it has one
+struct Source {
+    key: String,
+    path: PathBuf,
+    settings: ImportSettings
+}
+
+struct LoadedDatabase {
+    name: String,
+    mount_point: String, // Prefix of every key, empty for the root
+    priority: DatabasePriority,
+    db: Arc<Mounted>, // Shared with the threads loading entries from it
+    applied: bool // False for patches that don't apply to any of the archives below them
+}
+
+// An entry and the database its data row is read from, see AssetPipeline::locate().
+// Nothing is read until load() is called, which works from any thread.
+#[derive(Clone)]
+pub struct EntryLocation {
+    database: Option<(String, Arc<Mounted>)>, // None if the entry is in memory already (E.G the overlay)
+    entry: Arc<AssetEntry> // Under the key it's mounted at
+}
+
+impl EntryLocation {
+    pub fn database(&self) -> Option<&str> {
+        self.database.as_ref().map(|(name, _)| name.as_str())
+    }
+
+    // Without its data, unless it's in memory already
+    pub fn entry(&self) -> &Arc<AssetEntry> {
+        &self.entry
+    }
+
+    pub fn load(&self) -> Result<Arc<AssetEntry>, AssetDatabaseError> {
+        match &self.database {
+            Some((_, db)) => db.load_entry(&self.entry),
+            None => Ok(self.entry.clone())
+        }
+    }
+}
+
+// Decoded assets are cached by AssetCache, the pipeline only hands out entries
 pub struct AssetPipeline {
-    databases: HashMap<String, AssetDatabase> // TODO: unload unused databases
+    databases: Vec<LoadedDatabase>,
+    index: HashMap<String, usize>, // key -> databases, the one with the highest priority wins
+
+    public_key: Option<Vec<u8>>,
+    official_databases: HashSet<String>,
+
+    source_root: Option<PathBuf>, // Folder the archives were compiled from, only known during development
+    compile_options: CompileOptions, // Folders, zips and the overlay are imported with these
+
+    #[cfg(build = "debug")]
+    overlay: Option<AssetOverlay>,
+    subscribers: Mutex<Vec<Sender<String>>> // Notified with the key of every asset that changed
 }
 
 impl AssetPipeline {
-    pub fn compile_folder<P: AsRef<Path>>(path: P) -> Vec<AssetDatabase> {
-        let mut databases = Vec::new();
+    pub fn compile_folder<P: AsRef<Path>>(path: P) -> Result<Vec<AssetDatabase>, AssetDatabaseError> {
+        AssetPipeline::compile_folder_with(path, &CompileOptions::default())
+    }
+
+    // Entries end up in a stable order (by type, then by key) so archives can be diffed,
+    // everything that doesn't depend on other entries is compiled in parallel.
+    pub fn compile_folder_with<P: AsRef<Path>>(path: P, options: &CompileOptions) -> Result<Vec<AssetDatabase>, AssetDatabaseError> {
+        let root = path.as_ref();
+        let mut keys = HashMap::new();
 
-        databases.push(AssetDatabase::new());
+        let cache = match &options.cache_dir {
+            Some(cache_dir) => Some(BuildCache::open(cache_dir, options.zstd_dictionary.clone())?),
+            None => None
+        };
+        let cache = cache.as_ref();
 
         // Fetch all texture paths currently we only support
-        // png, jpg and bmp!
-        let texture_paths = glob(
-                path.as_ref().join("**/*.{png,jpg,bmp}").to_str().unwrap()
-            ).unwrap();
+        // png, jpg, bmp and gif!
+        let texture_paths = AssetPipeline::collect_sources(root, "**/*.{png,jpg,bmp,gif}", &mut keys)?;
 
         // Fetch all texture paths currently we only support
-        // ogg, mp3!
-        let audio_paths = glob(
-                path.as_ref().join("**/*.{ogg,mp3}").to_str().unwrap()
-            ).unwrap();
+        // ogg, mp3, wav and flac!
+        let audio_paths = AssetPipeline::collect_sources(root, "**/*.{ogg,mp3,wav,flac}", &mut keys)?;
+
+        // Only TrueType outlines, .otf files with CFF outlines fail to import
+        let font_paths = AssetPipeline::collect_sources(root, "**/*.{ttf,otf}", &mut keys)?;
+
+        // One file per locale, named after it
+        let string_paths = AssetPipeline::collect_sources(root, "**/*.{ftl,lang}", &mut keys)?;
+
+        let particle_paths = AssetPipeline::collect_sources(root, &format!("**/*.{}", particle::PARTICLE_EXTENSION), &mut keys)?;
 
-        // Iterate through all the asset results and
-        // Compress it and add the database entry into the last database on the stack
-        // each database has a maximum size of 128 MB so we have to keep that in mind
-        // TODO: Sprite Atlas
-        for entry in texture_paths {
-            let entry = entry.unwrap();
-            let name = entry.file_name();
+        // Frames and sounds of a video live in a .frames folder next to it, see video.rs
+        let video_paths = AssetPipeline::collect_sources(root, &format!("**/*.{}", VIDEO_EXTENSION), &mut keys)?;
 
-            let img = image::open(entry.path()).unwrap();
+        // .glsl files are pulled in by #include only
+        let shader_paths = AssetPipeline::collect_sources(root, "**/*.{vert,frag}", &mut keys)?;
 
-            let asset_entry = AssetEntry::from_image(
-                name.to_str().unwrap().to_string().split(".").collect::<Vec<&str>>()[0].to_string(),
-                img.into_rgba()
-            );
+        // Small textures are packed into atlas pages, the header tells us enough to decide that.
+        // Sprites only share pages with sprites of the same atlas group.
+        let mut sprite_groups = BTreeMap::new();
+        let mut standalone_paths = Vec::new();
+        for source in texture_paths {
+            if AssetPipeline::is_atlas_sprite(&source.key, &source.path, options)? {
+                sprite_groups.entry(source.settings.texture.atlas_group.clone())
+                    .or_insert_with(Vec::new)
+                    .push(source);
+            } else {
+                standalone_paths.push(source);
+            }
+        }
+
+        let mut entries = AssetPipeline::compile_each("texture", &standalone_paths, options, cache, AssetPipeline::import_texture)?;
+
+        for (group, sprites) in &sprite_groups {
+            let prefix = match group {
+                Some(group) => format!("{}/{}", ATLAS_PREFIX, group),
+                None => ATLAS_PREFIX.to_string()
+            };
+
+            entries.extend(AssetPipeline::compile_atlas(&prefix, sprites, options, cache)?);
+        }
+
+        entries.extend(AssetPipeline::compile_each("audio", &audio_paths, options, cache, |key, path| {
+            audio_import::import(key, path, options)
+        })?);
+
+        entries.extend(AssetPipeline::compile_each("font", &font_paths, options, cache, |key, path| {
+            font::import(key, path, &ImportSettings::load(path)?.font)
+        })?);
+
+        let string_tables = AssetPipeline::compile_each("strings", &string_paths, options, cache, |key, path| {
+            Ok(AssetEntry::from_string_table(key, &StringTable::from_file(path)?))
+        })?;
+
+        let tables = string_tables.iter()
+            .map(|entry| Ok((entry.key(), AssetEntry::clone(entry).into_string_table()?)))
+            .collect::<Result<Vec<_>, AssetDatabaseError>>()?;
+        for (key, missing) in string_table::missing_keys(&tables) {
+            log::warn!("{} is missing {}", key, missing.join(", "));
+        }
+
+        entries.extend(string_tables);
+
+        entries.extend(AssetPipeline::compile_each("particle", &particle_paths, options, cache, |key, path| {
+            Ok(AssetEntry::from_particles(key, &ParticleEffect::from_file(path)?))
+        })?);
 
-            AssetPipeline::check_or_insert(&mut databases, asset_entry);
+        entries.extend(AssetPipeline::compile_each("video", &video_paths, options, cache, |key, path| {
+            video::import(root, key, path, options)
+        })?);
+
+        // Shaders are cheap to import and their includes live in other files, so they skip the cache
+        entries.extend(AssetPipeline::compile_each("shader", &shader_paths, options, None, |key, path| {
+            Ok(AssetEntry::from_shader(key, &ShaderSource::from_file(root, path)?))
+        })?);
+
+        // A typo in a .particle file should fail the build, not show up as a missing texture in game
+        let mut graph = DependencyGraph::new();
+        for entry in &entries {
+            graph.insert(entry);
         }
 
-        // TODO: implement
-        // TODO: description
-        #[allow(unused_variables)]
-        for entry in audio_paths {
-            let entry = entry.unwrap();
-            let name = entry.file_name();
+        let dangling = graph.dangling();
+        for (key, reference) in &dangling {
+            log::error!("{} references {}, which doesn't exist", key, reference);
+        }
 
-            let data = std::fs::read(entry.path()).unwrap();
+        if let Some((key, reference)) = dangling.first() {
+            return Err(AssetDatabaseError::MissingReference { key: key.to_string(), reference: reference.to_string() });
+        }
 
-            let asset_entry = AssetEntry::from_audio(
-                name.to_str().unwrap().to_string().split(".").collect::<Vec<&str>>()[0].to_string(),
-                data
-            );
+        // Each database has a maximum size of 128 MB so we have to keep that in mind
+        let mut databases = vec![AssetPipeline::new_database(options)?];
+        for entry in entries {
+            AssetPipeline::check_or_insert(&mut databases, entry, options)?;
+        }
 
-            AssetPipeline::check_or_insert(&mut databases, asset_entry);
+        let deduplicated: usize = databases.iter().map(|db| db.deduplicated_size()).sum();
+        if deduplicated > 0 {
+            log::info!("Deduplication saved {}", bytesize::to_string(deduplicated as u64, false));
         }
 
-        databases
+        if let Some(cache) = cache {
+            let pruned = cache.prune()?;
+
+            log::info!("Build cache: {} reused, {} compiled, {} pruned", cache.hits(), cache.misses(), pruned);
+        }
+
+        Ok(databases)
     }
 
-    fn check_or_insert(databases: &mut Vec<AssetDatabase>, entry: AssetEntry) {
+    // Keys each string table is missing that other locales in its folder have, by the key of the table
+    pub fn missing_strings(databases: &[AssetDatabase]) -> Result<BTreeMap<String, Vec<String>>, AssetDatabaseError> {
+        let mut tables = Vec::new();
+        for db in databases {
+            for entry in db.iter() {
+                if entry.r#type() == AssetEntryType::StringTable {
+                    let entry = db.load_entry(entry)?;
+
+                    tables.push((entry.key(), AssetEntry::clone(&entry).into_string_table()?));
+                }
+            }
+        }
+
+        Ok(string_table::missing_keys(&tables))
+    }
+
+    // Files below root that none of the databases were compiled from (nothing includes them either),
+    // .meta files of assets that don't exist anymore and references that don't resolve
+    pub fn build_report<P: AsRef<Path>>(root: P, databases: &[AssetDatabase]) -> Result<BuildReport, AssetDatabaseError> {
+        let root = root.as_ref();
+        let graph = DependencyGraph::from_databases(databases);
+
+        let mut orphans = Vec::new();
+        for entry in AssetPipeline::glob_folder(root, "**/*")? {
+            let entry = entry.map_err(AssetPipeline::walk_error)?;
+            let path = entry.path();
+
+            let visible = matches!(path.file_name().and_then(|name| name.to_str()), Some(name) if !name.starts_with('.'));
+            if !entry.file_type().is_file() || !visible {
+                continue;
+            }
+
+            let used = match path.extension().and_then(|ext| ext.to_str()) {
+                Some(META_EXTENSION) => path.with_extension("").is_file(),
+                Some(animation::SIDECAR_EXTENSION) => true, // Its spritesheet can have any extension
+
+                _ => {
+                    let source = AssetPipeline::source(root, path)?;
+
+                    graph.is_used(&source.key) || graph.is_used(&AssetPipeline::key_from_path(root, path)?)
+                }
+            };
+
+            if !used {
+                orphans.push(AssetPipeline::key_from_path(root, path)?);
+            }
+        }
+
+        orphans.sort();
+
+        let dangling = graph.dangling().into_iter()
+            .map(|(key, reference)| (key.to_string(), reference.to_string()))
+            .collect();
+
+        Ok(BuildReport { orphans, dangling })
+    }
+
+    // Patch archives that turn base into target. They hold every entry that's new or changed
+    // and a tombstone for every key target doesn't have anymore. They apply on top of the
+    // content versions of base, options.content_version is the version they bring it to.
+    pub fn compile_patch(base: &[AssetDatabase], target: &[AssetDatabase], options: &CompileOptions) -> Result<Vec<AssetDatabase>, AssetDatabaseError> {
+        let mut base_versions = base.iter()
+            .map(|db| db.content_version().to_string())
+            .collect::<Vec<_>>();
+        base_versions.sort();
+        base_versions.dedup();
+
+        let mut base_entries = BTreeMap::new();
+        for db in base {
+            for entry in db.iter() {
+                base_entries.insert(entry.key(), (db, entry));
+            }
+        }
+
+        let mut patches = vec![AssetPipeline::new_database(options)?];
+        for db in target {
+            for entry in db.iter() {
+                let entry = db.load_entry(entry)?;
+
+                // A different codec alone doesn't need patching
+                let unchanged = match base_entries.remove(&entry.key()) {
+                    Some((base_db, base_entry)) => {
+                        let base_entry = base_db.load_entry(base_entry)?;
+
+                        base_entry.r#type() == entry.r#type()
+                            && base_entry.metadata() == entry.metadata()
+                            && base_entry.raw_data() == entry.raw_data()
+                    }
+
+                    None => false
+                };
+
+                if !unchanged {
+                    log::info!("Patching {}", entry.key());
+
+                    AssetPipeline::check_or_insert(&mut patches, AssetEntry::clone(&entry), options)?;
+                }
+            }
+        }
+
+        for patch in &mut patches {
+            patch.set_base_versions(Some(base_versions.clone()));
+        }
+
+        // Whatever is left in base_entries was removed
+        for key in base_entries.keys() {
+            log::info!("Removing {}", key);
+
+            patches[0].push_tombstone(key);
+        }
+
+        Ok(patches)
+    }
+
+    // A single file the way compile_folder would import it (without an atlas), None if it's not an asset.
+    // The key is the path relative to root unless its .meta file says otherwise,
+    // shader includes are resolved from root as well.
+    pub fn import_file<R: AsRef<Path>, P: AsRef<Path>>(root: R, path: P, options: &CompileOptions) -> Result<Option<AssetEntry>, AssetDatabaseError> {
+        let (root, path) = (root.as_ref(), path.as_ref());
+        let source = AssetPipeline::source(root, path)?;
+        let key = source.key.as_str();
+
+        let extension = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(extension) => extension.to_lowercase(),
+            None => return Ok(None)
+        };
+
+        let entry = match extension.as_str() {
+            "png" | "jpg" | "bmp" | "gif" => AssetPipeline::import_texture(key, path)?,
+            "ogg" | "mp3" | "wav" | "flac" => audio_import::import(key, path, options)?,
+            "ttf" | "otf" => font::import(key, path, &source.settings.font)?,
+            "ftl" | "lang" => AssetEntry::from_string_table(key, &StringTable::from_file(path)?),
+            "vert" | "frag" => AssetEntry::from_shader(key, &ShaderSource::from_file(root, path)?),
+            particle::PARTICLE_EXTENSION => AssetEntry::from_particles(key, &ParticleEffect::from_file(path)?),
+            VIDEO_EXTENSION => video::import(root, key, path, options)?,
+
+            _ => return Ok(None)
+        };
+
+        Ok(Some(AssetPipeline::with_settings(entry, &source.settings, options)?))
+    }
+
+    fn import_texture(key: &str, path: &Path) -> Result<AssetEntry, AssetDatabaseError> {
+        // Animated GIF/APNG or a spritesheet with a .anim file next to it
+        if let Some(animation) = AnimatedTexture::import(key, path)? {
+            return Ok(AssetEntry::from_animated_texture(key, &animation));
+        }
+
+        let img = image::open(path)
+            .map_err(|_| AssetDatabaseError::DecodeFailed { key: key.to_string() })?;
+
+        Ok(AssetEntry::from_image(key, img.into_rgba()))
+    }
+
+    // Every file matching pattern with its key, sorted by key. Files in .frames folders belong to a video.
+    fn collect_sources(root: &Path, pattern: &str, keys: &mut HashMap<String, PathBuf>) -> Result<Vec<Source>, AssetDatabaseError> {
+        let mut sources = Vec::new();
+
+        for entry in AssetPipeline::glob_folder(root, pattern)? {
+            let entry = entry.map_err(AssetPipeline::walk_error)?;
+            if video::is_frame_source(root, entry.path()) {
+                continue;
+            }
+
+            let source = AssetPipeline::source(root, entry.path())?;
+
+            AssetPipeline::reserve_key(&source.key, &source.path, keys)?;
+
+            sources.push(source);
+        }
+
+        sources.sort_by(|a, b| a.key.cmp(&b.key));
+
+        Ok(sources)
+    }
+
+    fn source(root: &Path, path: &Path) -> Result<Source, AssetDatabaseError> {
+        let settings = ImportSettings::load(path)?;
+        let key = match &settings.key {
+            Some(key) => key.clone(),
+            None => AssetPipeline::key_from_path(root, path)?
+        };
+
+        Ok(Source {
+            key,
+            path: path.to_path_buf(),
+            settings
+        })
+    }
+
+    // Everything that affects what a file compiles to, the file itself, its .anim sidecar, its .meta file
+    // and for videos the frames and sound
+    fn source_inputs(path: &Path) -> Result<Vec<u8>, AssetDatabaseError> {
+        let mut inputs = std::fs::read(path)?;
+
+        if path.extension().and_then(|ext| ext.to_str()) == Some(VIDEO_EXTENSION) {
+            for input in video::inputs(path)? {
+                inputs.extend_from_slice(b"\0input\0");
+                inputs.extend_from_slice(&std::fs::read(input)?);
+            }
+        }
+
+        let sidecar = path.with_extension(animation::SIDECAR_EXTENSION);
+        if sidecar.is_file() {
+            inputs.extend_from_slice(b"\0sidecar\0");
+            inputs.extend_from_slice(&std::fs::read(sidecar)?);
+        }
+
+        let meta = ImportSettings::path_of(path);
+        if meta.is_file() {
+            inputs.extend_from_slice(b"\0meta\0");
+            inputs.extend_from_slice(&std::fs::read(meta)?);
+        }
+
+        Ok(inputs)
+    }
+
+    // Imports every source on its own, in parallel, unless the cache already has it
+    fn compile_each<F>(importer: &str, sources: &[Source], options: &CompileOptions, cache: Option<&BuildCache>, import: F) -> Result<Vec<AssetEntry>, AssetDatabaseError>
+        where F: Fn(&str, &Path) -> Result<AssetEntry, AssetDatabaseError> + Sync
+    {
+        let compiled = sources.par_iter()
+            .map(|source| {
+                let hash = match cache {
+                    Some(_) => Some(BuildCache::hash(&[importer.as_bytes(), source.key.as_bytes(), &AssetPipeline::source_inputs(&source.path)?,
+                                                       &options.build_inputs()])),
+                    None => None
+                };
+
+                if let (Some(cache), Some(hash)) = (cache, &hash) {
+                    if let Some(mut entries) = cache.get(hash) {
+                        if entries.len() == 1 {
+                            return Ok(entries.remove(0));
+                        }
+                    }
+                }
+
+                let mut entry = AssetPipeline::with_settings(import(&source.key, &source.path)?, &source.settings, options)?;
+                entry.compress(options.zstd_dictionary.as_ref().map(|dict| dict.as_slice()))?;
+
+                if let (Some(cache), Some(hash)) = (cache, &hash) {
+                    cache.insert(hash, std::slice::from_ref(&entry))?;
+                }
+
+                Ok(entry)
+            })
+            .collect::<Result<Vec<AssetEntry>, AssetDatabaseError>>()?;
+
+        Ok(compiled)
+    }
+
+    // GIFs, APNGs and spritesheets are animations, only the header is read
+    fn is_animation(key: &str, path: &Path) -> Result<bool, AssetDatabaseError> {
+        let extension = path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase());
+
+        if extension.as_deref() == Some("gif") || path.with_extension(animation::SIDECAR_EXTENSION).is_file() {
+            return Ok(true);
+        }
+
+        if extension.as_deref() == Some("png") {
+            let decoder = image::png::PngDecoder::new(std::io::BufReader::new(std::fs::File::open(path)?))
+                .map_err(|_| AssetDatabaseError::DecodeFailed { key: key.to_string() })?;
+
+            return Ok(decoder.is_apng());
+        }
+
+        Ok(false)
+    }
+
+    fn is_atlas_sprite(key: &str, path: &Path, options: &CompileOptions) -> Result<bool, AssetDatabaseError> {
+        if AssetPipeline::is_animation(key, path)? {
+            return Ok(false);
+        }
+
+        let decode_failed = |_| AssetDatabaseError::DecodeFailed { key: key.to_string() };
+
+        let (width, height) = image::image_dimensions(path).map_err(decode_failed)?;
+        let atlas = AtlasBuilder::new(options.atlas_page_size, options.atlas_padding, options.atlas_extrude);
+
+        Ok(width <= options.atlas_max_sprite_size
+            && height <= options.atlas_max_sprite_size
+            && atlas.does_fit_size(width, height))
+    }
+
+    // A single sprite can move every other one around, so the atlas is cached as a whole
+    fn compile_atlas(prefix: &str, sprites: &[Source], options: &CompileOptions, cache: Option<&BuildCache>) -> Result<Vec<AssetEntry>, AssetDatabaseError> {
+        if sprites.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let hash = match cache {
+            Some(cache) => {
+                let mut inputs = vec![b"atlas".to_vec(),
+                                      prefix.as_bytes().to_vec(),
+                                      options.atlas_page_size.to_le_bytes().to_vec(),
+                                      options.atlas_padding.to_le_bytes().to_vec(),
+                                      options.atlas_extrude.to_le_bytes().to_vec(),
+                                      options.build_inputs()];
+
+                for source in sprites {
+                    inputs.push(source.key.as_bytes().to_vec());
+                    inputs.push(AssetPipeline::source_inputs(&source.path)?);
+                }
+
+                let hash = BuildCache::hash(&inputs.iter().map(|input| input.as_slice()).collect::<Vec<_>>());
+                if let Some(entries) = cache.get(&hash) {
+                    return Ok(entries);
+                }
+
+                Some(hash)
+            }
+
+            None => None
+        };
+
+        let images = sprites.par_iter()
+            .map(|source| {
+                let img = image::open(&source.path)
+                    .map_err(|_| AssetDatabaseError::DecodeFailed { key: source.key.clone() })?;
+
+                Ok((source.key.clone(), img.into_rgba()))
+            })
+            .collect::<Result<Vec<_>, AssetDatabaseError>>()?;
+
+        let mut atlas = AtlasBuilder::new(options.atlas_page_size, options.atlas_padding, options.atlas_extrude);
+        for (key, img) in images {
+            // is_atlas_sprite() only looked at the header
+            if !atlas.push(&key, img) {
+                return Err(AssetDatabaseError::DecodeFailed { key });
+            }
+        }
+
+        let settings = sprites.iter()
+            .map(|source| (source.key.as_str(), &source.settings))
+            .collect::<HashMap<_, _>>();
+
+        // Pages are sampled with the texture settings of the group, taken from its first sprite
+        let page_settings = ImportSettings {
+            texture: TextureSettings {
+                pivot: TextureSettings::default().pivot,
+                ..sprites[0].settings.texture.clone()
+            },
+            ..ImportSettings::default()
+        };
+
+        let mut entries = Vec::new();
+        for (i, mut page) in atlas.build().into_iter().enumerate() {
+            let page_key = format!("{}/{:04}", prefix, i);
+            let (width, height) = page.image.dimensions();
+
+            log::info!("Packed {} sprites into {} ({}x{})", page.sprites.len(), page_key, width, height);
+
+            // Packing order isn't very readable in a diff
+            page.sprites.sort_by(|a, b| a.0.cmp(&b.0));
+
+            for (key, rect) in &page.sprites {
+                let sprite = Sprite::new(&page_key, *rect, width, height);
+
+                entries.push(AssetPipeline::with_settings(AssetEntry::from_sprite(key, &sprite), settings[key.as_str()], options)?);
+            }
+
+            entries.push(AssetPipeline::with_settings(AssetEntry::from_image(page_key, page.image), &page_settings, options)?);
+        }
+
+        let dictionary = options.zstd_dictionary.as_ref().map(|dict| dict.as_slice());
+        entries.par_iter_mut().try_for_each(|entry| entry.compress(dictionary))?;
+
+        if let (Some(cache), Some(hash)) = (cache, hash) {
+            cache.insert(&hash, &entries)?;
+        }
+
+        Ok(entries)
+    }
+
+    // Keys are the path relative to the asset root, always separated by / (E.G textures/world.png)
+    pub fn key_from_path<P: AsRef<Path>, R: AsRef<Path>>(root: R, path: P) -> Result<String, AssetDatabaseError> {
+        let relative = path.as_ref().strip_prefix(root.as_ref())
+            .unwrap_or(path.as_ref());
+
+        let mut components = Vec::new();
+        for component in relative.components() {
+            match component {
+                std::path::Component::Normal(name) =>
+                    components.push(name.to_str().ok_or(AssetDatabaseError::InvalidKey)?),
+                std::path::Component::CurDir => {},
+
+                _ => return Err(AssetDatabaseError::InvalidKey)
+            }
+        }
+
+        Ok(components.join("/"))
+    }
+
+    // keys is every key we've seen so far, lower cased as not every file system is case sensitive
+    fn reserve_key(key: &str, path: &Path, keys: &mut HashMap<String, PathBuf>) -> Result<(), AssetDatabaseError> {
+        if let Some(first) = keys.insert(key.to_lowercase(), path.to_path_buf()) {
+            return Err(AssetDatabaseError::DuplicateKey {
+                key: key.to_string(),
+                first,
+                second: path.to_path_buf()
+            });
+        }
+
+        Ok(())
+    }
+
+    fn glob_folder<P: AsRef<Path>>(path: P, pattern: &str) -> Result<globwalk::GlobWalker, AssetDatabaseError> {
+        let pattern = path.as_ref().join(pattern);
+        let pattern = pattern.to_str().ok_or(AssetDatabaseError::InvalidKey)?;
+
+        glob(pattern).map_err(|err| AssetDatabaseError::Io(
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, err.to_string())))
+    }
+
+    fn walk_error(err: globwalk::WalkError) -> AssetDatabaseError {
+        AssetDatabaseError::Io(err.into())
+    }
+
+    // The codec of the .meta file wins over CompileOptions::codecs
+    // Textures are converted to the format they're uploaded in here, so atlas pages are as well
+    fn with_settings(entry: AssetEntry, settings: &ImportSettings, options: &CompileOptions) -> Result<AssetEntry, AssetDatabaseError> {
+        let codec = settings.codec.unwrap_or_else(|| options.codec_for(entry.r#type()));
+
+        let texture = &settings.texture;
+        let entry = match entry.r#type() {
+            AssetEntryType::Texture if texture.mipmaps || texture.premultiply_alpha || texture.format != PixelFormat::Rgba8 => {
+                let key = entry.key();
+                let processed = TextureData::process(entry.into_texture()?, texture);
+
+                AssetEntry::from_texture_data(key, &processed)
+            }
+
+            _ => entry
+        };
+
+        Ok(entry.with_codec(codec)
+            .with_metadata(settings.metadata()))
+    }
+
+    fn check_or_insert(databases: &mut Vec<AssetDatabase>, entry: AssetEntry, options: &CompileOptions) -> Result<(), AssetDatabaseError> {
         if entry.data.len() >= MAX_SIZE {
-            panic!("{} is too large! > 128 MB", entry.key()); // just crash at this point.
+            return Err(AssetDatabaseError::TooLarge { key: entry.key(), size: entry.data.len() });
+        }
+
+        // Identical data is only stored once, so it goes into the database that has it already
+        let payload = Payload::of(&entry);
+        if let Some(db) = databases.iter_mut().find(|db| db.stores(&payload) && db.fits(&payload, entry.size())) {
+            return db.push_entry(entry);
         }
 
         let last_db = databases.last_mut().unwrap();
-        if last_db.does_fit(&entry) { // Make sure our entry fits
-            last_db.push_entry(entry).unwrap();
-        } else { // Otherwise create a new db, if it still doesn't fit then we're fucked.
-            databases.push(AssetDatabase::new());
+        if last_db.fits(&payload, entry.size()) { // Make sure our entry fits
+            last_db.push_entry(entry)
+        } else { // Otherwise create a new db, it always fits as we checked the size above.
+            databases.push(AssetPipeline::new_database(options)?);
 
             let last_db = databases.last_mut().unwrap();
-            last_db.push_entry(entry).unwrap();
+            last_db.push_entry(entry)
         }
     }
 
-    pub fn new<S: AsRef<str>>(pattern: S) -> AssetPipeline {
+    // The entries are compressed with the dictionary already, so every database needs it
+    fn new_database(options: &CompileOptions) -> Result<AssetDatabase, AssetDatabaseError> {
+        let mut db = AssetDatabase::new();
+        db.set_dictionary(options.zstd_dictionary.clone())?;
+        db.set_content_version(&options.content_version);
+
+        Ok(db)
+    }
+
+    // All databases matching pattern are loaded as DatabasePriority::Base, patches
+    // among them are layered over the others (see rebuild_index).
+    // Broken databases (e.g a corrupt mod archive) are reported and skipped,
+    // only an invalid pattern is an error.
+    pub fn new<S: AsRef<str>>(pattern: S) -> Result<AssetPipeline, AssetDatabaseError> {
+        let public_key = match OFFICIAL_PUBLIC_KEY {
+            Some(public_key) => Some(hex::decode(public_key)
+                .map_err(|_| AssetDatabaseError::InvalidKeyPair)?),
+            None => None
+        };
+
+        AssetPipeline::with_public_key(pattern, public_key)
+    }
+
+    // Archives signed with public_key are official, everything else is treated as a mod
+    pub fn with_public_key<S: AsRef<str>>(pattern: S, public_key: Option<Vec<u8>>) -> Result<AssetPipeline, AssetDatabaseError> {
         let asset_databases = glob(
             pattern
-        ).unwrap();
+        ).map_err(|err| AssetDatabaseError::Io(
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, err.to_string())))?;
+
+        let mut pipeline = AssetPipeline {
+            databases: Vec::new(),
+            index: HashMap::new(),
+
+            public_key,
+            official_databases: HashSet::new(),
 
-        let mut databases = HashMap::new();
+            source_root: None,
+            compile_options: CompileOptions::default(),
+
+            #[cfg(build = "debug")]
+            overlay: None,
+            subscribers: Mutex::new(Vec::new())
+        };
 
         for asset_database in asset_databases {
-            let instant = std::time::Instant::now();
-            let path = asset_database.unwrap();
+            let path = match asset_database {
+                Ok(path) => path,
+                Err(err) => {
+                    log::error!("Failed to find asset database: {}", err);
+                    continue;
+                }
+            };
 
-            log::info!("------- Loading {}", path.file_name().to_str().unwrap());
+            if let Err(err) = pipeline.load_database(path.path(), DatabasePriority::Base) {
+                log::error!("Failed to load {}: {}", path.path().display(), err);
+            }
+        }
 
-            let db = AssetDatabase::from_bytes(std::fs::read(path.path()).unwrap())
-                .unwrap();
+        Ok(pipeline)
+    }
+
+    // Mounts an archive at the root
+    pub fn load_database<P: AsRef<Path>>(&mut self, path: P, priority: DatabasePriority) -> Result<(), AssetDatabaseError> {
+        self.mount("", MountSource::Archive(path.as_ref().to_path_buf()), priority)
+    }
+
+    // Also used for databases that don't come from a file, e.g freshly compiled ones
+    pub fn insert_database<S: AsRef<str>>(&mut self, name: S, priority: DatabasePriority, db: AssetDatabase) {
+        self.insert_mount(name.as_ref().to_string(), "", priority, Mounted::Database(Box::new(db)));
+    }
+
+    // Mounts source next to everything else, its keys show up as <mount_point>/<key>
+    // (an empty mount point mounts it at the root). Mounts are named after the file name
+    // of archives and zips and the path of directories, mounting a name again replaces it.
+    pub fn mount<S: AsRef<str>>(&mut self, mount_point: S, source: MountSource, priority: DatabasePriority) -> Result<(), AssetDatabaseError> {
+        let file_name = |path: &Path| path.file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| name.to_string())
+            .ok_or(AssetDatabaseError::InvalidKey);
+
+        let (name, mounted) = match source {
+            MountSource::Archive(path) => {
+                let name = file_name(&path)?;
+                let db = self.open_archive(&name, &path)?;
+
+                (name, Mounted::Database(Box::new(db)))
+            }
+
+            MountSource::Directory(path) => (path.display().to_string(), Mounted::Folder(FolderMount::open(&path, &self.compile_options)?)),
+            MountSource::Zip(path) => (file_name(&path)?, Mounted::Folder(FolderMount::open_zip(&path, &self.compile_options)?)),
+            MountSource::Memory(name, db) => (name, Mounted::Database(db))
+        };
+
+        self.insert_mount(name, mount_point.as_ref(), priority, mounted);
+
+        Ok(())
+    }
+
+    // False if nothing is mounted under that name
+    pub fn unmount<S: AsRef<str>>(&mut self, name: S) -> bool {
+        let len = self.databases.len();
+
+        self.databases.retain(|loaded| loaded.name != name.as_ref());
+        self.official_databases.remove(name.as_ref());
+
+        if self.databases.len() == len {
+            return false;
+        }
+
+        log::info!("Unmounted {}", name.as_ref());
+        self.rebuild_index();
+
+        true
+    }
+
+    fn open_archive(&mut self, name: &str, path: &Path) -> Result<AssetDatabase, AssetDatabaseError> {
+        let instant = std::time::Instant::now();
+
+        log::info!("------- Loading {}", name);
+
+        // Only the entry table is read here, data rows are decoded once they're requested
+        let db = AssetDatabase::open(path)?;
+
+        // Checking the signature has to hash the whole archive, so only do it if it's signed at all
+        self.official_databases.remove(name);
+        if let Some(public_key) = &self.public_key {
+            if db.is_signed() && db.verify_signature(public_key)? {
+                log::info!("------- {} is an official archive", name);
+
+                self.official_databases.insert(name.to_string());
+            } else {
+                log::warn!("------- {} is not signed by us, treating it as a mod", name);
+            }
+        }
+
+        log::info!("------- Done! took {:#?}", instant.elapsed());
+
+        Ok(db)
+    }
+
+    fn insert_mount(&mut self, name: String, mount_point: &str, priority: DatabasePriority, mounted: Mounted) {
+        if !mount_point.is_empty() {
+            log::info!("Mounted {} at {}", name, mount_point);
+        }
+
+        self.databases.retain(|loaded| loaded.name != name);
+        self.databases.push(LoadedDatabase {
+            name,
+            mount_point: mount_point.trim_matches('/').to_string(),
+            priority,
+            db: Arc::new(mounted),
+            applied: false
+        });
+
+        self.databases.sort_by(|a, b| (a.priority, a.db.is_patch(), &a.name).cmp(&(b.priority, b.db.is_patch(), &b.name)));
+
+        self.rebuild_index();
+    }
+
+    // Later databases override earlier ones. Each priority is layered on its own: first the
+    // regular archives, then the patches in the order they apply. A patch applies if it's made
+    // for one of the content versions below it, which it then moves on to its own version.
+    // Patches that never apply are skipped.
+    fn rebuild_index(&mut self) {
+        self.index.clear();
+
+        let mut start = 0;
+        while start < self.databases.len() {
+            let priority = self.databases[start].priority;
+            let end = self.databases[start..].iter()
+                .position(|loaded| loaded.priority != priority)
+                .map_or(self.databases.len(), |len| start + len);
+
+            let mut versions = HashSet::new();
+            let mut patches = Vec::new();
+            // Folders don't have a content version
+            for i in start..end {
+                match self.databases[i].db.database() {
+                    Some(db) if db.is_patch() => patches.push(i),
+                    Some(db) => {
+                        versions.insert(db.content_version().to_string());
+                        self.apply_database(i);
+                    }
+
+                    None => self.apply_database(i)
+                }
+            }
+
+            // Patches for any version need nothing below them
+            while let Some(next) = patches.iter().position(|i| {
+                let db = self.databases[*i].db.database().unwrap();
+
+                db.base_versions().is_empty() || versions.iter().any(|version| db.applies_to(version))
+            }) {
+                let i = patches.remove(next);
+                let mounted = self.databases[i].db.clone();
+                let db = mounted.database().unwrap();
+
+                versions.retain(|version| !db.base_versions().contains(version));
+                versions.insert(db.content_version().to_string());
+
+                self.apply_database(i);
+            }
+
+            for i in patches {
+                log::warn!("{} is a patch for {:?}, it doesn't apply to {:?}", self.databases[i].name,
+                    self.databases[i].db.database().unwrap().base_versions(), versions);
+
+                self.databases[i].applied = false;
+            }
+
+            start = end;
+        }
+    }
+
+    fn apply_database(&mut self, i: usize) {
+        let loaded = &self.databases[i];
+
+        if let Some(db) = loaded.db.database() {
+            for key in db.tombstones() {
+                let key = AssetPipeline::mounted_key(&loaded.mount_point, key);
+
+                if let Some(previous) = self.index.remove(&key) {
+                    log::info!("{} from {} is removed by {}", key, self.databases[previous].name, loaded.name);
+                }
+            }
+        }
+
+        for entry in loaded.db.iter() {
+            let key = AssetPipeline::mounted_key(&loaded.mount_point, &entry.entry_key);
+
+            if let Some(previous) = self.index.insert(key.clone(), i) {
+                log::info!("{} from {} overrides {}", key, loaded.name,
+                    self.databases[previous].name);
+            }
+        }
+
+        self.databases[i].applied = true;
+    }
+
+    // False for patches that were skipped because they don't apply to the archives below them
+    pub fn is_applied<S: AsRef<str>>(&self, database: S) -> bool {
+        self.databases.iter().any(|loaded| loaded.name == database.as_ref() && loaded.applied)
+    }
+
+    pub fn is_official<S: AsRef<str>>(&self, database: S) -> bool {
+        self.official_databases.contains(database.as_ref())
+    }
+
+    pub fn set_source_root<P: AsRef<Path>>(&mut self, path: P) {
+        self.source_root = Some(path.as_ref().to_path_buf());
+    }
+
+    pub fn source_root(&self) -> Option<&Path> {
+        self.source_root.as_deref()
+    }
+
+    // Only mounts made afterwards are imported with them
+    pub fn set_compile_options(&mut self, options: CompileOptions) {
+        self.compile_options = options;
+    }
+
+    pub fn compile_options(&self) -> &CompileOptions {
+        &self.compile_options
+    }
+
+    // Mounts the raw assets folder on top of all databases, files that change in there
+    // are re-imported by poll_changes(). Also makes it the source root.
+    #[cfg(build = "debug")]
+    pub fn mount_overlay<P: AsRef<Path>>(&mut self, path: P) -> Result<(), AssetDatabaseError> {
+        let overlay = AssetOverlay::new(path.as_ref(), &self.compile_options)?;
+
+        log::info!("Mounted {} as asset overlay", overlay.root().display());
+
+        self.source_root = Some(overlay.root().to_path_buf());
+        self.overlay = Some(overlay);
+
+        Ok(())
+    }
+
+    // Receives the key of every asset that changed, E.G through the overlay
+    pub fn subscribe(&self) -> Receiver<String> {
+        let (tx, rx) = channel();
+        self.subscribers.lock().unwrap().push(tx);
+
+        rx
+    }
+
+    // Picks up changed files and notifies subscribers, call it once per frame.
+    // Does nothing unless an overlay is mounted.
+    pub fn poll_changes(&mut self) -> Vec<String> {
+        #[allow(unused_mut)]
+        let mut changed = Vec::new();
+
+        #[cfg(build = "debug")]
+        {
+            if let Some(overlay) = self.overlay.as_mut() {
+                changed = overlay.poll();
+            }
+        }
+
+        if !changed.is_empty() {
+            // Dropped receivers unsubscribe
+            self.subscribers.lock().unwrap()
+                .retain(|subscriber| changed.iter().all(|key| subscriber.send(key.clone()).is_ok()));
+        }
+
+        changed
+    }
+
+    pub fn search<S: AsRef<str>>(&self, key: S) -> Result<Option<Arc<AssetEntry>>, AssetDatabaseError> {
+        match self.locate(key) {
+            Some(location) => Ok(Some(location.load()?)),
+            None => Ok(None)
+        }
+    }
+
+    // Finds the entry search() would return without reading its data row
+    pub fn locate<S: AsRef<str>>(&self, key: S) -> Option<EntryLocation> {
+        #[cfg(build = "debug")]
+        {
+            if let Some(entry) = self.overlay.as_ref().and_then(|overlay| overlay.get(key.as_ref())) {
+                return Some(EntryLocation { database: None, entry });
+            }
+        }
+
+        let key = key.as_ref();
+        let loaded = &self.databases[*self.index.get(key)?];
+
+        // Every key in the index starts with the mount point of its mount
+        let mounted_key = match loaded.mount_point.is_empty() {
+            true => key,
+            false => &key[loaded.mount_point.len() + 1..]
+        };
+
+        Some(EntryLocation {
+            database: Some((loaded.name.clone(), loaded.db.clone())),
+            entry: AssetPipeline::with_key(loaded.db.entry(mounted_key)?.clone(), key)
+        })
+    }
+
+    // <mount_point>/<key>
+    fn mounted_key(mount_point: &str, key: &str) -> String {
+        if mount_point.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}/{}", mount_point, key)
+        }
+    }
+
+    // Entries only know the key they have inside of their mount
+    fn with_key(entry: Arc<AssetEntry>, key: &str) -> Arc<AssetEntry> {
+        if entry.entry_key == key {
+            return entry;
+        }
+
+        let mut entry = AssetEntry::clone(&entry);
+        entry.entry_key = key.to_string();
+
+        Arc::new(entry)
+    }
+
+    // Resolves a texture key to the texture it's drawn from and the part of it that's covered,
+    // works for sprites packed into an atlas as well as textures that are stored on their own.
+    pub fn search_sprite<S: AsRef<str>>(&self, key: S) -> Result<Option<(Arc<AssetEntry>, Sprite)>, AssetDatabaseError> {
+        let entry = match self.search(&key)? {
+            Some(entry) => entry,
+            None => return Ok(None)
+        };
+
+        match entry.r#type() {
+            AssetEntryType::Sprite => {
+                let sprite = AssetEntry::clone(&entry).into_sprite()?;
+
+                // The page lives in the same mount as the sprite
+                let mount_point = self.index.get(key.as_ref())
+                    .map_or("", |i| self.databases[*i].mount_point.as_str());
+                let page_key = AssetPipeline::mounted_key(mount_point, &sprite.page);
+
+                match self.search(&page_key)? {
+                    Some(page) => Ok(Some((page, sprite))),
+                    None => Err(AssetDatabaseError::DecodeFailed { key: sprite.page })
+                }
+            }
+
+            AssetEntryType::Texture => {
+                let (width, height) = entry.texture_size()?;
+                let rect = AtlasRect { x: 0, y: 0, width, height };
 
-            log::info!("------- Done! took {:#?}", instant.elapsed());
+                let sprite = Sprite::new(key, rect, width, height);
 
-            databases.insert(path.file_name().to_str().unwrap().to_string(), db);
+                Ok(Some((entry, sprite)))
+            }
+
+            _ => Ok(None) // Not something we can draw
         }
+    }
+
+    // References and includes of every mounted entry, under the keys they're mounted at.
+    // Loose files of folder mounts only know theirs once they're imported.
+    pub fn dependency_graph(&self) -> DependencyGraph {
+        let mut graph = DependencyGraph::new();
 
-        AssetPipeline {
-            databases
+        for key in self.index.keys() {
+            let location = match self.locate(key) {
+                Some(location) => location,
+                None => continue
+            };
+
+            let mount_point = self.index.get(key).map_or("", |i| self.databases[*i].mount_point.as_str());
+            let mounted = |keys: &[String]| keys.iter()
+                .map(|key| AssetPipeline::mounted_key(mount_point, key))
+                .collect();
+
+            graph.insert_key(key, mounted(location.entry().references()), mounted(location.entry().includes()));
         }
+
+        graph
     }
 
-    pub fn search<S: AsRef<str>>(&self, key: S) -> Option<AssetEntry> {
-        for db in &self.databases {
-            if let Some(entry) = db.1.get_entry(key.as_ref().to_string()) {
-                return Some(entry);
+    // Name of the database the entry is going to be loaded from
+    pub fn database_of<S: AsRef<str>>(&self, key: S) -> Option<&str> {
+        self.index.get(key.as_ref()).map(|i| self.databases[*i].name.as_str())
+    }
+
+    // Ordered by priority
+    pub fn database_names(&self) -> Vec<&str> {
+        self.databases.iter().map(|loaded| loaded.name.as_str()).collect()
+    }
+
+    // See AssetDatabase::unload, false if it's not loaded from a file or unloaded already
+    pub fn unload_database<S: AsRef<str>>(&mut self, name: S) -> bool {
+        match self.databases.iter_mut().find(|loaded| loaded.name == name.as_ref()) {
+            Some(loaded) => {
+                let unloaded = loaded.db.unload();
+                if unloaded {
+                    log::info!("Unloaded {}", loaded.name);
+                }
+
+                unloaded
             }
+
+            None => false
         }
+    }
+
+    // Every database with its entries, nothing is read from the archives. Ordered by priority
+    pub fn database_entries(&self) -> Vec<(String, Vec<Arc<AssetEntry>>)> {
+        self.databases.iter()
+            .map(|database| {
+                let entries = database.db.iter()
+                    .map(|entry| AssetPipeline::with_key(entry.clone(), &AssetPipeline::mounted_key(&database.mount_point, &entry.entry_key)))
+                    .collect();
 
-        None
+                (database.name.clone(), entries)
+            })
+            .collect()
     }
 
-    pub fn all_databases(&self) -> HashMap<String, Vec<AssetEntry>> {
-        let mut entries_r = HashMap::new();
+    // Every database with all of its entries loaded, ordered by priority
+    pub fn all_databases(&self) -> Result<Vec<(String, Vec<Arc<AssetEntry>>)>, AssetDatabaseError> {
+        let mut entries_r = Vec::new();
 
         for database in &self.databases {
             let mut entries = vec![];
-            for entry in database.1.iter() {
-                entries.push(entry.clone());
+            for entry in database.db.iter() {
+                let key = AssetPipeline::mounted_key(&database.mount_point, &entry.entry_key);
+
+                entries.push(AssetPipeline::with_key(database.db.load_entry(entry)?, &key));
             }
-            entries_r.insert(database.0.clone(), entries);
+            entries_r.push((database.name.clone(), entries));
+        }
+
+        Ok(entries_r)
+    }
+}
+
+#[test]
+fn database_priority_test() {
+    let mut base = AssetDatabase::new();
+    base.push_entry(AssetEntry::from_audio("audio/test.ogg", vec![1])).unwrap();
+
+    let mut modded = AssetDatabase::new();
+    modded.push_entry(AssetEntry::from_audio("audio/test.ogg", vec![2])).unwrap();
+
+    let mut pipeline = AssetPipeline::with_public_key("does-not-exist-*.pxl", None).unwrap();
+    pipeline.insert_database("mod.pxl", DatabasePriority::Mod, modded);
+    pipeline.insert_database("assets-0000.pxl", DatabasePriority::Base, base);
+
+    let entry = pipeline.search("audio/test.ogg").unwrap().unwrap();
+    assert_eq!(entry.raw_data(), &vec![2]);
+    assert_eq!(pipeline.database_of("audio/test.ogg"), Some("mod.pxl"));
+}
+
+#[test]
+fn key_from_path_test() {
+    let root = Path::new("assets");
+
+    assert_eq!(AssetPipeline::key_from_path(root, root.join("tiles").join("grass.png")).unwrap(), "tiles/grass.png");
+    assert_eq!(AssetPipeline::key_from_path(root, root.join("grass.v2.png")).unwrap(), "grass.v2.png");
+    assert_eq!(AssetPipeline::key_from_path(root, "assets/./items/grass.png").unwrap(), "items/grass.png");
+}
+
+#[test]
+fn search_sprite_test() {
+    let mut atlas = AtlasBuilder::new(64, 0, 0);
+    assert!(atlas.push("tiles/grass.png", image::RgbaImage::from_pixel(4, 4, image::Rgba([0, 255, 0, 255]))));
+
+    let page = atlas.build().remove(0);
+    let sprite = Sprite::new("@atlas/0000", page.sprites[0].1, page.image.width(), page.image.height());
+
+    let mut db = AssetDatabase::new();
+    db.push_entry(AssetEntry::from_sprite("tiles/grass.png", &sprite)).unwrap();
+    db.push_entry(AssetEntry::from_image("@atlas/0000", page.image)).unwrap();
+    db.push_entry(AssetEntry::from_image("textures/world.png", image::RgbaImage::new(8, 2))).unwrap();
+
+    let mut pipeline = AssetPipeline::with_public_key("does-not-exist-*.pxl", None).unwrap();
+    pipeline.insert_database("assets-0000.pxl", DatabasePriority::Base, db);
+
+    let (page, found) = pipeline.search_sprite("tiles/grass.png").unwrap().unwrap();
+    assert_eq!(page.key(), "@atlas/0000");
+    assert_eq!(found, sprite);
+
+    let (texture, found) = pipeline.search_sprite("textures/world.png").unwrap().unwrap();
+    assert_eq!(texture.key(), "textures/world.png");
+    assert_eq!(found.uv, [0.0, 0.0, 1.0, 1.0]);
+}
+
+#[cfg(build = "debug")]
+#[test]
+fn overlay_reload_test() {
+    let root = std::env::temp_dir().join(format!("pxl_overlay_test_{}", std::process::id()));
+    std::fs::create_dir_all(root.join("textures")).unwrap();
+
+    let mut pipeline = AssetPipeline::with_public_key("does-not-exist-*.pxl", None).unwrap();
+    pipeline.mount_overlay(&root).unwrap();
+
+    let changes = pipeline.subscribe();
+
+    image::RgbaImage::new(3, 2).save(root.join("textures/tile.png")).unwrap();
+
+    // Give the watcher some time to pick it up
+    let mut changed = Vec::new();
+    for _ in 0..50 {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        changed = pipeline.poll_changes();
+        if !changed.is_empty() {
+            break;
         }
+    }
+
+    assert_eq!(changed, vec!["textures/tile.png"]);
+    assert_eq!(changes.try_recv().unwrap(), "textures/tile.png");
+
+    let entry = pipeline.search("textures/tile.png").unwrap().unwrap();
+    assert_eq!(entry.texture_size().unwrap(), (3, 2));
+
+    // Renaming it drops the old key, nothing is left behind for it
+    std::fs::rename(root.join("textures/tile.png"), root.join("textures/floor.png")).unwrap();
 
-        entries_r
+    let mut changed = Vec::new();
+    for _ in 0..50 {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        changed.extend(pipeline.poll_changes());
+        if changed.len() >= 2 {
+            break;
+        }
     }
+
+    changed.sort();
+    assert_eq!(changed, vec!["textures/floor.png", "textures/tile.png"]);
+    assert!(pipeline.search("textures/tile.png").unwrap().is_none());
+    assert!(pipeline.search("textures/floor.png").unwrap().is_some());
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn build_cache_test() {
+    let root = std::env::temp_dir().join(format!("pxl_build_cache_test_{}", std::process::id()));
+    std::fs::create_dir_all(root.join("assets/textures")).unwrap();
+
+    image::RgbaImage::from_pixel(8, 8, image::Rgba([255, 0, 0, 255])).save(root.join("assets/textures/b.png")).unwrap();
+    image::RgbaImage::from_pixel(4, 4, image::Rgba([0, 255, 0, 255])).save(root.join("assets/textures/a.png")).unwrap();
+    image::RgbaImage::from_pixel(300, 2, image::Rgba([0, 0, 255, 255])).save(root.join("assets/textures/wide.png")).unwrap();
+
+    let options = CompileOptions {
+        cache_dir: Some(root.join("cache")),
+        ..CompileOptions::default()
+    };
+
+    let compile = || {
+        let mut databases = AssetPipeline::compile_folder_with(root.join("assets"), &options).unwrap();
+        assert_eq!(databases.len(), 1);
+
+        let keys: Vec<String> = databases[0].iter().map(|entry| entry.key()).collect();
+        assert_eq!(keys, vec!["textures/wide.png", "textures/a.png", "textures/b.png", "@atlas/0000"]);
+
+        databases[0].to_bytes().unwrap()
+    };
+
+    let first = compile();
+    let cached = compile();
+    assert_eq!(first, cached);
+
+    // Compiling without the cache gives the same archive as well
+    let uncached = AssetPipeline::compile_folder(root.join("assets")).unwrap().remove(0).to_bytes().unwrap();
+    assert_eq!(first, uncached);
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn import_settings_compile_test() {
+    let root = std::env::temp_dir().join(format!("pxl_import_settings_test_{}", std::process::id()));
+    std::fs::create_dir_all(root.join("textures")).unwrap();
+
+    image::RgbaImage::new(4, 4).save(root.join("textures/grass.png")).unwrap();
+    image::RgbaImage::new(4, 4).save(root.join("textures/stone.png")).unwrap();
+    std::fs::write(root.join("textures/grass.png.meta"), r#"
+        key = "tiles/grass"
+        codec = "zstd"
+        tags = [ "nature" ]
+
+        [texture]
+        filter = "linear"
+        atlas_group = "tiles"
+    "#).unwrap();
+
+    let mut databases = AssetPipeline::compile_folder(&root).unwrap();
+    let db = AssetDatabase::from_bytes(databases[0].to_bytes().unwrap()).unwrap();
+
+    let keys: Vec<String> = db.iter().map(|entry| entry.key()).collect();
+    assert_eq!(keys, vec!["textures/stone.png", "@atlas/0000", "tiles/grass", "@atlas/tiles/0000"]);
+
+    let grass = db.entry("tiles/grass").unwrap();
+    assert_eq!(grass.codec(), Codec::Zstd);
+    assert!(grass.metadata().has_tag("nature"));
+
+    // The page is sampled the way its sprites asked for
+    let page = db.entry("@atlas/tiles/0000").unwrap();
+    assert_eq!(page.metadata().texture.filter, TextureFilter::Linear);
+    assert!(page.metadata().tags.is_empty());
+
+    assert_eq!(db.entry("textures/stone.png").unwrap().metadata(), &AssetMetadata::default());
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn patch_layering_test() {
+    let database = |version: &str, entries: &[(&str, u8)]| {
+        let mut db = AssetDatabase::new();
+        db.set_content_version(version);
+        for (key, data) in entries {
+            db.push_entry(AssetEntry::from_audio(key, vec![*data])).unwrap();
+        }
+
+        db
+    };
+
+    let base = database("1.0", &[("audio/a.ogg", 1), ("audio/b.ogg", 2), ("audio/c.ogg", 3)]);
+    let target = database("1.1", &[("audio/a.ogg", 1), ("audio/b.ogg", 20), ("audio/d.ogg", 4)]);
+
+    let options = CompileOptions { content_version: "1.1".to_string(), ..CompileOptions::default() };
+    let mut patches = AssetPipeline::compile_patch(&[base], &[target], &options).unwrap();
+    assert_eq!(patches.len(), 1);
+
+    let patch = patches.remove(0);
+    assert_eq!(patch.iter().map(|entry| entry.key()).collect::<Vec<_>>(), vec!["audio/b.ogg", "audio/d.ogg"]);
+    assert_eq!(patch.tombstones().iter().collect::<Vec<_>>(), vec!["audio/c.ogg"]);
+
+    // Sorts first by name, but has to wait for the 1.1 patch
+    let mut next = database("1.2", &[("audio/a.ogg", 5)]);
+    next.set_base_versions(Some(vec!["1.1".to_string()]));
+
+    let mut stale = database("0.9.1", &[("audio/a.ogg", 99)]);
+    stale.set_base_versions(Some(vec!["0.9".to_string()]));
+
+    let mut pipeline = AssetPipeline::with_public_key("does-not-exist-*.pxl", None).unwrap();
+    pipeline.insert_database("assets-0000.pxl", DatabasePriority::Base,
+                             database("1.0", &[("audio/a.ogg", 1), ("audio/b.ogg", 2), ("audio/c.ogg", 3)]));
+    pipeline.insert_database("assets-patch-1.1.pxl", DatabasePriority::Base, patch);
+    pipeline.insert_database("assets-patch-1.0.pxl", DatabasePriority::Base, next);
+    pipeline.insert_database("assets-patch-0.9.pxl", DatabasePriority::Base, stale);
+
+    let data = |key| pipeline.search(key).unwrap().map(|entry| entry.raw_data()[0]);
+    assert_eq!(data("audio/a.ogg"), Some(5));
+    assert_eq!(data("audio/b.ogg"), Some(20));
+    assert_eq!(data("audio/c.ogg"), None);
+    assert_eq!(data("audio/d.ogg"), Some(4));
+
+    assert!(pipeline.is_applied("assets-patch-1.0.pxl"));
+    assert!(!pipeline.is_applied("assets-patch-0.9.pxl"));
+}
+
+#[test]
+fn mount_test() {
+    let root = std::env::temp_dir().join(format!("pxl-mount-test-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("audio")).unwrap();
+    std::fs::write(root.join("audio/a.ogg"), vec![10]).unwrap();
+
+    let mut db = AssetDatabase::new();
+    db.push_entry(AssetEntry::from_audio("audio/a.ogg", vec![1])).unwrap();
+    db.push_entry(AssetEntry::from_audio("audio/b.ogg", vec![2])).unwrap();
+
+    let mut pipeline = AssetPipeline::with_public_key("does-not-exist-*.pxl", None).unwrap();
+    pipeline.mount("", MountSource::Memory("base".to_string(), Box::new(db)), DatabasePriority::Base).unwrap();
+    pipeline.mount("/mods/test/", MountSource::Directory(root.clone()), DatabasePriority::Mod).unwrap();
+
+    let data = |pipeline: &AssetPipeline, key| pipeline.search(key).unwrap().map(|entry| entry.audio_file()[0]);
+    assert_eq!(data(&pipeline, "audio/a.ogg"), Some(1));
+    assert_eq!(data(&pipeline, "mods/test/audio/a.ogg"), Some(10));
+    assert_eq!(data(&pipeline, "mods/test/audio/b.ogg"), None);
+    assert_eq!(pipeline.search("mods/test/audio/a.ogg").unwrap().unwrap().key(), "mods/test/audio/a.ogg");
+
+    // Mounted at the root the folder overrides the base
+    let mut db = AssetDatabase::new();
+    db.push_entry(AssetEntry::from_audio("audio/a.ogg", vec![1])).unwrap();
+    pipeline.mount("", MountSource::Memory("base".to_string(), Box::new(db)), DatabasePriority::Base).unwrap();
+    pipeline.mount("", MountSource::Directory(root.clone()), DatabasePriority::Mod).unwrap();
+    assert_eq!(data(&pipeline, "audio/a.ogg"), Some(10));
+    assert_eq!(data(&pipeline, "mods/test/audio/a.ogg"), None);
+
+    assert!(pipeline.unmount(root.display().to_string()));
+    assert!(!pipeline.unmount("missing"));
+    assert_eq!(data(&pipeline, "audio/a.ogg"), Some(1));
+
+    std::fs::remove_dir_all(&root).unwrap();
 }
@@ -0,0 +1,319 @@
+// Everything AssetPipeline can mount side by side, see AssetPipeline::mount().
+//
+// Archives only read their entry table when they're mounted, folders only look for files.
+// Either way an entry isn't read (or imported) before it's loaded.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::slice::Iter;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{particle, video, AssetDatabase, AssetDatabaseError, AssetEntry, AssetEntryType, AssetPipeline, Codec, CompileOptions};
+
+// Every file compile_folder imports, except for the ones only pulled in by others (E.G .glsl includes)
+const ASSET_PATTERN: &str = "**/*.{png,jpg,bmp,gif,ogg,mp3,wav,flac,ttf,otf,ftl,lang,particle,video,vert,frag}";
+
+// Mods are zips from anywhere, a tiny one could unpack to fill the disk
+const MAX_UNPACKED_SIZE: u64 = 0x40000000; // 1 GB
+
+static NEXT_UNPACKED: AtomicUsize = AtomicUsize::new(0);
+
+pub enum MountSource {
+    Archive(PathBuf),                  // A .pxl archive
+    Directory(PathBuf),                // Loose files, imported the way compile_folder does (without an atlas)
+    Zip(PathBuf),                      // Loose files in a zip
+    Memory(String, Box<AssetDatabase>) // A database that's in memory already under a name, E.G for tests
+}
+
+// What a mount reads its entries from
+pub(crate) enum Mounted {
+    Database(Box<AssetDatabase>),
+    Folder(FolderMount)
+}
+
+impl Mounted {
+    pub fn database(&self) -> Option<&AssetDatabase> {
+        match self {
+            Mounted::Database(db) => Some(db),
+            Mounted::Folder(_) => None
+        }
+    }
+
+    pub fn is_patch(&self) -> bool {
+        matches!(self.database(), Some(db) if db.is_patch())
+    }
+
+    pub fn iter(&self) -> Iter<'_, Arc<AssetEntry>> {
+        match self {
+            Mounted::Database(db) => db.iter(),
+            Mounted::Folder(folder) => folder.entries.iter()
+        }
+    }
+
+    pub fn entry(&self, key: &str) -> Option<&Arc<AssetEntry>> {
+        match self {
+            Mounted::Database(db) => db.entry(key),
+            Mounted::Folder(folder) => folder.index.get(key).map(|i| &folder.entries[*i])
+        }
+    }
+
+    pub fn load_entry(&self, entry: &Arc<AssetEntry>) -> Result<Arc<AssetEntry>, AssetDatabaseError> {
+        match self {
+            Mounted::Database(db) => db.load_entry(entry),
+            Mounted::Folder(folder) => folder.load_entry(entry)
+        }
+    }
+
+    // Folders never keep anything around
+    pub fn unload(&self) -> bool {
+        match self {
+            Mounted::Database(db) => db.unload(),
+            Mounted::Folder(_) => false
+        }
+    }
+}
+
+// A folder of loose files. The location of an entry is the index of its file and the file size.
+pub(crate) struct FolderMount {
+    root: PathBuf,
+    entries: Vec<Arc<AssetEntry>>,
+    index: HashMap<String, usize>, // key -> entries
+    paths: Vec<PathBuf>,           // Same order as entries
+    options: CompileOptions,       // The pipeline's at the time it was mounted
+
+    unpacked: bool // root is a temporary folder a zip was unpacked to, removed once dropped
+}
+
+impl FolderMount {
+    pub fn open(root: &Path, options: &CompileOptions) -> Result<FolderMount, AssetDatabaseError> {
+        let root = root.canonicalize()?;
+
+        let mut keys = HashMap::new();
+        let sources = AssetPipeline::collect_sources(&root, ASSET_PATTERN, &mut keys)?;
+
+        let mut folder = FolderMount {
+            root,
+            entries: Vec::new(),
+            index: HashMap::new(),
+            paths: Vec::new(),
+            options: options.clone(),
+
+            unpacked: false
+        };
+
+        for source in sources {
+            let extension = source.path.extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| ext.to_lowercase())
+                .unwrap_or_default();
+
+            let entry_type = match extension.as_str() {
+                "png" | "jpg" | "bmp" | "gif" if AssetPipeline::is_animation(&source.key, &source.path)? => AssetEntryType::AnimatedTexture,
+                "png" | "jpg" | "bmp" | "gif" => AssetEntryType::Texture,
+                "ogg" | "mp3" | "wav" | "flac" => AssetEntryType::Audio,
+                "ttf" | "otf" => AssetEntryType::Font,
+                "ftl" | "lang" => AssetEntryType::StringTable,
+                "vert" | "frag" => AssetEntryType::Shader,
+                particle::PARTICLE_EXTENSION => AssetEntryType::Particle,
+                video::VIDEO_EXTENSION => AssetEntryType::Video,
+
+                _ => continue
+            };
+
+            let size = std::fs::metadata(&source.path)?.len();
+
+            folder.index.insert(source.key.clone(), folder.entries.len());
+            folder.entries.push(Arc::new(AssetEntry {
+                entry_type,
+                entry_key: source.key,
+                codec: Codec::None, // Loose files aren't compressed
+                metadata: source.settings.metadata(),
+                references: Vec::new(), // Only known once the file is imported
+                includes: Vec::new(),
+                data: Vec::new(),
+                compressed_data: Vec::new(),
+                location: Some((folder.paths.len() as u64, std::cmp::min(size, u32::MAX as u64) as u32)),
+                checksum: None
+            }));
+            folder.paths.push(source.path);
+        }
+
+        log::info!("Found {} assets in {}", folder.entries.len(), folder.root.display());
+
+        Ok(folder)
+    }
+
+    // The importers work on files (shaders include others, textures can have an .anim file),
+    // so the zip is unpacked into a temporary folder that lives as long as the mount.
+    pub fn open_zip(path: &Path, options: &CompileOptions) -> Result<FolderMount, AssetDatabaseError> {
+        let mut zip = zip::ZipArchive::new(File::open(path)?).map_err(zip_error)?;
+
+        let root = std::env::temp_dir().join(format!("pxl-mount-{}-{}", std::process::id(),
+                                                     NEXT_UNPACKED.fetch_add(1, Ordering::Relaxed)));
+        std::fs::create_dir_all(&root)?;
+
+        let folder = unpack_zip(&mut zip, path, &root, crate::MAX_SIZE as u64, MAX_UNPACKED_SIZE)
+            .and_then(|_| FolderMount::open(&root, options));
+        match folder {
+            Ok(mut folder) => {
+                folder.unpacked = true;
+
+                Ok(folder)
+            }
+
+            Err(err) => {
+                std::fs::remove_dir_all(&root).ok();
+
+                Err(err)
+            }
+        }
+    }
+
+    // Imports the file the entry was found in, the entry keeps the key it's mounted under
+    fn load_entry(&self, entry: &Arc<AssetEntry>) -> Result<Arc<AssetEntry>, AssetDatabaseError> {
+        let path = match entry.location {
+            Some((i, _)) => &self.paths[i as usize],
+            None => return Ok(entry.clone())
+        };
+
+        let mut loaded = AssetPipeline::import_file(&self.root, path, &self.options)?
+            .ok_or_else(|| AssetDatabaseError::DecodeFailed { key: entry.key() })?;
+        loaded.entry_key = entry.entry_key.clone();
+
+        log::debug!("Imported {}<{:#?}> from {}", loaded.entry_key, loaded.entry_type, path.display());
+
+        Ok(Arc::new(loaded))
+    }
+}
+
+impl Drop for FolderMount {
+    fn drop(&mut self) {
+        if self.unpacked {
+            if let Err(err) = std::fs::remove_dir_all(&self.root) {
+                log::warn!("Failed to remove {}: {}", self.root.display(), err);
+            }
+        }
+    }
+}
+
+fn zip_error(err: zip::result::ZipError) -> AssetDatabaseError {
+    match err {
+        zip::result::ZipError::Io(err) => AssetDatabaseError::Io(err),
+
+        err => AssetDatabaseError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string()))
+    }
+}
+
+// No file may be bigger than an entry can be, all of them together no bigger than max_total_size.
+// The sizes in the zip could be lies, so it's what is actually unpacked that counts.
+fn unpack_zip(zip: &mut zip::ZipArchive<File>, path: &Path, root: &Path, max_file_size: u64, max_total_size: u64) -> Result<(), AssetDatabaseError> {
+    let mut unpacked = 0;
+
+    for i in 0..zip.len() {
+        let mut file = zip.by_index(i).map_err(zip_error)?;
+
+        // Names like ../../foo would escape the folder
+        let name = match file.enclosed_name() {
+            Some(name) if !file.is_dir() => name.to_path_buf(),
+            Some(_) => continue,
+            None => {
+                log::warn!("Skipping {} in {}, it points outside of the zip", file.name(), path.display());
+                continue;
+            }
+        };
+
+        let too_large = |size: u64| AssetDatabaseError::TooLarge { key: name.display().to_string(), size: size as usize };
+        if file.size() > max_file_size {
+            return Err(too_large(file.size()));
+        }
+
+        let out = root.join(&name);
+        if let Some(parent) = out.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let size = std::io::copy(&mut (&mut file).take(max_file_size + 1), &mut File::create(out)?)?;
+        if size > max_file_size {
+            return Err(too_large(size));
+        }
+
+        unpacked += size;
+        if unpacked > max_total_size {
+            return Err(AssetDatabaseError::Io(std::io::Error::new(std::io::ErrorKind::InvalidData,
+                format!("{} unpacks to more than {}", path.display(), bytesize::to_string(max_total_size, false)))));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn folder_mount_test() {
+    use std::io::Write;
+
+    let root = std::env::temp_dir().join(format!("pxl-folder-mount-test-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("audio")).unwrap();
+    std::fs::write(root.join("audio/test.ogg"), vec![1, 2, 3, 4]).unwrap();
+    std::fs::write(root.join("audio/test.ogg.meta"), "key = \"audio/alias\"\ntags = [ \"music\" ]\n").unwrap();
+    std::fs::write(root.join("notes.txt"), "not an asset").unwrap();
+
+    let folder = Mounted::Folder(FolderMount::open(&root, &CompileOptions::default()).unwrap());
+    assert_eq!(folder.iter().len(), 1);
+
+    let entry = folder.entry("audio/alias").unwrap();
+    assert!(!entry.is_loaded());
+    assert!(entry.metadata().has_tag("music"));
+    assert_eq!(folder.load_entry(entry).unwrap().audio_file(), &[1, 2, 3, 4]);
+
+    // Zips are unpacked somewhere else and removed again once they're unmounted
+    let zip_path = root.join("mod.zip");
+    let mut zip = zip::ZipWriter::new(File::create(&zip_path).unwrap());
+    zip.start_file("audio/zipped.ogg", zip::write::FileOptions::default()).unwrap();
+    zip.write_all(&[5, 6, 7]).unwrap();
+    zip.finish().unwrap();
+
+    // Files are imported with the options the mount was opened with
+    let mut options = CompileOptions::default();
+    options.codecs.insert(AssetEntryType::Audio, Codec::Gzip);
+
+    let zipped = FolderMount::open_zip(&zip_path, &options).unwrap();
+    let unpacked_root = zipped.root.clone();
+
+    let zipped = Mounted::Folder(zipped);
+    let entry = zipped.entry("audio/zipped.ogg").unwrap();
+    let loaded = zipped.load_entry(entry).unwrap();
+    assert_eq!(loaded.audio_file(), &[5, 6, 7]);
+    assert_eq!(loaded.codec(), Codec::Gzip);
+
+    drop(zipped);
+    assert!(!unpacked_root.exists());
+
+    // Neither a single file nor all of them together may unpack to more than the limits
+    let bomb_path = root.join("bomb.zip");
+    let mut zip = zip::ZipWriter::new(File::create(&bomb_path).unwrap());
+    for name in &["audio/a.ogg", "audio/b.ogg"] {
+        zip.start_file(*name, zip::write::FileOptions::default()).unwrap();
+        zip.write_all(&[0; 64]).unwrap();
+    }
+    zip.finish().unwrap();
+
+    let unpack = |max_file_size, max_total_size| {
+        let out = root.join("bomb");
+        std::fs::create_dir_all(&out).unwrap();
+
+        let mut zip = zip::ZipArchive::new(File::open(&bomb_path).unwrap()).unwrap();
+        let result = unpack_zip(&mut zip, &bomb_path, &out, max_file_size, max_total_size);
+        std::fs::remove_dir_all(&out).unwrap();
+
+        result
+    };
+
+    assert!(unpack(64, 128).is_ok());
+    assert!(matches!(unpack(63, 128), Err(AssetDatabaseError::TooLarge { .. })));
+    assert!(matches!(unpack(64, 127), Err(AssetDatabaseError::Io(_))));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,166 @@
+// Debug builds only: the raw assets folder mounted on top of the compiled archives.
+// Changed files are re-imported on the fly, so art changes don't need a rebuild.
+// Editing a .meta file re-imports the asset it belongs to.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::{video, AssetDatabaseError, AssetEntry, AssetPipeline, CompileOptions, META_EXTENSION};
+
+// Editors tend to write a file in multiple steps
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(200);
+
+pub(crate) struct AssetOverlay {
+    root: PathBuf,
+    entries: HashMap<String, Arc<AssetEntry>>,
+    keys: HashMap<PathBuf, String>, // A .meta file can give an asset a key that isn't its path
+    options: CompileOptions,
+
+    _watcher: RecommendedWatcher, // Stops watching once dropped
+    events: Receiver<DebouncedEvent>
+}
+
+impl AssetOverlay {
+    pub fn new(root: &Path, options: &CompileOptions) -> Result<AssetOverlay, AssetDatabaseError> {
+        // The watcher reports absolute paths, keys are relative to root
+        let root = root.canonicalize()?;
+
+        let (tx, events) = channel();
+        let mut watcher = notify::watcher(tx, DEBOUNCE_DELAY).map_err(AssetOverlay::watch_error)?;
+        watcher.watch(&root, RecursiveMode::Recursive).map_err(AssetOverlay::watch_error)?;
+
+        Ok(AssetOverlay {
+            root,
+            entries: HashMap::new(),
+            keys: HashMap::new(),
+            options: options.clone(),
+
+            _watcher: watcher,
+            events
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn get(&self, key: &str) -> Option<Arc<AssetEntry>> {
+        self.entries.get(key).cloned()
+    }
+
+    // Re-imports everything that changed since the last poll, returns the changed keys
+    pub fn poll(&mut self) -> Vec<String> {
+        let mut changed = Vec::new();
+
+        while let Ok(event) = self.events.try_recv() {
+            let results = match event {
+                DebouncedEvent::Create(path)
+                | DebouncedEvent::Write(path) => vec![self.import(&AssetOverlay::asset_of(path))],
+
+                // The old key would keep shadowing the archive with a file that's gone
+                DebouncedEvent::Rename(old, new) => vec![self.removed(old), self.import(&AssetOverlay::asset_of(new))],
+
+                DebouncedEvent::Remove(path) => vec![self.removed(path)],
+
+                DebouncedEvent::Error(err, path) => {
+                    log::warn!("Asset watcher error {:?}: {}", path, err);
+                    continue;
+                }
+
+                _ => continue
+            };
+
+            for result in results {
+                match result {
+                    Ok(keys) => {
+                        for key in keys {
+                            if !changed.contains(&key) {
+                                changed.push(key);
+                            }
+                        }
+                    }
+
+                    Err(err) => log::error!("Failed to reload asset: {}", err)
+                }
+            }
+        }
+
+        changed
+    }
+
+    // Returns every key that changed, the old key as well if the .meta file renamed the asset
+    fn import(&mut self, path: &Path) -> Result<Vec<String>, AssetDatabaseError> {
+        let extension = match path.extension().and_then(|ext| ext.to_str()) {
+            Some(extension) => extension.to_lowercase(),
+            None => return Ok(Vec::new())
+        };
+
+        // Only textures, audio and string tables are reloaded from here
+        if !matches!(extension.as_str(), "png" | "jpg" | "bmp" | "gif" | "ogg" | "mp3" | "wav" | "flac" | "ftl" | "lang") || !path.is_file()
+            || video::is_frame_source(&self.root, path) {
+            return Ok(Vec::new());
+        }
+
+        let entry = match AssetPipeline::import_file(&self.root, path, &self.options)? {
+            Some(entry) => entry,
+            None => return Ok(Vec::new())
+        };
+
+        let mut changed = self.remove(path);
+
+        let key = entry.key();
+        log::info!("Reloaded {}", key);
+
+        self.entries.insert(key.clone(), Arc::new(entry));
+        self.keys.insert(path.to_path_buf(), key.clone());
+
+        if !changed.contains(&key) {
+            changed.push(key);
+        }
+
+        Ok(changed)
+    }
+
+    // The archived version is visible again, unless only the .meta file is gone
+    fn removed(&mut self, path: PathBuf) -> Result<Vec<String>, AssetDatabaseError> {
+        if path.extension().and_then(|ext| ext.to_str()) == Some(META_EXTENSION) {
+            self.import(&AssetOverlay::asset_of(path))
+        } else {
+            Ok(self.remove(&path))
+        }
+    }
+
+    fn remove(&mut self, path: &Path) -> Vec<String> {
+        match self.keys.remove(path) {
+            Some(key) => {
+                self.entries.remove(&key);
+
+                vec![key]
+            }
+
+            None => Vec::new()
+        }
+    }
+
+    // textures/grass.png.meta -> textures/grass.png
+    fn asset_of(path: PathBuf) -> PathBuf {
+        if path.extension().and_then(|ext| ext.to_str()) == Some(META_EXTENSION) {
+            path.with_extension("")
+        } else {
+            path
+        }
+    }
+
+    fn watch_error(err: notify::Error) -> AssetDatabaseError {
+        match err {
+            notify::Error::Io(err) => AssetDatabaseError::Io(err),
+
+            err => AssetDatabaseError::Io(std::io::Error::new(std::io::ErrorKind::Other, err.to_string()))
+        }
+    }
+}
@@ -0,0 +1,368 @@
+// Particle effects are described in .particle files (TOML) and compiled into Particle entries,
+// graphics_engine::ParticleEmitter simulates and draws them.
+//
+// Example: effects/sparks.particle
+//   texture = "textures/particles/spark.png" # Texture or sprite key
+//   max_particles = 256        # At most 65536
+//   spawn_rate = 40.0          # Particles per second
+//   burst = 10                 # Particles spawned right away
+//   lifetime = [ 0.5, 1.0 ]    # Seconds, min and max
+//   direction = 90.0           # Degrees, 0 = right, 90 = up
+//   spread = 30.0              # Degrees around direction
+//   speed = [ 20.0, 40.0 ]     # Units per second, min and max
+//   gravity = [ 0.0, -98.0 ]
+//   shape = { type = "circle", radius = 8.0 } # point, circle or rect (width, height)
+//
+//   # Curves are sampled over the lifetime of a particle (0.0 - 1.0)
+//   speed_curve = [ { time = 0.0, value = 1.0 }, { time = 1.0, value = 0.2 } ]
+//   colour = [ { time = 0.0, value = [ 1.0, 1.0, 1.0, 1.0 ] }, { time = 1.0, value = [ 1.0, 0.3, 0.0, 0.0 ] } ]
+//   size = [ { time = 0.0, value = 4.0 }, { time = 1.0, value = 0.0 } ]
+
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use serde::Deserialize;
+
+use crate::shader::read_string;
+use crate::AssetDatabaseError;
+
+pub const PARTICLE_EXTENSION: &str = "particle";
+
+const MAX_CURVE_KEYS: u32 = 0x100;
+const MAX_PARTICLES: u32 = 0x10000; // Emitters allocate all of them up front
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EmitterShape {
+    Point,
+    Circle { radius: f32 },
+    Rect { width: f32, height: f32 }
+}
+
+impl Default for EmitterShape {
+    fn default() -> Self {
+        EmitterShape::Point
+    }
+}
+
+// Values that can be interpolated between two curve keys
+pub trait Lerp: Copy {
+    fn lerp(self, other: Self, t: f32) -> Self;
+}
+
+impl Lerp for f32 {
+    fn lerp(self, other: f32, t: f32) -> f32 {
+        self + (other - self) * t
+    }
+}
+
+impl Lerp for [f32; 4] {
+    fn lerp(self, other: [f32; 4], t: f32) -> [f32; 4] {
+        [
+            self[0].lerp(other[0], t),
+            self[1].lerp(other[1], t),
+            self[2].lerp(other[2], t),
+            self[3].lerp(other[3], t)
+        ]
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+pub struct CurveKey<T> {
+    pub time: f32,
+    pub value: T
+}
+
+// Piecewise linear curve, keys are sorted by time
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct Curve<T> {
+    pub keys: Vec<CurveKey<T>>
+}
+
+impl<T: Lerp> Curve<T> {
+    pub fn constant(value: T) -> Curve<T> {
+        Curve {
+            keys: vec![CurveKey { time: 0.0, value }]
+        }
+    }
+
+    pub fn sample(&self, time: f32) -> T {
+        let first = self.keys[0];
+        if time <= first.time {
+            return first.value;
+        }
+
+        for pair in self.keys.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+
+            if time <= b.time {
+                let t = (time - a.time) / (b.time - a.time);
+
+                return a.value.lerp(b.value, t);
+            }
+        }
+
+        self.keys[self.keys.len() - 1].value
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParticleEffect {
+    pub texture: String,
+    #[serde(default = "ParticleEffect::default_max_particles")]
+    pub max_particles: u32,
+    #[serde(default)]
+    pub spawn_rate: f32,
+    #[serde(default)]
+    pub burst: u32,
+    pub lifetime: [f32; 2],
+
+    #[serde(default)]
+    pub shape: EmitterShape,
+    #[serde(default)]
+    pub direction: f32,
+    #[serde(default)]
+    pub spread: f32,
+    #[serde(default)]
+    pub speed: [f32; 2],
+    #[serde(default)]
+    pub gravity: [f32; 2],
+
+    #[serde(default = "ParticleEffect::default_speed_curve")]
+    pub speed_curve: Curve<f32>,
+    #[serde(default = "ParticleEffect::default_colour")]
+    pub colour: Curve<[f32; 4]>,
+    #[serde(default = "ParticleEffect::default_size")]
+    pub size: Curve<f32>
+}
+
+impl ParticleEffect {
+    fn default_max_particles() -> u32 { 256 }
+    fn default_speed_curve() -> Curve<f32> { Curve::constant(1.0) }
+    fn default_colour() -> Curve<[f32; 4]> { Curve::constant([1.0; 4]) }
+    fn default_size() -> Curve<f32> { Curve::constant(8.0) }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<ParticleEffect, AssetDatabaseError> {
+        let path = path.as_ref();
+        let invalid = |message: String| AssetDatabaseError::ParseFailed { path: path.to_path_buf(), message };
+
+        let content = std::fs::read_to_string(path)?;
+        let effect: ParticleEffect = toml::from_str(&content)
+            .map_err(|err| invalid(err.to_string()))?;
+
+        effect.validate().map_err(|message| invalid(message.to_string()))?;
+
+        Ok(effect)
+    }
+
+    fn validate(&self) -> Result<(), &'static str> {
+        if self.max_particles == 0 || self.max_particles > MAX_PARTICLES {
+            return Err("max_particles must be between 1 and 65536");
+        }
+
+        // Written so NaN fails as well
+        if !(self.spawn_rate >= 0.0 && self.spawn_rate.is_finite()) {
+            return Err("spawn_rate must be a positive number");
+        }
+
+        if !(self.lifetime[0] > 0.0 && self.lifetime[0] <= self.lifetime[1] && self.lifetime[1].is_finite()) {
+            return Err("lifetime must be positive and min <= max");
+        }
+
+        if self.speed[0] > self.speed[1] {
+            return Err("speed must be min <= max");
+        }
+
+        if !is_sorted(&self.speed_curve) || !is_sorted(&self.colour) || !is_sorted(&self.size) {
+            return Err("curves need at least one key and must be sorted by time");
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn write_to(&self, w: &mut Vec<u8>) -> io::Result<()> {
+        w.write_u32::<LittleEndian>(self.texture.len() as u32)?;
+        w.write_all(self.texture.as_bytes())?;
+
+        w.write_u32::<LittleEndian>(self.max_particles)?;
+        w.write_f32::<LittleEndian>(self.spawn_rate)?;
+        w.write_u32::<LittleEndian>(self.burst)?;
+        write_f32s(w, &self.lifetime)?;
+
+        match self.shape {
+            EmitterShape::Point => w.write_u8(0)?,
+            EmitterShape::Circle { radius } => {
+                w.write_u8(1)?;
+                w.write_f32::<LittleEndian>(radius)?;
+            }
+            EmitterShape::Rect { width, height } => {
+                w.write_u8(2)?;
+                write_f32s(w, &[width, height])?;
+            }
+        }
+
+        w.write_f32::<LittleEndian>(self.direction)?;
+        w.write_f32::<LittleEndian>(self.spread)?;
+        write_f32s(w, &self.speed)?;
+        write_f32s(w, &self.gravity)?;
+
+        write_curve(w, &self.speed_curve, |w, v| w.write_f32::<LittleEndian>(*v))?;
+        write_curve(w, &self.colour, |w, v| write_f32s(w, v))?;
+        write_curve(w, &self.size, |w, v| w.write_f32::<LittleEndian>(*v))?;
+
+        Ok(())
+    }
+
+    pub(crate) fn read_from<R: Read>(r: &mut R) -> io::Result<ParticleEffect> {
+        let texture = read_string(r, crate::asset_database::MAX_KEY_LEN)?;
+
+        let max_particles = r.read_u32::<LittleEndian>()?;
+        let spawn_rate = r.read_f32::<LittleEndian>()?;
+        let burst = r.read_u32::<LittleEndian>()?;
+        let mut lifetime = [0.0; 2];
+        r.read_f32_into::<LittleEndian>(&mut lifetime)?;
+
+        let shape = match r.read_u8()? {
+            1 => EmitterShape::Circle { radius: r.read_f32::<LittleEndian>()? },
+            2 => EmitterShape::Rect {
+                width: r.read_f32::<LittleEndian>()?,
+                height: r.read_f32::<LittleEndian>()?
+            },
+
+            0 => EmitterShape::Point,
+
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown emitter shape"))
+        };
+
+        let direction = r.read_f32::<LittleEndian>()?;
+        let spread = r.read_f32::<LittleEndian>()?;
+        let mut speed = [0.0; 2];
+        r.read_f32_into::<LittleEndian>(&mut speed)?;
+        let mut gravity = [0.0; 2];
+        r.read_f32_into::<LittleEndian>(&mut gravity)?;
+
+        let speed_curve = read_curve(r, |r| r.read_f32::<LittleEndian>())?;
+        let colour = read_curve(r, |r| {
+            let mut colour = [0.0; 4];
+            r.read_f32_into::<LittleEndian>(&mut colour)?;
+
+            Ok(colour)
+        })?;
+        let size = read_curve(r, |r| r.read_f32::<LittleEndian>())?;
+
+        let effect = ParticleEffect {
+            texture,
+            max_particles,
+            spawn_rate,
+            burst,
+            lifetime,
+            shape,
+            direction,
+            spread,
+            speed,
+            gravity,
+            speed_curve,
+            colour,
+            size
+        };
+
+        effect.validate()
+            .map_err(|message| io::Error::new(io::ErrorKind::InvalidData, message))?;
+
+        Ok(effect)
+    }
+}
+
+fn is_sorted<T>(curve: &Curve<T>) -> bool {
+    !curve.keys.is_empty() && curve.keys.windows(2).all(|pair| pair[0].time <= pair[1].time)
+}
+
+fn write_f32s(w: &mut Vec<u8>, values: &[f32]) -> io::Result<()> {
+    for v in values {
+        w.write_f32::<LittleEndian>(*v)?;
+    }
+
+    Ok(())
+}
+
+fn write_curve<T, F>(w: &mut Vec<u8>, curve: &Curve<T>, write_value: F) -> io::Result<()>
+    where F: Fn(&mut Vec<u8>, &T) -> io::Result<()>
+{
+    w.write_u32::<LittleEndian>(curve.keys.len() as u32)?;
+
+    for key in &curve.keys {
+        w.write_f32::<LittleEndian>(key.time)?;
+        write_value(w, &key.value)?;
+    }
+
+    Ok(())
+}
+
+fn read_curve<T, R: Read, F>(r: &mut R, read_value: F) -> io::Result<Curve<T>>
+    where F: Fn(&mut R) -> io::Result<T>
+{
+    let len = r.read_u32::<LittleEndian>()?;
+    if len > MAX_CURVE_KEYS {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "too many curve keys"));
+    }
+
+    let mut keys = Vec::with_capacity(len as usize);
+    for _ in 0..len {
+        let time = r.read_f32::<LittleEndian>()?;
+        let value = read_value(r)?;
+
+        keys.push(CurveKey { time, value });
+    }
+
+    Ok(Curve { keys })
+}
+
+#[test]
+fn particle_effect_test() {
+    let effect: ParticleEffect = toml::from_str(r#"
+        texture = "textures/particles/spark.png"
+        spawn_rate = 40.0
+        lifetime = [ 0.5, 1.0 ]
+        speed = [ 20.0, 40.0 ]
+        shape = { type = "rect", width = 16.0, height = 4.0 }
+        size = [ { time = 0.0, value = 4.0 }, { time = 0.5, value = 8.0 }, { time = 1.0, value = 0.0 } ]
+    "#).unwrap();
+
+    assert!(effect.validate().is_ok());
+    assert_eq!(effect.max_particles, 256);
+    assert_eq!(effect.shape, EmitterShape::Rect { width: 16.0, height: 4.0 });
+
+    assert_eq!(effect.size.sample(-1.0), 4.0);
+    assert_eq!(effect.size.sample(0.25), 6.0);
+    assert_eq!(effect.size.sample(0.75), 4.0);
+    assert_eq!(effect.size.sample(2.0), 0.0);
+    assert_eq!(effect.colour.sample(0.5), [1.0; 4]);
+
+    let mut data = Vec::new();
+    effect.write_to(&mut data).unwrap();
+
+    assert_eq!(ParticleEffect::read_from(&mut io::Cursor::new(&data)).unwrap(), effect);
+
+    assert!(ParticleEffect { spawn_rate: f32::INFINITY, ..effect.clone() }.validate().is_err());
+    assert!(ParticleEffect { spawn_rate: -1.0, ..effect.clone() }.validate().is_err());
+    assert!(ParticleEffect { lifetime: [0.5, f32::INFINITY], ..effect.clone() }.validate().is_err());
+    assert!(ParticleEffect { lifetime: [f32::NAN, 1.0], ..effect.clone() }.validate().is_err());
+
+    // Broken archives are rejected instead of read as something else
+    let shape = 4 + effect.texture.len() + 4 * 4 + 4;
+    let mut broken = data.clone();
+    broken[shape] = 7;
+    assert_eq!(ParticleEffect::read_from(&mut io::Cursor::new(broken)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    let mut broken = data.clone();
+    broken[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(ParticleEffect::read_from(&mut io::Cursor::new(broken)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+    let mut broken = data;
+    let max_particles = 4 + effect.texture.len();
+    broken[max_particles..max_particles + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert_eq!(ParticleEffect::read_from(&mut io::Cursor::new(broken)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
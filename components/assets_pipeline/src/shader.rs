@@ -0,0 +1,232 @@
+// GLSL sources (.vert / .frag) with their #include directives resolved at build time.
+//
+//   #include "lighting.glsl"   -- relative to the including file
+//   #include <common/math.glsl> -- relative to the asset root
+//
+// Every file is only included once, #line directives keep compiler errors pointing
+// at the right file. The source number of a #line is the index into ShaderSource::files.
+// .glsl files are only ever included and are not stored on their own.
+
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{AssetDatabaseError, AssetPipeline};
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ShaderStage {
+    Vertex = 0,
+    Fragment = 1
+}
+
+impl ShaderStage {
+    pub fn from_extension(extension: &str) -> Option<ShaderStage> {
+        match extension {
+            "vert" => Some(ShaderStage::Vertex),
+            "frag" => Some(ShaderStage::Fragment),
+
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShaderSource {
+    pub stage: ShaderStage,
+    pub source: String,
+    pub files: Vec<String> // Keys of every file that ended up in source, the shader itself comes first
+}
+
+impl ShaderSource {
+    pub fn from_file<R: AsRef<Path>, P: AsRef<Path>>(root: R, path: P) -> Result<ShaderSource, AssetDatabaseError> {
+        let root = root.as_ref();
+        let path = path.as_ref();
+
+        let stage = path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(ShaderStage::from_extension)
+            .ok_or_else(|| AssetDatabaseError::ParseFailed {
+                path: path.to_path_buf(),
+                message: "not a .vert or .frag file".to_string()
+            })?;
+
+        let mut shader = ShaderSource {
+            stage,
+            source: String::new(),
+            files: Vec::new()
+        };
+
+        let mut stack = Vec::new();
+        shader.include(root, path, &mut stack)?;
+
+        Ok(shader)
+    }
+
+    fn include(&mut self, root: &Path, path: &Path, stack: &mut Vec<PathBuf>) -> Result<(), AssetDatabaseError> {
+        let key = AssetPipeline::key_from_path(root, path)?;
+        let parse_failed = |message: String| AssetDatabaseError::ParseFailed { path: path.to_path_buf(), message };
+
+        if stack.iter().any(|parent| parent == path) {
+            return Err(parse_failed("recursive #include".to_string()));
+        }
+
+        if self.files.contains(&key) {
+            return Ok(()); // Already included
+        }
+
+        let content = std::fs::read_to_string(path)?;
+
+        let file_index = self.files.len();
+        self.files.push(key);
+        stack.push(path.to_path_buf());
+
+        // #line can't come before #version, so the shader itself doesn't get one
+        if file_index != 0 {
+            self.source.push_str(&format!("#line 1 {}\n", file_index));
+        }
+
+        for (line_number, line) in content.lines().enumerate() {
+            let include = match ShaderSource::parse_include(line) {
+                Some(include) => include,
+                None => {
+                    self.source.push_str(line);
+                    self.source.push('\n');
+                    continue;
+                }
+            };
+
+            let include_path = match include {
+                Ok((file, true)) => normalize(&root.join(file)),
+                Ok((file, false)) => normalize(&path.parent().unwrap_or(root).join(file)),
+
+                Err(message) => return Err(parse_failed(format!("line {}: {}", line_number + 1, message)))
+            };
+
+            if !include_path.is_file() {
+                return Err(parse_failed(format!("line {}: {} doesn't exist", line_number + 1, include_path.display())));
+            }
+
+            self.include(root, &include_path, stack)?;
+
+            // Continue where we left off
+            self.source.push_str(&format!("#line {} {}\n", line_number + 2, file_index));
+        }
+
+        stack.pop();
+
+        Ok(())
+    }
+
+    // Some((file, from_root)) if the line is an #include directive
+    fn parse_include(line: &str) -> Option<Result<(&str, bool), &'static str>> {
+        let line = line.trim();
+        if !line.starts_with("#include") {
+            return None;
+        }
+
+        let file = line["#include".len()..].trim();
+
+        let from_root = file.starts_with('<') && file.ends_with('>');
+        let quoted = file.starts_with('"') && file.ends_with('"');
+
+        if file.len() < 3 || !(from_root || quoted) {
+            return Some(Err("expected #include \"file\" or #include <file>"));
+        }
+
+        Some(Ok((&file[1..file.len() - 1], from_root)))
+    }
+
+    pub(crate) fn write_to(&self, w: &mut Vec<u8>) -> io::Result<()> {
+        w.write_u8(self.stage as u8)?;
+
+        w.write_u32::<LittleEndian>(self.source.len() as u32)?;
+        w.extend_from_slice(self.source.as_bytes());
+
+        w.write_u32::<LittleEndian>(self.files.len() as u32)?;
+        for file in &self.files {
+            w.write_u32::<LittleEndian>(file.len() as u32)?;
+            w.extend_from_slice(file.as_bytes());
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn read_from<R: Read>(r: &mut R) -> io::Result<ShaderSource> {
+        let stage = match r.read_u8()? {
+            0 => ShaderStage::Vertex,
+            1 => ShaderStage::Fragment,
+
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown shader stage"))
+        };
+
+        let source = read_string(r, crate::MAX_SIZE as u32)?;
+
+        let file_count = r.read_u32::<LittleEndian>()?;
+        let mut files = Vec::new();
+        for _ in 0..file_count {
+            files.push(read_string(r, crate::asset_database::MAX_KEY_LEN)?);
+        }
+
+        Ok(ShaderSource {
+            stage,
+            source,
+            files
+        })
+    }
+}
+
+// Resolves ../ without touching the file system
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            std::path::Component::ParentDir => { normalized.pop(); }
+            std::path::Component::CurDir => {},
+
+            component => normalized.push(component)
+        }
+    }
+
+    normalized
+}
+
+pub(crate) fn read_string<R: Read>(r: &mut R, max_len: u32) -> io::Result<String> {
+    let len = r.read_u32::<LittleEndian>()?;
+    if len > max_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "string too long"));
+    }
+
+    let mut buff = vec![0x00; len as usize];
+    r.read_exact(&mut buff)?;
+
+    String::from_utf8(buff).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+#[test]
+fn shader_include_test() {
+    let root = std::env::temp_dir().join(format!("pxl_shader_include_test_{}", std::process::id()));
+    std::fs::create_dir_all(root.join("shaders/common")).unwrap();
+
+    std::fs::write(root.join("shaders/common/math.glsl"), "float square(float x) { return x * x; }\n").unwrap();
+    std::fs::write(root.join("shaders/light.glsl"), "#include \"./common/../common/math.glsl\"\nfloat light() { return square(0.5); }\n").unwrap();
+    std::fs::write(root.join("shaders/sprite.frag"),
+                   "#version 330 core\n#include <shaders/common/math.glsl>\n#include \"light.glsl\"\nvoid main() {}\n").unwrap();
+    std::fs::write(root.join("shaders/loop.vert"), "#include \"loop.vert\"\n").unwrap();
+
+    let shader = ShaderSource::from_file(&root, root.join("shaders/sprite.frag")).unwrap();
+
+    assert_eq!(shader.stage, ShaderStage::Fragment);
+    assert_eq!(shader.files, vec!["shaders/sprite.frag", "shaders/common/math.glsl", "shaders/light.glsl"]);
+    assert_eq!(shader.source, "#version 330 core\n\
+        #line 1 1\nfloat square(float x) { return x * x; }\n#line 3 0\n\
+        #line 1 2\n#line 2 2\nfloat light() { return square(0.5); }\n#line 4 0\n\
+        void main() {}\n");
+
+    let recursive = ShaderSource::from_file(&root, root.join("shaders/loop.vert"));
+    assert!(matches!(recursive, Err(AssetDatabaseError::ParseFailed { .. })));
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,1172 @@
+// Localized strings. Every locale has a file of its own named after it, E.G strings/en.ftl,
+// strings/de.ftl and strings/pt-BR.lang, each is compiled into a StringTable entry.
+// A Localization looks strings up in a locale, less specific versions of it and a fallback locale.
+//
+// .ftl files use the parts of Fluent (https://projectfluent.org) we need:
+//   # Comment
+//   -brand = Project Pixel
+//   window-title = { -brand }
+//   greeting = Hello { $name }!
+//   items = { $count ->
+//       [0] No items
+//       [one] One item
+//      *[other] { $count } items
+//   }
+//
+// Terms (-brand) and messages referenced by other messages are inlined while building,
+// attributes aren't supported.
+//
+// .lang files are one key = value per line with the same placeables, plurals are keys with
+// the plural category (or an exact number) in brackets and select on $count:
+//   greeting = Hello { $name }!
+//   items[one] = One item
+//   items[other] = { $count } items
+//
+// String Table Entry Layout:
+//   Magic          "PXS1"
+//   Locale         String
+//   Messages       [Message] { Key String, Pattern }
+//
+//   Pattern        [Element] { Tag u8, ... }
+//     0 Text       String
+//     1 Argument   String
+//     2 Select     Argument String, Default u32, [Variant] { Tag u8 (0 Number f64, 1 Name String), Pattern }
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Cursor, Read, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{AssetDatabaseError, AssetEntry, AssetEntryType, AssetPipeline};
+
+pub const STRING_TABLE_EXTENSIONS: [&str; 2] = ["ftl", "lang"];
+
+const STRING_TABLE_MAGIC: &[u8; 4] = b"PXS1";
+
+const MAX_MESSAGES: u32 = 0x10000;
+const MAX_STRING_LEN: u32 = 0x10000;
+const MAX_DEPTH: usize = 8; // Nested placeables and references
+
+const PLURAL_CATEGORIES: [&str; 6] = ["zero", "one", "two", "few", "many", "other"];
+
+// Values for the placeables of a string, numbers pick plural variants
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FormatArg<'a> {
+    Str(&'a str),
+    Number(f64)
+}
+
+impl<'a> From<&'a str> for FormatArg<'a> {
+    fn from(s: &'a str) -> Self {
+        FormatArg::Str(s)
+    }
+}
+
+impl<'a> From<&'a String> for FormatArg<'a> {
+    fn from(s: &'a String) -> Self {
+        FormatArg::Str(s.as_str())
+    }
+}
+
+macro_rules! impl_number_arg {
+    ($($t:ty),*) => {
+        $(
+            impl<'a> From<$t> for FormatArg<'a> {
+                fn from(n: $t) -> Self {
+                    FormatArg::Number(n as f64)
+                }
+            }
+        )*
+    }
+}
+
+impl_number_arg!(i32, u32, i64, u64, usize, f32, f64);
+
+#[derive(Clone, Debug, PartialEq)]
+enum VariantKey {
+    Number(f64),
+    Name(String) // Plural category or the value of a string argument
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Element {
+    Text(String),
+    Argument(String),
+    Reference(String), // Message or -term, only until the whole file is parsed
+    Select { argument: String, variants: Vec<(VariantKey, Pattern)>, default: usize }
+}
+
+type Pattern = Vec<Element>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StringTable {
+    locale: String,
+    messages: BTreeMap<String, Pattern>
+}
+
+impl StringTable {
+    // The locale is the name of the file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<StringTable, AssetDatabaseError> {
+        let path = path.as_ref();
+        let invalid = |message: String| AssetDatabaseError::ParseFailed { path: path.to_path_buf(), message };
+
+        let locale = path.file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| invalid("the file name isn't a locale".to_string()))?;
+        let extension = path.extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase())
+            .unwrap_or_default();
+
+        let source = std::fs::read_to_string(path)?;
+        match extension.as_str() {
+            "lang" => StringTable::parse_lang(locale, &source),
+            _ => StringTable::parse_fluent(locale, &source)
+        }.map_err(invalid)
+    }
+
+    pub fn parse_fluent(locale: &str, source: &str) -> Result<StringTable, String> {
+        let lines = source.lines().collect::<Vec<_>>();
+        let mut raw = BTreeMap::new();
+
+        let mut i = 0;
+        while i < lines.len() {
+            let (number, line) = (i + 1, lines[i]);
+            i += 1;
+
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with(char::is_whitespace) {
+                return Err(format!("line {}: expected a message, found indented text", number));
+            }
+
+            let equals = line.find('=')
+                .ok_or_else(|| format!("line {}: expected key = value", number))?;
+            let key = line[..equals].trim();
+            check_key(key).map_err(|message| format!("line {}: {}", number, message))?;
+
+            // The value goes on over every indented line (and the } closing a select), blank lines
+            // at its end belong to nobody
+            let mut end = i;
+            while end < lines.len() && (lines[end].trim().is_empty() || lines[end].starts_with(char::is_whitespace) || lines[end].starts_with('}')) {
+                end += 1;
+            }
+            while end > i && lines[end - 1].trim().is_empty() {
+                end -= 1;
+            }
+
+            let value = dedent(&line[equals + 1..], &lines[i..end]);
+            i = end;
+
+            let pattern = Parser::new(&value).pattern(false)
+                .map_err(|message| format!("line {}: {}", number, message))?;
+            if pattern.is_empty() {
+                return Err(format!("line {}: {} has no value", number, key));
+            }
+
+            if raw.insert(key.to_string(), pattern).is_some() {
+                return Err(format!("line {}: {} is defined twice", number, key));
+            }
+        }
+
+        StringTable::resolve(locale, &raw)
+    }
+
+    pub fn parse_lang(locale: &str, source: &str) -> Result<StringTable, String> {
+        let mut raw = BTreeMap::new();
+        let mut plurals = BTreeMap::new();
+
+        for (i, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = |message: String| format!("line {}: {}", i + 1, message);
+
+            let equals = line.find('=')
+                .ok_or_else(|| invalid("expected key = value".to_string()))?;
+            let (key, value) = (line[..equals].trim(), line[equals + 1..].trim());
+
+            let pattern = Parser::new(&unescape(value)).pattern(false).map_err(invalid)?;
+
+            match key.find('[') {
+                Some(open) if key.ends_with(']') => {
+                    let (name, variant) = (&key[..open], &key[open + 1..key.len() - 1]);
+                    check_key(name).map_err(invalid)?;
+
+                    let variant = match variant.parse::<f64>() {
+                        Ok(n) => VariantKey::Number(n),
+                        Err(_) if PLURAL_CATEGORIES.contains(&variant) => VariantKey::Name(variant.to_string()),
+                        Err(_) => return Err(invalid(format!("{} isn't a plural category", variant)))
+                    };
+
+                    plurals.entry(name.to_string())
+                        .or_insert_with(Vec::new)
+                        .push((variant, pattern));
+                }
+
+                _ => {
+                    check_key(key).map_err(invalid)?;
+
+                    if raw.insert(key.to_string(), pattern).is_some() {
+                        return Err(invalid(format!("{} is defined twice", key)));
+                    }
+                }
+            }
+        }
+
+        for (name, variants) in plurals {
+            let default = variants.iter()
+                .position(|(key, _)| *key == VariantKey::Name("other".to_string()))
+                .ok_or_else(|| format!("{} needs an [other] value", name))?;
+
+            let select = Element::Select { argument: "count".to_string(), variants, default };
+            if raw.insert(name.clone(), vec![select]).is_some() {
+                return Err(format!("{} is defined twice", name));
+            }
+        }
+
+        StringTable::resolve(locale, &raw)
+    }
+
+    // Inlines every reference, terms aren't messages of their own.
+    // Whatever read_from() would turn down fails here already.
+    fn resolve(locale: &str, raw: &BTreeMap<String, Pattern>) -> Result<StringTable, String> {
+        check_len(locale)?;
+
+        let mut messages = BTreeMap::new();
+
+        for (key, pattern) in raw {
+            if !key.starts_with('-') {
+                let pattern = resolve_pattern(raw, pattern, 0)
+                    .and_then(|pattern| check_len(key).and(check_pattern(&pattern, 0)).map(|_| pattern))
+                    .map_err(|message| format!("{}: {}", key, message))?;
+
+                messages.insert(key.clone(), pattern);
+            }
+        }
+
+        if messages.len() > MAX_MESSAGES as usize {
+            return Err(format!("more than {} messages", MAX_MESSAGES));
+        }
+
+        Ok(StringTable {
+            locale: locale.to_string(),
+            messages
+        })
+    }
+
+    pub fn locale(&self) -> &str {
+        &self.locale
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.messages.contains_key(key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.messages.keys().map(|key| key.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    // None if the table doesn't have key, arguments that aren't given are written as {$name}
+    pub fn format(&self, key: &str, args: &[(&str, FormatArg)]) -> Option<String> {
+        let pattern = self.messages.get(key)?;
+
+        let mut s = String::new();
+        self.write_pattern(pattern, args, &mut s);
+
+        Some(s)
+    }
+
+    fn write_pattern(&self, pattern: &[Element], args: &[(&str, FormatArg)], s: &mut String) {
+        for element in pattern {
+            match element {
+                Element::Text(text) => s.push_str(text),
+
+                Element::Argument(name) => match find_arg(args, name) {
+                    Some(FormatArg::Str(value)) => s.push_str(value),
+                    Some(FormatArg::Number(n)) => s.push_str(&n.to_string()),
+                    None => {
+                        s.push_str("{$");
+                        s.push_str(name);
+                        s.push('}');
+                    }
+                },
+
+                Element::Select { argument, variants, default } => {
+                    let i = self.select(find_arg(args, argument), variants).unwrap_or(*default);
+
+                    self.write_pattern(&variants[i].1, args, s);
+                }
+
+                Element::Reference(_) => {} // Resolved while parsing
+            }
+        }
+    }
+
+    // Exact numbers win over plural categories
+    fn select(&self, arg: Option<FormatArg>, variants: &[(VariantKey, Pattern)]) -> Option<usize> {
+        let name = |name: &str| variants.iter()
+            .position(|(key, _)| matches!(key, VariantKey::Name(key) if key == name));
+
+        match arg? {
+            FormatArg::Number(n) => variants.iter()
+                .position(|(key, _)| *key == VariantKey::Number(n))
+                .or_else(|| name(plural_category(&self.locale, n))),
+
+            FormatArg::Str(value) => name(value)
+        }
+    }
+
+    pub(crate) fn write_to(&self, w: &mut Vec<u8>) -> io::Result<()> {
+        w.write_all(STRING_TABLE_MAGIC)?;
+        write_string(w, &self.locale)?;
+
+        w.write_u32::<LittleEndian>(self.messages.len() as u32)?;
+        for (key, pattern) in &self.messages {
+            write_string(w, key)?;
+            write_pattern(w, pattern)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn read_from(data: &[u8]) -> io::Result<StringTable> {
+        if !data.starts_with(STRING_TABLE_MAGIC) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a string table"));
+        }
+
+        let mut r = Cursor::new(data);
+        r.set_position(STRING_TABLE_MAGIC.len() as u64);
+
+        let locale = read_string(&mut r)?;
+
+        let count = r.read_u32::<LittleEndian>()?;
+        if count > MAX_MESSAGES {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "too many messages"));
+        }
+
+        let mut messages = BTreeMap::new();
+        for _ in 0..count {
+            let key = read_string(&mut r)?;
+            let pattern = read_pattern(&mut r, 0)?;
+
+            messages.insert(key, pattern);
+        }
+
+        Ok(StringTable {
+            locale,
+            messages
+        })
+    }
+}
+
+// The string tables of a locale, most specific first
+pub struct Localization {
+    tables: Vec<StringTable>
+}
+
+impl Localization {
+    pub fn new(tables: Vec<StringTable>) -> Localization {
+        Localization {
+            tables
+        }
+    }
+
+    // folder/<locale>.ftl or .lang for the locale, the locales it's a variant of and the fallback,
+    // E.G pt-BR with fallback en tries pt-BR, pt and en. Fails if none of them exist.
+    pub fn load<S: AsRef<str>>(pipeline: &AssetPipeline, folder: S, locale: &str, fallback: &str) -> Result<Localization, AssetDatabaseError> {
+        let folder = folder.as_ref().trim_end_matches('/');
+
+        let mut tables = Vec::new();
+        for locale in locale_chain(locale, fallback) {
+            for extension in &STRING_TABLE_EXTENSIONS {
+                let key = format!("{}/{}.{}", folder, locale, extension);
+
+                if let Some(entry) = pipeline.search(&key)? {
+                    if entry.r#type() != AssetEntryType::StringTable {
+                        return Err(AssetDatabaseError::DecodeFailed { key });
+                    }
+
+                    tables.push(AssetEntry::clone(&entry).into_string_table()?);
+                    break;
+                }
+            }
+        }
+
+        if tables.is_empty() {
+            return Err(AssetDatabaseError::NotFound { key: format!("{}/{}", folder, locale) });
+        }
+
+        Ok(Localization::new(tables))
+    }
+
+    // Of the most specific table, None without tables
+    pub fn locale(&self) -> Option<&str> {
+        self.tables.first().map(|table| table.locale())
+    }
+
+    pub fn tables(&self) -> &[StringTable] {
+        &self.tables
+    }
+
+    pub fn get(&self, key: &str) -> String {
+        self.format(key, &[])
+    }
+
+    // The key itself if no table has it, plurals follow the rules of the table it's found in
+    pub fn format(&self, key: &str, args: &[(&str, FormatArg)]) -> String {
+        self.tables.iter()
+            .find_map(|table| table.format(key, args))
+            .unwrap_or_else(|| key.to_string())
+    }
+}
+
+// Keys every table of a folder is missing compared to the other locales in it, by table key
+pub(crate) fn missing_keys(tables: &[(String, StringTable)]) -> BTreeMap<String, Vec<String>> {
+    let folder_of = |key: &str| key.rfind('/').map_or("", |i| &key[..i]).to_string();
+
+    let mut folders = BTreeMap::new();
+    for (key, table) in tables {
+        folders.entry(folder_of(key))
+            .or_insert_with(BTreeSet::new)
+            .extend(table.keys());
+    }
+
+    let mut missing = BTreeMap::new();
+    for (key, table) in tables {
+        let keys = folders[&folder_of(key)].iter()
+            .filter(|message| !table.contains(message))
+            .map(|message| message.to_string())
+            .collect::<Vec<_>>();
+
+        if !keys.is_empty() {
+            missing.insert(key.clone(), keys);
+        }
+    }
+
+    missing
+}
+
+// CLDR plural rules of the languages we're likely to ship, everything else gets the English ones
+fn plural_category(locale: &str, n: f64) -> &'static str {
+    let language = locale.split(&['-', '_'][..]).next().unwrap_or("").to_lowercase();
+    let integer = n.fract() == 0.0;
+    let i = n.abs().trunc() as u64;
+    let (i10, i100) = (i % 10, i % 100);
+
+    let few = (2..=4).contains(&i10) && !(12..=14).contains(&i100);
+
+    match language.as_str() {
+        "ja" | "zh" | "ko" | "th" | "vi" | "id" | "ms" => "other",
+
+        "fr" | "pt" if i == 0 || i == 1 => "one",
+        "fr" | "pt" => "other",
+
+        "ru" | "uk" | "be" if !integer => "other",
+        "ru" | "uk" | "be" if i10 == 1 && i100 != 11 => "one",
+        "ru" | "uk" | "be" if few => "few",
+        "ru" | "uk" | "be" => "many",
+
+        "pl" if !integer => "other",
+        "pl" if i == 1 => "one",
+        "pl" if few => "few",
+        "pl" => "many",
+
+        "cs" | "sk" if !integer => "many",
+        "cs" | "sk" if i == 1 => "one",
+        "cs" | "sk" if (2..=4).contains(&i) => "few",
+        "cs" | "sk" => "other",
+
+        "ar" if !integer => "other",
+        "ar" => match (i, i100) {
+            (0, _) => "zero",
+            (1, _) => "one",
+            (2, _) => "two",
+            (_, 3..=10) => "few",
+            (_, 11..=99) => "many",
+            _ => "other"
+        },
+
+        _ if integer && i == 1 => "one",
+        _ => "other"
+    }
+}
+
+// pt-BR then pt then the fallback the same way, without duplicates
+fn locale_chain(locale: &str, fallback: &str) -> Vec<String> {
+    let mut chain: Vec<String> = Vec::new();
+
+    for locale in &[locale, fallback] {
+        let mut locale = locale.replace('_', "-");
+
+        loop {
+            if !locale.is_empty() && !chain.contains(&locale) {
+                chain.push(locale.clone());
+            }
+
+            match locale.rfind('-') {
+                Some(i) => locale.truncate(i),
+                None => break
+            }
+        }
+    }
+
+    chain
+}
+
+fn find_arg<'a>(args: &[(&str, FormatArg<'a>)], name: &str) -> Option<FormatArg<'a>> {
+    args.iter()
+        .find(|(arg, _)| *arg == name)
+        .map(|(_, value)| *value)
+}
+
+fn check_key(key: &str) -> Result<(), String> {
+    let name = key.strip_prefix('-').unwrap_or(key);
+
+    let mut chars = name.chars();
+    let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    match valid {
+        true => Ok(()),
+        false => Err(format!("{:?} isn't a valid key", key))
+    }
+}
+
+// The first line of a value and the lines that continue it, without their common indentation
+fn dedent(first: &str, lines: &[&str]) -> String {
+    let indent_of = |line: &str| line.len() - line.trim_start().len();
+    let indent = lines.iter()
+        .filter(|line| !line.trim().is_empty() && !line.trim_start().starts_with('}'))
+        .map(|line| indent_of(line))
+        .min()
+        .unwrap_or(0);
+
+    let mut value = first.trim().to_string();
+    for line in lines {
+        if !value.is_empty() {
+            value.push('\n');
+        }
+
+        value.push_str(&line[std::cmp::min(indent, indent_of(line))..]);
+    }
+
+    value.trim_end().to_string()
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::new();
+
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => unescaped.push('\n'),
+                Some('t') => unescaped.push('\t'),
+                Some(c) => unescaped.push(c),
+                None => unescaped.push('\\')
+            },
+
+            c => unescaped.push(c)
+        }
+    }
+
+    unescaped
+}
+
+fn resolve_pattern(raw: &BTreeMap<String, Pattern>, pattern: &[Element], depth: usize) -> Result<Pattern, String> {
+    if depth > MAX_DEPTH {
+        return Err("references are nested too deep or circular".to_string());
+    }
+
+    let mut resolved = Vec::new();
+    for element in pattern {
+        match element {
+            Element::Reference(key) => {
+                let referenced = raw.get(key)
+                    .ok_or_else(|| format!("{} doesn't exist", key))?;
+
+                for element in resolve_pattern(raw, referenced, depth + 1)? {
+                    push_element(&mut resolved, element);
+                }
+            }
+
+            Element::Select { argument, variants, default } => {
+                let variants = variants.iter()
+                    .map(|(key, pattern)| Ok((key.clone(), resolve_pattern(raw, pattern, depth)?)))
+                    .collect::<Result<Vec<_>, String>>()?;
+
+                resolved.push(Element::Select { argument: argument.clone(), variants, default: *default });
+            }
+
+            element => push_element(&mut resolved, element.clone())
+        }
+    }
+
+    Ok(resolved)
+}
+
+// The limits read_pattern() has, references can make a pattern longer or nest selects deeper
+fn check_pattern(pattern: &[Element], depth: usize) -> Result<(), String> {
+    if depth > MAX_DEPTH {
+        return Err("selects are nested too deep".to_string());
+    }
+
+    if pattern.len() > MAX_STRING_LEN as usize {
+        return Err("pattern is too long".to_string());
+    }
+
+    for element in pattern {
+        match element {
+            Element::Text(s) | Element::Argument(s) => check_len(s)?,
+
+            Element::Select { argument, variants, .. } => {
+                check_len(argument)?;
+
+                if variants.len() > MAX_STRING_LEN as usize {
+                    return Err("select has too many variants".to_string());
+                }
+
+                for (key, pattern) in variants {
+                    if let VariantKey::Name(name) = key {
+                        check_len(name)?;
+                    }
+
+                    check_pattern(pattern, depth + 1)?;
+                }
+            }
+
+            Element::Reference(key) => return Err(format!("unresolved reference {}", key))
+        }
+    }
+
+    Ok(())
+}
+
+fn check_len(s: &str) -> Result<(), String> {
+    match s.len() > MAX_STRING_LEN as usize {
+        true => Err(format!("text is longer than {} bytes", MAX_STRING_LEN)),
+        false => Ok(())
+    }
+}
+
+// Neighbouring text is merged
+fn push_element(pattern: &mut Pattern, element: Element) {
+    if let (Some(Element::Text(last)), Element::Text(text)) = (pattern.last_mut(), &element) {
+        last.push_str(text);
+    } else {
+        pattern.push(element);
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    depth: usize
+}
+
+impl Parser {
+    fn new(source: &str) -> Parser {
+        Parser {
+            chars: source.chars().collect(),
+            pos: 0,
+            depth: 0
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+
+            return true;
+        }
+
+        false
+    }
+
+    fn skip_whitespace(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            if c == ' ' || c == '\t' || c == '\r' || (newlines && c == '\n') {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    // Text and placeables up to the end, in a variant up to the next variant or the end of the select
+    fn pattern(&mut self, in_variant: bool) -> Result<Pattern, String> {
+        let mut pattern = Vec::new();
+
+        while let Some(c) = self.peek() {
+            match c {
+                '{' => {
+                    self.pos += 1;
+                    let element = self.placeable()?;
+
+                    push_element(&mut pattern, element);
+                }
+
+                '}' if in_variant => break,
+                '}' => return Err("unexpected }".to_string()),
+
+                '\n' if in_variant => {
+                    self.skip_whitespace(true);
+
+                    if matches!(self.peek(), Some('[') | Some('*') | Some('}')) {
+                        break;
+                    }
+
+                    push_element(&mut pattern, Element::Text("\n".to_string()));
+                }
+
+                '\n' if self.peek_at(1) == Some('.') => return Err("attributes aren't supported".to_string()),
+
+                c => {
+                    self.pos += 1;
+                    push_element(&mut pattern, Element::Text(c.to_string()));
+                }
+            }
+        }
+
+        if in_variant {
+            if let Some(Element::Text(text)) = pattern.last_mut() {
+                let len = text.trim_end().len();
+                text.truncate(len);
+            }
+        }
+
+        Ok(pattern)
+    }
+
+    // After the {
+    fn placeable(&mut self) -> Result<Element, String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err("placeables are nested too deep".to_string());
+        }
+
+        self.skip_whitespace(true);
+        let expression = self.expression()?;
+        self.skip_whitespace(true);
+
+        let element = if self.peek() == Some('-') && self.peek_at(1) == Some('>') {
+            self.pos += 2;
+
+            match expression {
+                Element::Argument(argument) => self.variants(argument)?,
+                _ => return Err("only $arguments can be selected on".to_string())
+            }
+        } else {
+            expression
+        };
+
+        self.skip_whitespace(true);
+        if !self.eat('}') {
+            return Err("expected }".to_string());
+        }
+
+        self.depth -= 1;
+
+        Ok(element)
+    }
+
+    fn expression(&mut self) -> Result<Element, String> {
+        let is_digit = |c: Option<char>| matches!(c, Some(c) if c.is_ascii_digit());
+
+        match self.peek() {
+            Some('$') => {
+                self.pos += 1;
+
+                Ok(Element::Argument(self.identifier()?))
+            }
+
+            Some('"') => Ok(Element::Text(self.string_literal()?)),
+            Some('-') if is_digit(self.peek_at(1)) => Ok(Element::Text(self.number()?)),
+            Some(c) if c.is_ascii_digit() => Ok(Element::Text(self.number()?)),
+
+            Some('-') => {
+                self.pos += 1;
+
+                Ok(Element::Reference(format!("-{}", self.identifier()?)))
+            }
+
+            Some(c) if c.is_ascii_alphabetic() => Ok(Element::Reference(self.identifier()?)),
+
+            Some('{') => {
+                self.pos += 1;
+
+                self.placeable()
+            }
+
+            _ => Err("expected a $argument, reference or literal".to_string())
+        }
+    }
+
+    // After the ->, up to the }
+    fn variants(&mut self, argument: String) -> Result<Element, String> {
+        let mut variants = Vec::new();
+        let mut default = None;
+
+        loop {
+            self.skip_whitespace(true);
+
+            match self.peek() {
+                Some('}') => break,
+                Some('*') if default.is_some() => return Err("only one variant can be the default".to_string()),
+                Some('*') => {
+                    self.pos += 1;
+                    default = Some(variants.len());
+                }
+
+                _ => {}
+            }
+
+            if !self.eat('[') {
+                return Err("expected a [variant]".to_string());
+            }
+
+            self.skip_whitespace(false);
+            let key = match self.peek() {
+                Some(c) if c.is_ascii_digit() || c == '-' => {
+                    let number = self.number()?;
+
+                    VariantKey::Number(number.parse().map_err(|_| format!("{} isn't a number", number))?)
+                }
+
+                _ => VariantKey::Name(self.identifier()?)
+            };
+
+            self.skip_whitespace(false);
+            if !self.eat(']') {
+                return Err("expected ]".to_string());
+            }
+
+            self.skip_whitespace(false);
+            let pattern = self.pattern(true)?;
+
+            variants.push((key, pattern));
+        }
+
+        let default = default.ok_or_else(|| "selects need a *[default] variant".to_string())?;
+
+        Ok(Element::Select { argument, variants, default })
+    }
+
+    fn identifier(&mut self) -> Result<String, String> {
+        let start = self.pos;
+
+        while let Some(c) = self.peek() {
+            let valid = match self.pos == start {
+                true => c.is_ascii_alphabetic(),
+                false => c.is_ascii_alphanumeric() || c == '_' || c == '-'
+            };
+
+            if !valid {
+                break;
+            }
+
+            self.pos += 1;
+        }
+
+        match self.pos > start {
+            true => Ok(self.chars[start..self.pos].iter().collect()),
+            false => Err("expected an identifier".to_string())
+        }
+    }
+
+    fn number(&mut self) -> Result<String, String> {
+        let start = self.pos;
+        self.eat('-');
+
+        while let Some(c) = self.peek() {
+            if !c.is_ascii_digit() && c != '.' {
+                break;
+            }
+
+            self.pos += 1;
+        }
+
+        let number = self.chars[start..self.pos].iter().collect::<String>();
+        match number.parse::<f64>() {
+            Ok(_) => Ok(number),
+            Err(_) => Err(format!("{} isn't a number", number))
+        }
+    }
+
+    // "Text with \" and \\ and  "
+    fn string_literal(&mut self) -> Result<String, String> {
+        self.pos += 1;
+
+        let mut s = String::new();
+        loop {
+            match self.peek() {
+                Some('"') => {
+                    self.pos += 1;
+
+                    return Ok(s);
+                }
+
+                Some('\\') => {
+                    let len = match self.peek_at(1) {
+                        Some('"') | Some('\\') => {
+                            s.push(self.chars[self.pos + 1]);
+                            2
+                        }
+
+                        Some(u @ 'u') | Some(u @ 'U') => {
+                            let digits = if u == 'u' { 4 } else { 6 };
+                            let hex = self.chars.iter().skip(self.pos + 2).take(digits).collect::<String>();
+
+                            let c = u32::from_str_radix(&hex, 16).ok()
+                                .filter(|_| hex.len() == digits)
+                                .and_then(std::char::from_u32)
+                                .ok_or_else(|| format!("\\{}{} isn't a character", u, hex))?;
+
+                            s.push(c);
+                            2 + digits
+                        }
+
+                        _ => return Err("unknown escape in string literal".to_string())
+                    };
+
+                    self.pos += len;
+                }
+
+                Some('\n') | None => return Err("unterminated string literal".to_string()),
+
+                Some(c) => {
+                    self.pos += 1;
+                    s.push(c);
+                }
+            }
+        }
+    }
+}
+
+fn write_string(w: &mut Vec<u8>, s: &str) -> io::Result<()> {
+    w.write_u32::<LittleEndian>(s.len() as u32)?;
+    w.write_all(s.as_bytes())
+}
+
+fn read_string<R: Read>(r: &mut R) -> io::Result<String> {
+    let len = r.read_u32::<LittleEndian>()?;
+    if len > MAX_STRING_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "string is too long"));
+    }
+
+    let mut s = vec![0x00; len as usize];
+    r.read_exact(&mut s)?;
+
+    String::from_utf8(s).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn write_pattern(w: &mut Vec<u8>, pattern: &[Element]) -> io::Result<()> {
+    w.write_u32::<LittleEndian>(pattern.len() as u32)?;
+
+    for element in pattern {
+        match element {
+            Element::Text(text) => {
+                w.write_u8(0)?;
+                write_string(w, text)?;
+            }
+
+            Element::Argument(name) => {
+                w.write_u8(1)?;
+                write_string(w, name)?;
+            }
+
+            Element::Select { argument, variants, default } => {
+                w.write_u8(2)?;
+                write_string(w, argument)?;
+                w.write_u32::<LittleEndian>(*default as u32)?;
+
+                w.write_u32::<LittleEndian>(variants.len() as u32)?;
+                for (key, pattern) in variants {
+                    match key {
+                        VariantKey::Number(n) => {
+                            w.write_u8(0)?;
+                            w.write_f64::<LittleEndian>(*n)?;
+                        }
+
+                        VariantKey::Name(name) => {
+                            w.write_u8(1)?;
+                            write_string(w, name)?;
+                        }
+                    }
+
+                    write_pattern(w, pattern)?;
+                }
+            }
+
+            Element::Reference(key) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("unresolved reference {}", key)));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn read_pattern<R: Read>(r: &mut R, depth: usize) -> io::Result<Pattern> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    if depth > MAX_DEPTH {
+        return Err(invalid("selects are nested too deep"));
+    }
+
+    let len = r.read_u32::<LittleEndian>()?;
+    if len > MAX_STRING_LEN {
+        return Err(invalid("pattern is too long"));
+    }
+
+    let mut pattern = Vec::new();
+    for _ in 0..len {
+        let element = match r.read_u8()? {
+            0 => Element::Text(read_string(r)?),
+            1 => Element::Argument(read_string(r)?),
+
+            2 => {
+                let argument = read_string(r)?;
+                let default = r.read_u32::<LittleEndian>()? as usize;
+
+                let count = r.read_u32::<LittleEndian>()?;
+                if default >= count as usize || count > MAX_STRING_LEN {
+                    return Err(invalid("invalid select"));
+                }
+
+                let mut variants = Vec::new();
+                for _ in 0..count {
+                    let key = match r.read_u8()? {
+                        0 => VariantKey::Number(r.read_f64::<LittleEndian>()?),
+                        1 => VariantKey::Name(read_string(r)?),
+
+                        _ => return Err(invalid("unknown variant key"))
+                    };
+
+                    variants.push((key, read_pattern(r, depth + 1)?));
+                }
+
+                Element::Select { argument, variants, default }
+            }
+
+            _ => return Err(invalid("unknown pattern element"))
+        };
+
+        pattern.push(element);
+    }
+
+    Ok(pattern)
+}
+
+#[test]
+fn string_table_test() {
+    let en = StringTable::parse_fluent("en", r#"
+# Shown in the title bar
+-brand = Project Pixel
+window-title = { -brand }
+greeting = Hello { $name }!
+braces = { "{" }literally{ "}" }
+items = { $count ->
+    [0] No items
+    [one] One item
+   *[other] { $count } items
+}
+multiline =
+    First line
+      indented
+
+    Last line
+"#).unwrap();
+
+    assert_eq!(en.locale(), "en");
+    assert_eq!(en.keys().collect::<Vec<_>>(), vec!["braces", "greeting", "items", "multiline", "window-title"]);
+
+    assert_eq!(en.format("window-title", &[]).unwrap(), "Project Pixel");
+    assert_eq!(en.format("greeting", &[("name", "Pixel".into())]).unwrap(), "Hello Pixel!");
+    assert_eq!(en.format("greeting", &[]).unwrap(), "Hello {$name}!");
+    assert_eq!(en.format("braces", &[]).unwrap(), "{literally}");
+    assert_eq!(en.format("items", &[("count", 0.into())]).unwrap(), "No items");
+    assert_eq!(en.format("items", &[("count", 1.into())]).unwrap(), "One item");
+    assert_eq!(en.format("items", &[("count", 5.into())]).unwrap(), "5 items");
+    assert_eq!(en.format("items", &[("count", 1.5.into())]).unwrap(), "1.5 items");
+    assert_eq!(en.format("multiline", &[]).unwrap(), "First line\n  indented\n\nLast line");
+    assert_eq!(en.format("missing", &[]), None);
+
+    let ru = StringTable::parse_lang("ru", "
+# Plurals select on $count
+items[one] = { $count } предмет
+items[few] = { $count } предмета
+items[many] = { $count } предметов
+items[other] = { $count } предмета
+quote = \"Hi\"\\n{ $name }
+").unwrap();
+
+    let items = |n: u32| ru.format("items", &[("count", n.into())]).unwrap();
+    assert_eq!(items(1), "1 предмет");
+    assert_eq!(items(3), "3 предмета");
+    assert_eq!(items(11), "11 предметов");
+    assert_eq!(items(21), "21 предмет");
+    assert_eq!(ru.format("quote", &[("name", "Pixel".into())]).unwrap(), "\"Hi\"\nPixel");
+
+    assert!(StringTable::parse_fluent("en", "a = { b }\nb = { a }").is_err());
+    assert!(StringTable::parse_fluent("en", "a = { missing }").is_err());
+    assert!(StringTable::parse_fluent("en", "a = { $n ->\n [one] x\n}").is_err());
+    assert!(StringTable::parse_fluent("en", "a = b\na = c").is_err());
+    assert!(StringTable::parse_fluent("en", "a = b\n    .title = c").is_err());
+    assert!(StringTable::parse_lang("en", "a[one] = b").is_err());
+
+    // Tables read_from() would turn down don't compile either, references can push them over
+    let nested = |depth: usize, inner: &str| format!("{}{}{}", "{ $n -> *[other] ".repeat(depth), inner, " }".repeat(depth));
+    let deepest = StringTable::parse_fluent("en", &format!("-t = {}
+a = {}", nested(4, "x"), nested(4, "{ -t }"))).unwrap();
+    let mut data = Vec::new();
+    deepest.write_to(&mut data).unwrap();
+    assert_eq!(StringTable::read_from(&data).unwrap(), deepest);
+
+    assert!(StringTable::parse_fluent("en", &format!("-t = {}
+a = {}", nested(5, "x"), nested(5, "{ -t }"))).is_err());
+
+    let long = "x".repeat(MAX_STRING_LEN as usize / 2 + 1);
+    assert!(StringTable::parse_fluent("en", &format!("a = {}", long)).is_ok());
+    assert!(StringTable::parse_fluent("en", &format!("a = {}{}", long, long)).is_err());
+    assert!(StringTable::parse_lang("en", &format!("-t = {}\na = {{ -t }}{{ -t }}", long)).is_err());
+
+    let mut data = Vec::new();
+    en.write_to(&mut data).unwrap();
+    assert_eq!(StringTable::read_from(&data).unwrap(), en);
+
+    let localization = Localization::new(vec![ru.clone(), en.clone()]);
+    assert_eq!(localization.format("items", &[("count", 2.into())]), "2 предмета");
+    assert_eq!(localization.get("window-title"), "Project Pixel");
+    assert_eq!(localization.get("missing"), "missing");
+
+    let missing = missing_keys(&[("strings/en.ftl".to_string(), en), ("strings/ru.lang".to_string(), ru)]);
+    assert_eq!(missing["strings/ru.lang"], vec!["braces", "greeting", "multiline", "window-title"]);
+    assert_eq!(missing["strings/en.ftl"], vec!["quote"]);
+
+    assert_eq!(locale_chain("pt_BR", "en"), vec!["pt-BR", "pt", "en"]);
+    assert_eq!(plural_category("fr", 1.5), "one");
+    assert_eq!(plural_category("ar", 105.0), "few");
+}
@@ -0,0 +1,512 @@
+// Textures are processed while building (see TextureSettings), so they can be uploaded as they are.
+//
+// Texture Entry Layout:
+//   Magic                  "PXT1"
+//   Width                  u32
+//   Height                 u32
+//   Format                 u8 (PixelFormat)
+//   Flags                  u8 (1 = premultiplied alpha)
+//   Level Count            u8 (1 unless the mip chain was precomputed)
+//   Levels                 [u8] (largest first, each one PixelFormat::level_size() bytes long)
+//
+// Textures of older archives don't have the header, they start with the width and are RGBA8.
+
+use std::io::{self, Cursor, Read};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use image::{imageops, Rgba, RgbaImage};
+use serde::Deserialize;
+
+use crate::{TextureFilter, TextureSettings};
+
+const TEXTURE_MAGIC: &[u8; 4] = b"PXT1";
+
+const FLAG_PREMULTIPLIED: u8 = 1;
+
+// A 1x1 level is the last one, even textures 2^32 - 1 pixels wide don't have more
+const MAX_LEVELS: u8 = 32;
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PixelFormat {
+    Rgba8 = 0,
+    R8 = 1,       // Only the red channel, for masks
+    Rgb565 = 2,   // Drops alpha
+    Rgba4444 = 3,
+    Bc1 = 4,      // 4x4 blocks of 8 bytes, drops alpha
+    Bc3 = 5       // 4x4 blocks of 16 bytes
+}
+
+impl PixelFormat {
+    pub fn from_u8(v: u8) -> Option<PixelFormat> {
+        match v {
+            0 => Some(PixelFormat::Rgba8),
+            1 => Some(PixelFormat::R8),
+            2 => Some(PixelFormat::Rgb565),
+            3 => Some(PixelFormat::Rgba4444),
+            4 => Some(PixelFormat::Bc1),
+            5 => Some(PixelFormat::Bc3),
+
+            _ => None
+        }
+    }
+
+    // Block compressed formats have to be uploaded with glCompressedTexImage2D
+    pub fn is_compressed(self) -> bool {
+        self == PixelFormat::Bc1 || self == PixelFormat::Bc3
+    }
+
+    // None if it doesn't fit in memory, the size can come from a broken archive
+    pub fn level_size(self, width: u32, height: u32) -> Option<usize> {
+        let pixels = (width as usize).checked_mul(height as usize)?;
+        let blocks = (block_count(width) as usize).checked_mul(block_count(height) as usize)?;
+
+        match self {
+            PixelFormat::Rgba8 => pixels.checked_mul(4),
+            PixelFormat::R8 => Some(pixels),
+            PixelFormat::Rgb565 | PixelFormat::Rgba4444 => pixels.checked_mul(2),
+            PixelFormat::Bc1 => blocks.checked_mul(8),
+            PixelFormat::Bc3 => blocks.checked_mul(16)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextureData {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    pub premultiplied: bool,
+    pub levels: Vec<Vec<u8>> // Every level is half the size of the one before, at least 1x1
+}
+
+impl TextureData {
+    // A single RGBA8 level, exactly what the image has
+    pub fn from_image(img: RgbaImage) -> TextureData {
+        TextureData {
+            width: img.width(),
+            height: img.height(),
+            format: PixelFormat::Rgba8,
+            premultiplied: false,
+            levels: vec![img.into_raw()]
+        }
+    }
+
+    pub fn process(mut img: RgbaImage, settings: &TextureSettings) -> TextureData {
+        if settings.premultiply_alpha {
+            for pixel in img.pixels_mut() {
+                let a = pixel[3] as u32;
+                for c in 0..3 {
+                    pixel[c] = ((pixel[c] as u32 * a + 127) / 255) as u8;
+                }
+            }
+        }
+
+        // Pixel art stays sharp in the smaller levels as well
+        let filter = match settings.filter {
+            TextureFilter::Nearest => imageops::FilterType::Nearest,
+            TextureFilter::Linear => imageops::FilterType::Triangle
+        };
+
+        let mut levels = vec![encode(&img, settings.format)];
+        if settings.mipmaps {
+            let mut level = img.clone();
+            while level.width() > 1 || level.height() > 1 {
+                level = imageops::resize(&level, std::cmp::max(level.width() / 2, 1),
+                                         std::cmp::max(level.height() / 2, 1), filter);
+                levels.push(encode(&level, settings.format));
+            }
+        }
+
+        TextureData {
+            width: img.width(),
+            height: img.height(),
+            format: settings.format,
+            premultiplied: settings.premultiply_alpha,
+            levels
+        }
+    }
+
+    pub fn level_size(&self, level: usize) -> (u32, u32) {
+        (std::cmp::max(self.width >> level, 1), std::cmp::max(self.height >> level, 1))
+    }
+
+    // The largest level as plain RGBA8 without premultiplied alpha
+    pub fn to_image(&self) -> RgbaImage {
+        let mut img = decode(&self.levels[0], self.width, self.height, self.format);
+
+        if self.premultiplied {
+            for pixel in img.pixels_mut() {
+                let a = pixel[3] as u32;
+                for c in 0..3 {
+                    pixel[c] = match a {
+                        0 => 0,
+                        a => std::cmp::min((pixel[c] as u32 * 255 + a / 2) / a, 255) as u8
+                    };
+                }
+            }
+        }
+
+        img
+    }
+
+    pub(crate) fn write_to(&self, w: &mut Vec<u8>) -> io::Result<()> {
+        w.extend_from_slice(TEXTURE_MAGIC);
+        w.write_u32::<LittleEndian>(self.width)?;
+        w.write_u32::<LittleEndian>(self.height)?;
+        w.write_u8(self.format as u8)?;
+        w.write_u8(if self.premultiplied { FLAG_PREMULTIPLIED } else { 0 })?;
+        w.write_u8(self.levels.len() as u8)?;
+
+        for level in &self.levels {
+            w.extend_from_slice(level);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn read_from(data: &[u8]) -> io::Result<TextureData> {
+        let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let mut r = Cursor::new(data);
+        if !data.starts_with(TEXTURE_MAGIC) {
+            let width = r.read_u32::<LittleEndian>()?;
+            let height = r.read_u32::<LittleEndian>()?;
+
+            let pixels = &data[r.position() as usize..];
+            let size = PixelFormat::Rgba8.level_size(width, height)
+                .ok_or_else(|| invalid("texture is too big"))?;
+            if pixels.len() < size {
+                return Err(invalid("missing pixels"));
+            }
+
+            let img = RgbaImage::from_raw(width, height, pixels[..size].to_vec())
+                .ok_or_else(|| invalid("missing pixels"))?;
+
+            return Ok(TextureData::from_image(img));
+        }
+
+        r.set_position(TEXTURE_MAGIC.len() as u64);
+
+        let width = r.read_u32::<LittleEndian>()?;
+        let height = r.read_u32::<LittleEndian>()?;
+        let format = PixelFormat::from_u8(r.read_u8()?)
+            .ok_or_else(|| invalid("unknown pixel format"))?;
+        let premultiplied = r.read_u8()? & FLAG_PREMULTIPLIED != 0;
+
+        let level_count = r.read_u8()?;
+        if level_count == 0 || level_count > MAX_LEVELS {
+            return Err(invalid("invalid level count"));
+        }
+
+        let mut texture = TextureData {
+            width,
+            height,
+            format,
+            premultiplied,
+            levels: Vec::new()
+        };
+
+        for i in 0..level_count as usize {
+            let (width, height) = texture.level_size(i);
+            let size = format.level_size(width, height)
+                .ok_or_else(|| invalid("texture is too big"))?;
+
+            // Checked against what's left so a broken header can't allocate gigabytes
+            if size > data.len() - r.position() as usize {
+                return Err(invalid("missing pixels"));
+            }
+
+            let mut level = vec![0; size];
+            r.read_exact(&mut level)?;
+            texture.levels.push(level);
+        }
+
+        Ok(texture)
+    }
+
+    pub(crate) fn read_size(data: &[u8]) -> io::Result<(u32, u32)> {
+        let mut r = Cursor::new(data);
+        if data.starts_with(TEXTURE_MAGIC) {
+            r.set_position(TEXTURE_MAGIC.len() as u64);
+        }
+
+        Ok((r.read_u32::<LittleEndian>()?, r.read_u32::<LittleEndian>()?))
+    }
+}
+
+fn encode(img: &RgbaImage, format: PixelFormat) -> Vec<u8> {
+    let mut data = Vec::with_capacity(format.level_size(img.width(), img.height()).unwrap_or(0));
+
+    match format {
+        PixelFormat::Rgba8 => data.extend_from_slice(img),
+        PixelFormat::R8 => data.extend(img.pixels().map(|pixel| pixel[0])),
+
+        PixelFormat::Rgb565 => for pixel in img.pixels() {
+            data.write_u16::<LittleEndian>(to_565(pixel)).unwrap();
+        },
+
+        PixelFormat::Rgba4444 => for pixel in img.pixels() {
+            let [r, g, b, a] = pixel.0;
+            let value = (scale(r, 15) << 12) | (scale(g, 15) << 8) | (scale(b, 15) << 4) | scale(a, 15);
+
+            data.write_u16::<LittleEndian>(value).unwrap();
+        },
+
+        PixelFormat::Bc1 | PixelFormat::Bc3 => for block in blocks(img) {
+            if format == PixelFormat::Bc3 {
+                data.extend_from_slice(&encode_alpha_block(&block));
+            }
+
+            data.extend_from_slice(&encode_colour_block(&block));
+        }
+    }
+
+    data
+}
+
+// Pixels the data is missing stay transparent, read_from() made sure there are none
+fn decode(data: &[u8], width: u32, height: u32, format: PixelFormat) -> RgbaImage {
+    let mut img = RgbaImage::new(width, height);
+
+    match format {
+        PixelFormat::Rgba8 => for (pixel, value) in img.pixels_mut().zip(data.chunks_exact(4)) {
+            *pixel = Rgba([value[0], value[1], value[2], value[3]]);
+        },
+
+        PixelFormat::R8 => for (pixel, value) in img.pixels_mut().zip(data) {
+            *pixel = Rgba([*value, *value, *value, 255]);
+        },
+
+        PixelFormat::Rgb565 => for (pixel, value) in img.pixels_mut().zip(data.chunks_exact(2)) {
+            *pixel = from_565(u16::from_le_bytes([value[0], value[1]]));
+        },
+
+        PixelFormat::Rgba4444 => for (pixel, value) in img.pixels_mut().zip(data.chunks_exact(2)) {
+            let value = u16::from_le_bytes([value[0], value[1]]);
+            let channel = |shift: u16| ((value >> shift) & 0xf) as u8 * 17;
+
+            *pixel = Rgba([channel(12), channel(8), channel(4), channel(0)]);
+        },
+
+        PixelFormat::Bc1 | PixelFormat::Bc3 => {
+            let block_size = if format == PixelFormat::Bc1 { 8 } else { 16 };
+
+            for (i, block) in data.chunks_exact(block_size).enumerate() {
+                let (x, y) = ((i as u32 % block_count(width)) * 4, (i as u32 / block_count(width)) * 4);
+
+                let (alpha, colour) = match format {
+                    PixelFormat::Bc3 => (Some(&block[..8]), &block[8..]),
+                    _ => (None, block)
+                };
+
+                let palette = colour_palette(&colour[..4], format == PixelFormat::Bc3);
+                let indices = u32::from_le_bytes([colour[4], colour[5], colour[6], colour[7]]);
+                let alpha = alpha.map(|alpha| (alpha_palette(alpha[0], alpha[1]), alpha_indices(alpha)));
+
+                for j in 0..16 {
+                    let (px, py) = (x + j % 4, y + j / 4);
+                    if px >= width || py >= height {
+                        continue;
+                    }
+
+                    let mut pixel = palette[(indices >> (j * 2)) as usize & 3];
+                    if let Some((palette, indices)) = &alpha {
+                        pixel[3] = palette[(indices >> (j * 3)) as usize & 7];
+                    }
+
+                    img.put_pixel(px, py, pixel);
+                }
+            }
+        }
+    }
+
+    img
+}
+
+// 0 - 255 -> 0 - max
+fn scale(v: u8, max: u16) -> u16 {
+    (v as u16 * max + 127) / 255
+}
+
+fn to_565(pixel: &Rgba<u8>) -> u16 {
+    (scale(pixel[0], 31) << 11) | (scale(pixel[1], 63) << 5) | scale(pixel[2], 31)
+}
+
+fn from_565(value: u16) -> Rgba<u8> {
+    let (r, g, b) = ((value >> 11) as u8, ((value >> 5) & 0x3f) as u8, (value & 0x1f) as u8);
+
+    Rgba([(r << 3) | (r >> 2), (g << 2) | (g >> 4), (b << 3) | (b >> 2), 255])
+}
+
+// Blocks needed to cover that many pixels, partly covered ones count as well
+fn block_count(pixels: u32) -> u32 {
+    ((pixels as u64 + 3) >> 2) as u32
+}
+
+// 4x4 pixel blocks, row by row. Edges are repeated for sizes that aren't a multiple of 4
+fn blocks(img: &RgbaImage) -> impl Iterator<Item = [Rgba<u8>; 16]> + '_ {
+    let (width, height) = img.dimensions();
+
+    (0..block_count(height)).flat_map(move |by| (0..block_count(width)).map(move |bx| {
+        let mut block = [Rgba([0; 4]); 16];
+        for (i, pixel) in block.iter_mut().enumerate() {
+            let x = std::cmp::min(bx * 4 + i as u32 % 4, width - 1);
+            let y = std::cmp::min(by * 4 + i as u32 / 4, height - 1);
+
+            *pixel = *img.get_pixel(x, y);
+        }
+
+        block
+    }))
+}
+
+// The endpoints are the corners of the bounding box of the block's colours
+fn encode_colour_block(block: &[Rgba<u8>; 16]) -> [u8; 8] {
+    let mut min = Rgba([255, 255, 255, 255]);
+    let mut max = Rgba([0, 0, 0, 255]);
+    for pixel in block {
+        for c in 0..3 {
+            min[c] = std::cmp::min(min[c], pixel[c]);
+            max[c] = std::cmp::max(max[c], pixel[c]);
+        }
+    }
+
+    // max >= min, so this is never the 3 colour mode of BC1 unless every pixel is the same colour
+    let (c0, c1) = (to_565(&max), to_565(&min));
+    let mut out = [0; 8];
+    out[..2].copy_from_slice(&c0.to_le_bytes());
+    out[2..4].copy_from_slice(&c1.to_le_bytes());
+
+    let palette = colour_palette(&out[..4], true);
+    let mut indices = 0u32;
+    for (i, pixel) in block.iter().enumerate() {
+        let distance = |colour: &Rgba<u8>| (0..3)
+            .map(|c| (colour[c] as i32 - pixel[c] as i32).pow(2))
+            .sum::<i32>();
+
+        let best = (0..4).min_by_key(|j| distance(&palette[*j])).unwrap();
+        indices |= (best as u32) << (i * 2);
+    }
+
+    out[4..].copy_from_slice(&indices.to_le_bytes());
+    out
+}
+
+// BC3 always uses 4 colours, BC1 only if the first endpoint is larger
+fn colour_palette(endpoints: &[u8], four_colours: bool) -> [Rgba<u8>; 4] {
+    let c0 = u16::from_le_bytes([endpoints[0], endpoints[1]]);
+    let c1 = u16::from_le_bytes([endpoints[2], endpoints[3]]);
+    let (p0, p1) = (from_565(c0), from_565(c1));
+
+    let mix = |w0: u16, w1: u16| {
+        let channel = |c: usize| ((p0[c] as u16 * w0 + p1[c] as u16 * w1) / (w0 + w1)) as u8;
+
+        Rgba([channel(0), channel(1), channel(2), 255])
+    };
+
+    if four_colours || c0 > c1 {
+        [p0, p1, mix(2, 1), mix(1, 2)]
+    } else {
+        [p0, p1, mix(1, 1), Rgba([0, 0, 0, 0])]
+    }
+}
+
+fn encode_alpha_block(block: &[Rgba<u8>; 16]) -> [u8; 8] {
+    let a0 = block.iter().map(|pixel| pixel[3]).max().unwrap();
+    let a1 = block.iter().map(|pixel| pixel[3]).min().unwrap();
+
+    let palette = alpha_palette(a0, a1);
+    let mut indices = 0u64;
+    for (i, pixel) in block.iter().enumerate() {
+        let best = (0..8).min_by_key(|j| (palette[*j] as i32 - pixel[3] as i32).abs()).unwrap();
+        indices |= (best as u64) << (i * 3);
+    }
+
+    let mut out = [0; 8];
+    out[0] = a0;
+    out[1] = a1;
+    out[2..].copy_from_slice(&indices.to_le_bytes()[..6]);
+    out
+}
+
+fn alpha_palette(a0: u8, a1: u8) -> [u8; 8] {
+    let (a0, a1) = (a0 as u16, a1 as u16);
+    let mut palette = [a0 as u8, a1 as u8, 0, 0, 0, 0, 0, 255];
+
+    if a0 > a1 {
+        for (k, alpha) in palette.iter_mut().enumerate().skip(2) {
+            *alpha = (((8 - k as u16) * a0 + (k as u16 - 1) * a1) / 7) as u8;
+        }
+    } else {
+        for (k, alpha) in palette.iter_mut().enumerate().take(6).skip(2) {
+            *alpha = (((6 - k as u16) * a0 + (k as u16 - 1) * a1) / 5) as u8;
+        }
+    }
+
+    palette
+}
+
+fn alpha_indices(block: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes[..6].copy_from_slice(&block[2..8]);
+
+    u64::from_le_bytes(bytes)
+}
+
+#[test]
+fn texture_data_test() {
+    let mut img = RgbaImage::new(6, 3);
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        *pixel = Rgba([x as u8 * 40, y as u8 * 80, 200, if x < 3 { 255 } else { 0 }]);
+    }
+
+    let settings = |format, premultiply_alpha, mipmaps| TextureSettings {
+        format,
+        premultiply_alpha,
+        mipmaps,
+        ..TextureSettings::default()
+    };
+
+    let texture = TextureData::process(img.clone(), &settings(PixelFormat::Rgba8, false, true));
+    assert_eq!(texture.levels.len(), 3); // 6x3, 3x1, 1x1
+    assert_eq!(texture.level_size(2), (1, 1));
+    assert_eq!(texture.to_image(), img);
+
+    let mut data = Vec::new();
+    texture.write_to(&mut data).unwrap();
+    assert_eq!(TextureData::read_from(&data).unwrap(), texture);
+    assert_eq!(TextureData::read_size(&data).unwrap(), (6, 3));
+    assert!(TextureData::read_from(&data[..data.len() - 1]).is_err());
+
+    // Sizes that overflow are broken archives, not panics
+    assert_eq!(PixelFormat::Rgba8.level_size(u32::MAX, u32::MAX), None);
+    let mut huge = data.clone();
+    huge[4..12].copy_from_slice(&[0xff; 8]);
+    assert!(TextureData::read_from(&huge).is_err());
+    assert!(TextureData::read_from(&[0xff; 16]).is_err()); // From before the header
+
+    // Transparent pixels lose their colour, everything else survives the round trip
+    let premultiplied = TextureData::process(img.clone(), &settings(PixelFormat::Rgba8, true, false));
+    assert_eq!(premultiplied.levels[0][12..16], [0, 0, 0, 0]);
+    assert_eq!(premultiplied.to_image().get_pixel(1, 1), img.get_pixel(1, 1));
+
+    for format in &[PixelFormat::R8, PixelFormat::Rgb565, PixelFormat::Rgba4444, PixelFormat::Bc1, PixelFormat::Bc3] {
+        let texture = TextureData::process(img.clone(), &settings(*format, false, true));
+        for (i, level) in texture.levels.iter().enumerate() {
+            let (width, height) = texture.level_size(i);
+            assert_eq!(Some(level.len()), format.level_size(width, height));
+        }
+
+        let decoded = texture.to_image();
+        let error = |c: usize, x, y| (decoded.get_pixel(x, y)[c] as i32 - img.get_pixel(x, y)[c] as i32).abs();
+
+        // Only the lossy formats that keep alpha have to get it right
+        assert!(error(0, 2, 1) <= 16, "{:?}", format);
+        if *format == PixelFormat::Rgba4444 || *format == PixelFormat::Bc3 {
+            assert_eq!(error(3, 0, 0), 0);
+            assert_eq!(error(3, 5, 2), 0);
+        }
+    }
+}
@@ -0,0 +1,470 @@
+// Just enough TrueType for the font importer: character mapping (cmap format 4 and 12),
+// horizontal metrics, glyf outlines (simple and composite) and the kern table.
+// OpenType fonts with CFF outlines aren't supported, neither is GPOS kerning.
+
+use std::collections::HashMap;
+
+// Composite glyphs can reference composite glyphs, real fonts don't go deeper than a few levels
+const MAX_COMPONENT_DEPTH: u32 = 8;
+
+// Quadratic curves are flattened until they're at most this far (in pixels) off
+const FLATNESS: f32 = 0.05;
+
+// A line of a flattened outline, x0 y0 x1 y1. Y points up like it does in the font.
+pub(crate) type Line = [f32; 4];
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn i16_at(data: &[u8], offset: usize) -> Option<i16> {
+    u16_at(data, offset).map(|v| v as i16)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn f2dot14_at(data: &[u8], offset: usize) -> Option<f32> {
+    i16_at(data, offset).map(|v| v as f32 / 16384.0)
+}
+
+pub(crate) struct TrueType<'a> {
+    cmap: &'a [u8], // The subtable used for lookups
+    cmap_format: u16,
+    hmtx: &'a [u8],
+    loca: &'a [u8],
+    glyf: &'a [u8],
+    kern: Option<&'a [u8]>,
+
+    long_loca: bool,
+    glyph_count: u16,
+    metric_count: u16,
+
+    pub units_per_em: u16,
+    pub ascent: i16,
+    pub descent: i16, // Negative
+    pub line_gap: i16
+}
+
+impl<'a> TrueType<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<TrueType<'a>, &'static str> {
+        TrueType::parse_tables(data)?.ok_or("the font is truncated")
+    }
+
+    fn parse_tables(data: &'a [u8]) -> Result<Option<TrueType<'a>>, &'static str> {
+        // Collections use the first font
+        let offset = match data.get(..4) {
+            Some(b"ttcf") => match u32_at(data, 12) {
+                Some(offset) => offset as usize,
+                None => return Ok(None)
+            },
+            _ => 0
+        };
+
+        match data.get(offset..offset + 4) {
+            Some(b"OTTO") => return Err("CFF outlines aren't supported, the font needs TrueType outlines"),
+            Some(b"\0\x01\0\0") | Some(b"true") => {}
+
+            _ => return Err("not a TrueType font")
+        }
+
+        let table_count = match u16_at(data, offset + 4) {
+            Some(count) => count as usize,
+            None => return Ok(None)
+        };
+
+        let mut tables = HashMap::new();
+        for i in 0..table_count {
+            let record = offset + 12 + i * 16;
+            let (table_offset, len) = match (u32_at(data, record + 8), u32_at(data, record + 12)) {
+                (Some(table_offset), Some(len)) => (table_offset as usize, len as usize),
+                _ => return Ok(None)
+            };
+
+            match data.get(table_offset..table_offset + len) {
+                Some(table) => tables.insert(&data[record..record + 4], table),
+                None => return Ok(None)
+            };
+        }
+
+        let table = |tag: &[u8; 4]| tables.get(&tag[..]).copied().ok_or("a required table is missing");
+        let (head, hhea, maxp, cmap) = (table(b"head")?, table(b"hhea")?, table(b"maxp")?, table(b"cmap")?);
+
+        let (cmap, cmap_format) = match TrueType::find_cmap(cmap) {
+            Some(cmap) => cmap,
+            None => return Err("the font doesn't map unicode characters")
+        };
+
+        let font = (|| Some(TrueType {
+            cmap,
+            cmap_format,
+            hmtx: table(b"hmtx").ok()?,
+            loca: table(b"loca").ok()?,
+            glyf: table(b"glyf").ok()?,
+            kern: tables.get(&b"kern"[..]).copied(),
+
+            long_loca: i16_at(head, 50)? != 0,
+            glyph_count: u16_at(maxp, 4)?,
+            metric_count: u16_at(hhea, 34)?,
+
+            units_per_em: u16_at(head, 18)?,
+            ascent: i16_at(hhea, 4)?,
+            descent: i16_at(hhea, 6)?,
+            line_gap: i16_at(hhea, 8)?
+        }))();
+
+        match font {
+            Some(font) if font.units_per_em == 0 || font.metric_count == 0 => Err("the font header is invalid"),
+            Some(font) => Ok(Some(font)),
+            None => Err("a required table is missing")
+        }
+    }
+
+    // Prefers a full unicode subtable (format 12) over the basic plane one (format 4)
+    fn find_cmap(cmap: &[u8]) -> Option<(&[u8], u16)> {
+        let mut best: Option<(&[u8], u16)> = None;
+
+        for i in 0..u16_at(cmap, 2)? as usize {
+            let record = 4 + i * 8;
+            let platform = u16_at(cmap, record)?;
+            let encoding = u16_at(cmap, record + 2)?;
+            let subtable = cmap.get(u32_at(cmap, record + 4)? as usize..)?;
+
+            let unicode = platform == 0 || (platform == 3 && (encoding == 1 || encoding == 10));
+            let format = u16_at(subtable, 0)?;
+
+            if unicode && (format == 12 || (format == 4 && best.is_none())) {
+                best = Some((subtable, format));
+            }
+        }
+
+        best
+    }
+
+    // 0 is the missing glyph
+    pub fn glyph_index(&self, c: char) -> u16 {
+        let c = c as u32;
+
+        let index = match self.cmap_format {
+            4 if c <= 0xffff => (|| {
+                let segments = u16_at(self.cmap, 6)? as usize / 2;
+                let (ends, starts, deltas, range_offsets) = (14, 16 + segments * 2, 16 + segments * 4, 16 + segments * 6);
+
+                for i in 0..segments {
+                    if u16_at(self.cmap, ends + i * 2)? as u32 >= c {
+                        let start = u16_at(self.cmap, starts + i * 2)? as u32;
+                        if start > c {
+                            return Some(0);
+                        }
+
+                        let delta = u16_at(self.cmap, deltas + i * 2)? as u32;
+                        let range_offset = u16_at(self.cmap, range_offsets + i * 2)? as usize;
+
+                        // The range offset is relative to where it's stored
+                        let glyph = match range_offset {
+                            0 => c,
+                            _ => match u16_at(self.cmap, range_offsets + i * 2 + range_offset + (c - start) as usize * 2)? {
+                                0 => return Some(0),
+                                glyph => glyph as u32
+                            }
+                        };
+
+                        return Some((glyph + delta) as u16);
+                    }
+                }
+
+                Some(0)
+            })(),
+
+            12 => (|| {
+                for i in 0..u32_at(self.cmap, 12)? as usize {
+                    let group = 16 + i * 12;
+                    let (start, end) = (u32_at(self.cmap, group)?, u32_at(self.cmap, group + 4)?);
+
+                    if (start..=end).contains(&c) {
+                        return Some((u32_at(self.cmap, group + 8)? + c - start) as u16);
+                    }
+                }
+
+                Some(0)
+            })(),
+
+            _ => None
+        };
+
+        match index {
+            Some(index) if index < self.glyph_count => index,
+            _ => 0
+        }
+    }
+
+    // In font units
+    pub fn advance(&self, glyph: u16) -> u16 {
+        let metric = std::cmp::min(glyph, self.metric_count - 1) as usize;
+
+        u16_at(self.hmtx, metric * 4).unwrap_or(0)
+    }
+
+    // Horizontal kerning pairs of the kern table in font units, left glyph, right glyph and amount
+    pub fn kerning(&self) -> Vec<(u16, u16, i16)> {
+        let mut pairs = Vec::new();
+
+        let kern = match self.kern {
+            Some(kern) if u16_at(kern, 0) == Some(0) => kern, // Apple's version 1 tables are skipped
+            _ => return pairs
+        };
+
+        let mut offset = 4;
+        for _ in 0..u16_at(kern, 2).unwrap_or(0) {
+            let (len, coverage) = match (u16_at(kern, offset + 2), u16_at(kern, offset + 4)) {
+                (Some(len), Some(coverage)) => (len as usize, coverage),
+                _ => break
+            };
+
+            // Format 0, horizontal, not a minimum and not cross stream
+            if coverage & 0xff07 == 1 {
+                for i in 0..u16_at(kern, offset + 6).unwrap_or(0) as usize {
+                    let pair = offset + 14 + i * 6;
+
+                    match (u16_at(kern, pair), u16_at(kern, pair + 2), i16_at(kern, pair + 4)) {
+                        (Some(left), Some(right), Some(amount)) => pairs.push((left, right, amount)),
+                        _ => break
+                    }
+                }
+            }
+
+            offset += len;
+        }
+
+        pairs
+    }
+
+    // The outline flattened into lines, scaled from font units to pixels
+    pub fn outline(&self, glyph: u16, scale: f32) -> Result<Vec<Line>, &'static str> {
+        let mut lines = Vec::new();
+
+        self.add_outline(glyph, [scale, 0.0, 0.0, scale, 0.0, 0.0], 0, &mut lines)
+            .ok_or("a glyph outline is broken")?;
+
+        Ok(lines)
+    }
+
+    fn glyph_data(&self, glyph: u16) -> Option<&[u8]> {
+        let glyph = glyph as usize;
+
+        let (start, end) = match self.long_loca {
+            true => (u32_at(self.loca, glyph * 4)? as usize, u32_at(self.loca, glyph * 4 + 4)? as usize),
+            false => (u16_at(self.loca, glyph * 2)? as usize * 2, u16_at(self.loca, glyph * 2 + 2)? as usize * 2)
+        };
+
+        match start < end {
+            true => self.glyf.get(start..end),
+            false => Some(&[]) // No outline, E.G a space
+        }
+    }
+
+    // transform is a b c d e f, x' = a * x + c * y + e and y' = b * x + d * y + f
+    fn add_outline(&self, glyph: u16, transform: [f32; 6], depth: u32, lines: &mut Vec<Line>) -> Option<()> {
+        let data = self.glyph_data(glyph)?;
+        if data.is_empty() {
+            return Some(());
+        }
+
+        let contours = i16_at(data, 0)?;
+        if contours >= 0 {
+            return self.add_simple_outline(data, contours as usize, transform, lines);
+        }
+
+        if depth >= MAX_COMPONENT_DEPTH {
+            return None;
+        }
+
+        let mut offset = 10;
+        loop {
+            let flags = u16_at(data, offset)?;
+            let component = u16_at(data, offset + 2)?;
+            offset += 4;
+
+            // Point matching (args aren't offsets) is treated as no offset at all
+            let (dx, dy) = match flags & 1 {
+                1 => (i16_at(data, offset)? as f32, i16_at(data, offset + 2)? as f32),
+                _ => (*data.get(offset)? as i8 as f32, *data.get(offset + 1)? as i8 as f32)
+            };
+            let (dx, dy) = if flags & 2 != 0 { (dx, dy) } else { (0.0, 0.0) };
+            offset += if flags & 1 != 0 { 4 } else { 2 };
+
+            let (a, b, c, d) = if flags & 0x8 != 0 {
+                let scale = f2dot14_at(data, offset)?;
+                offset += 2;
+                (scale, 0.0, 0.0, scale)
+            } else if flags & 0x40 != 0 {
+                offset += 4;
+                (f2dot14_at(data, offset - 4)?, 0.0, 0.0, f2dot14_at(data, offset - 2)?)
+            } else if flags & 0x80 != 0 {
+                offset += 8;
+                (f2dot14_at(data, offset - 8)?, f2dot14_at(data, offset - 6)?, f2dot14_at(data, offset - 4)?, f2dot14_at(data, offset - 2)?)
+            } else {
+                (1.0, 0.0, 0.0, 1.0)
+            };
+
+            let [pa, pb, pc, pd, pe, pf] = transform;
+            let combined = [
+                pa * a + pc * b,
+                pb * a + pd * b,
+                pa * c + pc * d,
+                pb * c + pd * d,
+                pa * dx + pc * dy + pe,
+                pb * dx + pd * dy + pf
+            ];
+
+            self.add_outline(component, combined, depth + 1, lines)?;
+
+            // More components follow
+            if flags & 0x20 == 0 {
+                return Some(());
+            }
+        }
+    }
+
+    fn add_simple_outline(&self, data: &[u8], contours: usize, transform: [f32; 6], lines: &mut Vec<Line>) -> Option<()> {
+        let mut ends = Vec::with_capacity(contours);
+        for i in 0..contours {
+            ends.push(u16_at(data, 10 + i * 2)? as usize);
+        }
+
+        let point_count = match ends.last() {
+            Some(last) => last + 1,
+            None => return Some(())
+        };
+
+        let mut offset = 12 + contours * 2 + u16_at(data, 10 + contours * 2)? as usize; // Skips the instructions
+
+        let mut flags = Vec::with_capacity(point_count);
+        while flags.len() < point_count {
+            let flag = *data.get(offset)?;
+            offset += 1;
+            flags.push(flag);
+
+            // Repeated
+            if flag & 8 != 0 {
+                for _ in 0..*data.get(offset)? {
+                    flags.push(flag);
+                }
+                offset += 1;
+            }
+        }
+        flags.truncate(point_count);
+
+        // X and y are stored as deltas, either one byte with the sign in the flags or two bytes
+        let mut read_coordinates = |short: u8, same: u8| -> Option<Vec<f32>> {
+            let mut value = 0i32;
+            let mut coordinates = Vec::with_capacity(point_count);
+
+            for flag in &flags {
+                if flag & short != 0 {
+                    let delta = *data.get(offset)? as i32;
+                    offset += 1;
+                    value += if flag & same != 0 { delta } else { -delta };
+                } else if flag & same == 0 {
+                    value += i16_at(data, offset)? as i32;
+                    offset += 2;
+                }
+
+                coordinates.push(value as f32);
+            }
+
+            Some(coordinates)
+        };
+
+        let xs = read_coordinates(0x2, 0x10)?;
+        let ys = read_coordinates(0x4, 0x20)?;
+
+        let [a, b, c, d, e, f] = transform;
+        let points = xs.iter().zip(&ys).zip(&flags)
+            .map(|((x, y), flag)| ([a * x + c * y + e, b * x + d * y + f], flag & 1 != 0))
+            .collect::<Vec<_>>();
+
+        let mut start = 0;
+        for end in ends {
+            if end < start || end >= points.len() {
+                return None;
+            }
+
+            add_contour(&points[start..=end], lines);
+            start = end + 1;
+        }
+
+        Some(())
+    }
+}
+
+// Two off curve points in a row have an implied on curve point in the middle
+fn add_contour(points: &[([f32; 2], bool)], lines: &mut Vec<Line>) {
+    let n = points.len();
+    if n < 2 {
+        return;
+    }
+
+    let mid = |a: [f32; 2], b: [f32; 2]| [(a[0] + b[0]) / 2.0, (a[1] + b[1]) / 2.0];
+
+    // Start on a point that is on the curve
+    let (first, start) = match points.iter().position(|(_, on_curve)| *on_curve) {
+        Some(i) => (i, points[i].0),
+        None => (0, mid(points[0].0, points[1].0))
+    };
+
+    let mut current = start;
+    let mut control: Option<[f32; 2]> = None;
+    for i in 1..=n {
+        let (point, on_curve) = points[(first + i) % n];
+
+        match (on_curve, control) {
+            (true, None) => {
+                lines.push([current[0], current[1], point[0], point[1]]);
+                current = point;
+            }
+            (true, Some(c)) => {
+                add_quad(current, c, point, lines);
+                current = point;
+                control = None;
+            }
+            (false, None) => control = Some(point),
+            (false, Some(c)) => {
+                let to = mid(c, point);
+                add_quad(current, c, to, lines);
+                current = to;
+                control = Some(point);
+            }
+        }
+    }
+
+    // Without an on curve point the loop ends on the control point the start is derived from
+    match control {
+        Some(c) => add_quad(current, c, start, lines),
+        None if current != start => lines.push([current[0], current[1], start[0], start[1]]),
+        None => {}
+    }
+}
+
+fn add_quad(p0: [f32; 2], p1: [f32; 2], p2: [f32; 2], lines: &mut Vec<Line>) {
+    // How far the curve bends away from the straight line
+    let dx = p0[0] - 2.0 * p1[0] + p2[0];
+    let dy = p0[1] - 2.0 * p1[1] + p2[1];
+    let deviation = (dx * dx + dy * dy).sqrt();
+
+    let steps = std::cmp::max(((deviation / (8.0 * FLATNESS)).sqrt().ceil()) as usize, 1);
+
+    let mut from = p0;
+    for i in 1..=steps {
+        let t = i as f32 / steps as f32;
+        let u = 1.0 - t;
+        let to = [
+            u * u * p0[0] + 2.0 * u * t * p1[0] + t * t * p2[0],
+            u * u * p0[1] + 2.0 * u * t * p1[1] + t * t * p2[1]
+        ];
+
+        lines.push([from[0], from[1], to[0], to[1]]);
+        from = to;
+    }
+}
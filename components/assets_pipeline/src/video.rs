@@ -0,0 +1,555 @@
+// Videos for intros and splash screens. A .video file describes where the frames and the
+// sound come from, the frames are compressed one by one so a VideoDecoder only ever holds the
+// frame it shows. graphics_engine::VideoPlayer uploads them and keeps them in sync with the sound.
+//
+// Example: intro/intro.video
+//   frames = "intro.frames"             # Folder of PNG/JPG/BMP frames played in name order,
+//                                       # or an animated GIF/APNG with its own frame timing
+//   fps = 30.0                          # For folders, defaults to 30
+//   audio = "intro.frames/intro.ogg"    # Optional, ogg, mp3, wav or flac
+//   keyframe_interval = 30              # Optional, seeking decodes from the keyframe before
+//   looping = false
+//
+// Paths are relative to the .video file. Folders ending in .frames aren't compiled on their own,
+// that's where frames and sounds of a video should live.
+//
+// Video Entry Layout:
+//   Magic          "PXV1"
+//   Width          u32
+//   Height         u32
+//   Duration       u32 (ms)
+//   Looping        u8
+//   Frames         [Frame] { Time u32 (ms), Keyframe u8, Data [u8] }
+//   Audio          [u8] (sound file with its AudioInfo header, empty if there is none)
+//
+// Frame data is LZ4 compressed RGBA8, frames that aren't keyframes are XORed with the frame
+// before them first, so whatever didn't change compresses to nothing.
+
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use audio_engine::{AudioInfo, AUDIO_HEADER_SIZE};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use image::RgbaImage;
+use rayon::prelude::*;
+use serde::Deserialize;
+
+use crate::{audio_import, shader, AnimatedTexture, AssetDatabaseError, AssetEntry, AssetPipeline, Codec, CompileOptions};
+
+pub const VIDEO_EXTENSION: &str = "video";
+pub const FRAMES_EXTENSION: &str = "frames";
+
+const VIDEO_MAGIC: &[u8; 4] = b"PXV1";
+
+const MAX_SIZE: u32 = 8192;
+const MAX_FRAMES: u32 = 0x100000;
+
+pub struct VideoFrame {
+    pub time_ms: u32, // When it's shown
+    pub keyframe: bool,
+    data: Vec<u8>
+}
+
+pub struct VideoData {
+    pub width: u32,
+    pub height: u32,
+    pub duration_ms: u32,
+    pub looping: bool,
+    pub frames: Vec<VideoFrame>,
+    pub audio: Option<Vec<u8>> // For AudioSystem::from_memory()
+}
+
+impl VideoData {
+    // Index of the frame that is visible after the video played for elapsed
+    pub fn frame_index_at(&self, elapsed: Duration) -> usize {
+        let mut time = elapsed.as_millis() as u64;
+        if self.looping && self.duration_ms > 0 {
+            time %= self.duration_ms as u64;
+        }
+
+        match self.frames.binary_search_by(|frame| (frame.time_ms as u64).cmp(&time)) {
+            Ok(i) => i,
+            Err(i) => i.saturating_sub(1)
+        }
+    }
+
+    pub fn is_finished(&self, elapsed: Duration) -> bool {
+        !self.looping && elapsed.as_millis() >= self.duration_ms as u128
+    }
+
+    // What the pipeline measured while importing the sound
+    pub fn audio_info(&self) -> Option<AudioInfo> {
+        self.audio.as_ref().and_then(|audio| AudioInfo::read_from(audio))
+    }
+
+    // The sound file as it was imported (or transcoded), without the audio header
+    pub fn audio_file(&self) -> Option<&[u8]> {
+        let audio = self.audio.as_ref()?;
+
+        match AudioInfo::read_from(audio) {
+            Some(_) => Some(&audio[AUDIO_HEADER_SIZE..]),
+            None => Some(audio)
+        }
+    }
+
+    // Frames are (image, time in ms), sorted by time. Every keyframe_interval frames there's a keyframe,
+    // in between as well if the frame changed so much that a keyframe is smaller.
+    pub(crate) fn encode<F>(key: &str, width: u32, height: u32, frame_count: usize, keyframe_interval: usize, load_frame: F) -> Result<Vec<VideoFrame>, AssetDatabaseError>
+        where F: Fn(usize) -> Result<(RgbaImage, u32), AssetDatabaseError> + Sync
+    {
+        let keyframe_interval = std::cmp::max(keyframe_interval, 1);
+        let chunks = (0..frame_count).step_by(keyframe_interval).collect::<Vec<_>>();
+
+        // Every chunk starts with a keyframe, so they don't depend on each other
+        let chunks = chunks.par_iter()
+            .map(|start| {
+                let mut frames = Vec::new();
+                let mut previous: Option<RgbaImage> = None;
+
+                for i in *start..std::cmp::min(start + keyframe_interval, frame_count) {
+                    let (image, time_ms) = load_frame(i)?;
+                    if image.dimensions() != (width, height) {
+                        log::error!("Frame {} of {} is {}x{}, the video is {}x{}", i, key, image.width(), image.height(), width, height);
+                        return Err(AssetDatabaseError::DecodeFailed { key: key.to_string() });
+                    }
+
+                    let keyframe = Codec::Lz4.compress(&image, None)?;
+                    let frame = match &previous {
+                        Some(previous) => {
+                            let delta = image.iter().zip(previous.iter())
+                                .map(|(a, b)| a ^ b)
+                                .collect::<Vec<_>>();
+                            let delta = Codec::Lz4.compress(&delta, None)?;
+
+                            match delta.len() < keyframe.len() {
+                                true => VideoFrame { time_ms, keyframe: false, data: delta },
+                                false => VideoFrame { time_ms, keyframe: true, data: keyframe }
+                            }
+                        }
+
+                        None => VideoFrame { time_ms, keyframe: true, data: keyframe }
+                    };
+
+                    frames.push(frame);
+                    previous = Some(image);
+                }
+
+                Ok(frames)
+            })
+            .collect::<Result<Vec<_>, AssetDatabaseError>>()?;
+
+        Ok(chunks.into_iter().flatten().collect())
+    }
+
+    pub(crate) fn write_to(&self, w: &mut Vec<u8>) -> io::Result<()> {
+        w.write_all(VIDEO_MAGIC)?;
+        w.write_u32::<LittleEndian>(self.width)?;
+        w.write_u32::<LittleEndian>(self.height)?;
+        w.write_u32::<LittleEndian>(self.duration_ms)?;
+        w.write_u8(self.looping as u8)?;
+
+        w.write_u32::<LittleEndian>(self.frames.len() as u32)?;
+        for frame in &self.frames {
+            w.write_u32::<LittleEndian>(frame.time_ms)?;
+            w.write_u8(frame.keyframe as u8)?;
+            w.write_u32::<LittleEndian>(frame.data.len() as u32)?;
+            w.write_all(&frame.data)?;
+        }
+
+        let audio = self.audio.as_deref().unwrap_or(&[]);
+        w.write_u32::<LittleEndian>(audio.len() as u32)?;
+        w.write_all(audio)
+    }
+
+    pub(crate) fn read_from(data: &[u8]) -> io::Result<VideoData> {
+        let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        if !data.starts_with(VIDEO_MAGIC) {
+            return Err(invalid("not a video"));
+        }
+
+        let mut r = Cursor::new(data);
+        r.set_position(VIDEO_MAGIC.len() as u64);
+
+        let width = r.read_u32::<LittleEndian>()?;
+        let height = r.read_u32::<LittleEndian>()?;
+        let duration_ms = r.read_u32::<LittleEndian>()?;
+        let looping = r.read_u8()? != 0;
+
+        if width == 0 || height == 0 || width > MAX_SIZE || height > MAX_SIZE {
+            return Err(invalid("invalid video size"));
+        }
+
+        let frame_count = r.read_u32::<LittleEndian>()?;
+        if frame_count == 0 || frame_count > MAX_FRAMES {
+            return Err(invalid("invalid frame count"));
+        }
+
+        let read_data = |r: &mut Cursor<&[u8]>| -> io::Result<Vec<u8>> {
+            let len = r.read_u32::<LittleEndian>()? as usize;
+            if len > data.len() {
+                return Err(invalid("frame is larger than the video"));
+            }
+
+            let mut buf = vec![0x00; len];
+            r.read_exact(&mut buf)?;
+
+            Ok(buf)
+        };
+
+        let mut frames = Vec::with_capacity(frame_count as usize);
+        for _ in 0..frame_count {
+            let time_ms = r.read_u32::<LittleEndian>()?;
+            let keyframe = r.read_u8()? != 0;
+            let data = read_data(&mut r)?;
+
+            frames.push(VideoFrame { time_ms, keyframe, data });
+        }
+
+        if !frames[0].keyframe || frames.windows(2).any(|pair| pair[0].time_ms > pair[1].time_ms) {
+            return Err(invalid("video doesn't start with a keyframe or isn't sorted"));
+        }
+
+        let audio = read_data(&mut r)?;
+
+        Ok(VideoData {
+            width,
+            height,
+            duration_ms,
+            looping,
+            frames,
+            audio: if audio.is_empty() { None } else { Some(audio) }
+        })
+    }
+}
+
+// Turns the frames of a video back into images, decoding forwards from the last keyframe
+pub struct VideoDecoder {
+    key: String, // Of the entry the video is from, for errors
+    video: VideoData,
+    frame: Option<usize>, // Decoded into image
+    image: RgbaImage
+}
+
+impl VideoDecoder {
+    pub fn new<S: AsRef<str>>(key: S, video: VideoData) -> VideoDecoder {
+        let image = RgbaImage::new(video.width, video.height);
+
+        VideoDecoder {
+            key: key.as_ref().to_string(),
+            video,
+            frame: None,
+            image
+        }
+    }
+
+    pub fn video(&self) -> &VideoData {
+        &self.video
+    }
+
+    // The frame image() holds, None before the first one was decoded
+    pub fn frame(&self) -> Option<usize> {
+        self.frame
+    }
+
+    pub fn image(&self) -> &RgbaImage {
+        &self.image
+    }
+
+    pub fn decode(&mut self, index: usize) -> Result<&RgbaImage, AssetDatabaseError> {
+        let index = std::cmp::min(index, self.video.frames.len() - 1);
+
+        // Going on from the current frame is cheaper unless there's a keyframe on the way
+        let start = match self.frame {
+            Some(current) if current <= index && !self.video.frames[current + 1..=index].iter().any(|frame| frame.keyframe) => current + 1,
+            _ => (0..=index).rev().find(|i| self.video.frames[*i].keyframe).unwrap_or(0)
+        };
+
+        for i in start..=index {
+            self.apply(i)?;
+        }
+
+        Ok(&self.image)
+    }
+
+    // The frame visible at elapsed, None if that's the frame decoded last
+    pub fn decode_at(&mut self, elapsed: Duration) -> Result<Option<&RgbaImage>, AssetDatabaseError> {
+        let index = self.video.frame_index_at(elapsed);
+        if self.frame == Some(index) {
+            return Ok(None);
+        }
+
+        self.decode(index).map(Some)
+    }
+
+    // The frame after the one decoded last with the time it's shown at, None after the last frame
+    pub fn next_frame(&mut self) -> Result<Option<(Duration, &RgbaImage)>, AssetDatabaseError> {
+        let index = self.frame.map_or(0, |frame| frame + 1);
+        if index >= self.video.frames.len() {
+            return Ok(None);
+        }
+
+        let time = Duration::from_millis(self.video.frames[index].time_ms as u64);
+
+        Ok(Some((time, self.decode(index)?)))
+    }
+
+    fn apply(&mut self, i: usize) -> Result<(), AssetDatabaseError> {
+        let frame = &self.video.frames[i];
+        let pixels = Codec::Lz4.decompress(&frame.data, None)
+            .map_err(|_| AssetDatabaseError::DecodeFailed { key: self.key.clone() })?;
+
+        if pixels.len() != self.image.len() {
+            return Err(AssetDatabaseError::DecodeFailed { key: self.key.clone() });
+        }
+
+        if frame.keyframe {
+            self.image.copy_from_slice(&pixels);
+        } else {
+            for (pixel, delta) in self.image.iter_mut().zip(pixels.iter()) {
+                *pixel ^= delta;
+            }
+        }
+
+        self.frame = Some(i);
+
+        Ok(())
+    }
+}
+
+// Contents of a .video file
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VideoDescription {
+    frames: PathBuf,
+    #[serde(default = "VideoDescription::default_fps")]
+    fps: f32,
+    audio: Option<PathBuf>,
+    #[serde(default = "VideoDescription::default_keyframe_interval")]
+    keyframe_interval: usize,
+    #[serde(default)]
+    looping: bool
+}
+
+impl VideoDescription {
+    fn default_fps() -> f32 { 30.0 }
+    fn default_keyframe_interval() -> usize { 30 }
+
+    fn load(path: &Path) -> Result<VideoDescription, AssetDatabaseError> {
+        let invalid = |message: String| AssetDatabaseError::ParseFailed { path: path.to_path_buf(), message };
+
+        let content = std::fs::read_to_string(path)?;
+        let description: VideoDescription = toml::from_str(&content)
+            .map_err(|err| invalid(err.to_string()))?;
+
+        if !(description.fps > 0.0 && description.fps <= 240.0) {
+            return Err(invalid("fps must be between 0 and 240".to_string()));
+        }
+
+        Ok(description)
+    }
+
+    // Frame files of a folder sorted by name, empty if frames is a single file
+    fn frame_files(&self, folder: &Path) -> Result<Vec<PathBuf>, AssetDatabaseError> {
+        let frames = folder.join(&self.frames);
+        if !frames.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&frames)? {
+            let path = entry?.path();
+            let extension = path.extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| ext.to_lowercase());
+
+            if matches!(extension.as_deref(), Some("png") | Some("jpg") | Some("bmp")) {
+                files.push(path);
+            }
+        }
+
+        files.sort();
+
+        Ok(files)
+    }
+}
+
+// Everything a .video file compiles from besides itself
+pub(crate) fn inputs(path: &Path) -> Result<Vec<PathBuf>, AssetDatabaseError> {
+    let description = VideoDescription::load(path)?;
+    let folder = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut inputs = description.frame_files(folder)?;
+    if inputs.is_empty() {
+        inputs.push(folder.join(&description.frames));
+    }
+
+    if let Some(audio) = &description.audio {
+        inputs.push(folder.join(audio));
+    }
+
+    Ok(inputs)
+}
+
+// True for files inside a .frames folder below root
+pub(crate) fn is_frame_source(root: &Path, path: &Path) -> bool {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+
+    match relative.parent() {
+        Some(parent) => parent.components().any(|component| {
+            Path::new(component.as_os_str()).extension().and_then(|ext| ext.to_str()) == Some(FRAMES_EXTENSION)
+        }),
+        None => false
+    }
+}
+
+// Inputs below root are recorded as includes of the entry
+pub(crate) fn import(root: &Path, key: &str, path: &Path, options: &CompileOptions) -> Result<AssetEntry, AssetDatabaseError> {
+    let description = VideoDescription::load(path)?;
+    let folder = path.parent().unwrap_or_else(|| Path::new(""));
+    let decode_failed = |_| AssetDatabaseError::DecodeFailed { key: key.to_string() };
+
+    let files = description.frame_files(folder)?;
+    let (width, height, duration_ms, frames) = if !files.is_empty() {
+        let (width, height) = image::image_dimensions(&files[0]).map_err(decode_failed)?;
+        let frame_ms = 1000.0 / description.fps as f64;
+
+        let frames = VideoData::encode(key, width, height, files.len(), description.keyframe_interval, |i| {
+            let image = image::open(&files[i]).map_err(decode_failed)?.into_rgba();
+
+            Ok((image, (i as f64 * frame_ms).round() as u32))
+        })?;
+
+        (width, height, (files.len() as f64 * frame_ms).round() as u32, frames)
+    } else {
+        let animation = import_animation(key, &folder.join(&description.frames))?;
+
+        let mut times = Vec::with_capacity(animation.frames.len());
+        let mut time = 0;
+        for frame in &animation.frames {
+            times.push(time);
+            time += frame.duration_ms;
+        }
+
+        let frames = VideoData::encode(key, animation.width, animation.height, animation.frames.len(), description.keyframe_interval, |i| {
+            Ok((animation.frames[i].image.clone(), times[i]))
+        })?;
+
+        (animation.width, animation.height, time, frames)
+    };
+
+    if frames.is_empty() {
+        return Err(AssetDatabaseError::ParseFailed { path: path.to_path_buf(), message: "the video has no frames".to_string() });
+    }
+
+    // Imported like any other sound, so it's normalized and transcoded the same way
+    let audio = match &description.audio {
+        Some(audio) => Some(audio_import::import(key, &folder.join(audio), options)?.data),
+        None => None
+    };
+
+    let video = VideoData {
+        width,
+        height,
+        duration_ms,
+        looping: description.looping,
+        frames,
+        audio
+    };
+
+    let mut entry = AssetEntry::from_video(key, &video);
+    for input in inputs(path)? {
+        let input = shader::normalize(&input);
+        if input.starts_with(root) {
+            entry.includes.push(AssetPipeline::key_from_path(root, input)?);
+        }
+    }
+
+    Ok(entry)
+}
+
+// GIFs and APNGs, single frame files are a video with one frame
+fn import_animation(key: &str, path: &Path) -> Result<AnimatedTexture, AssetDatabaseError> {
+    if let Some(animation) = AnimatedTexture::import(key, path)? {
+        return Ok(animation);
+    }
+
+    let image = image::open(path)
+        .map_err(|_| AssetDatabaseError::DecodeFailed { key: key.to_string() })?
+        .into_rgba();
+
+    let mut animation = AnimatedTexture::new(image.width(), image.height(), Default::default());
+    animation.push_frame(image, 0);
+
+    Ok(animation)
+}
+
+#[test]
+fn video_test() {
+    // A block moving over a gradient, with a cut to black at frame 5
+    let frame = |i: usize| {
+        let image = RgbaImage::from_fn(32, 16, |x, y| match i {
+            5..=9 => image::Rgba([0, 0, 0, 255]),
+            _ if x / 4 == i as u32 && y < 4 => image::Rgba([255, 0, 0, 255]),
+            _ => image::Rgba([x as u8 * 8, y as u8 * 16, 0, 255])
+        });
+
+        (image, i as u32 * 40)
+    };
+
+    let frames = VideoData::encode("intro.video", 32, 16, 10, 4, |i| Ok(frame(i))).unwrap();
+    let keyframes = frames.iter().map(|frame| frame.keyframe).collect::<Vec<_>>();
+    assert_eq!(keyframes, vec![true, false, false, false, true, true, false, false, true, false]);
+
+    let video = VideoData {
+        width: 32,
+        height: 16,
+        duration_ms: 400,
+        looping: false,
+        frames,
+        audio: Some(vec![1, 2, 3])
+    };
+
+    let mut data = Vec::new();
+    video.write_to(&mut data).unwrap();
+    let video = VideoData::read_from(&data).unwrap();
+
+    assert_eq!(video.frame_index_at(Duration::from_millis(0)), 0);
+    assert_eq!(video.frame_index_at(Duration::from_millis(79)), 1);
+    assert_eq!(video.frame_index_at(Duration::from_millis(80)), 2);
+    assert_eq!(video.frame_index_at(Duration::from_millis(1000)), 9);
+    assert!(video.is_finished(Duration::from_millis(400)));
+    assert_eq!(video.audio, Some(vec![1, 2, 3]));
+
+    let mut decoder = VideoDecoder::new("intro.video", video);
+    for i in 0..10 {
+        let (time, image) = decoder.next_frame().unwrap().unwrap();
+
+        assert_eq!(time, Duration::from_millis(i as u64 * 40));
+        assert_eq!(*image, frame(i).0);
+    }
+    assert!(decoder.next_frame().unwrap().is_none());
+
+    // Seeking backwards and over keyframes
+    assert_eq!(*decoder.decode(3).unwrap(), frame(3).0);
+    assert_eq!(*decoder.decode(2).unwrap(), frame(2).0);
+    assert_eq!(*decoder.decode(7).unwrap(), frame(7).0);
+    assert!(decoder.decode_at(Duration::from_millis(290)).unwrap().is_none());
+    assert_eq!(*decoder.decode_at(Duration::from_millis(40)).unwrap().unwrap(), frame(1).0);
+
+    // Frames claiming more than an entry can hold aren't decompressed
+    let mut broken = VideoData::read_from(&data).unwrap();
+    broken.frames[2].data[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+    let mut decoder = VideoDecoder::new("intro.video", broken);
+    assert_eq!(*decoder.decode(1).unwrap(), frame(1).0);
+    assert!(matches!(decoder.decode(2), Err(AssetDatabaseError::DecodeFailed { key }) if key == "intro.video"));
+
+    // So are frames of the wrong size
+    let result = VideoData::encode("intro.video", 32, 16, 2, 4, |i| Ok((RgbaImage::new(32, 16 + i as u32), 0)));
+    assert!(matches!(result, Err(AssetDatabaseError::DecodeFailed { .. })));
+
+    let root = Path::new("assets");
+    assert!(is_frame_source(root, Path::new("assets/intro/intro.frames/0001.png")));
+    assert!(!is_frame_source(root, Path::new("assets/intro/intro.png")));
+}
@@ -0,0 +1,152 @@
+// RIFF WAVE files for the audio importer. Reads integer PCM (8 - 32 bit) and 32 bit float,
+// writes 16 or 24 bit PCM.
+
+use std::convert::TryInto;
+
+use crate::audio_import::Pcm;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe; // The actual format is the start of the sub format GUID
+
+// The samples and the first loop of the smpl chunk. 8 bit samples are widened to 16 bit,
+// 32 bit ones (integer and float) are narrowed to 24 bit.
+pub(crate) fn decode(data: &[u8]) -> Option<(Pcm, Option<(u32, u32)>)> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return None;
+    }
+
+    let u16_at = |chunk: &[u8], offset: usize| chunk.get(offset..offset + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+    let u32_at = |chunk: &[u8], offset: usize| chunk.get(offset..offset + 4).map(|b| u32::from_le_bytes(b.try_into().unwrap()));
+
+    let mut format = None;
+    let mut samples = None;
+    let mut loop_points = None;
+
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let size = u32_at(data, offset + 4)? as usize;
+
+        // Some writers don't fix up the size of the data chunk if they're cut off
+        let chunk = &data[offset + 8..std::cmp::min(offset + 8 + size, data.len())];
+
+        match &data[offset..offset + 4] {
+            b"fmt " => {
+                let mut tag = u16_at(chunk, 0)?;
+                if tag == WAVE_FORMAT_EXTENSIBLE {
+                    tag = u16_at(chunk, 24)?;
+                }
+
+                format = Some((tag, u16_at(chunk, 2)?, u32_at(chunk, 4)?, u16_at(chunk, 14)?));
+            }
+
+            b"data" => samples = Some(chunk),
+
+            // The loop end is inclusive
+            b"smpl" if u32_at(chunk, 28)? > 0 => {
+                loop_points = Some((u32_at(chunk, 44)?, u32_at(chunk, 48)?.saturating_add(1)));
+            }
+
+            _ => {}
+        }
+
+        // Chunks are padded to an even size
+        offset += 8 + size + (size & 1);
+    }
+
+    let (tag, channels, sample_rate, bits) = format?;
+    let data = samples?;
+    if channels == 0 {
+        return None;
+    }
+
+    let (bits, mut samples): (u8, Vec<i32>) = match (tag, bits) {
+        (WAVE_FORMAT_PCM, 8) => (16, data.iter().map(|s| (*s as i32 - 128) << 8).collect()),
+        (WAVE_FORMAT_PCM, 16) => (16, data.chunks_exact(2).map(|s| i16::from_le_bytes([s[0], s[1]]) as i32).collect()),
+        (WAVE_FORMAT_PCM, 24) => (24, data.chunks_exact(3).map(|s| i32::from_le_bytes([0, s[0], s[1], s[2]]) >> 8).collect()),
+        (WAVE_FORMAT_PCM, 32) => (24, data.chunks_exact(4).map(|s| i32::from_le_bytes(s.try_into().unwrap()) >> 8).collect()),
+
+        (WAVE_FORMAT_IEEE_FLOAT, 32) => (24, data.chunks_exact(4)
+            .map(|s| (f32::from_le_bytes(s.try_into().unwrap()).max(-1.0).min(1.0) * 8388607.0).round() as i32)
+            .collect()),
+
+        _ => return None
+    };
+
+    // Drop a cut off frame at the end
+    samples.truncate(samples.len() / channels as usize * channels as usize);
+
+    let pcm = Pcm {
+        channels,
+        sample_rate,
+        bits,
+        samples
+    };
+
+    let loop_points = loop_points.filter(|(start, end)| start < end && *end as usize <= pcm.frames());
+
+    Some((pcm, loop_points))
+}
+
+pub(crate) fn encode(pcm: &Pcm) -> Vec<u8> {
+    let bits = if pcm.bits <= 16 { 16 } else { 24 };
+    let bytes = bits as usize / 8;
+    let shift = bits - pcm.bits as u32;
+
+    let data_size = pcm.samples.len() * bytes;
+    let padding = data_size & 1;
+
+    let mut data = Vec::with_capacity(44 + data_size + padding);
+    data.extend_from_slice(b"RIFF");
+    data.extend_from_slice(&((36 + data_size + padding) as u32).to_le_bytes());
+    data.extend_from_slice(b"WAVE");
+
+    data.extend_from_slice(b"fmt ");
+    data.extend_from_slice(&16u32.to_le_bytes());
+    data.extend_from_slice(&WAVE_FORMAT_PCM.to_le_bytes());
+    data.extend_from_slice(&pcm.channels.to_le_bytes());
+    data.extend_from_slice(&pcm.sample_rate.to_le_bytes());
+    data.extend_from_slice(&(pcm.sample_rate * pcm.channels as u32 * bytes as u32).to_le_bytes());
+    data.extend_from_slice(&(pcm.channels * bytes as u16).to_le_bytes());
+    data.extend_from_slice(&(bits as u16).to_le_bytes());
+
+    data.extend_from_slice(b"data");
+    data.extend_from_slice(&(data_size as u32).to_le_bytes());
+    for sample in &pcm.samples {
+        data.extend_from_slice(&(sample << shift).to_le_bytes()[..bytes]);
+    }
+
+    data.resize(data.len() + padding, 0);
+    data
+}
+
+#[test]
+fn wav_test() {
+    let pcm = Pcm {
+        channels: 2,
+        sample_rate: 22050,
+        bits: 24,
+        samples: vec![0, -1, 8388607, -8388608, 1234, -5678, 42]
+    };
+
+    // The cut off frame is dropped
+    let (decoded, loop_points) = decode(&encode(&pcm)).unwrap();
+    assert_eq!((decoded.channels, decoded.sample_rate, decoded.bits), (2, 22050, 24));
+    assert_eq!(decoded.samples, &pcm.samples[..6]);
+    assert_eq!(loop_points, None);
+
+    // 8 bit is unsigned, with a smpl chunk looping 1 - 2
+    let mut data = b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0\x01\0\x01\0\x44\xac\0\0\x44\xac\0\0\x01\0\x08\0data\x03\0\0\0\x80\xff\x00\0smpl\x3c\0\0\0".to_vec();
+    let mut smpl = vec![0; 60];
+    smpl[28] = 1;
+    smpl[44] = 1;
+    smpl[48] = 1;
+    data.extend_from_slice(&smpl);
+
+    let (decoded, loop_points) = decode(&data).unwrap();
+    assert_eq!((decoded.channels, decoded.sample_rate, decoded.bits), (1, 44100, 16));
+    assert_eq!(decoded.samples, vec![0, 127 << 8, -128 << 8]);
+    assert_eq!(loop_points, Some((1, 2)));
+
+    assert!(decode(b"RIFF\0\0\0\0WAVE").is_none());
+}
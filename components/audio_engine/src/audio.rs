@@ -1,5 +1,6 @@
 #[cfg(feature = "audio_fmod")]
 use crate::fmod_sys::*;
+use crate::AudioInfo;
 use std::ptr::{null, null_mut};
 
 #[allow(dead_code)]
@@ -17,7 +18,9 @@ pub struct Audio {
     pub(crate) audio_data: Vec<u8>, // To keep it alive
 
     #[cfg(feature = "audio_fmod")]
-    pub(crate) default_frequency: f32
+    pub(crate) default_frequency: f32,
+
+    pub(crate) info: Option<AudioInfo> // None if it wasn't imported by the asset pipeline
 }
 
 impl Audio {
@@ -35,6 +38,8 @@ impl Audio {
                 FMOD_Channel_GetFrequency(self.channel, &mut self.default_frequency);
             }
         }
+
+        self.set_volume(1.0);
     }
 
     pub fn play(&mut self) {
@@ -47,6 +52,8 @@ impl Audio {
                     null::<FMOD_CHANNELGROUP>() as _,
                     1,
                     &mut self.channel);
+
+                self.set_volume(1.0);
             }
 
             FMOD_Channel_SetPaused(self.channel, 0);
@@ -83,6 +90,7 @@ impl Audio {
         }
     }
 
+    // The gain the asset pipeline measured is applied on top
     pub fn set_volume(&mut self, volume: f32) {
         #[cfg(feature = "audio_fmod")]
         unsafe {
@@ -90,7 +98,9 @@ impl Audio {
                 return; // do nothing
             }
 
-            FMOD_Channel_SetVolume(self.channel, volume);
+            let gain = self.info.as_ref().map_or(1.0, |info| info.linear_gain());
+
+            FMOD_Channel_SetVolume(self.channel, volume * gain);
         }
     }
 
@@ -126,6 +136,40 @@ impl Audio {
         self.set_frequency_mul(1.0);
     }
 
+    // Milliseconds into the sound, 0 if it isn't playing
+    pub fn position(&self) -> u32 {
+        #[cfg(feature = "audio_fmod")]
+        unsafe {
+            let mut position = 0;
+
+            if !self.channel.is_null() {
+                FMOD_Channel_GetPosition(self.channel, &mut position, FMOD_TIMEUNIT_MS);
+            }
+
+            return position;
+        }
+
+        #[cfg(not(feature = "audio_fmod"))]
+        return 0;
+    }
+
+    // Paused sounds are still playing, sounds that reached their end or were stopped aren't
+    pub fn is_playing(&self) -> bool {
+        #[cfg(feature = "audio_fmod")]
+        unsafe {
+            let mut playing = 0;
+
+            if !self.channel.is_null() {
+                FMOD_Channel_IsPlaying(self.channel, &mut playing);
+            }
+
+            return playing != 0;
+        }
+
+        #[cfg(not(feature = "audio_fmod"))]
+        return false;
+    }
+
     pub fn len(&self) -> u32 {
         #[cfg(feature = "audio_fmod")]
         unsafe {
@@ -139,6 +183,19 @@ impl Audio {
         #[cfg(not(feature = "audio_fmod"))]
         return 0;
     }
+
+    pub fn info(&self) -> Option<&AudioInfo> {
+        self.info.as_ref()
+    }
+
+    // Bytes of the file we keep alive for FMOD, samples it decoded from that aren't included
+    pub fn memory_size(&self) -> usize {
+        #[cfg(feature = "audio_fmod")]
+        return self.audio_data.len();
+
+        #[cfg(not(feature = "audio_fmod"))]
+        return 0;
+    }
 }
 
 impl Drop for Audio {
@@ -0,0 +1,147 @@
+// What the asset pipeline measured while importing a sound, stored in front of the sound file.
+// AudioSystem::from_memory() strips it again and applies the gain and loop points.
+//
+// Audio Header Layout:
+//   Magic          "PXA1"
+//   Format         u8  (AudioFormat of the file after the header)
+//   Channels       u8
+//   Sample Rate    u32
+//   Length         u32 (samples per channel, 0 if unknown)
+//   Gain           f32 (dB, applied on top of the volume)
+//   Loop Start     u32 (samples)
+//   Loop End       u32 (samples, exclusive. 0 if the sound doesn't loop on its own)
+//
+// Everything is little endian. Sounds without the header are played as they are.
+
+use std::convert::TryInto;
+
+const AUDIO_MAGIC: &[u8; 4] = b"PXA1";
+
+pub const AUDIO_HEADER_SIZE: usize = 26;
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AudioFormat {
+    Unknown = 0,
+    Ogg = 1,
+    Mp3 = 2,
+    Wav = 3,
+    Flac = 4
+}
+
+impl AudioFormat {
+    pub fn from_u8(v: u8) -> AudioFormat {
+        match v {
+            1 => AudioFormat::Ogg,
+            2 => AudioFormat::Mp3,
+            3 => AudioFormat::Wav,
+            4 => AudioFormat::Flac,
+
+            _ => AudioFormat::Unknown
+        }
+    }
+
+    // From a file extension, case insensitive
+    pub fn from_extension(extension: &str) -> AudioFormat {
+        match extension.to_lowercase().as_str() {
+            "ogg" => AudioFormat::Ogg,
+            "mp3" => AudioFormat::Mp3,
+            "wav" => AudioFormat::Wav,
+            "flac" => AudioFormat::Flac,
+
+            _ => AudioFormat::Unknown
+        }
+    }
+
+    // Empty for Unknown
+    pub fn extension(self) -> &'static str {
+        match self {
+            AudioFormat::Ogg => "ogg",
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::Wav => "wav",
+            AudioFormat::Flac => "flac",
+
+            AudioFormat::Unknown => ""
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioInfo {
+    pub format: AudioFormat,
+    pub channels: u8,
+    pub sample_rate: u32,
+    pub length: u32,
+    pub gain: f32,
+    pub loop_points: Option<(u32, u32)> // Samples, start inclusive and end exclusive
+}
+
+impl AudioInfo {
+    pub fn duration_ms(&self) -> u32 {
+        match self.sample_rate {
+            0 => 0,
+            rate => (self.length as u64 * 1000 / rate as u64) as u32
+        }
+    }
+
+    // Volume multiplier for the gain
+    pub fn linear_gain(&self) -> f32 {
+        10f32.powf(self.gain / 20.0)
+    }
+
+    pub fn write_to(&self, w: &mut Vec<u8>) {
+        let (loop_start, loop_end) = self.loop_points.unwrap_or((0, 0));
+
+        w.extend_from_slice(AUDIO_MAGIC);
+        w.push(self.format as u8);
+        w.push(self.channels);
+        w.extend_from_slice(&self.sample_rate.to_le_bytes());
+        w.extend_from_slice(&self.length.to_le_bytes());
+        w.extend_from_slice(&self.gain.to_le_bytes());
+        w.extend_from_slice(&loop_start.to_le_bytes());
+        w.extend_from_slice(&loop_end.to_le_bytes());
+    }
+
+    // None if the data doesn't start with the header
+    pub fn read_from(data: &[u8]) -> Option<AudioInfo> {
+        if data.len() < AUDIO_HEADER_SIZE || !data.starts_with(AUDIO_MAGIC) {
+            return None;
+        }
+
+        let u32_at = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+
+        let (loop_start, loop_end) = (u32_at(18), u32_at(22));
+
+        Some(AudioInfo {
+            format: AudioFormat::from_u8(data[4]),
+            channels: data[5],
+            sample_rate: u32_at(6),
+            length: u32_at(10),
+            gain: f32::from_bits(u32_at(14)),
+            loop_points: if loop_end > loop_start { Some((loop_start, loop_end)) } else { None }
+        })
+    }
+}
+
+#[test]
+fn audio_info_test() {
+    let info = AudioInfo {
+        format: AudioFormat::Flac,
+        channels: 2,
+        sample_rate: 44100,
+        length: 88200,
+        gain: -6.0,
+        loop_points: Some((100, 88000))
+    };
+
+    let mut data = Vec::new();
+    info.write_to(&mut data);
+    assert_eq!(data.len(), AUDIO_HEADER_SIZE);
+
+    data.extend_from_slice(b"fLaC");
+    assert_eq!(AudioInfo::read_from(&data), Some(info.clone()));
+    assert_eq!(info.duration_ms(), 2000);
+    assert!((info.linear_gain() - 0.501).abs() < 0.001);
+
+    assert_eq!(AudioInfo::read_from(b"OggS"), None);
+}
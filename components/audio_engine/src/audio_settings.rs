@@ -0,0 +1,11 @@
+use serde::Deserialize;
+
+// How a sound is loaded and played, set per asset in its .meta file
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioSettings {
+    pub stream: bool,          // Decoded while playing instead of upfront, for music and other long sounds
+    pub looping: bool,
+    pub loop_start: u32,       // Milliseconds
+    pub loop_end: Option<u32>  // Milliseconds, None loops at the end of the sound
+}
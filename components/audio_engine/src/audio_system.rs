@@ -1,5 +1,5 @@
 use std::path::Path;
-use crate::Audio;
+use crate::{Audio, AudioInfo, AudioSettings, AUDIO_HEADER_SIZE};
 
 #[cfg(feature = "audio_fmod")]
 use crate::fmod_sys::*;
@@ -59,9 +59,17 @@ impl AudioSystem {
         self.from_memory(file)
     }
 
-    // TODO: implement
-    #[allow(dead_code, unused_variables)]
+    // Sounds imported by the asset pipeline start with an AudioInfo header,
+    // its gain is applied to the volume and its loop points are used unless the settings have their own.
     pub fn from_memory(&self, buf: Vec<u8>) -> Audio {
+        self.from_memory_with(buf, &AudioSettings::default())
+    }
+
+    #[allow(dead_code, unused_variables)]
+    pub fn from_memory_with(&self, buf: Vec<u8>, settings: &AudioSettings) -> Audio {
+        let info = AudioInfo::read_from(&buf);
+        let offset = if info.is_some() { AUDIO_HEADER_SIZE } else { 0 };
+
         #[cfg(feature = "audio_fmod")]
         let mut audio_ptr = std::ptr::null_mut();
 
@@ -71,15 +79,39 @@ impl AudioSystem {
             let mut sound_info = { sound_info.assume_init() };
 
             sound_info.cbsize = 224;
-            sound_info.length = buf.len() as u32;
+            sound_info.length = (buf.len() - offset) as u32;
 
-            let r = FMOD_System_CreateSound(self.sys, buf.as_ptr() as *const i8,
-                                            FMOD_OPENMEMORY,
+            let loop_points = info.as_ref().and_then(|info| info.loop_points);
+            let looping = settings.looping || loop_points.is_some();
+
+            let mut mode = FMOD_OPENMEMORY;
+            mode |= if settings.stream { FMOD_CREATESTREAM } else { FMOD_CREATESAMPLE };
+            mode |= if looping { FMOD_LOOP_NORMAL } else { FMOD_LOOP_OFF };
+
+            let r = FMOD_System_CreateSound(self.sys, buf[offset..].as_ptr() as *const i8,
+                                            mode,
                                             sound_info.as_mut(),
                                             &mut audio_ptr);
             if r != FMOD_RESULT_FMOD_OK {
                 panic!("Failed to load Audio {}", r);
             }
+
+            if settings.looping && (settings.loop_start > 0 || settings.loop_end.is_some()) {
+                // The loop end is inclusive
+                let mut len = 0;
+                FMOD_Sound_GetLength(audio_ptr, &mut len, FMOD_TIMEUNIT_MS);
+
+                let loop_end = settings.loop_end.unwrap_or(len).min(len).saturating_sub(1);
+
+                FMOD_Sound_SetLoopPoints(audio_ptr,
+                                         settings.loop_start.min(loop_end), FMOD_TIMEUNIT_MS,
+                                         loop_end, FMOD_TIMEUNIT_MS);
+            } else if let Some((loop_start, loop_end)) = loop_points {
+                // Sample exact, from the loop points of the file itself
+                FMOD_Sound_SetLoopPoints(audio_ptr,
+                                         loop_start, FMOD_TIMEUNIT_PCM,
+                                         loop_end - 1, FMOD_TIMEUNIT_PCM);
+            }
         }
 
         let mut audio = Audio {
@@ -96,7 +128,9 @@ impl AudioSystem {
             audio_data: buf,
 
             #[cfg(feature = "audio_fmod")]
-            default_frequency: 0.0
+            default_frequency: 0.0,
+
+            info
         };
 
         audio.init();
@@ -1,10 +1,14 @@
 #![feature(new_uninit)]
 
 mod audio;
+mod audio_info;
+mod audio_settings;
 mod audio_system;
 
 #[cfg(feature = "audio_fmod")]
 mod fmod_sys;
 
 pub use audio::Audio;
+pub use audio_info::{AudioFormat, AudioInfo, AUDIO_HEADER_SIZE};
+pub use audio_settings::AudioSettings;
 pub use audio_system::AudioSystem;
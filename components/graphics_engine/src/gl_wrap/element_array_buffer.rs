@@ -34,6 +34,21 @@ impl ElementArrayBuffer {
         buff
     }
 
+    // Replaces the whole buffer, meant for indices that change every frame
+    pub fn update(&self, indices: &[i32]) {
+        unsafe {
+            self.bind();
+
+            gl::BufferData(gl::ELEMENT_ARRAY_BUFFER,
+                           (indices.len() * std::mem::size_of::<i32>()) as _,
+                           indices.as_ptr() as *const c_void,
+                           gl::DYNAMIC_DRAW
+            );
+
+            self.unbind();
+        }
+    }
+
     pub fn id(&self) -> u32 {
         self.gl_id
     }
@@ -1,9 +1,18 @@
 use std::ffi::{CStr, CString};
+#[cfg(build = "debug")]
+use std::path::{Path, PathBuf};
+#[cfg(build = "debug")]
+use std::time::{Duration, Instant, SystemTime};
+
+use assets_pipeline::{AssetDatabaseError, AssetEntry, AssetEntryType, AssetPipeline, ShaderSource, ShaderStage};
 
 use crate::gl;
 
 pub struct Shader {
     program: u32,
+
+    #[cfg(build = "debug")]
+    watch: Option<ShaderWatch>
 }
 
 const SHADER_ERR_SRC_FRAG: &str = "
@@ -46,37 +55,126 @@ void main()
 
 impl Shader {
     pub fn new<S: AsRef<str>>(frag: S, vert: S) -> Option<Shader> {
-        let program;
-        unsafe {
-            program = gl::CreateProgram();
-            let vert_shader = gl::CreateShader(gl::VERTEX_SHADER);
-            let frag_shader = gl::CreateShader(gl::FRAGMENT_SHADER);
+        let program = match Shader::compile(frag.as_ref(), vert.as_ref()) {
+            Ok(program) => program,
+            Err(err) => {
+                log::error!("{}", err);
 
-            let vert_len = vert.as_ref().len() as i32;
-            let frag_len = frag.as_ref().len() as i32;
+                // Purple checkerboard, hard to miss
+                Shader::compile(SHADER_ERR_SRC_FRAG, SHADER_ERR_SRC_VERT).ok()?
+            }
+        };
+
+        // Boom, we got a working shader program for our GPU.
+        Some(Shader {
+            program,
 
-            let vert_ptr = vert.as_ref().as_ptr() as *const i8;
-            let frag_ptr = frag.as_ref().as_ptr() as *const i8;
+            #[cfg(build = "debug")]
+            watch: None
+        })
+    }
 
-            // Set our shader source
-            gl::ShaderSource(vert_shader, 1, &vert_ptr, &vert_len);
-            gl::ShaderSource(frag_shader, 1, &frag_ptr, &frag_len);
+    // Loads a vertex and fragment shader compiled by the asset pipeline.
+    // In debug builds the source files are watched if the pipeline knows where they are, see poll_reload()
+    pub fn load<S: AsRef<str>>(pipeline: &AssetPipeline, vert_key: S, frag_key: S) -> Result<Shader, AssetDatabaseError> {
+        let vert = Shader::load_source(pipeline, vert_key.as_ref(), ShaderStage::Vertex)?;
+        let frag = Shader::load_source(pipeline, frag_key.as_ref(), ShaderStage::Fragment)?;
 
-            // Compile our shader
-            gl::CompileShader(vert_shader);
-            if let Some(val) = Shader::check_compilation("vert", vert_shader) {
-                gl::DeleteProgram(program);
+        #[allow(unused_mut)]
+        let mut shader = Shader::new(&frag.source, &vert.source)
+            .ok_or_else(|| AssetDatabaseError::DecodeFailed { key: frag_key.as_ref().to_string() })?;
 
-                return Some(val);
+        #[cfg(build = "debug")]
+        {
+            if let Some(root) = pipeline.source_root() {
+                shader.watch = Some(ShaderWatch::new(root, vert_key.as_ref(), frag_key.as_ref(), &vert, &frag));
             }
+        }
 
-            gl::CompileShader(frag_shader);
-            if let Some(val) = Shader::check_compilation("frag", frag_shader) {
-                gl::DeleteProgram(program);
+        Ok(shader)
+    }
+
+    fn load_source(pipeline: &AssetPipeline, key: &str, stage: ShaderStage) -> Result<ShaderSource, AssetDatabaseError> {
+        let decode_failed = || AssetDatabaseError::DecodeFailed { key: key.to_string() };
+
+        let entry = pipeline.search(key)?.ok_or_else(decode_failed)?;
+        if entry.r#type() != AssetEntryType::Shader {
+            return Err(decode_failed());
+        }
+
+        let source = AssetEntry::clone(&entry).into_shader()?;
+        if source.stage != stage {
+            return Err(decode_failed());
+        }
+
+        Ok(source)
+    }
+
+    // Rebuilds the program in place, the old one stays if the new sources don't compile
+    pub fn reload<S: AsRef<str>>(&mut self, frag: S, vert: S) -> bool {
+        match Shader::compile(frag.as_ref(), vert.as_ref()) {
+            Ok(program) => {
+                unsafe {
+                    gl::DeleteProgram(self.program);
+                }
+
+                self.program = program;
+
+                true
+            }
+
+            Err(err) => {
+                log::error!("{}", err);
+
+                false
+            }
+        }
+    }
 
-                return Some(val);
+    // Reloads the shader if one of its source files changed, call it once per frame
+    #[cfg(build = "debug")]
+    pub fn poll_reload(&mut self) -> bool {
+        let sources = match self.watch.as_mut().and_then(|watch| watch.poll()) {
+            Some(sources) => sources,
+            None => return false
+        };
+
+        match sources {
+            Ok((vert, frag)) => {
+                let reloaded = self.reload(&frag.source, &vert.source);
+                if reloaded {
+                    log::info!("Reloaded shader {}", frag.files[0]);
+                }
+
+                if let Some(watch) = self.watch.as_mut() {
+                    watch.update_files(&vert, &frag);
+                }
+
+                reloaded
             }
 
+            Err(err) => {
+                log::error!("Failed to reload shader: {}", err);
+
+                false
+            }
+        }
+    }
+
+    fn compile(frag: &str, vert: &str) -> Result<u32, String> {
+        unsafe {
+            let vert_shader = Shader::compile_stage("vert", gl::VERTEX_SHADER, vert)?;
+            let frag_shader = match Shader::compile_stage("frag", gl::FRAGMENT_SHADER, frag) {
+                Ok(frag_shader) => frag_shader,
+                Err(err) => {
+                    gl::DeleteShader(vert_shader); // Don't leak the shader.
+
+                    return Err(err);
+                }
+            };
+
+            let program = gl::CreateProgram();
+
             // Attach our shader to our Program
             gl::AttachShader(program, vert_shader);
             gl::AttachShader(program, frag_shader);
@@ -87,41 +185,61 @@ impl Shader {
             // Delete the source objects
             gl::DeleteShader(vert_shader);
             gl::DeleteShader(frag_shader);
-        }
 
-        // Boom, we got a working shader program for our GPU.
-        Some(Shader {
-            program
-        })
+            let mut is_linked = 0;
+            gl::GetProgramiv(program, gl::LINK_STATUS, &mut is_linked);
+
+            if is_linked == 0 {
+                let mut max_len = 0;
+                gl::GetProgramiv(program, gl::INFO_LOG_LENGTH, &mut max_len);
+
+                let error_log = Shader::read_info_log(max_len, |len, log| gl::GetProgramInfoLog(program, max_len, len, log));
+
+                gl::DeleteProgram(program);
+
+                return Err(format!("Failed to link shader program {}", error_log));
+            }
+
+            Ok(program)
+        }
     }
 
-    // If error occurs, it'll get printed out and a purple error shader will be returned
-    // TODO: actually return the error shader
-    unsafe fn check_compilation<S: AsRef<str>>(name: S, shader: u32) -> Option<Shader> {
+    unsafe fn compile_stage(name: &str, kind: u32, src: &str) -> Result<u32, String> {
+        let shader = gl::CreateShader(kind);
+
+        let len = src.len() as i32;
+        let ptr = src.as_ptr() as *const i8;
+
+        // Set our shader source and compile it
+        gl::ShaderSource(shader, 1, &ptr, &len);
+        gl::CompileShader(shader);
+
         let mut is_compiled = 0;
         gl::GetShaderiv(shader, gl::COMPILE_STATUS, &mut is_compiled);
-        let is_compiled = is_compiled != 0;
 
-        if !is_compiled {
+        if is_compiled == 0 {
             let mut max_len = 0;
             gl::GetShaderiv(shader, gl::INFO_LOG_LENGTH, &mut max_len);
 
-            // Create a CStr
-            let mut error_log = vec![0i8; max_len as usize];
-            gl::GetShaderInfoLog(shader, max_len, &mut max_len,
-                                 error_log.as_mut_ptr());
-
-            let c_str = CStr::from_ptr(error_log.as_ptr());
-
-            log::error!("Failed to compile {} shader {}", name.as_ref(),
-                CString::from(c_str).to_str().unwrap());
+            let error_log = Shader::read_info_log(max_len, |len, log| gl::GetShaderInfoLog(shader, max_len, len, log));
 
             gl::DeleteShader(shader); // Don't leak the shader.
 
-            return Shader::new(SHADER_ERR_SRC_FRAG, SHADER_ERR_SRC_VERT)
+            return Err(format!("Failed to compile {} shader {}", name, error_log));
         }
 
-        None
+        Ok(shader)
+    }
+
+    unsafe fn read_info_log<F: FnOnce(*mut i32, *mut i8)>(max_len: i32, read: F) -> String {
+        // Create a CStr
+        let mut error_log = vec![0i8; std::cmp::max(max_len, 1) as usize];
+        let mut len = 0;
+        read(&mut len, error_log.as_mut_ptr());
+
+        let c_str = CStr::from_ptr(error_log.as_ptr());
+
+        CString::from(c_str).to_string_lossy().into_owned()
     }
 
     pub fn uniform_mat4f<S: AsRef<str>>(&self, name: S, val: &glm::Mat4) {
@@ -137,6 +255,19 @@ impl Shader {
         }
     }
 
+    pub fn uniform_1i<S: AsRef<str>>(&self, name: S, val: i32) {
+        unsafe {
+            let c_str = CString::new(name.as_ref()).unwrap();
+            let uni_loc = gl::GetUniformLocation(self.program, c_str.as_ptr());
+
+            if uni_loc < 0 {
+                log::warn!("uniform_1i {} was not found", name.as_ref());
+            }
+
+            gl::Uniform1i(uni_loc, val);
+        }
+    }
+
     pub fn id(&self) -> u32 {
         self.program
     }
@@ -160,4 +291,72 @@ impl Drop for Shader {
             gl::DeleteProgram(self.program);
         }
     }
-}
\ No newline at end of file
+}
+
+// Source files of a shader, polled for changes in debug builds
+#[cfg(build = "debug")]
+struct ShaderWatch {
+    root: PathBuf,
+    vert_key: String,
+    frag_key: String,
+
+    files: Vec<PathBuf>,
+    modified: Option<SystemTime>,
+    last_poll: Instant
+}
+
+#[cfg(build = "debug")]
+impl ShaderWatch {
+    const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+    fn new(root: &Path, vert_key: &str, frag_key: &str, vert: &ShaderSource, frag: &ShaderSource) -> ShaderWatch {
+        let mut watch = ShaderWatch {
+            root: root.to_path_buf(),
+            vert_key: vert_key.to_string(),
+            frag_key: frag_key.to_string(),
+
+            files: Vec::new(),
+            modified: None,
+            last_poll: Instant::now()
+        };
+
+        watch.update_files(vert, frag);
+
+        watch
+    }
+
+    fn update_files(&mut self, vert: &ShaderSource, frag: &ShaderSource) {
+        let root = &self.root;
+
+        self.files = vert.files.iter()
+            .chain(frag.files.iter())
+            .map(|key| root.join(key))
+            .collect();
+        self.modified = self.last_modified();
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        self.files.iter()
+            .filter_map(|file| std::fs::metadata(file).and_then(|meta| meta.modified()).ok())
+            .max()
+    }
+
+    // Freshly resolved sources if anything changed since the last time
+    fn poll(&mut self) -> Option<Result<(ShaderSource, ShaderSource), AssetDatabaseError>> {
+        if self.last_poll.elapsed() < ShaderWatch::POLL_INTERVAL {
+            return None;
+        }
+        self.last_poll = Instant::now();
+
+        let modified = self.last_modified();
+        if modified <= self.modified {
+            return None;
+        }
+        self.modified = modified; // Don't retry a broken shader until it's saved again
+
+        let vert = ShaderSource::from_file(&self.root, self.root.join(&self.vert_key));
+        let frag = ShaderSource::from_file(&self.root, self.root.join(&self.frag_key));
+
+        Some(vert.and_then(|vert| frag.map(|frag| (vert, frag))))
+    }
+}
@@ -1,39 +1,96 @@
 // a low level texture binding for sprite
 
+use assets_pipeline::{Asset, AssetDatabaseError, AssetEntry, AssetEntryType, PixelFormat, TextureData, TextureFilter, TextureSettings, TextureWrap};
 use image::RgbaImage;
 
 use crate::gl;
 
+// EXT_texture_compression_s3tc, every desktop GPU has it even though it's not part of core
+const COMPRESSED_RGBA_S3TC_DXT1_EXT: u32 = 0x83F1;
+const COMPRESSED_RGBA_S3TC_DXT5_EXT: u32 = 0x83F3;
+
 pub struct Texture2D {
     texture: u32,
 
     width: i32,
     height: i32,
+
+    settings: TextureSettings,
+    format: PixelFormat,
+    premultiplied: bool,
+    memory_size: usize
 }
 
 impl Texture2D {
     pub fn new(pixels: &[u8], width: i32, height: i32) -> Texture2D {
+        Texture2D::with_settings(pixels, width, height, &TextureSettings::default())
+    }
+
+    // RGBA8 pixels, mipmaps are generated here if the settings ask for them
+    pub fn with_settings(pixels: &[u8], width: i32, height: i32, settings: &TextureSettings) -> Texture2D {
+        Texture2D::from_texture_data(&TextureData {
+            width: width as u32,
+            height: height as u32,
+            format: PixelFormat::Rgba8,
+            premultiplied: false,
+            levels: vec![pixels.to_vec()]
+        }, settings)
+    }
+
+    // Uploads every level as it is, only textures without a precomputed mip chain generate one
+    pub fn from_texture_data(data: &TextureData, settings: &TextureSettings) -> Texture2D {
         let mut texture = Texture2D {
             texture: 0,
 
-            width,
-            height
+            width: data.width as i32,
+            height: data.height as i32,
+
+            settings: settings.clone(),
+            format: data.format,
+            premultiplied: data.premultiplied,
+            memory_size: data.levels.iter().map(|level| level.len()).sum()
+        };
+
+        let wrap = match settings.wrap {
+            TextureWrap::Repeat => gl::REPEAT,
+            TextureWrap::Clamp => gl::CLAMP_TO_EDGE,
+            TextureWrap::Mirror => gl::MIRRORED_REPEAT
+        };
+
+        let (min_filter, mag_filter) = match (settings.filter, settings.mipmaps) {
+            (TextureFilter::Nearest, false) => (gl::NEAREST, gl::NEAREST),
+            (TextureFilter::Nearest, true) => (gl::NEAREST_MIPMAP_NEAREST, gl::NEAREST),
+            (TextureFilter::Linear, false) => (gl::LINEAR, gl::LINEAR),
+            (TextureFilter::Linear, true) => (gl::LINEAR_MIPMAP_LINEAR, gl::LINEAR)
         };
 
         unsafe {
             gl::GenTextures(1, &mut texture.texture);
             texture.bind();
 
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::REPEAT as i32);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::REPEAT as i32);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::NEAREST as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, wrap as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, wrap as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, min_filter as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, mag_filter as i32);
 
-            gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGBA as i32,
-                           width, height, 0, gl::RGBA,
-                           gl::UNSIGNED_BYTE, pixels.as_ptr() as _);
+            // Rows of R8 and 16 bit textures aren't always 4 byte aligned
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+
+            for (i, level) in data.levels.iter().enumerate() {
+                let (width, height) = data.level_size(i);
 
-            gl::GenerateMipmap(gl::TEXTURE_2D);
+                Texture2D::upload_level(data.format, i as i32, width as i32, height as i32, level);
+            }
+
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAX_LEVEL, data.levels.len() as i32 - 1);
+            if settings.mipmaps && data.levels.len() == 1 && !data.format.is_compressed() {
+                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAX_LEVEL, 1000);
+                gl::GenerateMipmap(gl::TEXTURE_2D);
+
+                texture.memory_size += texture.memory_size / 3;
+            }
+
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 4);
 
             texture.unbind();
         }
@@ -41,6 +98,64 @@ impl Texture2D {
         texture
     }
 
+    unsafe fn upload_level(format: PixelFormat, level: i32, width: i32, height: i32, pixels: &[u8]) {
+        let (internal_format, pixel_format, pixel_type) = match format {
+            PixelFormat::Rgba8 => (gl::RGBA8, gl::RGBA, gl::UNSIGNED_BYTE),
+            PixelFormat::R8 => (gl::R8, gl::RED, gl::UNSIGNED_BYTE),
+            PixelFormat::Rgb565 => (gl::RGB5, gl::RGB, gl::UNSIGNED_SHORT_5_6_5),
+            PixelFormat::Rgba4444 => (gl::RGBA4, gl::RGBA, gl::UNSIGNED_SHORT_4_4_4_4),
+
+            PixelFormat::Bc1 | PixelFormat::Bc3 => {
+                let internal_format = match format {
+                    PixelFormat::Bc1 => COMPRESSED_RGBA_S3TC_DXT1_EXT,
+                    _ => COMPRESSED_RGBA_S3TC_DXT5_EXT
+                };
+
+                gl::CompressedTexImage2D(gl::TEXTURE_2D, level, internal_format, width, height, 0,
+                                         pixels.len() as i32, pixels.as_ptr() as _);
+                return;
+            }
+        };
+
+        gl::TexImage2D(gl::TEXTURE_2D, level, internal_format as i32,
+                       width, height, 0, pixel_format,
+                       pixel_type, pixels.as_ptr() as _);
+    }
+
+    // Sampled the way the .meta file of the texture asks for
+    pub fn from_entry(entry: AssetEntry) -> Result<Texture2D, AssetDatabaseError> {
+        let decoded = <Texture2D as Asset>::decode(entry)?;
+
+        <Texture2D as Asset>::load(decoded, &())
+    }
+
+    // Replaces the pixels in place, everything holding on to this texture sees the new image
+    pub fn update(&mut self, img: &RgbaImage) {
+        self.width = img.width() as i32;
+        self.height = img.height() as i32;
+        self.format = PixelFormat::Rgba8;
+        self.premultiplied = false;
+        self.memory_size = img.len();
+
+        unsafe {
+            self.bind();
+
+            gl::TexImage2D(gl::TEXTURE_2D, 0, gl::RGBA as i32,
+                           self.width, self.height, 0, gl::RGBA,
+                           gl::UNSIGNED_BYTE, img.as_ptr() as _);
+
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAX_LEVEL, 0);
+            if self.settings.mipmaps {
+                gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAX_LEVEL, 1000);
+                gl::GenerateMipmap(gl::TEXTURE_2D);
+
+                self.memory_size += self.memory_size / 3;
+            }
+
+            self.unbind();
+        }
+    }
+
     pub fn id(&self) -> u32 {
         self.texture
     }
@@ -52,6 +167,19 @@ impl Texture2D {
         self.height
     }
 
+    pub fn settings(&self) -> &TextureSettings {
+        &self.settings
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    // Premultiplied textures have to be blended with ONE, ONE_MINUS_SRC_ALPHA
+    pub fn is_premultiplied(&self) -> bool {
+        self.premultiplied
+    }
+
     pub fn bind(&self) {
         unsafe {
             gl::BindTexture(gl::TEXTURE_2D, self.texture);
@@ -75,10 +203,31 @@ impl Drop for Texture2D {
 
 impl From<RgbaImage> for Texture2D {
     fn from(img: RgbaImage) -> Self {
-        let width = img.width() as i32;
-        let height = img.height() as i32;
-        let pixels = img.into_raw();
+        Texture2D::from_texture_data(&TextureData::from_image(img), &TextureSettings::default())
+    }
+}
+
+// The pixels are decoded on a worker thread, only the upload needs the GL context
+impl Asset for Texture2D {
+    type Context = ();
+    type Decoded = (TextureData, TextureSettings);
+
+    fn decode(entry: AssetEntry) -> Result<Self::Decoded, AssetDatabaseError> {
+        if entry.r#type() != AssetEntryType::Texture {
+            return Err(AssetDatabaseError::DecodeFailed { key: entry.key() });
+        }
 
-        Texture2D::new(&pixels, width, height)
+        let settings = entry.metadata().texture.clone();
+
+        Ok((entry.into_texture_data()?, settings))
+    }
+
+    fn load((data, settings): Self::Decoded, _: &()) -> Result<Texture2D, AssetDatabaseError> {
+        Ok(Texture2D::from_texture_data(&data, &settings))
     }
-}
\ No newline at end of file
+
+    // What the GPU keeps, generated mipmaps add about a third on top
+    fn memory_size(&self) -> usize {
+        self.memory_size
+    }
+}
@@ -1,6 +1,5 @@
 // VAO
 
-use std::ptr;
 use std::ffi::c_void;
 
 use super::{ VertexBuffer, ElementArrayBuffer };
@@ -40,26 +39,29 @@ impl VertexArrayObject {
     }
 
     pub fn bind_to(&self, vbo: &VertexBuffer, ebo: &ElementArrayBuffer) {
+        // 12 XYZ 8 TX TY
+        self.bind_to_layout(vbo, ebo, &[3, 2]);
+    }
+
+    // layout is the amount of floats of every attribute, in attribute order
+    pub fn bind_to_layout(&self, vbo: &VertexBuffer, ebo: &ElementArrayBuffer, layout: &[i32]) {
         self.bind(); // bind VAO
         vbo.bind(); // bind VBO
         ebo.bind(); // bind EBO
 
-        // 12 XYZ 8 TX TY
+        let stride = layout.iter().sum::<i32>() * 4;
+
         unsafe { // Specify our data location
             let mut offset = 0;
-            gl::VertexAttribPointer(0, 3, // TODO: do not hardcode all this. let the developer decide.
-                                    gl::FLOAT, gl::FALSE,
-                                    5*4,
-                                    ptr::null());
-            gl::EnableVertexAttribArray(0);
-            offset += 3 * 4;
-
-            gl::VertexAttribPointer(1, 2,
-                                    gl::FLOAT, gl::FALSE,
-                                    5*4,
-                                    offset as *const c_void);
-            gl::EnableVertexAttribArray(1);
-            //offset += 2 * 4;
+            for (i, size) in layout.iter().enumerate() {
+                gl::VertexAttribPointer(i as u32, *size,
+                                        gl::FLOAT, gl::FALSE,
+                                        stride,
+                                        offset as *const c_void);
+                gl::EnableVertexAttribArray(i as u32);
+
+                offset += *size as usize * 4;
+            }
         }
 
         self.unbind(); // unbind VAO
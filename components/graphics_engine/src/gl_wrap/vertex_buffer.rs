@@ -34,6 +34,21 @@ impl VertexBuffer {
         buff
     }
 
+    // Replaces the whole buffer, meant for vertices that change every frame
+    pub fn update(&self, vertices: &[f32]) {
+        unsafe {
+            self.bind();
+
+            gl::BufferData(gl::ARRAY_BUFFER,
+                           (vertices.len() * std::mem::size_of::<f32>()) as _,
+                           vertices.as_ptr() as *const c_void,
+                           gl::DYNAMIC_DRAW
+            );
+
+            self.unbind();
+        }
+    }
+
     pub fn id(&self) -> u32 {
         self.gl_id
     }
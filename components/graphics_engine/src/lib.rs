@@ -8,10 +8,16 @@ pub mod gl {
 
 pub mod gl_wrap;
 
+mod particles;
 mod render_pipeline;
+mod text;
+mod video;
 
 #[cfg(build = "debug")]
 pub mod imgui_wrapper;
 pub mod imgui_ext;
 
+pub use particles::{Particle, ParticleEmitter, ParticleRenderer};
 pub use render_pipeline::RenderPipeline;
+pub use text::{Font, PlacedGlyph, TextAlign, TextLayout, TextRenderer, TextStyle};
+pub use video::VideoPlayer;
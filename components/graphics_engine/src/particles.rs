@@ -0,0 +1,329 @@
+// CPU particle simulation for effects compiled by the asset pipeline.
+// An emitter only simulates, the ParticleRenderer turns emitters into quads and draws them.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use assets_pipeline::{AssetDatabaseError, AssetEntry, AssetPipeline, EmitterShape, ParticleEffect};
+
+use crate::gl;
+use crate::gl_wrap::{ElementArrayBuffer, Shader, Texture2D, VertexArrayObject, VertexBuffer};
+
+const PARTICLE_SRC_VERT: &str = "
+#version 330 core
+layout (location = 0) in vec3 iPos;
+layout (location = 1) in vec2 iTexPos;
+layout (location = 2) in vec4 iColour;
+
+out vec2 TexPos;
+out vec4 Colour;
+
+uniform mat4 iMVP;
+
+void main()
+{
+    gl_Position = iMVP * vec4(iPos.xyz, 1.0);
+    TexPos = iTexPos;
+    Colour = iColour;
+}
+";
+
+const PARTICLE_SRC_FRAG: &str = "
+#version 330 core
+in vec2 TexPos;
+in vec4 Colour;
+
+out vec4 FragColor;
+
+uniform sampler2D iTexture;
+
+void main()
+{
+    FragColor = texture(iTexture, TexPos) * Colour;
+}
+";
+
+// 12 XYZ 8 TX TY 16 RGBA
+const VERTEX_LAYOUT: [i32; 3] = [3, 2, 4];
+const VERTEX_LEN: usize = 3 + 2 + 4;
+
+pub struct Particle {
+    pub position: glm::Vec2,
+    pub velocity: glm::Vec2,
+    pub age: f32,      // Seconds
+    pub lifetime: f32
+}
+
+impl Particle {
+    // How far the particle is through its life, 0.0 - 1.0
+    pub fn progress(&self) -> f32 {
+        self.age / self.lifetime
+    }
+}
+
+pub struct ParticleEmitter {
+    effect: Arc<ParticleEffect>,
+    particles: Vec<Particle>,
+
+    pub position: glm::Vec2,
+    pub emitting: bool, // Existing particles keep going when disabled
+
+    spawn_debt: f32, // Particles that should have been spawned but weren't yet, < 1
+    rng: u32
+}
+
+impl ParticleEmitter {
+    pub fn new(effect: Arc<ParticleEffect>, position: glm::Vec2) -> ParticleEmitter {
+        let seed = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|time| time.subsec_nanos())
+            .unwrap_or(0);
+
+        let mut emitter = ParticleEmitter {
+            particles: Vec::with_capacity(effect.max_particles as usize),
+            effect,
+
+            position,
+            emitting: true,
+
+            spawn_debt: 0.0,
+            rng: seed | 1 // xorshift gets stuck on 0
+        };
+
+        emitter.burst();
+
+        emitter
+    }
+
+    pub fn load<S: AsRef<str>>(pipeline: &AssetPipeline, key: S, position: glm::Vec2) -> Result<ParticleEmitter, AssetDatabaseError> {
+        let entry = pipeline.search(&key)?
+            .ok_or_else(|| AssetDatabaseError::NotFound { key: key.as_ref().to_string() })?;
+
+        let effect = AssetEntry::clone(&entry).into_particles()?;
+
+        Ok(ParticleEmitter::new(Arc::new(effect), position))
+    }
+
+    pub fn effect(&self) -> &ParticleEffect {
+        &self.effect
+    }
+
+    pub fn particles(&self) -> &[Particle] {
+        &self.particles
+    }
+
+    // Done once nothing is emitted and every particle died
+    pub fn is_finished(&self) -> bool {
+        !self.emitting && self.particles.is_empty()
+    }
+
+    // Spawns the effects burst, done on creation already
+    pub fn burst(&mut self) {
+        for _ in 0..std::cmp::min(self.effect.burst, self.effect.max_particles) {
+            self.spawn();
+        }
+    }
+
+    pub fn step(&mut self, delta: &Duration) {
+        let dt = delta.as_secs_f32();
+        let effect = self.effect.clone();
+
+        let gravity = glm::vec2(effect.gravity[0], effect.gravity[1]);
+        for particle in &mut self.particles {
+            let speed = effect.speed_curve.sample(particle.progress());
+
+            particle.position += particle.velocity * speed * dt;
+            particle.velocity += gravity * dt;
+            particle.age += dt;
+        }
+
+        self.particles.retain(|particle| particle.age < particle.lifetime);
+
+        if self.emitting {
+            // Past 2^24 taking 1.0 off doesn't change it anymore, nothing past max_particles spawns anyway
+            let room = (effect.max_particles as usize).saturating_sub(self.particles.len());
+            self.spawn_debt = (self.spawn_debt + effect.spawn_rate * dt).min(room as f32);
+
+            while self.spawn_debt >= 1.0 {
+                self.spawn();
+                self.spawn_debt -= 1.0;
+            }
+        }
+    }
+
+    fn spawn(&mut self) {
+        if self.particles.len() >= self.effect.max_particles as usize {
+            return;
+        }
+
+        let offset = match self.effect.shape {
+            EmitterShape::Point => glm::vec2(0.0, 0.0),
+            EmitterShape::Circle { radius } => {
+                // sqrt() spreads them evenly instead of clumping in the center
+                let angle = self.random(0.0, std::f32::consts::PI * 2.0);
+                let distance = radius * self.random(0.0, 1.0).sqrt();
+
+                glm::vec2(angle.cos(), angle.sin()) * distance
+            }
+            EmitterShape::Rect { width, height } => glm::vec2(
+                self.random(-width / 2.0, width / 2.0),
+                self.random(-height / 2.0, height / 2.0)
+            )
+        };
+
+        let spread = self.effect.spread / 2.0;
+        let angle = (self.effect.direction + self.random(-spread, spread)).to_radians();
+        let speed = self.random(self.effect.speed[0], self.effect.speed[1]);
+        let lifetime = self.random(self.effect.lifetime[0], self.effect.lifetime[1]);
+
+        self.particles.push(Particle {
+            position: self.position + offset,
+            velocity: glm::vec2(angle.cos(), angle.sin()) * speed,
+            age: 0.0,
+            lifetime
+        });
+    }
+
+    // xorshift32, good enough for particles
+    fn random(&mut self, min: f32, max: f32) -> f32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+
+        let t = (self.rng >> 8) as f32 / (1 << 24) as f32;
+
+        min + (max - min) * t
+    }
+}
+
+pub struct ParticleRenderer {
+    shader: Shader,
+    vao: VertexArrayObject,
+    vbo: VertexBuffer,
+    ebo: ElementArrayBuffer,
+
+    vertices: Vec<f32>,
+    indices: Vec<i32>
+}
+
+impl ParticleRenderer {
+    pub fn new() -> ParticleRenderer {
+        let shader = Shader::new(PARTICLE_SRC_FRAG, PARTICLE_SRC_VERT)
+            .expect("Failed to create particle shader");
+
+        let vao = VertexArrayObject::new();
+        let vbo = VertexBuffer::new(&[0.0; VERTEX_LEN * 4]);
+        let ebo = ElementArrayBuffer::new(&[0, 1, 2, 2, 3, 0]);
+
+        vao.bind_to_layout(&vbo, &ebo, &VERTEX_LAYOUT);
+
+        ParticleRenderer {
+            shader,
+            vao,
+            vbo,
+            ebo,
+
+            vertices: Vec::new(),
+            indices: Vec::new()
+        }
+    }
+
+    // Texture and uv rect the effect is drawn with, sprites resolve to their atlas page
+    pub fn load_texture(pipeline: &AssetPipeline, effect: &ParticleEffect) -> Result<(Texture2D, [f32; 4]), AssetDatabaseError> {
+        let (entry, sprite) = pipeline.search_sprite(&effect.texture)?
+            .ok_or_else(|| AssetDatabaseError::NotFound { key: effect.texture.clone() })?;
+
+        let texture = Texture2D::from_entry(AssetEntry::clone(&entry))?;
+
+        Ok((texture, sprite.uv))
+    }
+
+    pub fn draw(&mut self, emitter: &ParticleEmitter, texture: &Texture2D, uv: [f32; 4], mvp: &glm::Mat4) {
+        if emitter.particles.is_empty() {
+            return;
+        }
+
+        self.vertices.clear();
+        self.indices.clear();
+
+        let [u0, v0, u1, v1] = uv;
+        for particle in &emitter.particles {
+            let progress = particle.progress();
+
+            let half = emitter.effect.size.sample(progress) / 2.0;
+            let [mut r, mut g, mut b, a] = emitter.effect.colour.sample(progress);
+
+            // The texture is multiplied with the colour, so it has to be premultiplied as well
+            if texture.is_premultiplied() {
+                r *= a;
+                g *= a;
+                b *= a;
+            }
+            let (x, y) = (particle.position.x, particle.position.y);
+
+            let first = (self.vertices.len() / VERTEX_LEN) as i32;
+            self.vertices.extend_from_slice(&[
+                x - half, y - half, 0.0, u0, v1, r, g, b, a,
+                x + half, y - half, 0.0, u1, v1, r, g, b, a,
+                x + half, y + half, 0.0, u1, v0, r, g, b, a,
+                x - half, y + half, 0.0, u0, v0, r, g, b, a
+            ]);
+            self.indices.extend_from_slice(&[
+                first, first + 1, first + 2,
+                first + 2, first + 3, first
+            ]);
+        }
+
+        self.vbo.update(&self.vertices);
+        self.ebo.update(&self.indices);
+
+        self.shader.bind();
+        self.shader.uniform_mat4f("iMVP", mvp);
+        texture.bind();
+        self.vao.bind();
+
+        unsafe {
+            if texture.is_premultiplied() {
+                gl::BlendFunc(gl::ONE, gl::ONE_MINUS_SRC_ALPHA);
+            }
+
+            gl::DrawElements(gl::TRIANGLES, self.indices.len() as i32, gl::UNSIGNED_INT, std::ptr::null());
+
+            if texture.is_premultiplied() {
+                gl::BlendFunc(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA);
+            }
+        }
+
+        self.vao.unbind();
+        texture.unbind();
+        self.shader.unbind();
+    }
+}
+
+#[test]
+fn emitter_limit_test() {
+    use assets_pipeline::Curve;
+
+    let effect = ParticleEffect {
+        texture: "textures/particles/spark.png".to_string(),
+        max_particles: 64,
+        spawn_rate: 1.0e30,
+        burst: u32::MAX,
+        lifetime: [1.0, 2.0],
+        shape: EmitterShape::Point,
+        direction: 90.0,
+        spread: 30.0,
+        speed: [1.0, 2.0],
+        gravity: [0.0, 0.0],
+        speed_curve: Curve::constant(1.0),
+        colour: Curve::constant([1.0; 4]),
+        size: Curve::constant(8.0)
+    };
+
+    // Neither the burst nor the spawn rate spin past max_particles
+    let mut emitter = ParticleEmitter::new(Arc::new(effect), glm::vec2(0.0, 0.0));
+    assert_eq!(emitter.particles.len(), 64);
+
+    emitter.step(&Duration::from_millis(16));
+    assert_eq!(emitter.particles.len(), 64);
+}
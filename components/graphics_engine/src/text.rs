@@ -0,0 +1,395 @@
+// Text with the glyph atlases of font entries. TextLayout places the glyphs of a string,
+// breaking lines at '\n' and wherever they'd get wider than the max width (at the last space,
+// or anywhere in words that don't fit on a line of their own). TextRenderer draws a layout.
+
+use assets_pipeline::{Asset, AssetDatabaseError, AssetEntry, AssetEntryType, AssetPipeline, FontData, FontMode, TextureFilter, TextureSettings, TextureWrap};
+
+use crate::gl;
+use crate::gl_wrap::{ElementArrayBuffer, Shader, Texture2D, VertexArrayObject, VertexBuffer};
+
+const TEXT_SRC_VERT: &str = "
+#version 330 core
+layout (location = 0) in vec3 iPos;
+layout (location = 1) in vec2 iTexPos;
+layout (location = 2) in vec4 iColour;
+
+out vec2 TexPos;
+out vec4 Colour;
+
+uniform mat4 iMVP;
+
+void main()
+{
+    gl_Position = iMVP * vec4(iPos.xyz, 1.0);
+    TexPos = iTexPos;
+    Colour = iColour;
+}
+";
+
+// Distance fields are cut at 0.5 with a ramp one screen pixel wide, whatever the text is scaled to
+const TEXT_SRC_FRAG: &str = "
+#version 330 core
+in vec2 TexPos;
+in vec4 Colour;
+
+out vec4 FragColor;
+
+uniform sampler2D iTexture;
+uniform int iSdf;
+
+void main()
+{
+    float value = texture(iTexture, TexPos).r;
+    float alpha = value;
+
+    if (iSdf == 1) {
+        float width = max(fwidth(value), 0.0001);
+        alpha = clamp((value - 0.5) / width + 0.5, 0.0, 1.0);
+    }
+
+    FragColor = vec4(Colour.rgb, Colour.a * alpha);
+}
+";
+
+// 12 XYZ 8 TX TY 16 RGBA
+const VERTEX_LAYOUT: [i32; 3] = [3, 2, 4];
+const VERTEX_LEN: usize = 3 + 2 + 4;
+
+// A font entry with its atlas on the GPU
+pub struct Font {
+    data: FontData,
+    texture: Texture2D
+}
+
+impl Font {
+    // Distance fields need linear filtering, pixel fonts stay sharp
+    pub fn from_data(data: FontData) -> Font {
+        let settings = TextureSettings {
+            filter: match data.mode {
+                FontMode::Bitmap => TextureFilter::Nearest,
+                FontMode::Sdf => TextureFilter::Linear
+            },
+            wrap: TextureWrap::Clamp,
+            ..TextureSettings::default()
+        };
+
+        let texture = Texture2D::from_texture_data(&data.atlas, &settings);
+
+        Font {
+            data,
+            texture
+        }
+    }
+
+    pub fn load<S: AsRef<str>>(pipeline: &AssetPipeline, key: S) -> Result<Font, AssetDatabaseError> {
+        let entry = pipeline.search(&key)?
+            .ok_or_else(|| AssetDatabaseError::NotFound { key: key.as_ref().to_string() })?;
+
+        let decoded = <Font as Asset>::decode(AssetEntry::clone(&entry))?;
+
+        <Font as Asset>::load(decoded, &())
+    }
+
+    pub fn data(&self) -> &FontData {
+        &self.data
+    }
+
+    pub fn texture(&self) -> &Texture2D {
+        &self.texture
+    }
+}
+
+// The atlas is decoded on a worker thread, only the upload needs the GL context
+impl Asset for Font {
+    type Context = ();
+    type Decoded = FontData;
+
+    fn decode(entry: AssetEntry) -> Result<FontData, AssetDatabaseError> {
+        if entry.r#type() != AssetEntryType::Font {
+            return Err(AssetDatabaseError::DecodeFailed { key: entry.key() });
+        }
+
+        entry.into_font()
+    }
+
+    fn load(data: FontData, _: &()) -> Result<Font, AssetDatabaseError> {
+        Ok(Font::from_data(data))
+    }
+
+    fn memory_size(&self) -> usize {
+        <Texture2D as Asset>::memory_size(&self.texture) + self.data.atlas.levels.iter().map(|level| level.len()).sum::<usize>()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TextAlign {
+    Left,
+    Center,
+    Right
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextStyle {
+    pub size: f32, // Pixels per em, the glyphs are scaled from the size the font was built with
+    pub colour: [f32; 4],
+    pub align: TextAlign,
+    pub max_width: Option<f32>, // Longer lines are wrapped
+    pub line_spacing: f32       // Times the line height of the font
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        TextStyle {
+            size: 16.0,
+            colour: [1.0; 4],
+            align: TextAlign::Left,
+            max_width: None,
+            line_spacing: 1.0
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlacedGlyph {
+    pub character: char,
+    pub position: glm::Vec2, // Top left corner, relative to the top left corner of the text with y pointing down
+    pub size: glm::Vec2,
+    pub uv: [f32; 4]         // u0, v0, u1, v1
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextLayout {
+    pub glyphs: Vec<PlacedGlyph>,
+    pub width: f32, // The max width if there is one, otherwise the widest line
+    pub height: f32,
+    pub lines: usize
+}
+
+impl TextLayout {
+    pub fn new(font: &FontData, text: &str, style: &TextStyle) -> TextLayout {
+        let scale = style.size / font.size;
+        let line_height = font.line_height() * scale * style.line_spacing;
+
+        let mut lines = Vec::new();
+        for paragraph in text.split('\n') {
+            let chars = paragraph.trim_end_matches('\r').chars().collect::<Vec<_>>();
+
+            for (start, end) in TextLayout::wrap(font, &chars, scale, style.max_width) {
+                // Trailing spaces don't count for the alignment
+                let mut trimmed = end;
+                while trimmed > start && chars[trimmed - 1] == ' ' {
+                    trimmed -= 1;
+                }
+
+                let line = chars[start..trimmed].to_vec();
+                let width = TextLayout::measure(font, &line, scale);
+                lines.push((line, width));
+            }
+        }
+
+        let width = match style.max_width {
+            Some(max_width) => max_width,
+            None => lines.iter().map(|(_, width)| *width).fold(0.0, f32::max)
+        };
+
+        let mut glyphs = Vec::new();
+        for (i, (line, line_width)) in lines.iter().enumerate() {
+            let mut pen = match style.align {
+                TextAlign::Left => 0.0,
+                TextAlign::Center => (width - line_width) / 2.0,
+                TextAlign::Right => width - line_width
+            };
+            let baseline = font.ascent * scale + i as f32 * line_height;
+
+            for (j, c) in line.iter().enumerate() {
+                let glyph = match font.glyph(*c) {
+                    Some(glyph) => glyph,
+                    None => continue
+                };
+
+                if glyph.rect.width > 0 && glyph.rect.height > 0 {
+                    let mut position = glm::vec2(pen + glyph.offset[0] * scale, baseline + glyph.offset[1] * scale);
+
+                    // Pixel fonts stay on the pixel grid
+                    if font.mode == FontMode::Bitmap {
+                        position = glm::vec2(position.x.round(), position.y.round());
+                    }
+
+                    let (atlas_width, atlas_height) = (font.atlas.width as f32, font.atlas.height as f32);
+                    let rect = glyph.rect;
+
+                    glyphs.push(PlacedGlyph {
+                        character: *c,
+                        position,
+                        size: glm::vec2(rect.width as f32 * scale, rect.height as f32 * scale),
+                        uv: [
+                            rect.x as f32 / atlas_width,
+                            rect.y as f32 / atlas_height,
+                            (rect.x + rect.width) as f32 / atlas_width,
+                            (rect.y + rect.height) as f32 / atlas_height
+                        ]
+                    });
+                }
+
+                pen += glyph.advance * scale;
+                if let Some(next) = line.get(j + 1) {
+                    pen += font.kern(*c, *next) * scale;
+                }
+            }
+        }
+
+        TextLayout {
+            glyphs,
+            width,
+            height: lines.len() as f32 * line_height,
+            lines: lines.len()
+        }
+    }
+
+    // Start and end of every line of a paragraph, spaces at a break are dropped
+    fn wrap(font: &FontData, chars: &[char], scale: f32, max_width: Option<f32>) -> Vec<(usize, usize)> {
+        let max_width = match max_width {
+            Some(max_width) => max_width,
+            None => return vec![(0, chars.len())]
+        };
+
+        let mut lines = Vec::new();
+        let mut start = 0;
+        let mut pen = 0.0;
+        let mut last_space = None;
+
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let mut advance = font.glyph(c).map_or(0.0, |glyph| glyph.advance) * scale;
+            if i > start {
+                advance += font.kern(chars[i - 1], c) * scale;
+            }
+
+            // Spaces can hang over the edge, they're trimmed anyway
+            if c != ' ' && i > start && pen + advance > max_width {
+                match last_space {
+                    Some(space) if space >= start => {
+                        lines.push((start, space));
+                        start = space + 1;
+                    }
+                    _ => {
+                        lines.push((start, i));
+                        start = i;
+                    }
+                }
+
+                pen = TextLayout::measure(font, &chars[start..i], scale);
+                last_space = None;
+                continue;
+            }
+
+            if c == ' ' {
+                last_space = Some(i);
+            }
+
+            pen += advance;
+            i += 1;
+        }
+
+        lines.push((start, chars.len()));
+        lines
+    }
+
+    fn measure(font: &FontData, chars: &[char], scale: f32) -> f32 {
+        let advances = chars.iter()
+            .map(|c| font.glyph(*c).map_or(0.0, |glyph| glyph.advance))
+            .sum::<f32>();
+        let kerning = chars.windows(2)
+            .map(|pair| font.kern(pair[0], pair[1]))
+            .sum::<f32>();
+
+        (advances + kerning) * scale
+    }
+}
+
+pub struct TextRenderer {
+    shader: Shader,
+    vao: VertexArrayObject,
+    vbo: VertexBuffer,
+    ebo: ElementArrayBuffer,
+
+    vertices: Vec<f32>,
+    indices: Vec<i32>
+}
+
+impl TextRenderer {
+    pub fn new() -> TextRenderer {
+        let shader = Shader::new(TEXT_SRC_FRAG, TEXT_SRC_VERT)
+            .expect("Failed to create text shader");
+
+        let vao = VertexArrayObject::new();
+        let vbo = VertexBuffer::new(&[0.0; VERTEX_LEN * 4]);
+        let ebo = ElementArrayBuffer::new(&[0, 1, 2, 2, 3, 0]);
+
+        vao.bind_to_layout(&vbo, &ebo, &VERTEX_LAYOUT);
+
+        TextRenderer {
+            shader,
+            vao,
+            vbo,
+            ebo,
+
+            vertices: Vec::new(),
+            indices: Vec::new()
+        }
+    }
+
+    // position is the top left corner of the text, y points up like it does for particles
+    pub fn draw(&mut self, font: &Font, text: &str, position: glm::Vec2, style: &TextStyle, mvp: &glm::Mat4) -> TextLayout {
+        let layout = TextLayout::new(&font.data, text, style);
+        self.draw_layout(font, &layout, position, style.colour, mvp);
+
+        layout
+    }
+
+    pub fn draw_layout(&mut self, font: &Font, layout: &TextLayout, position: glm::Vec2, colour: [f32; 4], mvp: &glm::Mat4) {
+        if layout.glyphs.is_empty() {
+            return;
+        }
+
+        self.vertices.clear();
+        self.indices.clear();
+
+        let [r, g, b, a] = colour;
+        for glyph in &layout.glyphs {
+            let [u0, v0, u1, v1] = glyph.uv;
+            let (x0, y0) = (position.x + glyph.position.x, position.y - glyph.position.y);
+            let (x1, y1) = (x0 + glyph.size.x, y0 - glyph.size.y);
+
+            let first = (self.vertices.len() / VERTEX_LEN) as i32;
+            self.vertices.extend_from_slice(&[
+                x0, y1, 0.0, u0, v1, r, g, b, a,
+                x1, y1, 0.0, u1, v1, r, g, b, a,
+                x1, y0, 0.0, u1, v0, r, g, b, a,
+                x0, y0, 0.0, u0, v0, r, g, b, a
+            ]);
+            self.indices.extend_from_slice(&[
+                first, first + 1, first + 2,
+                first + 2, first + 3, first
+            ]);
+        }
+
+        self.vbo.update(&self.vertices);
+        self.ebo.update(&self.indices);
+
+        self.shader.bind();
+        self.shader.uniform_mat4f("iMVP", mvp);
+        self.shader.uniform_1i("iSdf", (font.data.mode == FontMode::Sdf) as i32);
+        font.texture.bind();
+        self.vao.bind();
+
+        unsafe {
+            gl::DrawElements(gl::TRIANGLES, self.indices.len() as i32, gl::UNSIGNED_INT, std::ptr::null());
+        }
+
+        self.vao.unbind();
+        font.texture.unbind();
+        self.shader.unbind();
+    }
+}
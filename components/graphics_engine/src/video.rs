@@ -0,0 +1,183 @@
+// Plays video entries into a texture. The sound of a video drives its clock while it's playing,
+// so frames are picked by where the sound is and never drift away from it.
+
+use std::time::Duration;
+
+use assets_pipeline::{AssetDatabaseError, AssetEntry, AssetEntryType, AssetPipeline, TextureFilter, TextureSettings, TextureWrap, VideoData, VideoDecoder};
+use audio_engine::{Audio, AudioSystem};
+
+use crate::gl_wrap::Texture2D;
+
+// How far the picture may be off from the sound before it jumps to it
+const MAX_DRIFT_MS: u128 = 50;
+
+// What moving the clock did, update() keeps the sound in line with it
+#[derive(Debug, PartialEq)]
+enum Step {
+    Playing,
+    Looped,  // Started over from the beginning
+    Finished // Stays on the last frame
+}
+
+pub struct VideoPlayer {
+    decoder: VideoDecoder,
+    texture: Texture2D,
+    audio: Option<Audio>,
+
+    elapsed: Duration,
+    playing: bool
+}
+
+impl VideoPlayer {
+    pub fn new<S: AsRef<str>>(key: S, video: VideoData, audio_system: &AudioSystem) -> Result<VideoPlayer, AssetDatabaseError> {
+        let audio = video.audio.clone().map(|audio| audio_system.from_memory(audio));
+
+        let mut decoder = VideoDecoder::new(key, video);
+        let settings = TextureSettings {
+            filter: TextureFilter::Linear,
+            wrap: TextureWrap::Clamp,
+            mipmaps: false,
+            ..TextureSettings::default()
+        };
+
+        let image = decoder.decode(0)?;
+        let texture = Texture2D::with_settings(image, image.width() as i32, image.height() as i32, &settings);
+
+        Ok(VideoPlayer {
+            decoder,
+            texture,
+            audio,
+
+            elapsed: Duration::from_millis(0),
+            playing: false
+        })
+    }
+
+    pub fn load<S: AsRef<str>>(pipeline: &AssetPipeline, key: S, audio_system: &AudioSystem) -> Result<VideoPlayer, AssetDatabaseError> {
+        let entry = pipeline.search(&key)?
+            .ok_or_else(|| AssetDatabaseError::NotFound { key: key.as_ref().to_string() })?;
+
+        if entry.r#type() != AssetEntryType::Video {
+            return Err(AssetDatabaseError::DecodeFailed { key: entry.key() });
+        }
+
+        VideoPlayer::new(entry.key(), AssetEntry::clone(&entry).into_video()?, audio_system)
+    }
+
+    pub fn play(&mut self) {
+        if self.playing {
+            return;
+        }
+
+        if let Some(audio) = &mut self.audio {
+            audio.play();
+        }
+
+        self.playing = true;
+    }
+
+    pub fn pause(&mut self) {
+        if !self.playing {
+            return;
+        }
+
+        // Audio::pause() toggles
+        if let Some(audio) = &mut self.audio {
+            audio.pause();
+        }
+
+        self.playing = false;
+    }
+
+    // Advances the clock by delta and uploads the frame that's visible now
+    pub fn update(&mut self, delta: Duration) -> Result<(), AssetDatabaseError> {
+        if !self.playing {
+            return Ok(());
+        }
+
+        let (elapsed, step) = advance(self.decoder.video(), self.elapsed, delta);
+        self.elapsed = elapsed;
+
+        match step {
+            // The sound starts over with the picture, whether it's shorter or longer
+            Step::Looped => if let Some(audio) = &mut self.audio {
+                audio.stop();
+                audio.play();
+            },
+
+            // A longer sound would keep playing on its own
+            Step::Finished => {
+                if let Some(audio) = &mut self.audio {
+                    audio.stop();
+                }
+
+                self.playing = false;
+            }
+
+            Step::Playing => if let Some(audio) = &self.audio {
+                if audio.is_playing() {
+                    let position = Duration::from_millis(audio.position() as u64);
+                    let drift = std::cmp::max(position, self.elapsed) - std::cmp::min(position, self.elapsed);
+
+                    if drift.as_millis() > MAX_DRIFT_MS {
+                        self.elapsed = position;
+                    }
+                }
+            }
+        }
+
+        if let Some(image) = self.decoder.decode_at(self.elapsed)? {
+            self.texture.update(image);
+        }
+
+        Ok(())
+    }
+
+    pub fn texture(&self) -> &Texture2D {
+        &self.texture
+    }
+
+    pub fn video(&self) -> &VideoData {
+        self.decoder.video()
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.decoder.video().is_finished(self.elapsed)
+    }
+}
+
+// The clock of video after it played for delta more than elapsed
+fn advance(video: &VideoData, elapsed: Duration, delta: Duration) -> (Duration, Step) {
+    let elapsed = elapsed + delta;
+    let duration_ms = video.duration_ms as u128;
+
+    if video.looping && duration_ms > 0 && elapsed.as_millis() >= duration_ms {
+        (Duration::from_millis((elapsed.as_millis() % duration_ms) as u64), Step::Looped)
+    } else if video.is_finished(elapsed) {
+        (elapsed, Step::Finished)
+    } else {
+        (elapsed, Step::Playing)
+    }
+}
+
+#[test]
+fn video_clock_test() {
+    let ms = Duration::from_millis;
+    let mut video = VideoData { width: 1, height: 1, duration_ms: 100, looping: false, frames: Vec::new(), audio: None };
+
+    assert_eq!(advance(&video, ms(50), ms(40)), (ms(90), Step::Playing));
+
+    // update() stops the sound here, it could be longer than the picture
+    assert_eq!(advance(&video, ms(90), ms(20)), (ms(110), Step::Finished));
+
+    video.looping = true;
+    assert_eq!(advance(&video, ms(90), ms(20)), (ms(10), Step::Looped));
+}
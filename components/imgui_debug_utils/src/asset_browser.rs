@@ -1,6 +1,8 @@
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
 
-use assets_pipeline::{AssetPipeline, AssetEntry, AssetEntryType};
+use assets_pipeline::{AssetCache, AssetPipeline, AssetEntry, AssetEntryType, Handle};
 use graphics_engine::gl_wrap::Texture2D;
 
 use imgui::*;
@@ -17,62 +19,73 @@ const PARTICLE_IMG: &[u8] = include_bytes!("../assets/particle.png");
 const VIDEO_IMG: &[u8] = include_bytes!("../assets/video.png");
 
 pub struct AssetBrowser {
-    databases: HashMap<String, Vec<AssetEntry>>,
+    databases: Vec<(String, Vec<Arc<AssetEntry>>)>,
 
     textures: HashMap<AssetEntryType, Texture2D>,
 
-    texture_cache: HashMap<String, Texture2D>,
-    audio_cache: HashMap<String, Audio>
+    sounds: HashMap<String, Handle<Audio>>, // Played from here, kept so they aren't evicted while playing
+
+
+    changes: Receiver<String> // Keys that changed in the asset pipeline
 }
 
 impl AssetBrowser {
+    // Only lists the entries, nothing is loaded until it's needed
     pub fn new(asset_pipeline: &AssetPipeline) -> AssetBrowser {
         AssetBrowser {
-            databases: asset_pipeline.all_databases(),
+            databases: asset_pipeline.database_entries(),
 
             textures: HashMap::new(),
-            texture_cache: HashMap::new(),
-            audio_cache: HashMap::new()
+            sounds: HashMap::new(),
+
+            changes: asset_pipeline.subscribe()
+        }
+    }
+
+    // Refreshes everything that changed since the last call, the asset cache reloads the assets themselves
+    pub fn reload(&mut self, asset_pipeline: &AssetPipeline) {
+        while let Ok(key) = self.changes.try_recv() {
+            let entry = match asset_pipeline.locate(&key) {
+                Some(location) => location.entry().clone(),
+                None => continue
+            };
+
+            for database in &mut self.databases {
+                for cached in &mut database.1 {
+                    if cached.key() == key {
+                        *cached = entry.clone();
+                    }
+                }
+            }
         }
     }
 
-    pub fn init(&mut self, audio_system: &AudioSystem) {
+    pub fn init(&mut self) {
         // TODO: add
         self.textures.insert(AssetEntryType::Shader, Texture2D::from(image::load_from_memory(UNKNOWN_IMG).unwrap().into_rgba()));
+        self.textures.insert(AssetEntryType::Font, Texture2D::from(image::load_from_memory(UNKNOWN_IMG).unwrap().into_rgba()));
+        self.textures.insert(AssetEntryType::StringTable, Texture2D::from(image::load_from_memory(UNKNOWN_IMG).unwrap().into_rgba()));
 
         self.textures.insert(AssetEntryType::Audio, Texture2D::from(image::load_from_memory(AUDIO_IMG).unwrap().into_rgba()));
         self.textures.insert(AssetEntryType::Unknown, Texture2D::from(image::load_from_memory(UNKNOWN_IMG).unwrap().into_rgba()));
         self.textures.insert(AssetEntryType::Texture, Texture2D::from(image::load_from_memory(TEXTURE_IMG).unwrap().into_rgba()));
+        self.textures.insert(AssetEntryType::Sprite, Texture2D::from(image::load_from_memory(TEXTURE_IMG).unwrap().into_rgba()));
         self.textures.insert(AssetEntryType::Particle, Texture2D::from(image::load_from_memory(PARTICLE_IMG).unwrap().into_rgba()));
         self.textures.insert(AssetEntryType::Video, Texture2D::from(image::load_from_memory(VIDEO_IMG).unwrap().into_rgba()));
         self.textures.insert(AssetEntryType::AnimatedTexture, Texture2D::from(image::load_from_memory(ANIMATED_TEXTURE_IMG).unwrap().into_rgba()));
-
-        for database in &self.databases {
-            for entry in database.1 {
-                match entry.r#type() {
-                    AssetEntryType::Texture => {
-                        self.texture_cache.insert(entry.key(), Texture2D::from(entry.clone().into_texture()));
-                    },
-                    AssetEntryType::Audio => {
-                        self.audio_cache.insert(entry.key(), entry.clone().into_audio(audio_system));
-                    },
-
-                    _ => {}
-                };
-            }
-        }
     }
 
-    fn print_tooltip(&self, ui: &Ui, entry: &AssetEntry) {
+    // Textures are only loaded once they're hovered, in the background
+    fn print_tooltip(ui: &Ui, entry: &AssetEntry, asset_pipeline: &AssetPipeline, asset_cache: &mut AssetCache) {
         if ui.is_item_hovered() {
             ui.tooltip(|| {
                 let mut text_width;
 
                 let key = entry.key();
-                let size_in_memory = bytesize::to_string(entry.raw_data().len() as u64, false);
+                let size = bytesize::to_string(entry.size() as u64, false);
 
                 let im_entry_key = ImString::new(&key);
-                let im_size_in_memory = ImString::new(size_in_memory);
+                let im_size = ImString::new(size);
                 let im_type = ImString::new(format!("{:?}", entry.r#type()));
 
                 text_width = ui.calc_text_size(&im_entry_key, false, 0.0)[0];
@@ -80,10 +93,10 @@ impl AssetBrowser {
                 ui.same_line(200.0 - text_width);
                 ui.text(&im_entry_key);
 
-                text_width = ui.calc_text_size(&im_size_in_memory, false, 0.0)[0];
-                ui.text(im_str!("Size In Memory:"));
+                text_width = ui.calc_text_size(&im_size, false, 0.0)[0];
+                ui.text(im_str!("Size:"));
                 ui.same_line(200.0 - text_width);
-                ui.text(&im_size_in_memory);
+                ui.text(&im_size);
 
                 text_width = ui.calc_text_size(&im_type, false, 0.0)[0];
                 ui.text(im_str!("Type:"));
@@ -92,7 +105,16 @@ impl AssetBrowser {
 
                 match entry.r#type() {
                     AssetEntryType::Texture => {
-                        let tex = self.texture_cache.get(&entry.key()).unwrap();
+                        let handle = match asset_cache.load_async::<Texture2D, _>(asset_pipeline, &key) {
+                            Ok(handle) => handle,
+                            Err(_) => return // Removed, reload() catches up with it
+                        };
+
+                        // Pending, or it failed and process() reported why already
+                        let tex = match handle.get() {
+                            Some(tex) => tex,
+                            None => return
+                        };
 
                         let field_aspect = 96.0 / 96.0;
                         let original_aspect = tex.width() as f32 / tex.height() as f32;
@@ -116,7 +138,7 @@ impl AssetBrowser {
         }
     }
 
-    pub fn update(&mut self, ui: &Ui) {
+    pub fn update(&mut self, ui: &Ui, asset_pipeline: &AssetPipeline, asset_cache: &mut AssetCache, audio_system: &AudioSystem) {
         let wnd_size = ui.window_size();
 
         Window::new(im_str!("Asset Browser"))
@@ -134,7 +156,7 @@ impl AssetBrowser {
                         let mut off = 0.0;
 
                         for database in &self.databases {
-                            ui.text(ImString::new(database.0));
+                            ui.text(ImString::new(&database.0));
 
                             if ui.is_item_hovered() {
                                 ui.set_mouse_cursor(Some(MouseCursor::Hand))
@@ -142,7 +164,7 @@ impl AssetBrowser {
 
                             off += 10.0;
 
-                            for entry in database.1 {
+                            for entry in &database.1 {
                                 let cursor_pos = ui.cursor_pos();
                                 ui.set_cursor_pos([cursor_pos[0] + off, cursor_pos[1]]);
 
@@ -155,7 +177,7 @@ impl AssetBrowser {
 
                                 ui.text(ImString::new(entry.key()));
 
-                                self.print_tooltip(ui, &entry);
+                                AssetBrowser::print_tooltip(ui, &entry, asset_pipeline, asset_cache);
                             }
                         }
                     });
@@ -205,16 +227,22 @@ impl AssetBrowser {
                                         }
 
                                         if ui.is_mouse_clicked(MouseButton::Left) && ui.is_item_hovered() {
-                                            let audio = self.audio_cache.get_mut(&entry.key()).unwrap();
+                                            match asset_cache.load::<Audio, _>(asset_pipeline, entry.key(), audio_system) {
+                                                Ok(audio) => {
+                                                    audio.lock().pause();
+
+                                                    self.sounds.insert(entry.key(), audio);
+                                                }
 
-                                            audio.pause();
+                                                Err(err) => log::error!("{}", err)
+                                            }
                                         }
                                     }
 
                                     _ => {}
                                 }
 
-                                self.print_tooltip(ui, entry);
+                                AssetBrowser::print_tooltip(ui, entry, asset_pipeline, asset_cache);
                             }
                         }
                     });
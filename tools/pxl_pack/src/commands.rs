@@ -0,0 +1,690 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use clap::ArgMatches;
+use image::GenericImageView;
+use serde::Serialize;
+
+use assets_pipeline::{AssetDatabase, AssetDatabaseError, AssetEntry, AssetEntryType, AssetPipeline, AudioFormat, CompileOptions, DependencyGraph, VideoDecoder};
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum AddMode {
+    Add,     // The key must not exist yet
+    Replace  // The key must exist already
+}
+
+#[derive(Serialize)]
+struct EntryInfo {
+    key: String,
+    #[serde(rename = "type")]
+    entry_type: String,
+    codec: &'static str,
+    size: usize,        // Decoded
+    stored_size: usize, // As it's stored in the archive
+    tags: Vec<String>   // From the .meta file of the asset
+}
+
+#[derive(Serialize)]
+struct ExtractedEntry {
+    key: String,
+    path: PathBuf
+}
+
+#[derive(Serialize)]
+struct WrittenArchive {
+    path: PathBuf,
+    entries: usize,
+    size: usize,
+    deduplicated: usize, // Bytes not stored because entries share their data
+    signed: bool
+}
+
+impl WrittenArchive {
+    fn print(&self) {
+        let mut line = format!("Wrote {} ({} entries, {}", self.path.display(), self.entries, bytesize::to_string(self.size as u64, false));
+        if self.deduplicated > 0 {
+            line += &format!(", {} saved by deduplication", bytesize::to_string(self.deduplicated as u64, false));
+        }
+
+        println!("{})", line);
+    }
+}
+
+#[derive(Serialize)]
+struct EntryError {
+    key: String,
+    error: String
+}
+
+#[derive(Serialize)]
+struct ListReport {
+    entries: Vec<EntryInfo>,
+    errors: Vec<EntryError>
+}
+
+#[derive(Serialize)]
+struct VerifyReport {
+    archive: PathBuf,
+    archive_hash_ok: bool,
+    signed: bool,
+    signature_ok: Option<bool>, // None if we don't have a public key to check against
+    errors: Vec<EntryError>
+}
+
+#[derive(Serialize)]
+struct ChangedEntry {
+    key: String,
+    reason: String
+}
+
+#[derive(Serialize)]
+struct DiffReport {
+    added: Vec<String>,
+    removed: Vec<String>,
+    changed: Vec<ChangedEntry>
+}
+
+#[derive(Serialize)]
+struct EntryDependencies {
+    key: String,
+    dependencies: Vec<String>, // What it references and includes
+    dependents: Vec<String>    // What references or includes it
+}
+
+#[derive(Serialize)]
+struct DanglingReference {
+    key: String,
+    reference: String
+}
+
+pub fn list(args: &ArgMatches, json: bool) -> Result<bool, AssetDatabaseError> {
+    let db = AssetDatabase::open(args.value_of("archive").unwrap())?;
+
+    // An entry that doesn't load is reported, the rest are still listed
+    let mut infos = Vec::new();
+    let mut errors = Vec::new();
+    for entry in db.iter() {
+        let loaded = match db.load_entry(entry) {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                errors.push(EntryError { key: entry.key(), error: err.to_string() });
+                continue;
+            }
+        };
+
+        infos.push(EntryInfo {
+            key: entry.key(),
+            entry_type: format!("{:?}", entry.r#type()),
+            codec: entry.codec().name(),
+            size: loaded.raw_data().len(),
+            stored_size: entry.size(),
+            tags: loaded.metadata().tags.clone()
+        });
+    }
+
+    let ok = errors.is_empty();
+
+    if json {
+        print_json(&ListReport { entries: infos, errors });
+        return Ok(ok);
+    }
+
+    println!("{:<16} {:<5} {:>10} {:>10}  Key", "Type", "Codec", "Size", "Stored");
+    for info in &infos {
+        let tags = if info.tags.is_empty() { String::new() } else { format!(" [{}]", info.tags.join(", ")) };
+
+        println!("{:<16} {:<5} {:>10} {:>10}  {}{}", info.entry_type, info.codec,
+                 bytesize::to_string(info.size as u64, false),
+                 bytesize::to_string(info.stored_size as u64, false),
+                 info.key, tags);
+    }
+
+    // Entries that share a data row only take up space once. Entries that didn't load
+    // aren't listed but may still share a row, so this is only an estimate then.
+    let size: usize = infos.iter().map(|info| info.size).sum();
+    let stored_size: usize = infos.iter().map(|info| info.stored_size).sum::<usize>().saturating_sub(db.deduplicated_size());
+    print!("{} entries, {} stored as {}", infos.len(),
+           bytesize::to_string(size as u64, false), bytesize::to_string(stored_size as u64, false));
+
+    if db.deduplicated_size() > 0 {
+        print!(" ({} saved by deduplication)", bytesize::to_string(db.deduplicated_size() as u64, false));
+    }
+    println!();
+
+    if !db.content_version().is_empty() {
+        println!("Content version {}", db.content_version());
+    }
+
+    if db.is_patch() {
+        let base_versions = if db.base_versions().is_empty() { "any version".to_string() } else { db.base_versions().join(", ") };
+
+        println!("Patch for {}, removes {} keys", base_versions, db.tombstones().len());
+        for key in db.tombstones() {
+            println!("  - {}", key);
+        }
+    }
+
+    for error in &errors {
+        println!("{}: {}", error.key, error.error);
+    }
+
+    Ok(ok)
+}
+
+pub fn extract(args: &ArgMatches, json: bool) -> Result<bool, AssetDatabaseError> {
+    let db = AssetDatabase::open(args.value_of("archive").unwrap())?;
+    let output = Path::new(args.value_of("output").unwrap());
+
+    let keys: Vec<String> = match args.values_of("keys") {
+        Some(keys) => keys.map(|key| key.to_string()).collect(),
+        None => db.iter().map(|entry| entry.key()).collect() // --all
+    };
+
+    let mut extracted = Vec::new();
+    for key in keys {
+        let entry = db.get_entry(&key)?.ok_or_else(|| not_found(&key))?;
+        let path = extract_entry(&db, AssetEntry::clone(&entry), output)?;
+
+        if !json {
+            println!("{} -> {}", key, path.display());
+        }
+
+        extracted.push(ExtractedEntry { key, path });
+    }
+
+    if json {
+        print_json(&extracted);
+    }
+
+    Ok(true)
+}
+
+// Textures, sprites and the atlases of fonts become PNGs, animations and videos a folder of PNGs and shaders their source.
+// Everything else is written the way it's stored, with .bin appended if it's not a file type of its own.
+fn extract_entry(db: &AssetDatabase, entry: AssetEntry, output: &Path) -> Result<PathBuf, AssetDatabaseError> {
+    let key = entry.key();
+
+    // Keys come from the archive, a mod could try to write outside of the output folder
+    let relative = Path::new(&key);
+    if !relative.components().all(|component| matches!(component, std::path::Component::Normal(_))) {
+        return Err(AssetDatabaseError::InvalidKey);
+    }
+
+    let mut path = output.join(relative);
+    std::fs::create_dir_all(path.parent().unwrap_or(output))?;
+
+    match entry.r#type() {
+        AssetEntryType::Texture => {
+            if path.extension().and_then(|ext| ext.to_str()) != Some("png") {
+                path = append_extension(&path, "png");
+            }
+
+            entry.into_texture()?.save(&path).map_err(image_error)?;
+        }
+
+        // Cut out of its atlas page, which is in the same archive
+        AssetEntryType::Sprite => {
+            if path.extension().and_then(|ext| ext.to_str()) != Some("png") {
+                path = append_extension(&path, "png");
+            }
+
+            let sprite = entry.into_sprite()?;
+            let page = db.get_entry(&sprite.page)?.ok_or_else(|| not_found(&sprite.page))?;
+            if page.r#type() != AssetEntryType::Texture {
+                return Err(AssetDatabaseError::DecodeFailed { key });
+            }
+
+            let page = AssetEntry::clone(&page).into_texture()?;
+            let rect = sprite.rect;
+            if rect.x as u64 + rect.width as u64 > page.width() as u64 || rect.y as u64 + rect.height as u64 > page.height() as u64 {
+                return Err(AssetDatabaseError::DecodeFailed { key });
+            }
+
+            page.view(rect.x, rect.y, rect.width, rect.height).to_image().save(&path).map_err(image_error)?;
+        }
+
+        AssetEntryType::AnimatedTexture => {
+            path = append_extension(&path, "frames");
+            std::fs::create_dir_all(&path)?;
+
+            for (i, frame) in entry.into_animated_texture()?.frames.iter().enumerate() {
+                frame.image.save(path.join(format!("{:04}.png", i))).map_err(image_error)?;
+            }
+        }
+
+        // Frames the way a .video file imports them, with the sound next to them
+        AssetEntryType::Video => {
+            path = append_extension(&path, "frames");
+            std::fs::create_dir_all(&path)?;
+
+            let video = entry.into_video()?;
+            if let Some(audio) = video.audio_file() {
+                let extension = match video.audio_info().map(|info| info.format.extension()) {
+                    Some(extension) if !extension.is_empty() => extension,
+                    _ => "bin"
+                };
+
+                std::fs::write(path.join(format!("audio.{}", extension)), audio)?;
+            }
+
+            let mut decoder = VideoDecoder::new(&key, video);
+            let mut i = 0;
+            while let Some((_, image)) = decoder.next_frame()? {
+                image.save(path.join(format!("{:04}.png", i))).map_err(image_error)?;
+                i += 1;
+            }
+        }
+
+        AssetEntryType::Shader => std::fs::write(&path, entry.into_shader()?.source)?,
+        AssetEntryType::Audio => std::fs::write(&path, entry.audio_file())?,
+
+        AssetEntryType::Font => {
+            path = append_extension(&path, "png");
+            entry.into_font()?.atlas.to_image().save(&path).map_err(image_error)?;
+        }
+
+        _ => {
+            path = append_extension(&path, "bin");
+            std::fs::write(&path, entry.raw_data())?;
+        }
+    }
+
+    Ok(path)
+}
+
+pub fn add(args: &ArgMatches, json: bool, mode: AddMode) -> Result<bool, AssetDatabaseError> {
+    let archive = Path::new(args.value_of("archive").unwrap());
+    // Keys are made by stripping root, that only works if both are written the same way
+    let root = Path::new(args.value_of("root").unwrap()).canonicalize()?;
+
+    // Adding to an archive that doesn't exist yet creates it
+    let mut db = if mode == AddMode::Add && !archive.exists() {
+        AssetDatabase::new()
+    } else {
+        AssetDatabase::from_bytes(std::fs::read(archive)?)?
+    };
+
+    for file in args.values_of("files").unwrap() {
+        let file = Path::new(file).canonicalize()?;
+
+        let entry = AssetPipeline::import_file(&root, &file, &CompileOptions::default())?
+            .ok_or_else(|| AssetDatabaseError::ParseFailed {
+                path: file.clone(),
+                message: "unknown file type".to_string()
+            })?;
+
+        let key = entry.key();
+        match (mode, db.entry(&key).is_some()) {
+            (AddMode::Add, true) => return Err(AssetDatabaseError::DuplicateKey {
+                key,
+                first: archive.to_path_buf(),
+                second: file
+            }),
+            (AddMode::Replace, false) => return Err(not_found(&key)),
+
+            _ => {}
+        }
+
+        if !json {
+            println!("{} {}", if mode == AddMode::Add { "Added" } else { "Replaced" }, key);
+        }
+
+        db.push_entry(entry)?;
+    }
+
+    write_archive(&mut db, archive, args.value_of("signing_key"), json)
+}
+
+pub fn remove(args: &ArgMatches, json: bool) -> Result<bool, AssetDatabaseError> {
+    let archive = Path::new(args.value_of("archive").unwrap());
+    let mut db = AssetDatabase::from_bytes(std::fs::read(archive)?)?;
+
+    for key in args.values_of("keys").unwrap() {
+        db.remove_entry(key).ok_or_else(|| not_found(key))?;
+
+        if !json {
+            println!("Removed {}", key);
+        }
+    }
+
+    write_archive(&mut db, archive, args.value_of("signing_key"), json)
+}
+
+pub fn pack(args: &ArgMatches, json: bool) -> Result<bool, AssetDatabaseError> {
+    let options = compile_options(args);
+
+    let dir = Path::new(args.value_of("dir").unwrap()).canonicalize()?;
+    let databases = AssetPipeline::compile_folder_with(&dir, &options)?;
+
+    for (key, missing) in AssetPipeline::missing_strings(&databases)? {
+        eprintln!("warning: {} is missing {}", key, missing.join(", "));
+    }
+
+    for orphan in AssetPipeline::build_report(&dir, &databases)?.orphans {
+        eprintln!("warning: {} isn't used by any asset", orphan);
+    }
+
+    write_archives(databases, args, json)
+}
+
+// Compiles the folder and writes what changed compared to the base archives
+pub fn patch(args: &ArgMatches, json: bool) -> Result<bool, AssetDatabaseError> {
+    let options = compile_options(args);
+
+    let mut base = Vec::new();
+    for path in args.values_of("base").unwrap() {
+        base.push(AssetDatabase::open(path)?);
+    }
+
+    if let Some(patch) = base.iter().find(|db| db.is_patch()) {
+        eprintln!("warning: a base archive is a patch itself ({})", patch.base_versions().join(", "));
+    }
+
+    let dir = Path::new(args.value_of("dir").unwrap()).canonicalize()?;
+    let target = AssetPipeline::compile_folder_with(dir, &options)?;
+    let patches = AssetPipeline::compile_patch(&base, &target, &options)?;
+
+    if !json {
+        let removed = patches.iter().map(|patch| patch.tombstones().len()).sum::<usize>();
+        println!("Patching {} to {}, {} entries removed", patches[0].base_versions().join(", "), options.content_version, removed);
+    }
+
+    write_archives(patches, args, json)
+}
+
+fn compile_options(args: &ArgMatches) -> CompileOptions {
+    CompileOptions {
+        cache_dir: args.value_of("cache").map(PathBuf::from),
+        content_version: args.value_of("content_version").unwrap_or("").to_string(),
+        audio_format: args.value_of("audio_format").map(AudioFormat::from_extension),
+        audio_loudness: args.value_of("loudness").map(|lufs| lufs.parse().unwrap()), // Checked by the validator
+        ..CompileOptions::default()
+    }
+}
+
+// Writes <output>-0000.pxl, <output>-0001.pxl, ...
+fn write_archives(databases: Vec<AssetDatabase>, args: &ArgMatches, json: bool) -> Result<bool, AssetDatabaseError> {
+    let signing_key = read_signing_key(args.value_of("signing_key"))?;
+
+    let mut written = Vec::new();
+    for (i, mut db) in databases.into_iter().enumerate() {
+        let path = PathBuf::from(format!("{}-{:04}.pxl", args.value_of("output").unwrap(), i));
+
+        let bytes = match &signing_key {
+            Some(signing_key) => db.to_signed_bytes(signing_key)?,
+            None => db.to_bytes()?
+        };
+        std::fs::write(&path, &bytes)?;
+
+        let archive = WrittenArchive {
+            path,
+            entries: db.iter().len(),
+            size: bytes.len(),
+            deduplicated: db.deduplicated_size(),
+            signed: signing_key.is_some()
+        };
+
+        if !json {
+            archive.print();
+        }
+
+        written.push(archive);
+    }
+
+    if json {
+        print_json(&written);
+    }
+
+    Ok(true)
+}
+
+pub fn verify(args: &ArgMatches, json: bool) -> Result<bool, AssetDatabaseError> {
+    let archive = PathBuf::from(args.value_of("archive").unwrap());
+    let db = AssetDatabase::open(&archive)?;
+
+    // verify() checks the archive hash before any of the entries
+    let archive_hash_ok = !matches!(db.verify(), Err(AssetDatabaseError::ArchiveHashMismatch));
+
+    // Loading checks the checksum and that the data row decompresses
+    let mut errors = Vec::new();
+    for entry in db.iter() {
+        if let Err(err) = db.load_entry(entry) {
+            errors.push(EntryError { key: entry.key(), error: err.to_string() });
+        }
+    }
+
+    let public_key = match args.value_of("public_key").or(assets_pipeline::OFFICIAL_PUBLIC_KEY) {
+        Some(public_key) => Some(hex::decode(public_key).map_err(|_| AssetDatabaseError::InvalidKeyPair)?),
+        None => None
+    };
+
+    let signature_ok = match &public_key {
+        Some(public_key) if db.is_signed() => match db.verify_signature(public_key) {
+            Err(AssetDatabaseError::ArchiveHashMismatch) => Some(false),
+            result => Some(result?)
+        },
+        _ => None
+    };
+
+    let report = VerifyReport {
+        archive,
+        archive_hash_ok,
+        signed: db.is_signed(),
+        signature_ok,
+        errors
+    };
+
+    let ok = report.archive_hash_ok && report.errors.is_empty() && report.signature_ok != Some(false);
+
+    if json {
+        print_json(&report);
+        return Ok(ok);
+    }
+
+    if !report.archive_hash_ok {
+        println!("Archive hash doesn't match, the archive is damaged or was tampered with");
+    }
+
+    match (report.signed, report.signature_ok) {
+        (false, _) => println!("Archive is not signed"),
+        (true, None) => println!("Archive is signed, no public key to check it against"),
+        (true, Some(true)) => println!("Signature is valid"),
+        (true, Some(false)) => println!("Signature is NOT valid")
+    }
+
+    for error in &report.errors {
+        println!("{}: {}", error.key, error.error);
+    }
+
+    println!("{}", if ok { "OK" } else { "FAILED" });
+
+    Ok(ok)
+}
+
+// What the given keys need and what needs them, without keys every reference that doesn't resolve
+pub fn deps(args: &ArgMatches, json: bool) -> Result<bool, AssetDatabaseError> {
+    let mut databases = Vec::new();
+    for path in args.values_of("archives").unwrap() {
+        databases.push(AssetDatabase::open(path)?);
+    }
+
+    let graph = DependencyGraph::from_databases(&databases);
+
+    let keys = match args.values_of("keys") {
+        Some(keys) => keys.collect::<Vec<_>>(),
+        None => {
+            let dangling = graph.dangling().into_iter()
+                .map(|(key, reference)| DanglingReference { key: key.to_string(), reference: reference.to_string() })
+                .collect::<Vec<_>>();
+
+            if json {
+                print_json(&dangling);
+            } else {
+                for reference in &dangling {
+                    println!("{} references {}, which doesn't exist", reference.key, reference.reference);
+                }
+
+                println!("{} dangling references", dangling.len());
+            }
+
+            return Ok(dangling.is_empty());
+        }
+    };
+
+    let mut entries = Vec::new();
+    for key in keys {
+        if !graph.is_used(key) {
+            return Err(not_found(key));
+        }
+
+        entries.push(EntryDependencies {
+            key: key.to_string(),
+            dependencies: graph.dependencies(key).into_iter().map(String::from).collect(),
+            dependents: graph.dependents(key).into_iter().map(String::from).collect()
+        });
+    }
+
+    if json {
+        print_json(&entries);
+        return Ok(true);
+    }
+
+    for entry in &entries {
+        println!("{}", entry.key);
+        for dependency in &entry.dependencies {
+            println!("  needs {}", dependency);
+        }
+        for dependent in &entry.dependents {
+            println!("  needed by {}", dependent);
+        }
+    }
+
+    Ok(true)
+}
+
+pub fn diff(args: &ArgMatches, json: bool) -> Result<bool, AssetDatabaseError> {
+    let a = AssetDatabase::open(args.value_of("a").unwrap())?;
+    let b = AssetDatabase::open(args.value_of("b").unwrap())?;
+
+    let mut report = DiffReport {
+        added: Vec::new(),
+        removed: Vec::new(),
+        changed: Vec::new()
+    };
+
+    for entry in a.iter() {
+        let other = match b.entry(entry.key()) {
+            Some(other) => other,
+            None => {
+                report.removed.push(entry.key());
+                continue;
+            }
+        };
+
+        // Import settings count as a change, the same way compile_patch sees them.
+        // An entry that doesn't load is reported as changed, the rest are still compared.
+        let reason = if entry.r#type() != other.r#type() {
+            Some(format!("type {:?} -> {:?}", entry.r#type(), other.r#type()))
+        } else {
+            match (a.load_entry(entry), b.load_entry(other)) {
+                (Err(err), _) | (_, Err(err)) => Some(format!("doesn't load: {}", err)),
+                (Ok(loaded), Ok(other_loaded)) if loaded.raw_data() != other_loaded.raw_data() => Some("content".to_string()),
+
+                _ if entry.metadata() != other.metadata() => Some("metadata".to_string()),
+                _ if entry.codec() != other.codec() => Some(format!("codec {} -> {}", entry.codec().name(), other.codec().name())),
+                _ => None
+            }
+        };
+
+        if let Some(reason) = reason {
+            report.changed.push(ChangedEntry { key: entry.key(), reason });
+        }
+    }
+
+    let keys: HashSet<String> = a.iter().map(|entry| entry.key()).collect();
+    report.added = b.iter()
+        .map(|entry| entry.key())
+        .filter(|key| !keys.contains(key))
+        .collect();
+
+    let identical = report.added.is_empty() && report.removed.is_empty() && report.changed.is_empty();
+
+    if json {
+        print_json(&report);
+        return Ok(identical);
+    }
+
+    for key in &report.added {
+        println!("+ {}", key);
+    }
+    for key in &report.removed {
+        println!("- {}", key);
+    }
+    for changed in &report.changed {
+        println!("~ {} ({})", changed.key, changed.reason);
+    }
+
+    Ok(identical)
+}
+
+// Written next to the archive first, so a failed write doesn't destroy it
+fn write_archive(db: &mut AssetDatabase, path: &Path, signing_key: Option<&str>, json: bool) -> Result<bool, AssetDatabaseError> {
+    let signing_key = read_signing_key(signing_key)?;
+    if db.is_signed() && signing_key.is_none() {
+        eprintln!("warning: {} was signed, it's written unsigned now", path.display());
+    }
+
+    let bytes = match &signing_key {
+        Some(signing_key) => db.to_signed_bytes(signing_key)?,
+        None => db.to_bytes()?
+    };
+
+    let tmp_path = append_extension(path, "tmp");
+    std::fs::write(&tmp_path, &bytes)?;
+    std::fs::rename(&tmp_path, path)?;
+
+    let written = WrittenArchive {
+        path: path.to_path_buf(),
+        entries: db.iter().len(),
+        size: bytes.len(),
+        deduplicated: db.deduplicated_size(),
+        signed: signing_key.is_some()
+    };
+
+    if json {
+        print_json(&written);
+    } else {
+        written.print();
+    }
+
+    Ok(true)
+}
+
+fn read_signing_key(path: Option<&str>) -> Result<Option<Vec<u8>>, AssetDatabaseError> {
+    match path {
+        Some(path) => Ok(Some(std::fs::read(path)?)),
+        None => Ok(None)
+    }
+}
+
+fn append_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+
+    PathBuf::from(path)
+}
+
+fn not_found(key: &str) -> AssetDatabaseError {
+    AssetDatabaseError::NotFound { key: key.to_string() }
+}
+
+fn image_error(err: image::ImageError) -> AssetDatabaseError {
+    AssetDatabaseError::Io(io::Error::new(io::ErrorKind::Other, err.to_string()))
+}
+
+fn print_json<T: Serialize>(value: &T) {
+    // Our reports only contain strings and numbers, serializing them can't fail
+    println!("{}", serde_json::to_string_pretty(value).unwrap());
+}
@@ -0,0 +1,249 @@
+// pxl-pack, inspects and edits .pxl archives without building the game.
+//
+//   pxl-pack list assets-0000.pxl
+//   pxl-pack extract assets-0000.pxl textures/world.png -o out
+//   pxl-pack add mod.pxl textures/world.png --root my_mod
+//   pxl-pack pack my_mod -o mod
+//   pxl-pack patch assets --base assets-*.pxl --content-version 1.1.0 -o assets-patch-1.1.0
+//   pxl-pack diff old.pxl new.pxl --json
+//   pxl-pack deps assets-*.pxl --key textures/particles/spark.png
+//
+// Every command takes --json for scripting, commands that change an archive write it unsigned
+// unless --signing-key is given.
+
+mod commands;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use assets_pipeline::AssetDatabaseError;
+
+fn main() {
+    match run(&app().get_matches()) {
+        Ok(true) => {},
+        Ok(false) => std::process::exit(1), // verify found problems, diff differences or deps dangling references
+
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(2);
+        }
+    }
+}
+
+fn app() -> App<'static, 'static> {
+    App::new("pxl-pack")
+        .version(clap::crate_version!())
+        .about("Inspects and edits .pxl asset archives")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(Arg::with_name("json")
+            .long("json")
+            .global(true)
+            .help("Prints machine readable JSON instead of text"))
+
+        .subcommand(SubCommand::with_name("list")
+            .about("Lists every entry with its type, codec and size")
+            .arg(archive_arg()))
+
+        .subcommand(SubCommand::with_name("extract")
+            .about("Writes entries to disk, textures are converted to PNG")
+            .arg(archive_arg())
+            .arg(Arg::with_name("keys")
+                .multiple(true)
+                .required_unless("all"))
+            .arg(Arg::with_name("all")
+                .long("all")
+                .conflicts_with("keys")
+                .help("Extracts every entry"))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .default_value(".")
+                .help("Folder the entries are written to")))
+
+        .subcommand(SubCommand::with_name("add")
+            .about("Adds files to an archive, fails if a key exists already")
+            .arg(archive_arg())
+            .args(&file_args()))
+
+        .subcommand(SubCommand::with_name("replace")
+            .about("Replaces entries of an archive, fails if a key doesn't exist yet")
+            .arg(archive_arg())
+            .args(&file_args()))
+
+        .subcommand(SubCommand::with_name("remove")
+            .about("Removes entries from an archive")
+            .arg(archive_arg())
+            .arg(Arg::with_name("keys")
+                .multiple(true)
+                .required(true))
+            .arg(signing_key_arg()))
+
+        .subcommand(SubCommand::with_name("pack")
+            .about("Compiles a folder into archives, the same way the game build does")
+            .args(&compile_args("assets")))
+
+        .subcommand(SubCommand::with_name("patch")
+            .about("Compiles a folder into patch archives holding only what changed since the base archives")
+            .arg(Arg::with_name("base")
+                .long("base")
+                .takes_value(true)
+                .multiple(true)
+                .required(true)
+                .help("Archives of the version the patch applies to"))
+            .args(&compile_args("assets-patch")))
+
+        .subcommand(SubCommand::with_name("verify")
+            .about("Checks the archive hash, every checksum and that every entry decodes")
+            .arg(archive_arg())
+            .arg(Arg::with_name("public_key")
+                .long("public-key")
+                .takes_value(true)
+                .help("Hex encoded ed25519 public key the archive should be signed with")))
+
+        .subcommand(SubCommand::with_name("deps")
+            .about("Shows what entries need and what needs them, without --key every reference that doesn't resolve")
+            .arg(Arg::with_name("archives")
+                .multiple(true)
+                .required(true)
+                .help("Archives of one build, later ones replace entries of earlier ones"))
+            .arg(Arg::with_name("keys")
+                .long("key")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)))
+
+        .subcommand(SubCommand::with_name("diff")
+            .about("Compares the entries of two archives")
+            .arg(Arg::with_name("a")
+                .required(true))
+            .arg(Arg::with_name("b")
+                .required(true)))
+}
+
+fn run(matches: &ArgMatches) -> Result<bool, AssetDatabaseError> {
+    let json = matches.is_present("json");
+
+    match matches.subcommand() {
+        ("list", Some(args)) => commands::list(args, json),
+        ("extract", Some(args)) => commands::extract(args, json),
+        ("add", Some(args)) => commands::add(args, json, commands::AddMode::Add),
+        ("replace", Some(args)) => commands::add(args, json, commands::AddMode::Replace),
+        ("remove", Some(args)) => commands::remove(args, json),
+        ("pack", Some(args)) => commands::pack(args, json),
+        ("patch", Some(args)) => commands::patch(args, json),
+        ("verify", Some(args)) => commands::verify(args, json),
+        ("deps", Some(args)) => commands::deps(args, json),
+        ("diff", Some(args)) => commands::diff(args, json),
+
+        _ => unreachable!() // SubcommandRequiredElseHelp
+    }
+}
+
+fn archive_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("archive")
+        .required(true)
+        .help("Path to the .pxl archive")
+}
+
+fn signing_key_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("signing_key")
+        .long("signing-key")
+        .takes_value(true)
+        .help("File containing the raw 32 byte ed25519 secret key the archive is signed with")
+}
+
+fn compile_args<'a, 'b>(default_output: &'a str) -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("dir")
+            .required(true),
+        Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .takes_value(true)
+            .default_value(default_output)
+            .help("Archives are written to <output>-0000.pxl, <output>-0001.pxl, ..."),
+        Arg::with_name("cache")
+            .long("cache")
+            .takes_value(true)
+            .help("Build cache folder, unchanged files are reused from there"),
+        Arg::with_name("content_version")
+            .long("content-version")
+            .takes_value(true)
+            .help("Version stored in the archives, patches name the versions they apply to"),
+        Arg::with_name("audio_format")
+            .long("audio-format")
+            .takes_value(true)
+            .possible_values(&["wav", "flac"])
+            .help("Transcodes WAV and FLAC sources to this format"),
+        Arg::with_name("loudness")
+            .long("loudness")
+            .takes_value(true)
+            .allow_hyphen_values(true)
+            .validator(|value| value.parse::<f32>().map(|_| ()).map_err(|err| err.to_string()))
+            .help("Normalizes WAV and FLAC sources to this loudness in LUFS, E.G -16"),
+        signing_key_arg()
+    ]
+}
+
+fn file_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("files")
+            .multiple(true)
+            .required(true),
+        Arg::with_name("root")
+            .long("root")
+            .takes_value(true)
+            .default_value(".")
+            .help("Keys are the file paths relative to this folder"),
+        signing_key_arg()
+    ]
+}
+
+#[test]
+fn pxl_pack_test() {
+    let root = std::env::temp_dir().join(format!("pxl_pack_test_{}", std::process::id()));
+    let assets = root.join("assets");
+    std::fs::create_dir_all(assets.join("textures")).unwrap();
+
+    let grass = image::RgbaImage::from_pixel(8, 4, image::Rgba([0, 255, 0, 255]));
+    grass.save(assets.join("textures/grass.png")).unwrap();
+    image::RgbaImage::from_pixel(4, 4, image::Rgba([255, 0, 0, 255])).save(assets.join("textures/stone.png")).unwrap();
+
+    let path = |path: &str| root.join(path).to_str().unwrap().to_string();
+    let pxl_pack = |args: &[&str]| {
+        let matches = app().get_matches_from_safe(std::iter::once("pxl-pack").chain(args.iter().copied())).unwrap();
+        run(&matches)
+    };
+
+    let archive = path("out-0000.pxl");
+    assert!(pxl_pack(&["pack", &path("assets"), "-o", &path("out")]).unwrap());
+    assert!(pxl_pack(&["list", &archive]).unwrap());
+    std::fs::copy(&archive, path("old.pxl")).unwrap();
+
+    // Both are small enough for the atlas, the sprite comes out the way it went in
+    assert!(pxl_pack(&["extract", &archive, "textures/grass.png", "-o", &path("extracted")]).unwrap());
+    assert_eq!(image::open(path("extracted/textures/grass.png")).unwrap().into_rgba(), grass);
+    assert!(matches!(pxl_pack(&["extract", &archive, "textures/missing.png"]), Err(AssetDatabaseError::NotFound { .. })));
+
+    let wood = assets.join("textures/wood.png");
+    image::RgbaImage::from_pixel(300, 2, image::Rgba([128, 64, 0, 255])).save(&wood).unwrap();
+    let wood = wood.to_str().unwrap();
+
+    assert!(pxl_pack(&["add", &archive, wood, "--root", &path("assets")]).unwrap());
+    assert!(matches!(pxl_pack(&["add", &archive, wood, "--root", &path("assets")]), Err(AssetDatabaseError::DuplicateKey { .. })));
+    assert!(!pxl_pack(&["diff", &path("old.pxl"), &archive]).unwrap());
+
+    image::RgbaImage::from_pixel(300, 3, image::Rgba([128, 64, 0, 255])).save(wood).unwrap();
+    assert!(pxl_pack(&["replace", &archive, wood, "--root", &path("assets")]).unwrap());
+    assert!(pxl_pack(&["extract", &archive, "textures/wood.png", "-o", &path("extracted")]).unwrap());
+    assert_eq!(image::image_dimensions(path("extracted/textures/wood.png")).unwrap(), (300, 3));
+
+    assert!(pxl_pack(&["remove", &archive, "textures/wood.png"]).unwrap());
+    assert!(matches!(pxl_pack(&["remove", &archive, "textures/wood.png"]), Err(AssetDatabaseError::NotFound { .. })));
+
+    // Back to what it was packed as
+    assert!(pxl_pack(&["diff", &path("old.pxl"), &archive]).unwrap());
+    assert!(pxl_pack(&["verify", &archive]).unwrap());
+
+    std::fs::remove_dir_all(&root).unwrap();
+}